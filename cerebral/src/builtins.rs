@@ -0,0 +1,365 @@
+//! Functions implemented by the interpreter. Each one is declared here once with
+//! its type and implementation, the type checker gets the types from `imports`.
+//!
+//! The I/O builtins are always declared, so a program type-checks the same either way,
+//! but they fail when called unless I/O is allowed by the `Capabilities` of the host.
+
+use {
+    crate::interpret::{host_call, HostCall, HostFn, IntrpErr, Value},
+    clog::types::{
+        Type, LIST_CONS, LIST_NIL, LIST_TYPE, OPTION_NONE, OPTION_SOME, OPTION_TYPE, RESULT_ERR, RESULT_OK,
+        RESULT_TYPE,
+    },
+    std::{
+        env, fs,
+        io::{self, BufRead},
+        rc::Rc,
+    },
+};
+
+type BuiltinResult<'a> = Result<Rc<Value<'a>>, IntrpErr<'a>>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    /// number of arguments applied before it's called
+    pub arity: usize,
+    pub ty: fn() -> Type,
+    /// called with exactly `arity` arguments
+    pub call: for<'a> fn(&[Rc<Value<'a>>]) -> BuiltinResult<'a>,
+}
+
+/// position of callcc among the builtins
+pub const CALLCC: u16 = 0;
+
+pub static BUILTINS: &[Builtin] = &[
+    // (('a -> 'b) -> 'a) -> 'a, applied by the machine because it captures the stack
+    Builtin {
+        name: "callcc",
+        arity: 1,
+        ty: || function(
+            function(function(Type::Generic(0), Type::Generic(1)), Type::Generic(0)),
+            Type::Generic(0),
+        ),
+        call: callcc,
+    },
+    Builtin {
+        name: "print",
+        arity: 1,
+        ty: || function(Type::String, Type::Unit),
+        call: print,
+    },
+    // older name of int2str
+    Builtin {
+        name: "i2str",
+        arity: 1,
+        ty: || function(Type::Int, Type::String),
+        call: int2str,
+    },
+    Builtin {
+        name: "int2str",
+        arity: 1,
+        ty: || function(Type::Int, Type::String),
+        call: int2str,
+    },
+    Builtin {
+        name: "str2int",
+        arity: 1,
+        ty: || function(Type::String, option(Type::Int)),
+        call: str2int,
+    },
+    Builtin {
+        name: "strlen",
+        arity: 1,
+        ty: || function(Type::String, Type::Int),
+        call: strlen,
+    },
+    Builtin {
+        name: "substr",
+        arity: 3,
+        ty: || function(Type::String, function(Type::Int, function(Type::Int, Type::String))),
+        call: substr,
+    },
+    Builtin {
+        name: "ord",
+        arity: 1,
+        ty: || function(Type::String, Type::Int),
+        call: ord,
+    },
+    Builtin {
+        name: "chr",
+        arity: 1,
+        ty: || function(Type::Int, Type::String),
+        call: chr,
+    },
+    Builtin {
+        name: "min",
+        arity: 2,
+        ty: || function(Type::Int, function(Type::Int, Type::Int)),
+        call: min,
+    },
+    Builtin {
+        name: "max",
+        arity: 2,
+        ty: || function(Type::Int, function(Type::Int, Type::Int)),
+        call: max,
+    },
+    Builtin {
+        name: "abs",
+        arity: 1,
+        ty: || function(Type::Int, Type::Int),
+        call: abs,
+    },
+    Builtin {
+        name: "read_line",
+        arity: 1,
+        ty: || function(Type::Unit, option(Type::String)),
+        call: read_line,
+    },
+];
+
+/// builtins reaching outside the program, declared after BUILTINS
+pub static IO_BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "read_file",
+        arity: 1,
+        ty: || function(Type::String, result(Type::String)),
+        call: read_file,
+    },
+    // path, then contents
+    Builtin {
+        name: "write_file",
+        arity: 2,
+        ty: || function(Type::String, function(Type::String, result(Type::Unit))),
+        call: write_file,
+    },
+    Builtin {
+        name: "list_dir",
+        arity: 1,
+        ty: || function(Type::String, result(list(Type::String))),
+        call: list_dir,
+    },
+    Builtin {
+        name: "getenv",
+        arity: 1,
+        ty: || function(Type::String, option(Type::String)),
+        call: getenv,
+    },
+    // applied by host_fns because it returns the arguments given there
+    Builtin {
+        name: "args",
+        arity: 1,
+        ty: || function(Type::Unit, list(Type::String)),
+        call: args,
+    },
+];
+
+/// what programs may do besides computing and using the standard input and output
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// the I/O builtins can be called
+    pub io: bool,
+    /// the arguments of the program, returned by args
+    pub args: Vec<String>,
+}
+
+/// names and types of all builtins, to be passed to the type checker
+pub fn imports() -> Vec<(&'static str, Type)> {
+    BUILTINS.iter().chain(IO_BUILTINS).map(|b| (b.name, (b.ty)())).collect()
+}
+
+/// the builtins in the same order as their imports, with the capabilities of a program
+pub fn host_fns(caps: &Capabilities) -> Vec<HostFn> {
+    let builtins = BUILTINS.iter().map(|b| (b, Rc::new(b.call) as HostCall));
+    let io = IO_BUILTINS.iter().map(|b| {
+        let name = b.name;
+        let call: HostCall = if !caps.io {
+            Rc::new(host_call(move |_| {
+                Err(IntrpErr::Host(name, "I/O isn't allowed, run cerebral with --allow-io".to_owned()))
+            }))
+        } else if name == "args" {
+            let args = caps.args.clone();
+            Rc::new(host_call(move |_| Ok(Rc::new(list_of(args.iter().map(|a| Value::String(a.clone())))))))
+        } else {
+            Rc::new(b.call)
+        };
+        (b, call)
+    });
+    builtins.chain(io).map(|(b, call)| HostFn { name: b.name, ty: (b.ty)(), arity: b.arity, call }).collect()
+}
+
+fn function(from: Type, to: Type) -> Type {
+    Type::Function(Box::new(from), Box::new(to))
+}
+
+fn option(t: Type) -> Type {
+    Type::Sum(OPTION_TYPE, vec![t])
+}
+
+/// results of I/O, with the message of the error
+fn result(t: Type) -> Type {
+    Type::Sum(RESULT_TYPE, vec![t, Type::String])
+}
+
+fn list(t: Type) -> Type {
+    Type::Sum(LIST_TYPE, vec![t])
+}
+
+fn some(v: Value) -> Rc<Value> {
+    Rc::new(Value::SumVar(OPTION_TYPE, OPTION_SOME, Rc::new(v)))
+}
+
+fn none<'a>() -> Rc<Value<'a>> {
+    Rc::new(Value::SumVar(OPTION_TYPE, OPTION_NONE, Rc::new(Value::Unit)))
+}
+
+fn ok(v: Value) -> Rc<Value> {
+    Rc::new(Value::SumVar(RESULT_TYPE, RESULT_OK, Rc::new(v)))
+}
+
+fn err<'a>(e: io::Error) -> Rc<Value<'a>> {
+    Rc::new(Value::SumVar(RESULT_TYPE, RESULT_ERR, Rc::new(Value::String(e.to_string()))))
+}
+
+/// a list of values in the same order
+fn list_of<'a, I>(values: I) -> Value<'a>
+where
+    I: DoubleEndedIterator<Item = Value<'a>>,
+{
+    let mut l = Value::SumVar(LIST_TYPE, LIST_NIL, Rc::new(Value::Unit));
+    for v in values.rev() {
+        l = Value::SumVar(LIST_TYPE, LIST_CONS, Rc::new(Value::Tuple(vec![Rc::new(v), Rc::new(l)])));
+    }
+    l
+}
+
+fn int<'a>(v: &Value, name: &'static str) -> Result<isize, IntrpErr<'a>> {
+    match *v {
+        Value::Int(i) => Ok(i),
+        _ => Err(IntrpErr::BadArgument(name)),
+    }
+}
+
+fn string<'v, 'a>(v: &'v Value, name: &'static str) -> Result<&'v str, IntrpErr<'a>> {
+    match *v {
+        Value::String(ref s) => Ok(s),
+        _ => Err(IntrpErr::BadArgument(name)),
+    }
+}
+
+fn print<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    print!("{}", string(&args[0], "print")?.replace("\\n", "\n"));
+    Ok(Rc::new(Value::Unit))
+}
+
+fn int2str<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(Rc::new(Value::String(int(&args[0], "int2str")?.to_string())))
+}
+
+fn str2int<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(match string(&args[0], "str2int")?.parse() {
+        Ok(i) => some(Value::Int(i)),
+        Err(_) => none(),
+    })
+}
+
+fn strlen<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(Rc::new(Value::Int(string(&args[0], "strlen")?.chars().count() as isize)))
+}
+
+/// the characters of a string from a start index with a length
+fn substr<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    let s = string(&args[0], "substr")?;
+    let (start, len) = (int(&args[1], "substr")?, int(&args[2], "substr")?);
+    if start < 0 || len < 0 || start + len > s.chars().count() as isize {
+        return Err(IntrpErr::BadArgument("substr"));
+    }
+    let sub = s.chars().skip(start as usize).take(len as usize).collect();
+    Ok(Rc::new(Value::String(sub)))
+}
+
+/// code of the first character of a string
+fn ord<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    match string(&args[0], "ord")?.chars().next() {
+        Some(c) => Ok(Rc::new(Value::Int(c as isize))),
+        None => Err(IntrpErr::BadArgument("ord")),
+    }
+}
+
+/// string of the character with a code
+fn chr<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    let code = int(&args[0], "chr")?;
+    if code < 0 || code > u32::MAX as isize {
+        return Err(IntrpErr::BadArgument("chr"));
+    }
+    match std::char::from_u32(code as u32) {
+        Some(c) => Ok(Rc::new(Value::String(c.to_string()))),
+        None => Err(IntrpErr::BadArgument("chr")),
+    }
+}
+
+fn min<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(Rc::new(Value::Int(int(&args[0], "min")?.min(int(&args[1], "min")?))))
+}
+
+fn max<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(Rc::new(Value::Int(int(&args[0], "max")?.max(int(&args[1], "max")?))))
+}
+
+fn abs<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(Rc::new(Value::Int(int(&args[0], "abs")?.wrapping_abs())))
+}
+
+/// next line of the standard input without its line ending, none at the end of input
+fn read_line<'a>(_: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => Ok(none()),
+        Ok(_) => {
+            let len = line.trim_end_matches(&['\n', '\r'][..]).len();
+            line.truncate(len);
+            Ok(some(Value::String(line)))
+        }
+    }
+}
+
+fn read_file<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(match fs::read_to_string(string(&args[0], "read_file")?) {
+        Ok(contents) => ok(Value::String(contents)),
+        Err(e) => err(e),
+    })
+}
+
+fn write_file<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(match fs::write(string(&args[0], "write_file")?, string(&args[1], "write_file")?) {
+        Ok(()) => ok(Value::Unit),
+        Err(e) => err(e),
+    })
+}
+
+/// names of the entries of a directory, sorted
+fn list_dir<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    let entries: io::Result<Vec<String>> = fs::read_dir(string(&args[0], "list_dir")?)
+        .and_then(|dir| dir.map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned())).collect());
+    Ok(match entries {
+        Ok(mut names) => {
+            names.sort();
+            ok(list_of(names.into_iter().map(Value::String)))
+        }
+        Err(e) => err(e),
+    })
+}
+
+fn getenv<'a>(args: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Ok(match env::var(string(&args[0], "getenv")?) {
+        Ok(v) => some(Value::String(v)),
+        Err(_) => none(),
+    })
+}
+
+fn args<'a>(_: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Err(IntrpErr::BadArgument("args"))
+}
+
+fn callcc<'a>(_: &[Rc<Value<'a>>]) -> BuiltinResult<'a> {
+    Err(IntrpErr::BadArgument("callcc"))
+}
@@ -0,0 +1,624 @@
+//! Reading top-level values of a program into Rust values with serde, so a program can
+//! be used as a typed configuration file.
+//!
+//! A value is read along with its inferred type, and a Rust type asking for a shape the
+//! type doesn't have is an error saying where the value is and what its type is:
+//!
+//! - int, bool, string and () are read as themselves, ints also as floats
+//! - a ref is read as the value it holds
+//! - tuples are read as tuples, sequences and structs, with the fields in order
+//! - Option is read as options
+//! - lists, types like `List t = | nil () | cons (t, List t)`, are read as sequences,
+//!   and lists of pairs as maps
+//! - other sum types are read as enums with their constructors as variants, and as the
+//!   value of their constructor when they have one and a struct is asked for
+
+#[cfg(test)]
+mod test {
+    use {
+        crate::{Engine, EngineError},
+        serde::Deserialize,
+        std::collections::HashMap,
+    };
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Server {
+        host: String,
+        ports: Vec<u16>,
+        tls: Option<Tls>,
+        mode: Mode,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Tls {
+        cert: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[allow(non_camel_case_types)]
+    enum Mode {
+        fast,
+        limited(u32, bool),
+        named(String),
+    }
+
+    fn engine(src: &str) -> Engine {
+        let mut engine = Engine::new();
+        engine.load(src).unwrap();
+        engine
+    }
+
+    #[test]
+    fn read_values() {
+        let engine = engine(
+            "type Mode = | fast () | limited (int, bool) | named string
+            type Tls = | tls string
+            let ports = map { p => p + 8000 } (range 0 3)
+            let server = (\"localhost\", ports, some (tls \"cert.pem\"), limited (10, true))
+            let servers = cons (server, cons ((\"example.org\", nil (), none (), named \"x\"), nil ()))
+            let env = cons ((\"a\", ref 1), cons ((\"b\", ref 2), nil ()))
+            let (x, y) = (1, \"y\")",
+        );
+        let server = Server {
+            host: "localhost".to_owned(),
+            ports: vec![8000, 8001, 8002],
+            tls: Some(Tls { cert: "cert.pem".to_owned() }),
+            mode: Mode::limited(10, true),
+        };
+        assert_eq!(engine.config::<Server>("server"), Ok(server));
+        let servers: Vec<Server> = engine.config("servers").unwrap();
+        assert_eq!(servers[1].mode, Mode::named("x".to_owned()));
+        assert_eq!(servers[1].tls, None);
+        let env: HashMap<String, i64> = engine.config("env").unwrap();
+        assert_eq!(env["b"], 2);
+        assert_eq!(engine.config::<(u8, String)>("ports").ok(), None);
+        assert_eq!(engine.config::<i32>("x"), Ok(1));
+        assert_eq!(engine.config::<String>("y"), Ok("y".to_owned()));
+        assert_eq!(engine.config::<f64>("x"), Ok(1.0));
+    }
+
+    #[test]
+    fn mismatches() {
+        let engine = engine(
+            "type Mode = | fast () | limited (int, bool) | named string
+            let server = (\"localhost\", cons (80, nil ()), none (), fast ())
+            let wrong_port = (\"localhost\", cons (80, cons (0 - 1, nil ())), none (), fast ())
+            let wrong_mode = (\"localhost\", nil (), none (), (1, 2))
+            let short = (\"localhost\", nil ())
+            let tls = some \"cert.pem\"
+            let f = { x => x + 1 }",
+        );
+        assert_eq!(engine.config::<Server>("server").unwrap().mode, Mode::fast);
+        let message = |name| match engine.config::<Server>(name) {
+            Err(EngineError::Config(e)) => e.to_string(),
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(
+            message("wrong_port"),
+            "wrong_port.ports.1: invalid value: integer `-1`, expected u16"
+        );
+        assert_eq!(message("wrong_mode"), "wrong_mode.mode: expected a sum type, found a value of type (int, int)");
+        assert_eq!(message("short"), "short: expected a tuple of 4 fields, found a value of type (string, List(a))");
+        assert_eq!(message("f"), "f: expected a tuple of 4 fields, found a value of type int -> int");
+        match engine.config::<Tls>("tls") {
+            Err(EngineError::Config(e)) => assert_eq!(e.to_string(), "tls.cert: expected a string, found a value of type Option(string)"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(engine.config::<i32>("missing"), Err(EngineError::NotFound("missing".to_owned())));
+    }
+}
+
+use {
+    crate::interpret::{Context, Value},
+    clog::{
+        imper_ast::Module,
+        types::{Type, OPTION_NONE, OPTION_TYPE},
+    },
+    serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    std::{fmt, rc::Rc, vec},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    /// the name of the global followed by the fields and positions leading to the
+    /// value, e.g. `server.ports.1`
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    /// the error at a path unless it already has one
+    fn at(mut self, path: &str) -> Self {
+        if self.path.is_empty() {
+            self.path = path.to_owned();
+        }
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl de::Error for ConfigError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConfigError { path: String::new(), message: msg.to_string() }
+    }
+}
+
+/// read the value of a top-level name of an evaluated module as a T
+pub fn from_global<T: DeserializeOwned>(ctx: &Context, name: &str) -> Result<T, ConfigError> {
+    let error = |message: &str| ConfigError { path: name.to_owned(), message: message.to_owned() };
+    let value = ctx.global(name).ok_or_else(|| error("no top-level value has this name"))?;
+    let ty = ctx.global_type(name).ok_or_else(|| error("the type of the value isn't known"))?;
+    T::deserialize(Reader { module: ctx.module(), value, ty: ty.clone(), path: name.to_owned() })
+}
+
+/// the shape a Rust type asks for
+enum Want {
+    Any,
+    Bool,
+    Int,
+    String,
+    Unit,
+    Option,
+    Seq,
+    Tuple(usize),
+    Map,
+    Struct(&'static [&'static str]),
+    Enum,
+}
+
+/// a value with its type, read by serde
+struct Reader<'v, 'a> {
+    module: &'v Module<'a>,
+    value: Rc<Value<'a>>,
+    ty: Type,
+    path: String,
+}
+
+impl<'v, 'a> Reader<'v, 'a> {
+    fn read<'de, V: Visitor<'de>>(self, want: Want, visitor: V) -> Result<V::Value, ConfigError> {
+        let path = self.path.clone();
+        let r = self.deref();
+        let result = match (want, &r.ty) {
+            (Want::Any, _)
+            | (Want::Bool, Type::Bool)
+            | (Want::Int, Type::Int)
+            | (Want::String, Type::String)
+            | (Want::Unit, Type::Unit) => r.read_any(visitor),
+            (Want::Option, _) if r.is_option() => r.read_option(visitor),
+            (Want::Seq, Type::Tuple(_)) => visitor.visit_seq(r.fields(None)?),
+            (Want::Seq, _) if r.is_list() => visitor.visit_seq(List { list: r, index: 0 }),
+            (Want::Tuple(len), Type::Tuple(types)) if types.len() == len => visitor.visit_seq(r.fields(None)?),
+            (Want::Map, _) if r.is_list_of_pairs() => {
+                visitor.visit_map(Pairs { list: List { list: r, index: 0 }, value: None })
+            }
+            (Want::Struct(fields), _) => r.read_struct(fields, visitor),
+            (Want::Enum, Type::Sum(..)) => visitor.visit_enum(r),
+            (Want::Bool, _) => Err(r.mismatch("a bool")),
+            (Want::Int, _) => Err(r.mismatch("an int")),
+            (Want::String, _) => Err(r.mismatch("a string")),
+            (Want::Unit, _) => Err(r.mismatch("()")),
+            (Want::Option, _) => Err(r.mismatch("an Option")),
+            (Want::Seq, _) => Err(r.mismatch("a tuple or a list")),
+            (Want::Tuple(len), _) => Err(r.mismatch(&format!("a tuple of {} values", len))),
+            (Want::Map, _) => Err(r.mismatch("a list of pairs")),
+            (Want::Enum, _) => Err(r.mismatch("a sum type")),
+        };
+        result.map_err(|e| e.at(&path))
+    }
+
+    fn read_any<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        match (&self.ty, &*self.value) {
+            (Type::Int, &Value::Int(i)) => visitor.visit_i64(i as i64),
+            (Type::Bool, &Value::Bool(b)) => visitor.visit_bool(b),
+            (Type::String, Value::String(s)) => visitor.visit_str(s),
+            (Type::Unit, Value::Unit) => visitor.visit_unit(),
+            (Type::Tuple(_), _) => visitor.visit_seq(self.fields(None)?),
+            (Type::Sum(..), _) if self.is_option() => self.read_option(visitor),
+            (Type::Sum(..), _) if self.is_list_of_pairs() => {
+                visitor.visit_map(Pairs { list: List { list: self, index: 0 }, value: None })
+            }
+            (Type::Sum(..), _) if self.is_list() => visitor.visit_seq(List { list: self, index: 0 }),
+            (Type::Sum(..), _) => visitor.visit_enum(self),
+            (Type::Function(..), _) => Err(self.error("functions can't be read")),
+            (Type::Generic(_), _) | (Type::Variable(_), _) => Err(self.error("the type of the value isn't known")),
+            _ => Err(self.bad_value()),
+        }
+    }
+
+    fn read_option<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        match *self.value {
+            Value::SumVar(_, OPTION_NONE, _) => visitor.visit_none(),
+            _ => {
+                let (_, value, ty) = self.constructor()?;
+                let path = self.path.clone();
+                visitor.visit_some(Reader { module: self.module, value, ty, path })
+            }
+        }
+    }
+
+    /// a tuple with as many fields, or the only field, of a value or of its constructor
+    fn read_struct<'de, V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, ConfigError>
+    where
+        V: Visitor<'de>,
+    {
+        let r = match self.ty {
+            Type::Sum(n, _) if !self.is_option() && self.module.type_decls[n as usize].variants.len() == 1 => {
+                let (_, value, ty) = self.constructor()?;
+                Reader { value, ty, ..self }.deref()
+            }
+            _ => self,
+        };
+        match r.ty {
+            Type::Tuple(ref types) if types.len() == fields.len() => visitor.visit_seq(r.fields(Some(fields))?),
+            Type::Tuple(_) if fields.len() != 1 => {
+                Err(r.mismatch(&format!("a tuple of {} fields", fields.len())))
+            }
+            _ if fields.len() == 1 => {
+                let path = format!("{}.{}", r.path, fields[0]);
+                visitor.visit_seq(Fields(vec![Reader { path, ..r }].into_iter()))
+            }
+            _ => Err(r.mismatch(&format!("a tuple of {} fields", fields.len()))),
+        }
+    }
+
+    /// the value a ref holds, through any number of refs
+    fn deref(self) -> Self {
+        let held = match (&self.ty, &*self.value) {
+            (Type::Ref(t), Value::Ref(cell)) => Some(((**t).clone(), cell.borrow().clone())),
+            _ => None,
+        };
+        match held {
+            Some((ty, value)) => Reader { value, ty, ..self }.deref(),
+            None => self,
+        }
+    }
+
+    /// the values of a tuple, named by the fields of a struct or by their positions
+    fn fields(&self, names: Option<&[&str]>) -> Result<Fields<'v, 'a>, ConfigError> {
+        match (&self.ty, &*self.value) {
+            (Type::Tuple(types), Value::Tuple(values)) if types.len() == values.len() => {
+                let readers = types.iter().zip(values).enumerate().map(|(i, (ty, value))| Reader {
+                    module: self.module,
+                    value: value.clone(),
+                    ty: ty.clone(),
+                    path: match names {
+                        Some(names) => format!("{}.{}", self.path, names[i]),
+                        None => format!("{}.{}", self.path, i),
+                    },
+                });
+                Ok(Fields(readers.collect::<Vec<_>>().into_iter()))
+            }
+            _ => Err(self.bad_value()),
+        }
+    }
+
+    /// position of the constructor of a sum value, with its value and the type of its value
+    fn constructor(&self) -> Result<(u16, Rc<Value<'a>>, Type), ConfigError> {
+        match (&self.ty, &*self.value) {
+            (Type::Sum(n, args), Value::SumVar(n2, m, value)) if n == n2 => {
+                let ty = self.module.type_decls[*n as usize].variants[*m as usize - 1].1.substitute_generics(args);
+                Ok((*m, value.clone(), ty))
+            }
+            _ => Err(self.bad_value()),
+        }
+    }
+
+    fn is_option(&self) -> bool {
+        match self.ty {
+            Type::Sum(n, _) => n == OPTION_TYPE,
+            _ => false,
+        }
+    }
+
+    /// is the type declared like `List t = | nil () | cons (t, List t)`
+    fn is_list(&self) -> bool {
+        match self.ty {
+            Type::Sum(n, ref args) if args.len() == 1 => {
+                let variants = &self.module.type_decls[n as usize].variants;
+                let cons = Type::Tuple(vec![Type::Generic(0), Type::Sum(n, vec![Type::Generic(0)])]);
+                variants.len() == 2 && variants[0].1 == Type::Unit && variants[1].1 == cons
+            }
+            _ => false,
+        }
+    }
+
+    fn is_list_of_pairs(&self) -> bool {
+        match self.ty {
+            Type::Sum(_, ref args) if self.is_list() => match args[0] {
+                Type::Tuple(ref types) => types.len() == 2,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn type_name(&self) -> String {
+        let mut name = String::new();
+        self.ty.pretty_format(&mut name, &self.module.type_decls);
+        name
+    }
+
+    fn error(&self, message: &str) -> ConfigError {
+        ConfigError { path: self.path.clone(), message: message.to_owned() }
+    }
+
+    fn mismatch(&self, expected: &str) -> ConfigError {
+        self.error(&format!("expected {}, found a value of type {}", expected, self.type_name()))
+    }
+
+    /// the value isn't of its inferred type, which the type checker should prevent
+    fn bad_value(&self) -> ConfigError {
+        self.error(&format!("the value isn't of its type {}", self.type_name()))
+    }
+}
+
+impl<'de, 'v, 'a> de::Deserializer<'de> for Reader<'v, 'a> {
+    type Error = ConfigError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Any, visitor)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Bool, visitor)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Int, visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Option, visitor)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Unit, visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Unit, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Seq, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Tuple(len), visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(self, _: &'static str, len: usize, visitor: V) -> Result<V::Value, ConfigError>
+    where
+        V: Visitor<'de>,
+    {
+        self.read(Want::Tuple(len), visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Map, visitor)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError>
+    where
+        V: Visitor<'de>,
+    {
+        self.read(Want::Struct(fields), visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError>
+    where
+        V: Visitor<'de>,
+    {
+        self.read(Want::Enum, visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::String, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_unit()
+    }
+}
+
+/// the values of a tuple
+struct Fields<'v, 'a>(vec::IntoIter<Reader<'v, 'a>>);
+
+impl<'de, 'v, 'a> de::SeqAccess<'de> for Fields<'v, 'a> {
+    type Error = ConfigError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, ConfigError> {
+        match self.0.next() {
+            Some(r) => seed.deserialize(r).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// the elements of a list, from the cell at an index
+struct List<'v, 'a> {
+    list: Reader<'v, 'a>,
+    index: usize,
+}
+
+impl<'v, 'a> List<'v, 'a> {
+    fn next(&mut self) -> Result<Option<Reader<'v, 'a>>, ConfigError> {
+        let (_, value, ty) = self.list.constructor()?;
+        match (ty, &*value) {
+            (Type::Unit, _) => Ok(None),
+            (Type::Tuple(mut types), Value::Tuple(values)) if types.len() == 2 && values.len() == 2 => {
+                let head = Reader {
+                    module: self.list.module,
+                    value: values[0].clone(),
+                    ty: types.swap_remove(0),
+                    path: format!("{}.{}", self.list.path, self.index),
+                };
+                self.list.value = values[1].clone();
+                self.index += 1;
+                Ok(Some(head))
+            }
+            _ => Err(self.list.bad_value()),
+        }
+    }
+}
+
+impl<'de, 'v, 'a> de::SeqAccess<'de> for List<'v, 'a> {
+    type Error = ConfigError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, ConfigError> {
+        match self.next()? {
+            Some(r) => seed.deserialize(r).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// the pairs of a list as the keys and values of a map
+struct Pairs<'v, 'a> {
+    list: List<'v, 'a>,
+    /// value of the last key read
+    value: Option<Reader<'v, 'a>>,
+}
+
+impl<'de, 'v, 'a> de::MapAccess<'de> for Pairs<'v, 'a> {
+    type Error = ConfigError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, ConfigError> {
+        let pair = match self.list.next()? {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let mut fields = pair.fields(None)?.0;
+        let key = fields.next().unwrap();
+        self.value = fields.next();
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, ConfigError> {
+        seed.deserialize(self.value.take().expect("value read before its key"))
+    }
+}
+
+impl<'de, 'v, 'a> de::EnumAccess<'de> for Reader<'v, 'a> {
+    type Error = ConfigError;
+    type Variant = Reader<'v, 'a>;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<(T::Value, Self), ConfigError> {
+        let (m, value, ty) = self.constructor()?;
+        let n = match self.ty {
+            Type::Sum(n, _) => n,
+            _ => unreachable!(),
+        };
+        let name = self.module.type_decls[n as usize].variants[m as usize - 1].0;
+        let variant = seed.deserialize(IntoDeserializer::<ConfigError>::into_deserializer(name))?;
+        let path = format!("{}.{}", self.path, name);
+        Ok((variant, Reader { value, ty, path, ..self }))
+    }
+}
+
+impl<'de, 'v, 'a> de::VariantAccess<'de> for Reader<'v, 'a> {
+    type Error = ConfigError;
+
+    fn unit_variant(self) -> Result<(), ConfigError> {
+        match self.ty {
+            Type::Unit => Ok(()),
+            _ => Err(self.mismatch("()")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, ConfigError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, ConfigError> {
+        self.read(Want::Tuple(len), visitor)
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, ConfigError>
+    where
+        V: Visitor<'de>,
+    {
+        self.read(Want::Struct(fields), visitor)
+    }
+}
@@ -0,0 +1,418 @@
+//! Embedding ceen in Rust programs.
+//!
+//! An `Engine` has the builtins and functions registered by the host, loads a program
+//! with the prelude and calls its top-level functions. Values cross between Rust and
+//! ceen through `IntoValue` and `FromValue`.
+//! ```ignore
+//! let mut engine = Engine::new();
+//! engine.register("twice", Type::Function(Box::new(Type::Int), Box::new(Type::Int)), |args| {
+//!     isize::from_value(&args[0]).map(|n| 2 * n).ok_or_else(|| "not an int".to_owned())
+//! });
+//! engine.load("let f = { n => twice n + 1 }")?;
+//! assert_eq!(engine.call::<_, isize>("f", (20,))?, 41);
+//! ```
+
+#[cfg(test)]
+mod test {
+    use {super::*, std::cell::RefCell};
+
+    fn int_to_int() -> Type {
+        Type::Function(Box::new(Type::Int), Box::new(Type::Int))
+    }
+
+    #[test]
+    fn register_and_call() {
+        let mut engine = Engine::new();
+        engine.register("twice", int_to_int(), |args| {
+            isize::from_value(&args[0]).map(|n| 2 * n).ok_or_else(|| "not an int".to_owned())
+        });
+        engine.register("natural", int_to_int(), |args| match isize::from_value(&args[0]) {
+            Some(n) if n >= 0 => Ok(n),
+            _ => Err("negative".to_owned()),
+        });
+        engine.load("let f = { n => twice n + 1 }
+            let add = { a b => a + b }
+            let greet = { name => \"hello \" ++ name }
+            let answer = 42
+            let pair = { n => (n, n > 0) }
+            let positive = { n => if n > 0 then some n else none () end }
+            let sum = { l => foldl add 0 l }
+            let g = { n => natural n }").unwrap();
+        assert_eq!(engine.call::<_, isize>("f", (20,)), Ok(41));
+        assert_eq!(engine.call::<_, isize>("add", (1, 2)), Ok(3));
+        assert_eq!(engine.call::<_, String>("greet", ("world",)), Ok("hello world".to_owned()));
+        assert_eq!(engine.call::<_, isize>("answer", ()), Ok(42));
+        assert_eq!(engine.call::<_, (i64, bool)>("pair", (-3,)), Ok((-3, false)));
+        assert_eq!(engine.call::<_, Option<isize>>("positive", (5,)), Ok(Some(5)));
+        assert_eq!(engine.call::<_, Option<isize>>("positive", (0,)), Ok(None));
+        assert_eq!(engine.call::<_, isize>("g", (7,)), Ok(7));
+        match engine.call::<_, isize>("g", (-7,)) {
+            Err(EngineError::Runtime(report)) => {
+                assert!(report.starts_with("error: natural failed: negative\n"));
+                assert!(report.contains("in g"));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(engine.call::<_, bool>("answer", ()), Err(EngineError::Conversion));
+        assert_eq!(engine.call::<_, isize>("missing", ()), Err(EngineError::NotFound("missing".to_owned())));
+    }
+
+    #[test]
+    fn limits() {
+        let output = Rc::new(RefCell::new(vec![]));
+        let sink = output.clone();
+        let mut engine = Engine::new().with_limits(Limits { steps: Some(10_000), ..Limits::default() });
+        engine.register("emit", int_to_int(), move |args| {
+            let n = isize::from_value(&args[0]).ok_or_else(|| "not an int".to_owned())?;
+            sink.borrow_mut().push(n);
+            Ok(n)
+        });
+        engine.load("rec count = { n => count (emit n + 1) }\nlet total = { n => foldl { a b => a + b } 0 (range 0 n) }").unwrap();
+        match engine.call::<_, isize>("count", (0,)) {
+            Err(EngineError::Runtime(report)) => assert!(report.starts_with("error: step limit exceeded\n")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // the calls before the limit happened
+        assert!(output.borrow().len() > 100);
+        assert_eq!(output.borrow()[..3], [0, 1, 2]);
+        // each call has its own steps
+        for _ in 0..10 {
+            assert_eq!(engine.call::<_, isize>("total", (100,)), Ok(4950));
+        }
+    }
+
+    #[test]
+    fn load_errors() {
+        let mut engine = Engine::new();
+        assert_eq!(engine.load("let x = 1\nlet y = "), Err(EngineError::Parse(vec![(2, 8)])));
+        match engine.load("let x = 1 + true") {
+            Err(EngineError::Type(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match engine.load("let x = 1\nlet y = x / 0") {
+            Err(EngineError::Runtime(report)) => assert!(report.contains("DivisionByZero ()\n  at line 2")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // a failed load leaves no program
+        assert_eq!(engine.call::<_, isize>("x", ()), Err(EngineError::NotFound("x".to_owned())));
+        let mut engine = Engine::new().without_prelude();
+        assert!(engine.load("let l = range 0 3").is_err());
+        engine.load("type List t = | nil () | cons (t, List t)\nlet l = nil ()").unwrap();
+    }
+}
+
+use {
+    crate::{
+        builtins::{self, Capabilities},
+        config::{self, ConfigError},
+        interpret::{host_call, Context, HostFn, IntrpErr, Limits, Value},
+        prelude,
+    },
+    clog::{
+        imper_ast::Module,
+        parse,
+        type_check,
+        types::{Type, OPTION_NONE, OPTION_SOME, OPTION_TYPE},
+    },
+    serde::de::DeserializeOwned,
+    std::{convert::TryFrom, rc::Rc},
+};
+
+/// conversion of a ceen value to a Rust value
+pub trait FromValue: Sized {
+    /// None if the value doesn't have the shape of Self
+    fn from_value(v: &Value) -> Option<Self>;
+}
+
+/// conversion of a Rust value to a ceen value
+pub trait IntoValue {
+    fn into_value<'a>(self) -> Value<'a>;
+}
+
+impl FromValue for () {
+    fn from_value(v: &Value) -> Option<Self> {
+        match *v {
+            Value::Unit => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for () {
+    fn into_value<'a>(self) -> Value<'a> {
+        Value::Unit
+    }
+}
+
+macro_rules! int_impls {
+    ($($t:ty),+) => {$(
+        /// None if the int doesn't fit
+        impl FromValue for $t {
+            fn from_value(v: &Value) -> Option<Self> {
+                match *v {
+                    Value::Int(n) => <$t>::try_from(n).ok(),
+                    _ => None,
+                }
+            }
+        }
+
+        impl IntoValue for $t {
+            fn into_value<'a>(self) -> Value<'a> {
+                Value::Int(self as isize)
+            }
+        }
+    )+};
+}
+
+int_impls!(isize, i64, i32);
+
+impl FromValue for bool {
+    fn from_value(v: &Value) -> Option<Self> {
+        match *v {
+            Value::Bool(p) => Some(p),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value<'a>(self) -> Value<'a> {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(v: &Value) -> Option<Self> {
+        match *v {
+            Value::String(ref s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value<'a>(self) -> Value<'a> {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value<'a>(self) -> Value<'a> {
+        Value::String(self.to_owned())
+    }
+}
+
+/// the built-in Option type
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(v: &Value) -> Option<Self> {
+        match *v {
+            Value::SumVar(OPTION_TYPE, OPTION_NONE, _) => Some(None),
+            Value::SumVar(OPTION_TYPE, OPTION_SOME, ref v) => T::from_value(v).map(Some),
+            _ => None,
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value<'a>(self) -> Value<'a> {
+        match self {
+            None => Value::SumVar(OPTION_TYPE, OPTION_NONE, Rc::new(Value::Unit)),
+            Some(v) => Value::SumVar(OPTION_TYPE, OPTION_SOME, Rc::new(v.into_value())),
+        }
+    }
+}
+
+/// arguments of a call, a tuple of values each converted to one argument
+pub trait IntoArgs {
+    fn into_args<'a>(self) -> Vec<Rc<Value<'a>>>;
+}
+
+impl IntoArgs for () {
+    fn into_args<'a>(self) -> Vec<Rc<Value<'a>>> {
+        Vec::new()
+    }
+}
+
+macro_rules! tuple_impls {
+    ($($t:ident $v:ident),+) => {
+        impl<$($t: FromValue),+> FromValue for ($($t,)+) {
+            fn from_value(v: &Value) -> Option<Self> {
+                match *v {
+                    Value::Tuple(ref values) => match values.as_slice() {
+                        [$($v),+] => Some(($($t::from_value($v)?,)+)),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }
+        }
+
+        impl<$($t: IntoValue),+> IntoValue for ($($t,)+) {
+            fn into_value<'a>(self) -> Value<'a> {
+                let ($($v,)+) = self;
+                Value::Tuple(vec![$(Rc::new($v.into_value())),+])
+            }
+        }
+
+        impl<$($t: IntoValue),+> IntoArgs for ($($t,)+) {
+            fn into_args<'a>(self) -> Vec<Rc<Value<'a>>> {
+                let ($($v,)+) = self;
+                vec![$(Rc::new($v.into_value())),+]
+            }
+        }
+    };
+}
+
+// a tuple of one element is only used as arguments, ceen has no such tuples
+impl<A: IntoValue> IntoArgs for (A,) {
+    fn into_args<'a>(self) -> Vec<Rc<Value<'a>>> {
+        vec![Rc::new(self.0.into_value())]
+    }
+}
+
+tuple_impls!(A a, B b);
+tuple_impls!(A a, B b, C c);
+tuple_impls!(A a, B b, C c, D d);
+
+#[derive(Debug, PartialEq)]
+pub enum EngineError {
+    /// the program doesn't parse, with the line and column of each error
+    Parse(Vec<(usize, usize)>),
+    /// the program doesn't type-check
+    Type(String),
+    /// evaluation stopped, with the report of the runtime error
+    Runtime(String),
+    /// no program is loaded or it has no top-level name like this
+    NotFound(String),
+    /// a returned value isn't of the Rust type asked for
+    Conversion,
+    /// a top-level value read with `Engine::config` doesn't have the shape of the Rust type
+    Config(ConfigError),
+}
+
+/// A loaded program. The module borrows the source and the context borrows the module,
+/// so their lifetimes are erased to keep them together. References to them never leave
+/// the program: values are converted to Rust values and host functions take values for
+/// any lifetime. The fields are dropped in order, borrowers first.
+struct Program {
+    ctx: Context<'static>,
+    module: Box<Module<'static>>,
+    /// the source of the program without comments, to report errors in
+    contents: String,
+    /// the parsed source, the program followed by the prelude if it's loaded
+    _src: String,
+}
+
+pub struct Engine {
+    hosts: Vec<HostFn>,
+    with_prelude: bool,
+    limits: Limits,
+    program: Option<Program>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    /// an engine with the builtins and the prelude
+    pub fn new() -> Self {
+        Engine {
+            hosts: builtins::host_fns(&Capabilities::default()),
+            with_prelude: true,
+            limits: Limits::default(),
+            program: None,
+        }
+    }
+
+    /// don't declare the prelude in programs loaded from now on
+    pub fn without_prelude(mut self) -> Self {
+        self.with_prelude = false;
+        self
+    }
+
+    /// let the I/O builtins of programs loaded from now on run, with the arguments returned by `args`
+    pub fn allow_io(mut self, args: Vec<String>) -> Self {
+        let builtins = builtins::host_fns(&Capabilities { io: true, args });
+        self.hosts.splice(..builtins.len(), builtins);
+        self
+    }
+
+    /// Stop loading a program and each call when they go past limits, counted separately.
+    /// Evaluation past a limit is a runtime error.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Make a function available to programs loaded from now on. The function takes as
+    /// many arguments as the arrows of its type, an error stops evaluation with its message.
+    pub fn register<F, R>(&mut self, name: &'static str, ty: Type, f: F)
+    where
+        F: for<'a> Fn(&[Rc<Value<'a>>]) -> Result<R, String> + 'static,
+        R: IntoValue,
+    {
+        let mut arity = 0;
+        let mut t = &ty;
+        while let Type::Function(_, ref to) = *t {
+            arity += 1;
+            t = to;
+        }
+        let call = host_call(move |args| match f(args) {
+            Ok(v) => Ok(Rc::new(v.into_value())),
+            Err(message) => Err(IntrpErr::Host(name, message)),
+        });
+        self.hosts.push(HostFn { name, ty, arity, call: Rc::new(call) });
+    }
+
+    /// type-check a program and evaluate its top-level declarations, replacing the loaded program
+    pub fn load(&mut self, src: &str) -> Result<(), EngineError> {
+        self.program = None;
+        let contents = parse::uncomment(src);
+        let src = if self.with_prelude { prelude::append_to(&contents) } else { contents.clone() };
+        // the string's buffer doesn't move when it's moved into the program
+        let src_ref: &'static str = unsafe { &*(src.as_str() as *const str) };
+        let mut bindings = parse::parse(src_ref).map_err(|offsets| {
+            EngineError::Parse(offsets.into_iter().map(|offset| parse::line_col(src_ref, offset)).collect())
+        })?;
+        if self.with_prelude {
+            prelude::move_first(&mut bindings);
+        }
+        let imports: Vec<_> = self.hosts.iter().map(|host| (host.name, host.ty.clone())).collect();
+        let module = type_check::ast2imper_ast(bindings, &imports)
+            .map_err(|e| EngineError::Type(format!("{:?}", e)))?;
+        let module = Box::new(module);
+        let module_ref: &'static Module<'static> = unsafe { &*(&*module as *const _) };
+        let mut ctx = Context::with_hosts(module_ref, Rc::new(self.hosts.clone()));
+        ctx.set_limits(self.limits);
+        if let Err(e) = ctx.eval_toplevel() {
+            return Err(EngineError::Runtime(e.report(module_ref, &contents)));
+        }
+        self.program = Some(Program { ctx, module, contents, _src: src });
+        Ok(())
+    }
+
+    /// Apply a top-level value of the loaded program to arguments, e.g. `(1, "a")` for
+    /// two arguments. With `()` the value itself is returned.
+    pub fn call<A: IntoArgs, R: FromValue>(&self, name: &str, args: A) -> Result<R, EngineError> {
+        let not_found = || EngineError::NotFound(name.to_owned());
+        let program = self.program.as_ref().ok_or_else(not_found)?;
+        let f = program.ctx.global(name).ok_or_else(not_found)?;
+        program.ctx.reset_usage();
+        match program.ctx.apply_values(f, args.into_args()) {
+            Ok(v) => R::from_value(&v).ok_or(EngineError::Conversion),
+            Err(e) => Err(EngineError::Runtime(e.report(&program.module, &program.contents))),
+        }
+    }
+
+    /// Read a top-level value of the loaded program as a Rust value, see `config` for
+    /// how types are read.
+    pub fn config<T: DeserializeOwned>(&self, name: &str) -> Result<T, EngineError> {
+        let not_found = || EngineError::NotFound(name.to_owned());
+        let program = self.program.as_ref().ok_or_else(not_found)?;
+        program.ctx.global(name).ok_or_else(not_found)?;
+        config::from_global(&program.ctx, name).map_err(EngineError::Config)
+    }
+}
@@ -1,15 +1,35 @@
 //! Author Mohammed Nurul Hoque, Aug 16, 2020
 //! An interpreter for clog
+//!
+//! The interpreter is a CEK-style machine. Its state is the expression being evaluated
+//! (control), the locals and captures of the running closure (environment) and an
+//! explicit stack of frames saying what to do with the value of the expression
+//! (continuation). Because evaluation does not recurse on the Rust stack, the frames
+//! between a perform and its handler can be captured and resumed later, and callcc
+//! can capture the whole stack. A call pushes a frame recording the called closure,
+//! which replaces the caller's when the call is in tail position (closure bodies and
+//! the arms of conditionals), so tail calls run in constant space. These frames make
+//! the trace of runtime errors.
+//!
+//! The machine counts its steps, the depth of its stack and the values it allocates,
+//! so that evaluation of a program that doesn't terminate or grows without bound
+//! stops at the `Limits` of its context.
 
 use std::{
+    cell::{Cell, RefCell},
     fmt,
+    mem,
+    ptr,
     rc::Rc,
 };
 
+use crate::{builtins::{self, Capabilities}, prelude};
+
 use clog::{
+    ast::Span,
     dtree::DTree,
     imper_ast::{ConstraintValue, Expr, Module, ValPath},
-    types::{BinOpcode, Literal, UnOpcode},
+    types::{BinOpcode, Literal, Type, UnOpcode, EXN_TYPE, EXN_DIV_BY_ZERO, EXN_MATCH_FAILURE},
 };
 
 #[cfg(test)]
@@ -17,37 +37,116 @@ mod test {
     use super::*;
     use clog::parse::parse;
     use clog::type_check::ast2imper_ast;
+    use crate::builtins::imports;
 
     macro_rules! expr {
         (Int($e:expr)) => {
             Expr::Literal(Literal::Int($e))
         };
         (+ ($($e1:tt)+), ($($e2:tt)+)) => {
-            Expr::BinOp(box(expr!($($e1)+)), BinOpcode::Add, box(expr!($($e2)+)))
+            Expr::BinOp(Box::new(expr!($($e1)+)), BinOpcode::Add, Box::new(expr!($($e2)+)), (0, 0))
         };
         (- ($($e:tt)+)) => {
-            Expr::UnOp(UnOpcode::Minus, box(expr!($($e)+)))
+            Expr::UnOp(UnOpcode::Minus, Box::new(expr!($($e)+)))
         };
     }
     #[test]
     fn interpret_simple_expr() {
         let e = expr!(+ (Int(1)), (-(Int(3))));
-        let module = ast2imper_ast(vec![]).unwrap();
+        let module = ast2imper_ast(vec![], &imports()).unwrap();
         let ctx = Context::new(&module);
-        println!("{}", ctx.eval_exp(&e).unwrap());
+        assert_eq!(*ctx.eval_exp(&e).unwrap(), Value::Int(-2));
+    }
+
+    #[test]
+    fn print_values() {
+        let prgrm = "type List t = | nil () | cons (t, List t)
+            type Option t = | none () | some t
+            rec range = { i j => if i = j then nil () else cons (i, range (i + 1) j) end }
+            let l = cons (1, cons (2, nil ()))
+            let t = (1, \"a\")
+            let o = some (some (ref none ()))
+            let f = { x y => x + 1 = y }
+            let g = f 1
+            let m = substr \"abc\" 1
+            let long = range 0 30";
+        let parsed = parse(prgrm).unwrap();
+        let compiled = ast2imper_ast(parsed, &imports()).unwrap();
+        let mut ctx = Context::new(&compiled);
+        ctx.eval_toplevel().unwrap();
+        let mut printer = Printer::new(&compiled);
+        let print = |printer: &Printer, name| printer.value(&ctx.global(name).unwrap());
+        assert_eq!(print(&printer, "l"), "cons(1, cons(2, nil ()))");
+        assert_eq!(print(&printer, "t"), "(1, \"a\")");
+        assert_eq!(print(&printer, "o"), "some (some (ref (none ())))");
+        assert_eq!(print(&printer, "f"), "<fn int -> int -> bool>");
+        assert_eq!(print(&printer, "g"), "<fn int -> bool>");
+        assert_eq!(print(&printer, "m"), "<fn int -> string>");
+        assert_eq!(
+            printer.binding("t", ctx.global_type("t"), &ctx.global("t").unwrap()),
+            "t : (int, string) = (1, \"a\")"
+        );
+
+        let long = print(&printer, "long");
+        assert!(long.starts_with("cons(0, cons(1, cons(2, "));
+        assert!(long.lines().count() > 1);
+        assert!(long.lines().all(|line| line.len() <= 80));
+        assert!(long.lines().skip(1).all(|line| line.starts_with("    cons(")));
+        printer.width = 1000;
+        assert_eq!(print(&printer, "long").lines().count(), 1);
+        printer.max_depth = 4;
+        assert_eq!(print(&printer, "long"), "cons(0, cons(1, cons(2, ...)))");
     }
 
     #[test]
     fn interpret_function() {
         let prgrm = "let add = {m => {n => m + n}}";
         let parsed = parse(prgrm).unwrap();
-        let compiled = ast2imper_ast(parsed).unwrap();
+        let compiled = ast2imper_ast(parsed, &imports()).unwrap();
         let ctx = Context::new(&compiled);
     }
+
+    #[test]
+    fn limits() {
+        let prgrm = "rec spin = { n => spin (n + 1) }
+            rec deep = { 0 => 0, n => 1 + deep (n - 1) }
+            rec grow = { l => grow (cons (0, l)) }
+            let grow_empty = { () => grow (nil ()) }
+            rec double = { s => double (s ++ s) }
+            let small = deep 100";
+        let parsed = parse(prgrm).unwrap();
+        let compiled = ast2imper_ast(parsed, &imports()).unwrap();
+        let mut ctx = Context::new(&compiled);
+        ctx.set_limits(Limits { steps: Some(100_000), depth: Some(1000), heap: Some(10_000) });
+        ctx.eval_toplevel().unwrap();
+        let (steps, _) = ctx.usage();
+        assert!(steps > 100);
+        let limit = |name, arg| match ctx.apply_values(ctx.global(name).unwrap(), vec![Rc::new(arg)]) {
+            Err(RuntimeError { error: IntrpErr::LimitExceeded(limit), .. }) => limit,
+            r => panic!("unexpected result {:?}", r),
+        };
+        ctx.reset_usage();
+        assert_eq!(limit("spin", Value::Int(0)), Limit::Steps);
+        ctx.reset_usage();
+        assert_eq!(limit("deep", Value::Int(10_000)), Limit::Depth);
+        ctx.reset_usage();
+        assert_eq!(limit("grow_empty", Value::Unit), Limit::Heap);
+        // strings count by their size
+        ctx.reset_usage();
+        assert_eq!(limit("double", Value::String("ab".to_owned())), Limit::Heap);
+        assert!(ctx.usage().0 < 1000);
+        // the counts go on until they're reset
+        ctx.reset_usage();
+        let deep = |n| ctx.apply_values(ctx.global("deep").unwrap(), vec![Rc::new(Value::Int(n))]).unwrap();
+        deep(10);
+        let (steps, _) = ctx.usage();
+        deep(10);
+        assert_eq!(ctx.usage(), (2 * steps, 0));
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
-pub enum Value {
+pub enum Value<'a> {
     Unit,
     Int(isize),
     Bool(bool),
@@ -55,223 +154,921 @@ pub enum Value {
     // value of sum tag, not directly accessible
     Tag(u16),
     /// nth type's mth constructor applied to value
-    SumVar(u16, u16, Rc<Value>),
-    Tuple(Vec<Rc<Value>>),
+    SumVar(u16, u16, Rc<Value<'a>>),
+    Tuple(Vec<Rc<Value<'a>>>),
     /// nth function from context, caputuring list of values and
     /// currying partially applied with second list of values
-    Closure(u16, Vec<Rc<Value>>, Vec<Rc<Value>>),
+    Closure(u16, Captures<'a>, Vec<Rc<Value<'a>>>),
     Constructor(u16, u16),
-    Imported(&'static str),
+    /// nth function of the host partially applied with a list of values
+    Imported(u16, Vec<Rc<Value<'a>>>),
+    /// mutable reference cell, shared by all copies of the Rc holding it
+    Ref(RefCell<Rc<Value<'a>>>),
+    /// a captured continuation, resumed by applying it to a value
+    Cont(Kont<'a>),
+    /// a continuation captured by callcc, applying it abandons the current one
+    Escape(Kont<'a>),
 }
 
 #[derive(Debug)]
-pub enum IntrpErr {
+pub enum IntrpErr<'a> {
     TypeMismatch,
     InvalidPath,
     NonExhaustivePattern,
+    /// a raised exception of type exn not caught yet
+    Exception(Rc<Value<'a>>),
+    /// nth effect operation performed outside any handler of it
+    UnhandledEffect(u16),
+    /// a builtin applied to a value it doesn't accept
+    BadArgument(&'static str),
+    /// a function registered by the host failed with a message
+    Host(&'static str, String),
+    /// evaluation went past one of the limits of the context
+    LimitExceeded(Limit),
+}
+
+/// Bounds on the evaluation in a context, counted from its creation or the last
+/// `Context::reset_usage`. None is unbounded, the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// steps of the machine: expressions started, values returned to frames and raises
+    pub steps: Option<u64>,
+    /// frames on the stack, e.g. calls not in tail position and their pending arguments
+    pub depth: Option<usize>,
+    /// values allocated that hold other values: tuples, constructor values, closures,
+    /// partial applications and refs, and strings made by concatenation counted by size
+    pub heap: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    Depth,
+    Heap,
+}
+
+/// the implementation of a function provided by the host
+pub type HostCall = Rc<dyn for<'a> Fn(&[Rc<Value<'a>>]) -> Result<Rc<Value<'a>>, IntrpErr<'a>>>;
+
+/// gives a closure the signature of host functions, which isn't inferred for closures
+pub fn host_call<F>(f: F) -> F
+where
+    F: for<'a> Fn(&[Rc<Value<'a>>]) -> Result<Rc<Value<'a>>, IntrpErr<'a>>,
+{
+    f
+}
+
+/// A function provided by the host, either a builtin or registered by an embedder.
+/// A module refers to it by its position among the module's imports.
+#[derive(Clone)]
+pub struct HostFn {
+    pub name: &'static str,
+    pub ty: Type,
+    /// number of arguments applied before it's called
+    pub arity: usize,
+    /// called with exactly `arity` arguments
+    pub call: HostCall,
+}
+
+/// An error that stopped evaluation and where it happened
+#[derive(Debug)]
+pub struct RuntimeError<'a> {
+    pub error: IntrpErr<'a>,
+    /// the last application, arithmetic operation or raise evaluated
+    pub span: Option<Span>,
+    /// the active calls, innermost first: the called closure and the call site
+    pub trace: Vec<(u16, Option<Span>)>,
+    /// index of the top-level declaration being evaluated
+    pub binding: Option<usize>,
+}
+
+impl<'a> RuntimeError<'a> {
+    fn new(error: IntrpErr<'a>, span: Option<Span>, stack: &[Frame<'a>]) -> Self {
+        let trace = stack
+            .iter()
+            .rev()
+            .filter_map(|frame| match *frame {
+                Frame::Call(n, site) => Some((n, site)),
+                _ => None,
+            })
+            .collect();
+        RuntimeError { error, span, trace, binding: None }
+    }
+
+    /// describe the error, src is the parsed source to find line numbers in
+    pub fn report(&self, module: &Module, src: &str) -> String {
+        let at = |span: Span| prelude::position(src, span).to_string();
+        let mut s = match self.error {
+            IntrpErr::Exception(ref exn) => format!("uncaught exception {}", Printer::new(module).value(exn)),
+            IntrpErr::UnhandledEffect(op) => format!("unhandled effect {}", module.effects[op as usize].0),
+            IntrpErr::BadArgument(name) => format!("invalid argument to {}", name),
+            IntrpErr::Host(name, ref message) => format!("{} failed: {}", name, message),
+            IntrpErr::LimitExceeded(Limit::Steps) => "step limit exceeded".to_owned(),
+            IntrpErr::LimitExceeded(Limit::Depth) => "stack depth limit exceeded".to_owned(),
+            IntrpErr::LimitExceeded(Limit::Heap) => "heap limit exceeded".to_owned(),
+            ref e => format!("internal error {:?}", e),
+        };
+        s = format!("error: {}\n", s);
+        if let Some(span) = self.span {
+            s += &format!("  at {}\n", at(span));
+        }
+        // consecutive calls with the same site are folded, e.g. non-tail recursion
+        let mut i = 0;
+        while i < self.trace.len() {
+            let (n, site) = self.trace[i];
+            let repeated = self.trace[i..].iter().take_while(|&&call| call == (n, site)).count();
+            match module.closures[n as usize].name {
+                Some(name) => s += &format!("  in {}", name),
+                None => s += &format!("  in <closure {}>", n),
+            }
+            if let Some(site) = site {
+                s += &format!(", called at {}", at(site));
+            }
+            if repeated > 1 {
+                s += &format!(" ({} times)", repeated);
+            }
+            s += "\n";
+            i += repeated;
+        }
+        if let Some(i) = self.binding {
+            s += &format!("  in the declaration at {}\n", at(module.globals[i].3));
+        }
+        s
+    }
+}
+
+impl<'a> Value<'a> {
+    /// move the values held by self to pending, leaving self without children.
+    /// Values with no children are left in place to avoid allocating a placeholder.
+    fn take_children(&mut self, pending: &mut Vec<Rc<Value<'a>>>) {
+        fn has_children(v: &Rc<Value>) -> bool {
+            matches!(
+                **v,
+                Value::SumVar(..) | Value::Tuple(_) | Value::Closure(..) | Value::Imported(..) | Value::Ref(_)
+            )
+        }
+        match self {
+            Value::SumVar(_, _, v) if has_children(v) => pending.push(mem::replace(v, Rc::new(Value::Unit))),
+            Value::Tuple(v) => pending.append(v),
+            Value::Closure(_, captures, args) => {
+                if let Some(captures) = Rc::get_mut(captures) {
+                    pending.append(captures);
+                }
+                pending.append(args);
+            }
+            Value::Imported(_, args) => pending.append(args),
+            Value::Ref(cell) if has_children(&cell.borrow()) => pending.push(cell.replace(Rc::new(Value::Unit))),
+            _ => (),
+        }
+    }
+}
+
+/// Values are dropped iteratively, the default recursive drop overflows
+/// the stack on long lists.
+impl<'a> Drop for Value<'a> {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(v) = pending.pop() {
+            if let Ok(mut v) = Rc::try_unwrap(v) {
+                v.take_children(&mut pending);
+            }
+        }
+    }
+}
+
+/// the value of a built-in exception
+fn builtin_exn<'a>(position: u16) -> IntrpErr<'a> {
+    IntrpErr::Exception(Rc::new(Value::SumVar(EXN_TYPE, position, Rc::new(Value::Unit))))
+}
+
+/// the captured values of a closure, shared by its partial applications and calls
+type Captures<'a> = Rc<Vec<Rc<Value<'a>>>>;
+
+/// locals and captures of the running closure
+#[derive(Default)]
+struct Env<'a> {
+    locals: Vec<Rc<Value<'a>>>,
+    captures: Captures<'a>,
+}
+
+/// A continuation frame, says what to do with the value of the current expression.
+/// Frames that evaluate more expressions keep the environment to evaluate them in.
+#[derive(Clone)]
+enum Frame<'a> {
+    /// evaluate the right operand
+    BinOpLeft(BinOpcode, &'a Expr<'a>, Rc<Env<'a>>, Span),
+    /// apply the operation to the left operand and the value
+    BinOpRight(BinOpcode, Rc<Value<'a>>, Span),
+    UnOp(UnOpcode),
+    /// the elements left to evaluate and the values of the previous ones
+    Tuple(&'a [Expr<'a>], Vec<Rc<Value<'a>>>, Rc<Env<'a>>),
+    /// evaluate the argument, the value is the function
+    AppArg(&'a Expr<'a>, Rc<Env<'a>>, Span),
+    /// apply the function to the value
+    AppCall(Rc<Value<'a>>, Span),
+    /// evaluate the arguments of a call, the value is the function
+    CallFn(&'a [Expr<'a>], Rc<Env<'a>>, Span),
+    /// the function, the arguments left to evaluate and the values of the previous ones
+    CallArgs(Rc<Value<'a>>, &'a [Expr<'a>], Vec<Rc<Value<'a>>>, Rc<Env<'a>>, Span),
+    SumVal(u16, u16),
+    /// the branches of a conditional
+    Cond(&'a Expr<'a>, &'a Expr<'a>, Rc<Env<'a>>),
+    Raise(Span),
+    /// exceptions raised below are matched against the handler closure,
+    /// whose captures are taken from the environment
+    Try(u16, Rc<Env<'a>>),
+    Perform(u16),
+    /// delimits the computation handled by an effect handler
+    Handle(Rc<Handler<'a>>),
+    /// the body of nth closure is being evaluated, called from the span if any
+    Call(u16, Option<Span>),
+}
+
+impl<'a> Frame<'a> {
+    /// position of the operation the frame performs when resumed
+    fn span(&self) -> Option<Span> {
+        match *self {
+            Frame::BinOpRight(_, _, span)
+            | Frame::AppCall(_, span)
+            | Frame::CallArgs(_, _, _, _, span)
+            | Frame::Raise(span) => Some(span),
+            _ => None,
+        }
+    }
+}
+
+/// the arms of an effect handler as closures with their captures
+struct Handler<'a> {
+    ret: Option<(u16, Captures<'a>)>,
+    /// (effect, closure, captures)
+    ops: Vec<(u16, u16, Captures<'a>)>,
+}
+
+/// the frames of a captured continuation, the first frame is the bottom of the stack
+#[derive(Clone)]
+pub struct Kont<'a>(Rc<Vec<Frame<'a>>>);
+
+impl<'a> PartialEq for Kont<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<'a> Eq for Kont<'a> {}
+
+impl<'a> fmt::Debug for Kont<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Kont({} frames)", self.0.len())
+    }
+}
+
+/// the next step of the machine
+enum Control<'a> {
+    /// evaluate the expression
+    Eval(&'a Expr<'a>),
+    /// pass the value to the top frame
+    Return(Rc<Value<'a>>),
+    /// unwind the stack to the closest matching exception handler
+    Raise(Rc<Value<'a>>),
 }
 
-pub struct Context<'a, 'input> {
-    module: &'a Module<'input>,
-    statics: Vec<Rc<Value>>,
-    locals: Vec<Rc<Value>>,
-    captures: Vec<Rc<Value>>,
+pub struct Context<'a> {
+    module: &'a Module<'a>,
+    statics: Vec<Rc<Value<'a>>>,
+    /// functions imported by the module, in the same order
+    hosts: Rc<Vec<HostFn>>,
+    limits: Limits,
+    /// steps and values allocated so far, see Limits
+    steps: Cell<u64>,
+    allocated: Cell<u64>,
 }
 
-impl<'a, 'input> Context<'a, 'input> {
-    pub fn new(module: &'a Module<'input>) -> Self {
+impl<'a> Context<'a> {
+    /// context of a module type-checked with the builtins as its imports, without I/O
+    pub fn new(module: &'a Module<'a>) -> Self {
+        Context::with_hosts(module, Rc::new(builtins::host_fns(&Capabilities::default())))
+    }
+
+    /// context of a module type-checked with the names and types of hosts as its imports
+    pub fn with_hosts(module: &'a Module<'a>, hosts: Rc<Vec<HostFn>>) -> Self {
+        debug_assert_eq!(module.imports.len(), hosts.len());
         Context {
             module,
             statics: vec![],
-            locals: vec![],
-            captures: vec![],
+            hosts,
+            limits: Limits::default(),
+            steps: Cell::new(0),
+            allocated: Cell::new(0),
         }
     }
 
-    pub fn eval_toplevel(&mut self) {
-        for (e, _, _) in &self.module.globals {
-            let value = self.eval_exp(e).unwrap();
-            // println!("{}", value.display(self.module));
+    /// stop evaluation that goes past limits from now on
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// count steps and allocated values from zero again, e.g. before each call of a host
+    pub fn reset_usage(&self) {
+        self.steps.set(0);
+        self.allocated.set(0);
+    }
+
+    /// steps taken and values allocated so far
+    pub fn usage(&self) -> (u64, u64) {
+        (self.steps.get(), self.allocated.get())
+    }
+
+    pub fn eval_toplevel(&mut self) -> Result<(), RuntimeError<'a>> {
+        let module = self.module;
+        for (i, (e, constraints, _, span)) in module.globals.iter().enumerate() {
+            let value = self.eval_exp(e).map_err(|err| RuntimeError { binding: Some(i), ..err })?;
             self.statics.push(value);
-            // check satisfaction of constraints and print?
+            // tags come before the paths of variant values in the order of ValPaths,
+            // so a path is only followed if the tags leading to it matched
+            let satisfied = constraints.iter().all(|(path, constraint)| match path {
+                ValPath::StaticVal(v) => match pathvec_from_valvec(v, &self.statics) {
+                    Ok(value) => satisfies(&value, constraint),
+                    Err(_) => false,
+                },
+                _ => false,
+            });
+            if !satisfied {
+                return Err(RuntimeError {
+                    error: builtin_exn(EXN_MATCH_FAILURE),
+                    span: Some(*span),
+                    trace: vec![],
+                    binding: Some(i),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn module(&self) -> &'a Module<'a> {
+        self.module
+    }
+
+    /// value of a top-level name once its declaration is evaluated
+    pub fn global(&self, name: &str) -> Option<Rc<Value<'a>>> {
+        match self.module.globals_names.get(name) {
+            Some(ValPath::StaticVal(path)) if (path[0] as usize) < self.statics.len() => {
+                pathvec_from_valvec(path, &self.statics).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// inferred type of a top-level name, if it's bound in a tuple or directly
+    pub fn global_type(&self, name: &str) -> Option<&'a Type> {
+        match self.module.globals_names.get(name) {
+            Some(ValPath::StaticVal(path)) => {
+                let mut t = &self.module.globals.get(path[0] as usize)?.2;
+                for &i in &path[1..] {
+                    match *t {
+                        Type::Tuple(ref types) => t = types.get(i as usize)?,
+                        _ => return None,
+                    }
+                }
+                Some(t)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn eval_exp(&self, expr: &'a Expr<'a>) -> Result<Rc<Value<'a>>, RuntimeError<'a>> {
+        self.run(expr, Rc::new(Env::default()))
+    }
+
+    /// apply a function to arguments one at a time, like an application in the program
+    pub fn apply_values(
+        &self,
+        f: Rc<Value<'a>>,
+        args: Vec<Rc<Value<'a>>>,
+    ) -> Result<Rc<Value<'a>>, RuntimeError<'a>> {
+        let mut f = f;
+        for arg in args {
+            let mut env = Rc::new(Env::default());
+            let mut stack = Vec::new();
+            let control = match self.apply(f, arg, &mut env, &mut stack, None) {
+                Ok(control) => control,
+                Err(IntrpErr::Exception(exn)) => Control::Raise(exn),
+                Err(e) => return Err(RuntimeError::new(e, None, &stack)),
+            };
+            f = self.run_machine(control, env, stack)?;
+        }
+        Ok(f)
+    }
+
+    /// run the machine until the value of expr is returned to an empty stack
+    fn run(&self, expr: &'a Expr<'a>, env: Rc<Env<'a>>) -> Result<Rc<Value<'a>>, RuntimeError<'a>> {
+        self.run_machine(Control::Eval(expr), env, Vec::new())
+    }
+
+    /// run the machine from a control until a value is returned to an empty stack
+    fn run_machine(
+        &self,
+        mut control: Control<'a>,
+        mut env: Rc<Env<'a>>,
+        mut stack: Vec<Frame<'a>>,
+    ) -> Result<Rc<Value<'a>>, RuntimeError<'a>> {
+        // position of the last operation that could fail
+        let mut site = None;
+        let max_steps = self.limits.steps.unwrap_or(u64::MAX);
+        let max_depth = self.limits.depth.unwrap_or(usize::MAX);
+        loop {
+            let steps = self.steps.get() + 1;
+            self.steps.set(steps);
+            if steps > max_steps || stack.len() > max_depth {
+                let limit = if steps > max_steps { Limit::Steps } else { Limit::Depth };
+                return Err(RuntimeError::new(IntrpErr::LimitExceeded(limit), site, &stack));
+            }
+            let result = match control {
+                Control::Eval(e) => self.eval(e, &mut env, &mut stack),
+                Control::Return(v) => match stack.pop() {
+                    None => return Ok(v),
+                    Some(frame) => {
+                        if let Some(span) = frame.span() {
+                            site = Some(span);
+                        }
+                        self.resume(frame, v, &mut env, &mut stack)
+                    }
+                },
+                Control::Raise(exn) => {
+                    control = match self.unwind(exn, &mut env, &mut stack) {
+                        Ok(control) => control,
+                        Err(e) => return Err(RuntimeError::new(e, site, &stack)),
+                    };
+                    continue;
+                }
+            };
+            control = match result {
+                Ok(control) => control,
+                // exceptions raised by the machine itself, e.g. division by zero
+                Err(IntrpErr::Exception(exn)) => Control::Raise(exn),
+                Err(e) => return Err(RuntimeError::new(e, site, &stack)),
+            };
         }
     }
 
-    fn resolve(&self, path: &ValPath) -> Result<Rc<Value>, IntrpErr> {
+    fn resolve(&self, path: &ValPath, env: &Env<'a>) -> Result<Rc<Value<'a>>, IntrpErr<'a>> {
         match path {
-            ValPath::Local(ref v) => pathvec_from_valvec(v, &self.locals),
+            ValPath::Local(ref v) => pathvec_from_valvec(v, &env.locals),
             ValPath::StaticVal(ref v) => pathvec_from_valvec(v, &self.statics),
-            ValPath::CaptureLocal(i, _) => pathvec_from_valvec(&[*i], &self.captures),
-            ValPath::CaptureCaptured(i, _) => pathvec_from_valvec(&[*i], &self.captures),
+            ValPath::CaptureLocal(i, _) => pathvec_from_valvec(&[*i], &env.captures),
+            ValPath::CaptureCaptured(i, _) => pathvec_from_valvec(&[*i], &env.captures),
             ValPath::Constructor(i, j) => Ok(Rc::new(Value::Constructor(*i, *j))),
-            ValPath::Imported(s) => Ok(Rc::new(Value::Imported(s))),
+            ValPath::Imported(n) => Ok(Rc::new(Value::Imported(*n, Vec::new()))),
         }
     }
 
-    pub fn eval_exp(&self, expr: &Expr) -> Result<Rc<Value>, IntrpErr> {
+    /// start evaluating an expression, pushing a frame for the rest of it if needed
+    fn eval(
+        &self,
+        expr: &'a Expr<'a>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
         //println!["{:?}", &expr];
-        match expr {
-            &Expr::Literal(Literal::Unit) => Ok(Rc::new(Value::Unit)),
-            &Expr::Literal(Literal::Int(n)) => Ok(Rc::new(Value::Int(n))),
-            &Expr::Literal(Literal::Bool(p)) => Ok(Rc::new(Value::Bool(p))),
-            &Expr::Literal(Literal::String(s)) => Ok(Rc::new(Value::String(s.to_owned()))),
-            &Expr::Bound(ref path) => self.resolve(path),
-            &Expr::UnOp(op, ref e) => self.eval_unop(op, e),
-            &Expr::BinOp(ref e1, op, ref e2) => self.eval_binop(e1, op, e2),
-            &Expr::Closure(n) => Ok(Rc::new(Value::Closure(n, self.gen_captures(n)?, vec![]))),
-            &Expr::Tuple(ref v) => self.eval_tuple(v),
-            &Expr::Application(ref e1, ref e2) => self.eval_appl(e1, e2),
+        let control = match expr {
+            &Expr::Literal(Literal::Unit) => Control::Return(Rc::new(Value::Unit)),
+            &Expr::Literal(Literal::Int(n)) => Control::Return(Rc::new(Value::Int(n))),
+            &Expr::Literal(Literal::Bool(p)) => Control::Return(Rc::new(Value::Bool(p))),
+            &Expr::Literal(Literal::String(s)) => Control::Return(Rc::new(Value::String(s.to_owned()))),
+            &Expr::Bound(ref path) => Control::Return(self.resolve(path, env)?),
+            &Expr::UnOp(op, ref e) => {
+                stack.push(Frame::UnOp(op));
+                Control::Eval(e)
+            }
+            &Expr::BinOp(ref e1, op, ref e2, span) => {
+                stack.push(Frame::BinOpLeft(op, e2, env.clone(), span));
+                Control::Eval(e1)
+            }
+            &Expr::Closure(n) => {
+                self.alloc(1)?;
+                Control::Return(Rc::new(Value::Closure(n, self.gen_captures(n, env)?, vec![])))
+            }
+            &Expr::Tuple(ref v) => match v.split_first() {
+                None => Control::Return(Rc::new(Value::Tuple(vec![]))),
+                Some((first, rest)) => {
+                    stack.push(Frame::Tuple(rest, Vec::with_capacity(v.len()), env.clone()));
+                    Control::Eval(first)
+                }
+            },
+            &Expr::Application(ref e1, ref e2, span) => {
+                stack.push(Frame::AppArg(e2, env.clone(), span));
+                Control::Eval(e1)
+            }
+            &Expr::Call(ref f, ref args, span) => {
+                stack.push(Frame::CallFn(args, env.clone(), span));
+                Control::Eval(f)
+            }
             &Expr::SumVal {
                 target,
                 position,
                 ref value,
-            } => Ok(Rc::new(Value::SumVar(
-                target,
-                position,
-                self.eval_exp(value)?,
-            ))),
-            &Expr::Conditional(ref cond, ref e1, ref e2) => match *self.eval_exp(cond)? {
-                Value::Bool(true) => self.eval_exp(e1),
-                Value::Bool(false) => self.eval_exp(e2),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
+            } => {
+                stack.push(Frame::SumVal(target, position));
+                Control::Eval(value)
+            }
+            &Expr::Conditional(ref cond, ref e1, ref e2) => {
+                stack.push(Frame::Cond(e1, e2, env.clone()));
+                Control::Eval(cond)
+            }
+            &Expr::Raise(ref e, span) => {
+                stack.push(Frame::Raise(span));
+                Control::Eval(e)
+            }
+            &Expr::Try(ref e, handler) => {
+                stack.push(Frame::Try(handler, env.clone()));
+                Control::Eval(e)
+            }
+            &Expr::Perform(op, ref e) => {
+                stack.push(Frame::Perform(op));
+                Control::Eval(e)
+            }
+            &Expr::Handle { ref body, ret, ref ops } => {
+                let ret = match ret {
+                    Some(n) => Some((n, self.gen_captures(n, env)?)),
+                    None => None,
+                };
+                let ops = ops
+                    .iter()
+                    .map(|&(op, n)| Ok((op, n, self.gen_captures(n, env)?)))
+                    .collect::<Result<_, _>>()?;
+                stack.push(Frame::Handle(Rc::new(Handler { ret, ops })));
+                Control::Eval(body)
+            }
             &Expr::Error => panic!("Error"),
-        }
-    }
-
-    fn eval_unop(&self, op: UnOpcode, e: &Expr) -> Result<Rc<Value>, IntrpErr> {
-        match (op, &*self.eval_exp(e)?) {
-            (UnOpcode::Not, &Value::Bool(p)) => Ok(Rc::new(Value::Bool(!p))),
-            (UnOpcode::Minus, &Value::Int(n)) => Ok(Rc::new(Value::Int(-n))),
-            _ => Err(IntrpErr::TypeMismatch),
-        }
+        };
+        Ok(control)
     }
 
-    fn eval_binop(&self, e1: &Expr, op: BinOpcode, e2: &Expr) -> Result<Rc<Value>, IntrpErr> {
-        match (&*self.eval_exp(e1)?, &*self.eval_exp(e2)?) {
-            (Value::Int(n), Value::Int(m)) => match op {
-                BinOpcode::Add => Ok(Rc::new(Value::Int(n + m))),
-                BinOpcode::Sub => Ok(Rc::new(Value::Int(n - m))),
-                BinOpcode::Mul => Ok(Rc::new(Value::Int(n * m))),
-                BinOpcode::Div => Ok(Rc::new(Value::Int(n / m))),
-                BinOpcode::Mod => Ok(Rc::new(Value::Int(n % m))),
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(n == m))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(n != m))),
-                BinOpcode::Greater => Ok(Rc::new(Value::Bool(n > m))),
-                BinOpcode::GreaterEq => Ok(Rc::new(Value::Bool(n >= m))),
-                BinOpcode::Less => Ok(Rc::new(Value::Bool(n < m))),
-                BinOpcode::LessEq => Ok(Rc::new(Value::Bool(n <= m))),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
-            (&Value::Bool(p), &Value::Bool(q)) => match op {
-                BinOpcode::And => Ok(Rc::new(Value::Bool(p && q))),
-                BinOpcode::Or => Ok(Rc::new(Value::Bool(p || q))),
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(p == q))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(p != q))),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
-            (Value::Unit, Value::Unit) => match op {
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(true))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(false))),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
-            (Value::String(s1), Value::String(s2)) => match op {
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(s1 == s2))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(s1 != s2))),
-                BinOpcode::Concat => Ok(Rc::new(Value::String(s1.clone()+s2))),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
-
-            // NOTE: All equality operations below need reconsideration
-            (Value::SumVar(..), Value::SumVar(..)) => match op {
-                BinOpcode::Equal | BinOpcode::NotEq => panic!("sum type equality"),
-                _ => Err(IntrpErr::TypeMismatch),
-            },
-            (Value::Tuple(v1), Value::Tuple(v2)) => match op {
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(v1 == v2))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(v1 != v2))),
-                _ => Err(IntrpErr::TypeMismatch),
+    /// pass the value v to the frame popped from the top of the stack
+    fn resume(
+        &self,
+        frame: Frame<'a>,
+        v: Rc<Value<'a>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
+        let control = match frame {
+            Frame::BinOpLeft(op, e2, frame_env, span) => {
+                *env = frame_env;
+                stack.push(Frame::BinOpRight(op, v, span));
+                Control::Eval(e2)
+            }
+            Frame::BinOpRight(op, v1, _) => {
+                let v = eval_binop(op, &v1, v)?;
+                if let (BinOpcode::Concat, Value::String(s)) = (op, &*v) {
+                    self.alloc(1 + (s.len() / mem::size_of::<Value>()) as u64)?;
+                }
+                Control::Return(v)
+            }
+            Frame::UnOp(op) => {
+                if op == UnOpcode::Ref {
+                    self.alloc(1)?;
+                }
+                Control::Return(eval_unop(op, v)?)
+            }
+            Frame::Tuple(rest, mut values, frame_env) => {
+                values.push(v);
+                match rest.split_first() {
+                    None => {
+                        self.alloc(1)?;
+                        Control::Return(Rc::new(Value::Tuple(values)))
+                    }
+                    Some((next, rest)) => {
+                        stack.push(Frame::Tuple(rest, values, frame_env.clone()));
+                        *env = frame_env;
+                        Control::Eval(next)
+                    }
+                }
+            }
+            Frame::AppArg(e2, frame_env, span) => {
+                *env = frame_env;
+                stack.push(Frame::AppCall(v, span));
+                Control::Eval(e2)
+            }
+            Frame::AppCall(f, span) => return self.apply(f, v, env, stack, Some(span)),
+            Frame::CallFn(args, frame_env, span) => match args.split_first() {
+                None => return self.call_all(v, Vec::new(), env, stack, span),
+                Some((first, rest)) => {
+                    stack.push(Frame::CallArgs(v, rest, Vec::with_capacity(args.len()), frame_env.clone(), span));
+                    *env = frame_env;
+                    Control::Eval(first)
+                }
             },
-            (c1 @ Value::Closure(..), c2 @ Value::Closure(..)) => match op {
-                BinOpcode::Equal => Ok(Rc::new(Value::Bool(c1 == c2))),
-                BinOpcode::NotEq => Ok(Rc::new(Value::Bool(c1 != c2))),
-                _ => Err(IntrpErr::TypeMismatch),
+            Frame::CallArgs(f, rest, mut values, frame_env, span) => {
+                values.push(v);
+                match rest.split_first() {
+                    None => return self.call_all(f, values, env, stack, span),
+                    Some((next, rest)) => {
+                        stack.push(Frame::CallArgs(f, rest, values, frame_env.clone(), span));
+                        *env = frame_env;
+                        Control::Eval(next)
+                    }
+                }
+            }
+            Frame::SumVal(target, position) => {
+                self.alloc(1)?;
+                Control::Return(Rc::new(Value::SumVar(target, position, v)))
+            }
+            Frame::Cond(e1, e2, frame_env) => {
+                *env = frame_env;
+                match *v {
+                    Value::Bool(true) => Control::Eval(e1),
+                    Value::Bool(false) => Control::Eval(e2),
+                    _ => return Err(IntrpErr::TypeMismatch),
+                }
+            }
+            Frame::Raise(_) => Control::Raise(v),
+            Frame::Try(..) => Control::Return(v),
+            Frame::Perform(op) => return self.perform(op, v, env, stack),
+            Frame::Handle(handler) => match handler.ret {
+                Some((n, ref captures)) => return self.call(n, captures.clone(), vec![v], env, stack, None),
+                None => Control::Return(v),
             },
-            _ => Err(IntrpErr::TypeMismatch),
-        }
-    }
-
-    fn eval_tuple(&self, v: &Vec<Expr>) -> Result<Rc<Value>, IntrpErr> {
-        Ok(Rc::new(Value::Tuple(
-            v.iter()
-                .map(|e| self.eval_exp(e))
-                .collect::<Result<Vec<_>, _>>()?,
-        )))
+            Frame::Call(..) => Control::Return(v),
+        };
+        Ok(control)
     }
 
-    fn eval_appl(&self, e1: &Expr, e2: &Expr) -> Result<Rc<Value>, IntrpErr> {
-        match *self.eval_exp(e1)? {
+    /// apply a function value to an argument at the call site
+    fn apply(
+        &self,
+        f: Rc<Value<'a>>,
+        v: Rc<Value<'a>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+        site: Option<Span>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
+        match *f {
             Value::Closure(n, ref cap, ref cur) => {
                 let mut cur = cur.clone();
-                cur.push(self.eval_exp(e2)?);
+                cur.push(v);
                 if cur.len() < self.module.closures[n as usize].args.len() {
-                    Ok(Rc::new(Value::Closure(n, cap.clone(), cur)))
+                    self.alloc(1)?;
+                    Ok(Control::Return(Rc::new(Value::Closure(n, cap.clone(), cur))))
                 } else {
-                    self.call_fn(n, cap.clone(), cur)
+                    self.call(n, cap.clone(), cur, env, stack, site)
                 }
             }
             Value::Constructor(i, j) => {
-                let e2 = self.eval_exp(e2)?;
-                Ok(Rc::new(Value::SumVar(i, j, e2)))
+                self.alloc(1)?;
+                Ok(Control::Return(Rc::new(Value::SumVar(i, j, v))))
+            }
+            Value::Imported(builtins::CALLCC, _) => {
+                let k = Value::Escape(Kont(Rc::new(stack.clone())));
+                self.apply(v, Rc::new(k), env, stack, site)
+            }
+            Value::Imported(n, ref args) => {
+                let host = self.hosts.get(n as usize).ok_or(IntrpErr::InvalidPath)?;
+                let mut args = args.clone();
+                args.push(v);
+                if args.len() < host.arity {
+                    self.alloc(1)?;
+                    Ok(Control::Return(Rc::new(Value::Imported(n, args))))
+                } else {
+                    Ok(Control::Return((host.call)(&args)?))
+                }
+            }
+            Value::Cont(Kont(ref frames)) => {
+                stack.extend(frames.iter().cloned());
+                Ok(Control::Return(v))
             }
-            Value::Imported(name) => {
-                let e2 = self.eval_exp(e2)?;
-                stl_call(name, e2)
+            Value::Escape(Kont(ref frames)) => {
+                stack.clone_from(frames);
+                Ok(Control::Return(v))
+            }
+            _ => Err(IntrpErr::TypeMismatch),
+        }
+    }
+
+    /// call a closure with all its arguments at once, no partial application is made
+    fn call_all(
+        &self,
+        f: Rc<Value<'a>>,
+        args: Vec<Rc<Value<'a>>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+        site: Span,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
+        match *f {
+            Value::Closure(n, ref cap, ref cur) if cur.is_empty() => {
+                if args.len() != self.module.closures[n as usize].args.len() {
+                    return Err(IntrpErr::TypeMismatch);
+                }
+                self.call(n, cap.clone(), args, env, stack, Some(site))
             }
             _ => Err(IntrpErr::TypeMismatch),
         }
     }
 
-    fn call_fn(
+    /// enter the body of nth closure matching its arguments
+    fn call(
         &self,
         n: u16,
-        captures: Vec<Rc<Value>>,
-        locals: Vec<Rc<Value>>,
-    ) -> Result<Rc<Value>, IntrpErr> {
+        captures: Captures<'a>,
+        locals: Vec<Rc<Value<'a>>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+        site: Option<Span>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
         let func = &self.module.closures[n as usize];
-        let matched_arm = match_tree(&func.dtree, &locals)?;
-        let ctx = Context {
-            module: self.module,
-            statics: self.statics.clone(),
-            captures,
-            locals,
+        let matched_arm = match match_tree(&func.dtree, &locals) {
+            Err(IntrpErr::NonExhaustivePattern) => return Err(builtin_exn(EXN_MATCH_FAILURE)),
+            res => res?,
         };
-        ctx.eval_exp(&func.branches[matched_arm as usize])
+        Ok(self.enter(n, matched_arm, captures, locals, env, stack, site))
+    }
+
+    /// evaluate an arm of nth closure given its captures and arguments
+    fn enter(
+        &self,
+        n: u16,
+        arm: u16,
+        captures: Captures<'a>,
+        locals: Vec<Rc<Value<'a>>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+        site: Option<Span>,
+    ) -> Control<'a> {
+        // nothing is left to do in the caller of a tail call, its frame is replaced
+        if let Some(Frame::Call(..)) = stack.last() {
+            stack.pop();
+        }
+        stack.push(Frame::Call(n, site));
+        *env = Rc::new(Env { locals, captures });
+        Control::Eval(&self.module.closures[n as usize].branches[arm as usize])
+    }
+
+    /// pop frames up to the closest exception handler with an arm matching exn.
+    /// If there is none the stack is left for the trace of the error.
+    fn unwind(
+        &self,
+        exn: Rc<Value<'a>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
+        for i in (0..stack.len()).rev() {
+            if let Frame::Try(handler, ref handler_env) = stack[i] {
+                let locals = vec![exn.clone()];
+                match match_tree(&self.module.closures[handler as usize].dtree, &locals) {
+                    Ok(arm) => {
+                        let captures = self.gen_captures(handler, handler_env)?;
+                        stack.truncate(i);
+                        return Ok(self.enter(handler, arm, captures, locals, env, stack, None));
+                    }
+                    Err(IntrpErr::NonExhaustivePattern) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(IntrpErr::Exception(exn))
+    }
+
+    /// capture the frames up to the closest handler of op and run its arm.
+    /// Handlers are deep, the handler frame is part of the continuation.
+    fn perform(
+        &self,
+        op: u16,
+        v: Rc<Value<'a>>,
+        env: &mut Rc<Env<'a>>,
+        stack: &mut Vec<Frame<'a>>,
+    ) -> Result<Control<'a>, IntrpErr<'a>> {
+        let found = stack.iter().enumerate().rev().find_map(|(i, frame)| match frame {
+            Frame::Handle(handler) => handler
+                .ops
+                .iter()
+                .find(|(effect, ..)| *effect == op)
+                .map(|(_, n, captures)| (i, *n, captures.clone())),
+            _ => None,
+        });
+        match found {
+            Some((i, n, captures)) => {
+                let k = Value::Cont(Kont(Rc::new(stack.split_off(i))));
+                self.call(n, captures, vec![v, Rc::new(k)], env, stack, None)
+            }
+            None => Err(IntrpErr::UnhandledEffect(op)),
+        }
+    }
+
+    /// count n values allocated, failing past the heap limit
+    fn alloc(&self, n: u64) -> Result<(), IntrpErr<'a>> {
+        let allocated = self.allocated.get() + n;
+        self.allocated.set(allocated);
+        match self.limits.heap {
+            Some(max) if allocated > max => Err(IntrpErr::LimitExceeded(Limit::Heap)),
+            _ => Ok(()),
+        }
     }
 
     /// Generate vector of captured value for nth closure
-    fn gen_captures(&self, n: u16) -> Result<Vec<Rc<Value>>, IntrpErr> {
+    fn gen_captures(&self, n: u16, env: &Env<'a>) -> Result<Captures<'a>, IntrpErr<'a>> {
         let closure = &self.module.closures[n as usize];
         let mut captures = vec![None; closure.captures.len()];
 
         for (path, _) in &closure.captures {
             match path {
                 ValPath::CaptureLocal(i, ref v) => {
-                    captures[*i as usize].replace(pathvec_from_valvec(v, &self.locals)?);
+                    captures[*i as usize].replace(pathvec_from_valvec(v, &env.locals)?);
                 }
                 ValPath::CaptureCaptured(i, j) => {
-                    captures[*i as usize].replace(pathvec_from_valvec(&[*j], &self.captures)?);
+                    captures[*i as usize].replace(pathvec_from_valvec(&[*j], &env.captures)?);
                 }
                 _ => return Err(IntrpErr::InvalidPath),
             }
         }
 
-        Ok(captures.into_iter().flatten().collect())
+        Ok(Rc::new(captures.into_iter().flatten().collect()))
     }
 }
 
-pub fn pathvec_from_valvec(path: &[u16], valvec: &Vec<Rc<Value>>) -> Result<Rc<Value>, IntrpErr> {
-    fn pathvec_from_val(path: &[u16], val: &Rc<Value>) -> Result<Rc<Value>, IntrpErr> {
+fn eval_unop<'a>(op: UnOpcode, val: Rc<Value<'a>>) -> Result<Rc<Value<'a>>, IntrpErr<'a>> {
+    match (op, &*val) {
+        (UnOpcode::Not, &Value::Bool(p)) => Ok(Rc::new(Value::Bool(!p))),
+        (UnOpcode::Minus, &Value::Int(n)) => Ok(Rc::new(Value::Int(n.wrapping_neg()))),
+        (UnOpcode::Ref, _) => Ok(Rc::new(Value::Ref(RefCell::new(val.clone())))),
+        (UnOpcode::Deref, Value::Ref(cell)) => Ok(cell.borrow().clone()),
+        _ => Err(IntrpErr::TypeMismatch),
+    }
+}
+
+fn eval_binop<'a>(op: BinOpcode, v1: &Rc<Value<'a>>, v2: Rc<Value<'a>>) -> Result<Rc<Value<'a>>, IntrpErr<'a>> {
+    if let BinOpcode::Assign = op {
+        return match **v1 {
+            Value::Ref(ref cell) => {
+                cell.replace(v2);
+                Ok(Rc::new(Value::Unit))
+            }
+            _ => Err(IntrpErr::TypeMismatch),
+        };
+    }
+    match (&**v1, &*v2) {
+        (Value::Int(n), Value::Int(m)) => match op {
+            // integers wrap around on overflow
+            BinOpcode::Add => Ok(Rc::new(Value::Int(n.wrapping_add(*m)))),
+            BinOpcode::Sub => Ok(Rc::new(Value::Int(n.wrapping_sub(*m)))),
+            BinOpcode::Mul => Ok(Rc::new(Value::Int(n.wrapping_mul(*m)))),
+            BinOpcode::Div | BinOpcode::Mod if *m == 0 => Err(builtin_exn(EXN_DIV_BY_ZERO)),
+            BinOpcode::Div => Ok(Rc::new(Value::Int(n.wrapping_div(*m)))),
+            BinOpcode::Mod => Ok(Rc::new(Value::Int(n.wrapping_rem(*m)))),
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(n == m))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(n != m))),
+            BinOpcode::Greater => Ok(Rc::new(Value::Bool(n > m))),
+            BinOpcode::GreaterEq => Ok(Rc::new(Value::Bool(n >= m))),
+            BinOpcode::Less => Ok(Rc::new(Value::Bool(n < m))),
+            BinOpcode::LessEq => Ok(Rc::new(Value::Bool(n <= m))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        (&Value::Bool(p), &Value::Bool(q)) => match op {
+            BinOpcode::And => Ok(Rc::new(Value::Bool(p && q))),
+            BinOpcode::Or => Ok(Rc::new(Value::Bool(p || q))),
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(p == q))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(p != q))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        (Value::Unit, Value::Unit) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(true))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(false))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        (Value::String(s1), Value::String(s2)) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(s1 == s2))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(s1 != s2))),
+            BinOpcode::Concat => Ok(Rc::new(Value::String(s1.clone()+s2))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+
+        // NOTE: All equality operations below need reconsideration
+        (s1 @ Value::SumVar(..), s2 @ Value::SumVar(..)) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(s1 == s2))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(s1 != s2))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        (Value::Tuple(v1), Value::Tuple(v2)) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(v1 == v2))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(v1 != v2))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        (c1 @ Value::Closure(..), c2 @ Value::Closure(..)) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(c1 == c2))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(c1 != c2))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        // references are equal only if they are the same cell
+        (r1 @ Value::Ref(_), r2 @ Value::Ref(_)) => match op {
+            BinOpcode::Equal => Ok(Rc::new(Value::Bool(ptr::eq(r1, r2)))),
+            BinOpcode::NotEq => Ok(Rc::new(Value::Bool(!ptr::eq(r1, r2)))),
+            _ => Err(IntrpErr::TypeMismatch),
+        },
+        _ => Err(IntrpErr::TypeMismatch),
+    }
+}
+
+pub fn pathvec_from_valvec<'a>(path: &[u16], valvec: &Vec<Rc<Value<'a>>>) -> Result<Rc<Value<'a>>, IntrpErr<'a>> {
+    fn pathvec_from_val<'a>(path: &[u16], val: &Rc<Value<'a>>) -> Result<Rc<Value<'a>>, IntrpErr<'a>> {
         match path {
             [] => Ok(val.clone()),
             [n, tail @ ..] => match **val {
@@ -295,7 +1092,7 @@ pub fn pathvec_from_valvec(path: &[u16], valvec: &Vec<Rc<Value>>) -> Result<Rc<V
     }
 }
 
-impl fmt::Display for Value {
+impl<'a> fmt::Display for Value<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Unit => write!(f, "()"),
@@ -303,74 +1100,191 @@ impl fmt::Display for Value {
             Value::Bool(false) => write!(f, "false"),
             Value::Int(i) => write!(f, "{}", i),
             Value::String(s) => write!(f, "{}", s),
-            Value::Tuple(v) => v
-                .iter()
-                .map(|x| {
+            Value::Tuple(v) => {
+                write!(f, "(")?;
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
                     x.fmt(f)?;
-                    write!(f, ", ")
-                })
-                .collect(),
+                }
+                write!(f, ")")
+            }
             Value::Closure(n, ..) => write!(f, "<closure {}>", n),
-            Value::SumVar(n, m, val) => write!(f, "<type {}>::<variant {}>{}", n, m, val),
+            Value::SumVar(n, m, val) => write!(f, "<type {}>::<variant {}> {}", n, m, val),
             Value::Tag(n) => write!(f, "<tag {}>", n),
-            Value::Constructor(n, m) => write!(f, "<Constructor({}, {}", n, m),
-            Value::Imported(s) => write!(f, "fn::{}", s),
+            Value::Constructor(n, m) => write!(f, "<Constructor({}, {})>", n, m),
+            Value::Imported(n, _) => write!(f, "<import {}>", n),
+            Value::Ref(cell) => write!(f, "ref {}", cell.borrow()),
+            Value::Cont(_) | Value::Escape(_) => write!(f, "<continuation>"),
         }
     }
 }
 
-// impl Value {
-//     fn display<'input>(&self, module: &Module<'input>) -> String {
-//         fn _display<'input>(v: &Value, module: &Module<'input>, s: &mut String) -> fmt::Result {
-//             match v {
-//                 Value::Unit => write!(s, "()"),
-//                 Value::Bool(true) => write!(s, "true"),
-//                 Value::Bool(false) => write!(s, "false"),
-//                 Value::Int(i) => write!(s, "{}", i),
-//                 Value::String(sval) => write!(s, "{}", sval),
-//                 Value::Tuple(v) => {
-//                     write!(s, "(")?;
-//                     for i in 0..(v.len() - 1) {
-//                         _display(&v[i], module, s).expect("format error!");
-//                         write!(s, ", ")?;
-//                     }
-//                     _display(&v.last().expect("Empty Tuple!"), module, s).expect("format error!");
-//                     write!(s, ")")
-//                 }
-//                 Value::Closure(n, ..) => {
-//                     write!(s, "<closure ")?;
-//                     let closure = &module.closures[*n as usize];
-//                     for t in &closure.args {
-//                         write!(s, "{:#?} -> ", t)?;
-//                     }
-//                     write!(s, "{:#?}>", closure.return_type)
-//                 }
-//                 Value::SumVar(n, m, val) => {
-//                     write!(s, "{}::", module.type_decls[*n as usize].name)?;
-//                     write!(
-//                         s,
-//                         "{} ",
-//                         module.type_decls[*n as usize].variants[*m as usize - 1].0
-//                     )?;
-//                     _display(val, module, s)
-//                 }
-//                 Value::Tag(n) => write!(s, "<tag {}>", n),
-//                 Value::Constructor(n, m) => write!(
-//                     s,
-//                     "{}::{}",
-//                     module.type_decls[*n as usize].name,
-//                     module.type_decls[*n as usize].variants[*m as usize].0
-//                 ),
-//                 Value::Imported(name) => write!(s, "fn::{}", name),
-//             }
-//         }
-//         let mut s = String::new();
-//         _display(self, module, &mut s).expect("format error!");
-//         s
-//     }
-// }
-
-pub fn match_tree(tree: &DTree, valvec: &Vec<Rc<Value>>) -> Result<u16, IntrpErr> {
+/// Prints values with the names of their constructors and the types of closures.
+/// Lines longer than the width are broken after the last comma or space that fits,
+/// continuing with more indentation, and values nested deeper than the depth limit
+/// are elided.
+pub struct Printer<'m> {
+    module: &'m Module<'m>,
+    /// maximum line width
+    pub width: usize,
+    /// compound values nested deeper are printed as ...
+    pub max_depth: usize,
+}
+
+/// text being printed, wrapped as it's written
+struct Wrapped {
+    text: String,
+    width: usize,
+    /// indentation of continuation lines
+    indent: usize,
+    /// position of the last space on the current line where it can be broken
+    last_break: Option<usize>,
+}
+
+impl Wrapped {
+    fn push(&mut self, s: &str) {
+        self.text.push_str(s);
+        let line_start = self.text.rfind('\n').map_or(0, |i| i + 1);
+        if self.text[line_start..].chars().count() > self.width {
+            if let Some(i) = self.last_break.take() {
+                let newline = format!("\n{}", " ".repeat(self.indent));
+                self.text.replace_range(i..=i, &newline);
+            }
+        }
+    }
+
+    /// a space the line can be broken at
+    fn space(&mut self) {
+        self.last_break = Some(self.text.len());
+        self.push(" ");
+    }
+}
+
+impl<'m> Printer<'m> {
+    pub fn new(module: &'m Module<'m>) -> Self {
+        Printer { module, width: 80, max_depth: 100 }
+    }
+
+    pub fn value(&self, v: &Value) -> String {
+        self.print(String::new(), v)
+    }
+
+    /// print a top-level name with its type if it's known
+    pub fn binding(&self, name: &str, t: Option<&Type>, v: &Value) -> String {
+        let mut prefix = name.to_owned();
+        if let Some(t) = t {
+            prefix += " : ";
+            t.pretty_format(&mut prefix, &self.module.type_decls);
+        }
+        prefix += " = ";
+        self.print(prefix, v)
+    }
+
+    fn print(&self, prefix: String, v: &Value) -> String {
+        let mut out = Wrapped { text: prefix, width: self.width, indent: 4, last_break: None };
+        self.write(v, 0, &mut out);
+        out.text
+    }
+
+    fn write(&self, v: &Value, depth: usize, out: &mut Wrapped) {
+        match *v {
+            Value::Tuple(_) | Value::SumVar(..) | Value::Ref(_) if depth > self.max_depth => out.push("..."),
+            Value::Unit => out.push("()"),
+            Value::Bool(p) => out.push(if p { "true" } else { "false" }),
+            Value::Int(n) => out.push(&n.to_string()),
+            Value::String(ref s) => out.push(&format!("\"{}\"", s)),
+            Value::Tuple(ref values) => self.write_tuple(values, depth, out),
+            Value::SumVar(n, m, ref payload) => {
+                out.push(self.variant_name(n, m));
+                match **payload {
+                    Value::Tuple(ref values) => self.write_tuple(values, depth + 1, out),
+                    _ => {
+                        out.space();
+                        self.write_arg(payload, depth + 1, out);
+                    }
+                }
+            }
+            Value::Ref(ref cell) => {
+                out.push("ref");
+                out.space();
+                self.write_arg(&cell.borrow(), depth + 1, out);
+            }
+            Value::Closure(n, _, ref applied) => {
+                let closure = &self.module.closures[n as usize];
+                let mut t = closure.return_type.clone();
+                for arg in closure.args[applied.len()..].iter().rev() {
+                    t = Type::Function(Box::new(arg.clone()), Box::new(t));
+                }
+                t.generalize_type();
+                self.write_fn_type(t, out);
+            }
+            Value::Constructor(n, m) => out.push(self.variant_name(n, m)),
+            Value::Imported(n, ref applied) => {
+                let mut t = self.module.imports[n as usize].1.clone();
+                for _ in applied {
+                    t = match t {
+                        Type::Function(_, to) => *to,
+                        t => t,
+                    };
+                }
+                self.write_fn_type(t, out);
+            }
+            Value::Tag(n) => out.push(&format!("<tag {}>", n)),
+            Value::Cont(_) | Value::Escape(_) => out.push("<continuation>"),
+        }
+    }
+
+    fn write_tuple(&self, values: &[Rc<Value>], depth: usize, out: &mut Wrapped) {
+        out.push("(");
+        for (i, v) in values.iter().enumerate() {
+            if i > 0 {
+                out.push(",");
+                out.space();
+            }
+            self.write(v, depth + 1, out);
+        }
+        out.push(")");
+    }
+
+    /// the argument of a constructor or ref, in parentheses if it's an application too
+    fn write_arg(&self, v: &Value, depth: usize, out: &mut Wrapped) {
+        match *v {
+            Value::SumVar(..) | Value::Ref(_) if depth <= self.max_depth => {
+                out.push("(");
+                self.write(v, depth, out);
+                out.push(")");
+            }
+            _ => self.write(v, depth, out),
+        }
+    }
+
+    fn write_fn_type(&self, t: Type, out: &mut Wrapped) {
+        let mut s = "<fn ".to_owned();
+        t.pretty_format(&mut s, &self.module.type_decls);
+        s.push('>');
+        out.push(&s);
+    }
+
+    fn variant_name(&self, n: u16, m: u16) -> &'m str {
+        self.module.type_decls[n as usize].variants[m as usize - 1].0
+    }
+}
+
+/// does the value at the path of a constraint satisfy it
+fn satisfies(value: &Value, constraint: &ConstraintValue) -> bool {
+    match (value, constraint) {
+        // see Literal::get_constraint
+        (&Value::Bool(p), &ConstraintValue::Finite(n, _)) => p == (n == 0),
+        (&Value::Tag(t), &ConstraintValue::Finite(n, _)) => t == n,
+        (&Value::Int(m), &ConstraintValue::Int(n)) => m == n,
+        (Value::String(s1), ConstraintValue::Str(s2)) => s1 == s2,
+        _ => false,
+    }
+}
+
+pub fn match_tree<'a>(tree: &DTree, valvec: &Vec<Rc<Value<'a>>>) -> Result<u16, IntrpErr<'a>> {
     match tree {
         &DTree::Empty => Err(IntrpErr::NonExhaustivePattern),
         &DTree::Exit(m) => Ok(m),
@@ -380,10 +1294,11 @@ pub fn match_tree(tree: &DTree, valvec: &Vec<Rc<Value>>) -> Result<u16, IntrpErr
         } => {
             let val = pathvec_from_valvec(v, valvec)?;
             match *val {
-                Value::Bool(false) => match_tree(&branches[0], valvec),
-                Value::Bool(true) => match_tree(&branches[1], valvec),
-                // XXX: double check off by 1.
-                Value::Tag(n) => match_tree(&branches[n as usize], valvec),
+                // see Literal::get_constraint
+                Value::Bool(true) => match_tree(&branches[0], valvec),
+                Value::Bool(false) => match_tree(&branches[1], valvec),
+                // exceptions declared after the tree was built have no branch
+                Value::Tag(n) => match_tree(branches.get(n as usize).unwrap_or(&DTree::Empty), valvec),
                 _ => Err(IntrpErr::TypeMismatch),
             }
         }
@@ -405,21 +1320,3 @@ pub fn match_tree(tree: &DTree, valvec: &Vec<Rc<Value>>) -> Result<u16, IntrpErr
         _ => Err(IntrpErr::InvalidPath),
     }
 }
-
-
-fn stl_call(function: &str, value: Rc<Value>) -> Result<Rc<Value>,IntrpErr> {
-    match function {
-        "print" => if let Value::String(ref s) = *value {
-                print!("{}", s.replace("\\n", "\n"));
-                Ok(Rc::new(Value::Unit))
-            } else {
-                panic!("Unexpected")
-            },
-        "i2str" => if let Value::Int(i) = *value {
-                Ok(Rc::new(Value::String(format!("{}", i))))
-            } else {
-                panic!("Unexpected")
-            }
-        _ => panic!("Not implemented")
-    }
-}
\ No newline at end of file
@@ -0,0 +1,10 @@
+//! cerebral, an interpreter for ceen. The binary runs a program file, `Engine`
+//! embeds programs in Rust.
+
+pub mod builtins;
+pub mod config;
+pub mod engine;
+pub mod interpret;
+pub mod prelude;
+
+pub use engine::{Engine, EngineError, FromValue, IntoArgs, IntoValue};
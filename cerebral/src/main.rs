@@ -1,32 +1,144 @@
-#![feature(box_syntax)]
-
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::process;
+use std::rc::Rc;
 use std::io::{
     stdin,
+    stdout,
     prelude::*
 };
+use std::str::FromStr;
 
 use clog::{
+    codegen,
+    ir,
+    optimize,
     parse,
     type_check,
 };
+use cerebral::{builtins::{self, Capabilities}, interpret, prelude};
+use lugha_vm::{asm, bytecode::Program, lbc, vm};
 
-mod interpret;
+/// exit code when evaluation stops with a runtime error
+const RUNTIME_ERROR: i32 = 2;
+/// exit code when a bytecode file is rejected
+const INVALID_BYTECODE: i32 = 3;
 
 fn main() {
-    let mut args = env::args();
-    args.next();
-    let input_file = args.next().expect("No input file given");
+    let mut input_file = None;
+    let mut with_prelude = true;
+    let mut use_vm = false;
+    let mut emit = None;
+    let mut disassemble = false;
+    let mut dump_ir = false;
+    let mut optimized = false;
+    let mut gc_stress = false;
+    let mut caps = Capabilities::default();
+    let mut limits = interpret::Limits::default();
+    // flags come before the input file, the arguments after it are the program's
+    for arg in env::args().skip(1) {
+        if input_file.is_some() {
+            caps.args.push(arg);
+            continue;
+        }
+        let (flag, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => (arg.as_str(), ""),
+        };
+        match flag {
+            "--no-prelude" => with_prelude = false,
+            "--allow-io" => caps.io = true,
+            "--vm" => use_vm = true,
+            "--emit" => emit = Some(value.to_owned()),
+            "--disassemble" => disassemble = true,
+            "--dump-ir" => dump_ir = true,
+            "--optimize" => optimized = true,
+            "--gc-stress" => gc_stress = true,
+            "--max-steps" => limits.steps = Some(number(flag, value)),
+            "--max-depth" => limits.depth = Some(number(flag, value)),
+            "--max-heap" => limits.heap = Some(number(flag, value)),
+            _ => input_file = Some(arg),
+        }
+    }
+    let input_file = input_file.expect("No input file given");
+    if input_file.ends_with(".lbc") {
+        let bytes = fs::read(&input_file).expect("Cannot read file");
+        let program = match lbc::read(&bytes) {
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("{}: {}", input_file, e);
+                process::exit(INVALID_BYTECODE);
+            }
+        };
+        if disassemble {
+            print!("{}", asm::disassemble(&program));
+            return;
+        }
+        return run_program(&program, caps, gc_stress);
+    }
     let mut f = File::open(input_file).expect("File not found");
     let mut contents = String::new();
     f.read_to_string(&mut contents)
         .expect("Cannot read file");
     let contents = parse::uncomment(&mut contents);
-    let result = parse::parse(&contents).unwrap();
-    let module = type_check::ast2imper_ast(result).unwrap();
-    let mut ctx = interpret::Context::new(&module);
-    ctx.eval_toplevel();
+    let src = if with_prelude { prelude::append_to(&contents) } else { contents.clone() };
+    let mut result = parse::parse(&src).unwrap();
+    if with_prelude {
+        prelude::move_first(&mut result);
+    }
+    let mut module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+    for warning in &module.warnings {
+        eprintln!("{}", warning.report(&contents));
+    }
+    if optimized {
+        optimize::optimize(&mut module);
+    }
+    if dump_ir {
+        print!("{}", ir::lower(&module));
+        return;
+    }
+    // the positions of the program are kept in the bytecode for its errors
+    let locate = |span| prelude::position(&contents, span);
+    if disassemble {
+        print!("{}", asm::disassemble(&codegen::compile(&module, &locate)));
+        return;
+    }
+    if let Some(path) = emit {
+        fs::write(&path, lbc::write(&codegen::compile(&module, &locate))).expect("Cannot write bytecode");
+        return;
+    }
+    if use_vm {
+        return run_program(&codegen::compile(&module, &locate), caps, gc_stress);
+    }
+    let mut ctx = interpret::Context::with_hosts(&module, Rc::new(builtins::host_fns(&caps)));
+    ctx.set_limits(limits);
+    let evaluated = ctx.eval_toplevel();
+    if let Err(e) = evaluated {
+        // the output printed before the error comes first
+        stdout().flush().expect("Cannot write output");
+        eprint!("{}", e.report(&module, &contents));
+        process::exit(RUNTIME_ERROR);
+    }
+}
+
+/// run a compiled program on the VM instead of the interpreter
+fn run_program(program: &Program, caps: Capabilities, gc_stress: bool) {
+    let caps = lugha_vm::builtins::Capabilities { io: caps.io, args: caps.args };
+    let evaluated = match vm::Vm::new(program) {
+        Ok(machine) if gc_stress => machine.with_capabilities(caps).with_gc_stress().run(),
+        Ok(machine) => machine.with_capabilities(caps).run(),
+        Err(error) => Err(vm::RuntimeError { error, pos: None, trace: vec![], global: None }),
+    };
+    if let Err(e) = evaluated {
+        stdout().flush().expect("Cannot write output");
+        eprint!("{}", e.report(program));
+        process::exit(RUNTIME_ERROR);
+    }
+}
+
+/// value of a flag like --max-steps=1000
+fn number<T: FromStr>(flag: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| panic!("{} takes a number, e.g. {}=1000", flag, flag))
 }
 
 fn repl() {
@@ -37,9 +149,25 @@ fn repl() {
             s.pop();
             let contents = parse::uncomment(&mut s);
             let result = parse::parse(&contents).unwrap();
-            let module = type_check::ast2imper_ast(result).unwrap();
+            let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+            for warning in &module.warnings {
+                eprintln!("{}", warning.report(&contents));
+            }
             let mut ctx = interpret::Context::new(&module);
-            ctx.eval_toplevel();
+            let evaluated = ctx.eval_toplevel();
+            match evaluated {
+                Ok(()) => {
+                    let printer = interpret::Printer::new(&module);
+                    let mut names: Vec<_> = module.globals_names.keys().collect();
+                    names.sort();
+                    for name in names {
+                        if let Some(v) = ctx.global(name) {
+                            println!("{}", printer.binding(name, ctx.global_type(name), &v));
+                        }
+                    }
+                }
+                Err(e) => eprint!("{}", e.report(&module, &contents)),
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,38 @@
+//! The prelude, types and functions declared before the bindings of every program.
+//!
+//! The prelude is parsed after the source of the program so that positions in the
+//! program are unchanged, positions past its end are in the prelude. Its bindings
+//! are then moved before the program's, and type-checked with them in one module.
+
+use clog::{
+    ast::{Binding, Span},
+    parse,
+};
+use lugha_vm::bytecode::Pos;
+
+pub const SOURCE: &str = include_str!("prelude.mal");
+
+/// an uncommented program followed by the prelude
+pub fn append_to(src: &str) -> String {
+    format!("{}\n{}", src, parse::uncomment(SOURCE))
+}
+
+/// move the bindings of the prelude, parsed from the end of the source, before the program's
+pub fn move_first(bindings: &mut Vec<Binding>) {
+    let prelude = parse::uncomment(SOURCE);
+    let len = parse::parse(&prelude).expect("the prelude doesn't parse").len();
+    let program = bindings.len() - len;
+    bindings.rotate_left(program);
+}
+
+/// line and column of a position past the end of an uncommented program, in the prelude
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    parse::line_col(&parse::uncomment(SOURCE), offset - src.len() - 1)
+}
+
+/// where a span of an uncommented program followed by the prelude starts
+pub fn position(src: &str, (start, _): Span) -> Pos {
+    let prelude = start > src.len();
+    let (line, col) = if prelude { line_col(src, start) } else { parse::line_col(src, start) };
+    Pos { line: line as u32, col: col as u32, prelude }
+}
@@ -7,6 +7,7 @@ mod test {
             type_check,
             parse,
         },
+        cerebral::builtins,
         std::{
             fs::File,
             io::prelude::*,
@@ -33,7 +34,7 @@ mod test {
             .expect("Cannot read file");
         let contents = uncomment(&mut contents);
         let result = parse::parse(&contents).unwrap();
-        type_check::ast2imper_ast(result).unwrap();
+        type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
     }
 
     #[test]
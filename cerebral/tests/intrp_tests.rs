@@ -6,10 +6,14 @@ mod test {
             type_check,
             parse,
         },
-        cerebral::interpret,
+        cerebral::{builtins, interpret, prelude},
         std::{
-            fs::File,
+            env,
+            fs::{self, File},
             io::prelude::*,
+            path::Path,
+            process::{self, Command, Stdio},
+            rc::Rc,
         },
     };
     fn test_simple() {
@@ -19,9 +23,9 @@ mod test {
             .expect("Cannot read file");
         let contents = parse::uncomment(&mut contents);
         let result = parse::parse(&contents).unwrap();
-        let module = type_check::ast2imper_ast(result).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
         let mut ctx = interpret::Context::new(&module);
-        ctx.eval_toplevel();
+        ctx.eval_toplevel().unwrap();
     }
 
     fn test_fact() {
@@ -34,9 +38,9 @@ mod test {
             .expect("Cannot read file");
         let contents = parse::uncomment(&mut contents);
         let result = parse::parse(&contents).unwrap();
-        let module = type_check::ast2imper_ast(result).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
         let mut ctx = interpret::Context::new(&module);
-        ctx.eval_toplevel();
+        ctx.eval_toplevel().unwrap();
     }
 
     #[test]
@@ -47,8 +51,319 @@ mod test {
             .expect("Cannot read file");
         let contents = parse::uncomment(&mut contents);
         let result = parse::parse(&contents).unwrap();
-        let module = type_check::ast2imper_ast(result).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
         let mut ctx = interpret::Context::new(&module);
-        ctx.eval_toplevel();
+        ctx.eval_toplevel().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_ref() {
+        let mut f = File::open("tests/ref.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_exn() {
+        let mut f = File::open("tests/exn.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_effect() {
+        let mut f = File::open("tests/effect.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_callcc() {
+        let mut f = File::open("tests/callcc.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_builtins() {
+        let mut f = File::open("tests/builtins.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_io() {
+        let mut f = File::open("tests/io.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        // I/O fails unless it's allowed
+        let mut ctx = interpret::Context::new(&module);
+        let report = ctx.eval_toplevel().unwrap_err().report(&module, &contents);
+        assert!(report.starts_with("error: args failed: I/O isn't allowed, run cerebral with --allow-io\n"));
+
+        let dir = env::temp_dir().join(format!("cerebral-io-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let caps = builtins::Capabilities { io: true, args: vec![dir.to_str().unwrap().to_owned()] };
+        let mut ctx = interpret::Context::with_hosts(&module, Rc::new(builtins::host_fns(&caps)));
+        let evaluated = ctx.eval_toplevel();
+        fs::remove_dir_all(&dir).unwrap();
+        evaluated.unwrap();
+    }
+
+    #[test]
+    fn test_prelude() {
+        let mut f = File::open("tests/prelude.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let src = prelude::append_to(&contents);
+        let mut result = parse::parse(&src).unwrap();
+        prelude::move_first(&mut result);
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_prelude_positions() {
+        let contents = parse::uncomment("let l = map { x => 1 / x }\n    (cons (0, nil ()))");
+        let src = prelude::append_to(&contents);
+        let mut result = parse::parse(&src).unwrap();
+        prelude::move_first(&mut result);
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        let report = ctx.eval_toplevel().unwrap_err().report(&module, &contents);
+        // the division is in the program, map calls the closure from the prelude
+        assert!(report.contains("  at line 1, column 20\n"));
+        assert!(report.contains("of the prelude\n  in map, called at line 1, column 9\n"));
+        assert!(report.ends_with("in the declaration at line 1, column 1\n"));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut f = File::open("tests/tail.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        ctx.eval_toplevel().unwrap();
+    }
+
+    #[test]
+    fn test_runtime_err() {
+        use clog::types::{EXN_TYPE, EXN_DIV_BY_ZERO};
+        let mut f = File::open("tests/runtime_err.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        let mut ctx = interpret::Context::new(&module);
+        let err = ctx.eval_toplevel().unwrap_err();
+        match err.error {
+            interpret::IntrpErr::Exception(ref exn) => match **exn {
+                interpret::Value::SumVar(EXN_TYPE, EXN_DIV_BY_ZERO, _) => (),
+                ref v => panic!("unexpected exception {:?}", v),
+            },
+            ref e => panic!("unexpected error {:?}", e),
+        }
+        let line = |span: (usize, usize)| parse::line_col(&contents, span.0).0;
+        // the division in average, called by sumAll, which recursed twice
+        assert_eq!(line(err.span.unwrap()), 11);
+        assert_eq!(err.trace.len(), 4);
+        assert_eq!(module.closures[err.trace[0].0 as usize].name, Some("average"));
+        assert_eq!(line(err.trace[0].1.unwrap()), 15);
+        assert_eq!(module.closures[err.trace[1].0 as usize].name, Some("sumAll"));
+        assert_eq!(line(module.globals[err.binding.unwrap()].3), 19);
+        let report = err.report(&module, &contents);
+        assert!(report.starts_with("error: uncaught exception DivisionByZero ()\n  at line 11, column 12\n"));
+        assert!(report.contains("in sumAll, called at line 16, column 26 (2 times)"));
+
+        // the VM reports the same positions, also from a bytecode file
+        let stderr = |args: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_cerebral")).args(args).output().unwrap();
+            String::from_utf8(output.stderr).unwrap()
+        };
+        assert_eq!(stderr(&["--vm", "tests/runtime_err.mal"]), report);
+        let lbc = env::temp_dir().join(format!("cerebral-runtime-err-{}.lbc", process::id()));
+        stderr(&[&format!("--emit={}", lbc.display()), "tests/runtime_err.mal"]);
+        assert_eq!(stderr(&[lbc.to_str().unwrap()]), report);
+        fs::remove_file(&lbc).unwrap();
+    }
+
+    #[test]
+    fn test_sum_equality() {
+        let output = Command::new(env!("CARGO_BIN_EXE_cerebral")).arg("tests/equality.mal").output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "equal\ndifferent\ndifferent\ndifferent\nequal\n");
+    }
+
+    #[test]
+    fn test_overflow() {
+        let output = Command::new(env!("CARGO_BIN_EXE_cerebral")).arg("tests/overflow.mal").output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let (min, max) = (isize::MIN.to_string(), isize::MAX.to_string());
+        let expected = [&min, &max, "-2", &min, &min, "0", &min].iter().map(|n| format!("{}\n", n)).collect::<String>();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_constraints() {
+        use clog::types::{EXN_TYPE, EXN_MATCH_FAILURE};
+        let mut f = File::open("tests/constraint.mal").expect("file not found");
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)
+            .expect("Cannot read file");
+        let contents = parse::uncomment(&mut contents);
+        let result = parse::parse(&contents).unwrap();
+        let module = type_check::ast2imper_ast(result, &builtins::imports()).unwrap();
+        assert_eq!(module.warnings.len(), 4);
+        let mut ctx = interpret::Context::new(&module);
+        // only the last declaration doesn't match
+        let err = ctx.eval_toplevel().unwrap_err();
+        assert_eq!(err.binding, Some(4));
+        assert_eq!(err.span, Some(module.globals[4].3));
+        match err.error {
+            interpret::IntrpErr::Exception(ref exn) => match **exn {
+                interpret::Value::SumVar(EXN_TYPE, EXN_MATCH_FAILURE, _) => (),
+                ref v => panic!("unexpected exception {:?}", v),
+            },
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_vm_output() {
+        // programs that don't parse yet, and tail.mal which is compared on its own
+        let skipped = ["ex2.mal", "experimental.mal", "tail.mal"];
+        let run = |path: &Path, flags: &[&str]| {
+            // a directory for the I/O test to write in
+            let dir = env::temp_dir().join(format!("cerebral-vm-{}-{}", process::id(), flags.len()));
+            fs::create_dir(&dir).unwrap();
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_cerebral"));
+            let output = cmd.args(flags).arg("--allow-io").arg(path).arg(&dir).stdin(Stdio::null()).output().unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+            (output.status.code(), String::from_utf8(output.stdout).unwrap())
+        };
+        let mut compared = 0;
+        for entry in fs::read_dir("tests").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if !name.ends_with(".mal") || skipped.contains(&name) {
+                continue;
+            }
+            let interpreted = run(&path, &[]);
+            assert_eq!(run(&path, &["--vm"]), interpreted, "output of {}", name);
+            // collecting the garbage on every allocation doesn't change it
+            assert_eq!(run(&path, &["--vm", "--gc-stress"]), interpreted, "output of {} with --gc-stress", name);
+            // nor does optimizing the program
+            assert_eq!(run(&path, &["--optimize"]), interpreted, "output of {} optimized", name);
+            assert_eq!(run(&path, &["--optimize", "--vm"]), interpreted, "output of {} optimized with --vm", name);
+            compared += 1;
+        }
+        assert!(compared > 10);
+    }
+
+    #[test]
+    // tail.mal takes most of a minute in each mode in a debug build, run it in release
+    // or with --ignored
+    #[cfg_attr(debug_assertions, ignore)]
+    fn test_vm_tail_output() {
+        let run = |flags: &[&str]| {
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_cerebral"));
+            let output = cmd.args(flags).arg("tests/tail.mal").stdin(Stdio::null()).output().unwrap();
+            (output.status.code(), String::from_utf8(output.stdout).unwrap())
+        };
+        assert_eq!(run(&["--vm"]), run(&[]));
+    }
+
+    #[test]
+    fn test_dump_ir() {
+        // programs that don't parse yet
+        let skipped = ["ex2.mal", "experimental.mal"];
+        for entry in fs::read_dir("tests").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if !name.ends_with(".mal") || skipped.contains(&name) {
+                continue;
+            }
+            let run = |flags: &[&str]| Command::new(env!("CARGO_BIN_EXE_cerebral")).args(flags).arg(&path).output().unwrap();
+            // every program that compiles is lowered, optimized or not
+            let compiled = run(&["--disassemble"]).status.success();
+            for flags in &[&["--dump-ir"][..], &["--optimize", "--dump-ir"]] {
+                let dumped = run(flags);
+                assert_eq!(dumped.status.success(), compiled, "lowering {} with {:?}", name, flags);
+                if compiled {
+                    assert!(String::from_utf8(dumped.stdout).unwrap().contains("\ninit\n:B0\n"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_emit_bytecode() {
+        let cerebral = env!("CARGO_BIN_EXE_cerebral");
+        let lbc = env::temp_dir().join(format!("cerebral-emit-{}.lbc", process::id()));
+        let emit = format!("--emit={}", lbc.display());
+        let emitted = Command::new(cerebral).arg(emit).arg("tests/effect.mal").output().unwrap();
+        assert!(emitted.status.success());
+        assert!(emitted.stdout.is_empty());
+        let run = |path: &Path| Command::new(cerebral).arg(path).output().unwrap();
+        let (interpreted, loaded) = (run(Path::new("tests/effect.mal")), run(&lbc));
+        assert_eq!(loaded.status.code(), interpreted.status.code());
+        assert_eq!(loaded.stdout, interpreted.stdout);
+        let listing = Command::new(cerebral).arg("--disassemble").arg(&lbc).output().unwrap();
+        assert!(String::from_utf8(listing.stdout).unwrap().contains("\ncode 0 "));
+
+        // a corrupted file is rejected before running
+        let mut bytes = fs::read(&lbc).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&lbc, bytes).unwrap();
+        let rejected = run(&lbc);
+        fs::remove_file(&lbc).unwrap();
+        // with the reader's error, not a panic
+        assert_eq!(rejected.status.code(), Some(3));
+        let stderr = String::from_utf8(rejected.stderr).unwrap();
+        assert!(stderr.contains("truncated"));
+        assert!(!stderr.contains("panicked"));
+    }
+}
@@ -2,6 +2,9 @@
 
 use crate::types::{ProtoType, Literal, BinOpcode, UnOpcode};
 
+/// byte offsets of the start and end of a node in the parsed source
+pub type Span = (usize, usize);
+
 /// Binds a name to a type definition or a expression to a pattern,
 /// every top level declaration is of this type
 #[derive(Debug)]
@@ -15,7 +18,16 @@ pub enum Binding<'input> {
         /// variants' names and arguments' types
         variants: Vec<(&'input str, ProtoType<'input>)> },
     /// A value binding, bool for is recursive?
-    Value(Pattern<'input>, Expr<'input>, bool),
+    Value(Pattern<'input>, Expr<'input>, bool, Span),
+    /// An exception declaration, adds a variant to the built-in exn type
+    Exception(&'input str, ProtoType<'input>),
+    /// An effect operation declaration
+    Effect {
+        name: &'input str,
+        /// type of the value performed with
+        from: ProtoType<'input>,
+        /// type of the value resumed with
+        to: ProtoType<'input> },
 }
 
 /// A pattern or LHS of a binding to match
@@ -46,7 +58,7 @@ pub enum Expr<'input> {
     Tuple(Vec<Expr<'input>>),
 
     /// the value of applying a binary operation on two Exprs
-    BinOp(Box<Expr<'input>>, BinOpcode, Box<Expr<'input>>),
+    BinOp(Box<Expr<'input>>, BinOpcode, Box<Expr<'input>>, Span),
     /// the value of applying a unary operation on an Expr
     UnOp(UnOpcode, Box<Expr<'input>>),
 
@@ -54,9 +66,26 @@ pub enum Expr<'input> {
     Closure(Vec<(Vec<Pattern<'input>>, Expr<'input>)>),
     /// Apply an expression on an expression,
     /// including constructing values of sum types
-    Application(Box<Expr<'input>>, Box<Expr<'input>>),
+    Application(Box<Expr<'input>>, Box<Expr<'input>>, Span),
     /// if e1 then e2 else e3
     Conditional(Box<Expr<'input>>, Box<Expr<'input>>, Box<Expr<'input>>),
+    /// raise an exception value of type exn
+    Raise(Box<Expr<'input>>, Span),
+    /// try e with { handler arms }, each arm has a single pattern
+    Try(Box<Expr<'input>>, Vec<(Vec<Pattern<'input>>, Expr<'input>)>),
+    /// perform an effect operation with a value
+    Perform(&'input str, Box<Expr<'input>>),
+    /// handle e with { handler arms }
+    Handle(Box<Expr<'input>>, Vec<HandlerArm<'input>>),
     /// Parse error
     Error(usize, usize),
+}
+
+/// An arm of an effect handler
+#[derive(Debug)]
+pub enum HandlerArm<'input> {
+    /// return x => e, applied to the value of the handled expression
+    Return(Pattern<'input>, Expr<'input>),
+    /// Op v k => e, v is the performed value and k the continuation
+    Op(&'input str, Pattern<'input>, Pattern<'input>, Expr<'input>),
 }
\ No newline at end of file
@@ -0,0 +1,620 @@
+//! Compiles a module to a program of the lugha-vm machine.
+//!
+//! Each closure of the module is the code at the same index in the program. The
+//! bodies of `try` and `handle` and the top-level declarations get codes after them.
+//! Pattern matching is compiled from the decision trees: a boolean node to a
+//! conditional jump, a sum node to a jump table on the tag and an int or string node
+//! to a switch over the sorted constrained values.
+//!
+//! Paths are followed with the types of the values they start from, a tuple index is a
+//! field while a sum index is its tag or the value of its variant.
+//!
+//! The top-level declarations and the instructions of operators, applications and
+//! raises get the positions of their spans in the source, for the errors of the VM.
+
+use std::collections::HashMap;
+
+use lugha_vm::bytecode::{self, Code, Const, Instr, Pos, Program, Table, TypeInfo};
+
+use crate::{
+    ast::Span,
+    imper_ast::{ConstraintValue, Expr, Module, ValPath},
+    lower::{self, Capture, Matcher, Step},
+    types::{BinOpcode, Literal, Type, UnOpcode},
+};
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::parse::{line_col, parse},
+        crate::type_check::ast2imper_ast,
+        lugha_vm::{
+            asm, lbc,
+            vm::Vm,
+        },
+    };
+
+    fn compile_src(src: &str) -> Program {
+        let print = Type::Function(Box::new(Type::String), Box::new(Type::Unit));
+        let module = ast2imper_ast(parse(src).unwrap(), &[("print", print)]).unwrap();
+        let program = compile(&module, &|(start, _)| {
+            let (line, col) = line_col(src, start);
+            Pos { line: line as u32, col: col as u32, prelude: false }
+        });
+        // compiled programs are valid and their listings read back the same
+        lbc::validate(&program).unwrap();
+        assert_eq!(asm::assemble(&asm::disassemble(&program)).unwrap(), program);
+        program
+    }
+
+    /// the globals as they're printed, the same when the garbage is collected on
+    /// every allocation
+    fn globals(program: &Program) -> Vec<String> {
+        let globals = |mut vm: Vm| {
+            vm.run().unwrap();
+            (0..program.globals.len()).map(|i| vm.show(vm.global(i).unwrap())).collect::<Vec<_>>()
+        };
+        let shown = globals(Vm::new(program).unwrap());
+        assert_eq!(globals(Vm::new(program).unwrap().with_gc_stress()), shown);
+        shown
+    }
+
+    #[test]
+    fn matching() {
+        let program = compile_src(
+            "type Shape = | circle int | rect (int, int) | empty ()
+            let area = {
+                (circle r) => 3 * r * r,
+                (rect (w, h)) => w * h,
+                (empty ()) => 0,
+            }
+            let name = { 0 => \"zero\", 1 => \"one\", n => if n < 0 then \"negative\" else \"many\" end }
+            let word = { \"one\" => 1, \"two\" => 2, _ => 0 }
+            let all = (area (circle 2), area (rect (2, 3)), area (empty ()), name 1, name 5, word \"two\")",
+        );
+        let tables = &program.codes[0].tables;
+        assert_eq!(tables.len(), 1);
+        assert!(match tables[0] {
+            Table::Jump { ref targets, .. } => targets.len() == 3,
+            _ => false,
+        });
+        assert!(match program.codes[1].tables[0] {
+            Table::Int { ref cases, .. } => cases.iter().map(|c| c.0).collect::<Vec<_>>() == [0, 1],
+            _ => false,
+        });
+        assert_eq!(globals(&program)[3], "(12, 6, 0, \"one\", \"many\", 2)");
+    }
+
+    #[test]
+    fn constraints() {
+        let program = compile_src("let (1, x) = (1, 2)\nlet (3, y) = (x, 3)\nlet z = 4");
+        let mut vm = Vm::new(&program).unwrap();
+        let e = vm.run().unwrap_err();
+        assert_eq!(e.global, Some(1));
+        assert_eq!(vm.show(vm.global(0).unwrap()), "(1, 2)");
+        assert_eq!(e.report(&program), concat!(
+            "error: uncaught exception MatchFailure ()\n",
+            "  at line 2, column 1\n",
+            "  in the declaration at line 2, column 1\n",
+        ));
+    }
+
+    #[test]
+    fn positions() {
+        let program = compile_src("let half = { n => n / 2 }\nlet f = { n => 1 + half n / n }\nlet x = f 0");
+        let e = Vm::new(&program).unwrap().run().unwrap_err();
+        // the division in f, called by the declaration on line 3
+        assert_eq!(e.report(&program), concat!(
+            "error: uncaught exception DivisionByZero ()\n",
+            "  at line 2, column 20\n",
+            "  in f, called at line 3, column 9\n",
+            "  in the declaration at line 3, column 1\n",
+        ));
+    }
+}
+
+/// compile a type-checked module to a program, with the positions of the spans of the
+/// module found by locate
+pub fn compile(module: &Module, locate: &dyn Fn(Span) -> Pos) -> Program {
+    let mut program = Program {
+        types: module
+            .type_decls
+            .iter()
+            .map(|decl| TypeInfo {
+                name: decl.name.to_owned(),
+                variants: decl.variants.iter().map(|v| v.0.to_owned()).collect(),
+            })
+            .collect(),
+        effects: module.effects.iter().map(|e| e.0.to_owned()).collect(),
+        imports: module.imports.iter().map(|i| i.0.to_owned()).collect(),
+        codes: vec![Code::default(); module.closures.len()],
+        positions: module.globals.iter().map(|g| locate(g.3)).collect(),
+        ..Program::default()
+    };
+    let handlers = lower::handlers(module);
+    let mut compiler = Compiler { module, program: &mut program, consts: HashMap::new(), handlers, locate };
+    for n in 0..module.closures.len() {
+        let code = compiler.closure(n as u16);
+        compiler.program.codes[n] = code;
+    }
+    for i in 0..module.globals.len() {
+        let code = compiler.global(i);
+        compiler.program.globals.push(code);
+    }
+    program
+}
+
+struct Compiler<'m, 'p> {
+    module: &'m Module<'m>,
+    program: &'p mut Program,
+    /// positions of the constants already in the program
+    consts: HashMap<Const, u32>,
+    /// closures handling exceptions, raising them again when no arm matches
+    handlers: Vec<u16>,
+    /// the position of a span in the source
+    locate: &'p dyn Fn(Span) -> Pos,
+}
+
+/// types of the arguments of the closure being compiled
+struct Env<'m> {
+    locals: &'m [Type],
+}
+
+type Label = usize;
+
+/// where the position of a label goes once it's placed
+enum Fixup {
+    /// the target of a jump instruction
+    Instr(usize),
+    /// the nth target of a table
+    Case(u16, usize),
+    Default(u16),
+}
+
+/// the code being written, jumps go to labels placed later
+struct Emitter {
+    code: Code,
+    labels: Vec<Option<u32>>,
+    fixups: Vec<(Fixup, Label)>,
+    /// the label of the code failing to match, raising MatchFailure, if it's used
+    fail: Option<Label>,
+    /// the code is an exception handler, failing raises the exception again
+    reraise: bool,
+}
+
+impl Emitter {
+    fn new(name: Option<&str>, arity: u16, captures: u16) -> Self {
+        let code = Code { name: name.map(|n| n.to_owned()), arity, captures, ..Code::default() };
+        Emitter { code, labels: Vec::new(), fixups: Vec::new(), fail: None, reraise: false }
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        self.code.instrs.push(instr);
+    }
+
+    /// an instruction evaluating the expression at a position
+    fn emit_at(&mut self, instr: Instr, pos: Pos) {
+        self.code.positions.push((self.code.instrs.len() as u32, pos));
+        self.emit(instr);
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: Label) {
+        // a jump to the label is dropped when it would go to the next instruction
+        if let Some(&(Fixup::Instr(i), l)) = self.fixups.last() {
+            if l == label && i + 1 == self.code.instrs.len() && self.code.instrs[i] == Instr::Jump(0) {
+                self.fixups.pop();
+                self.code.instrs.pop();
+            }
+        }
+        self.labels[label] = Some(self.code.instrs.len() as u32);
+    }
+
+    /// a jump instruction to a label
+    fn jump(&mut self, instr: fn(u32) -> Instr, label: Label) {
+        self.fixups.push((Fixup::Instr(self.code.instrs.len()), label));
+        self.emit(instr(0));
+    }
+
+    fn fail_label(&mut self) -> Label {
+        match self.fail {
+            Some(label) => label,
+            None => {
+                let label = self.label();
+                self.fail = Some(label);
+                label
+            }
+        }
+    }
+
+    /// add a table whose targets and default are labels
+    fn table(&mut self, table: Table, targets: Vec<Label>, default: Label) -> u16 {
+        let n = self.code.tables.len() as u16;
+        self.code.tables.push(table);
+        for (i, label) in targets.into_iter().enumerate() {
+            self.fixups.push((Fixup::Case(n, i), label));
+        }
+        self.fixups.push((Fixup::Default(n), default));
+        n
+    }
+
+    /// the code with the failure code if it's used and the jumps resolved
+    fn finish(mut self) -> Code {
+        if let Some(label) = self.fail {
+            self.place(label);
+            if self.reraise {
+                self.emit(Instr::Local(0));
+                self.emit(Instr::Raise);
+            } else {
+                self.emit(Instr::Fail);
+            }
+        }
+        for (fixup, label) in self.fixups {
+            let target = self.labels[label].expect("jump to a label never placed");
+            match fixup {
+                Fixup::Instr(i) => {
+                    self.code.instrs[i] = match self.code.instrs[i] {
+                        Instr::Jump(_) => Instr::Jump(target),
+                        Instr::JumpIfFalse(_) => Instr::JumpIfFalse(target),
+                        instr => panic!("fixup of {:?}", instr),
+                    }
+                }
+                Fixup::Case(t, i) => match self.code.tables[t as usize] {
+                    Table::Jump { ref mut targets, .. } => targets[i] = target,
+                    Table::Int { ref mut cases, .. } => cases[i].1 = target,
+                    Table::Str { ref mut cases, .. } => cases[i].1 = target,
+                    Table::Handler { .. } => (),
+                },
+                Fixup::Default(t) => match self.code.tables[t as usize] {
+                    Table::Jump { ref mut default, .. }
+                    | Table::Int { ref mut default, .. }
+                    | Table::Str { ref mut default, .. } => *default = target,
+                    Table::Handler { .. } => (),
+                },
+            }
+        }
+        self.code
+    }
+}
+
+impl<'m, 'p> Compiler<'m, 'p> {
+    fn constant(&mut self, c: Const) -> u32 {
+        let consts = &mut self.program.consts;
+        *self.consts.entry(c).or_insert_with_key(|c| {
+            consts.push(c.clone());
+            (consts.len() - 1) as u32
+        })
+    }
+
+    /// a code with no instructions yet, filled in later
+    fn new_code(&mut self) -> u16 {
+        self.program.codes.push(Code::default());
+        (self.program.codes.len() - 1) as u16
+    }
+
+    /// the code of nth closure: matching its arguments, then its arms
+    fn closure(&mut self, n: u16) -> Code {
+        let closure = &self.module.closures[n as usize];
+        let env = Env { locals: &closure.args };
+        let mut out = Emitter::new(closure.name, closure.args.len() as u16, closure.captures.len() as u16);
+        out.reraise = self.handlers.contains(&n);
+        let arms: Vec<Label> = closure.branches.iter().map(|_| out.label()).collect();
+        lower::match_tree(&mut Matching { compiler: self, env: &env, out: &mut out }, &closure.dtree, &arms);
+        for (arm, e) in arms.into_iter().zip(&closure.branches) {
+            out.place(arm);
+            self.tail_expr(e, &env, &mut out);
+        }
+        out.finish()
+    }
+
+    /// the code of nth top-level declaration, setting its global and checking the
+    /// constraints of its patterns
+    fn global(&mut self, i: usize) -> u16 {
+        let (ref e, ref constraints, ref t, _) = self.module.globals[i];
+        let env = Env { locals: &[] };
+        let mut out = Emitter::new(None, 0, 0);
+        self.expr(e, &env, &mut out);
+        out.emit(Instr::SetGlobal(i as u16));
+        // tags come before the paths of variant values in the order of ValPaths,
+        // so a path is only followed if the tags leading to it matched
+        for (path, constraint) in constraints {
+            let path = match path {
+                ValPath::StaticVal(v) => v,
+                _ => panic!("constraint on {:?}", path),
+            };
+            out.emit(Instr::Global(path[0]));
+            let t = self.path(t, &path[1..], &mut out);
+            self.expr(&Expr::Literal(lower::expected(constraint, &t)), &env, &mut out);
+            out.emit(Instr::BinOp(bytecode::BinOp::Eq));
+            let fail = out.fail_label();
+            out.jump(Instr::JumpIfFalse, fail);
+        }
+        out.emit(Instr::Unit);
+        out.emit(Instr::Return);
+        let n = self.new_code();
+        self.program.codes[n as usize] = out.finish();
+        n
+    }
+
+    /// Follow a path in a value of type t on the stack, the type of the value at the
+    /// end of the path, an int for a tag.
+    fn path(&self, t: &Type, path: &[u16], out: &mut Emitter) -> Type {
+        let (steps, t) = lower::follow(self.module, t, path);
+        for (step, _) in steps {
+            out.emit(match step {
+                Step::Field(n) => Instr::Field(n),
+                Step::Tag => Instr::Tag,
+                Step::Payload(_) => Instr::Payload,
+            });
+        }
+        t
+    }
+
+    /// push the value at a path
+    fn load(&mut self, path: &ValPath, env: &Env, out: &mut Emitter) {
+        match path {
+            ValPath::Local(v) => {
+                out.emit(Instr::Local(v[0]));
+                self.path(&env.locals[v[0] as usize], &v[1..], out);
+            }
+            ValPath::StaticVal(v) => {
+                out.emit(Instr::Global(v[0]));
+                self.path(&self.module.globals[v[0] as usize].2, &v[1..], out);
+            }
+            &ValPath::CaptureLocal(i, _) | &ValPath::CaptureCaptured(i, _) => out.emit(Instr::Capture(i)),
+            &ValPath::Constructor(i, j) => out.emit(Instr::Constructor(i, j)),
+            &ValPath::Imported(n) => out.emit(Instr::Import(n)),
+        }
+    }
+
+    /// push the captured values of nth closure and make it
+    fn make_closure(&mut self, n: u16, env: &Env, out: &mut Emitter) {
+        for (capture, _) in lower::captures(&self.module.closures[n as usize]) {
+            match capture {
+                Capture::Local(path) => self.load(&path, env, out),
+                Capture::Captured(j) => out.emit(Instr::Capture(j)),
+            }
+        }
+        out.emit(Instr::Closure(n));
+    }
+
+    /// a code running an expression in the environment of the code being compiled
+    fn block(&mut self, e: &Expr, env: &Env, name: Option<&str>) -> u16 {
+        let n = self.new_code();
+        let mut out = Emitter::new(name, 0, 0);
+        self.tail_expr(e, env, &mut out);
+        self.program.codes[n as usize] = out.finish();
+        n
+    }
+
+    /// code returning the value of an expression
+    fn tail_expr(&mut self, e: &Expr, env: &Env, out: &mut Emitter) {
+        match e {
+            &Expr::Application(ref f, ref arg, span) => {
+                self.expr(f, env, out);
+                self.expr(arg, env, out);
+                out.emit_at(Instr::TailApply, (self.locate)(span));
+            }
+            &Expr::Call(ref f, ref args, span) => {
+                self.expr(f, env, out);
+                for arg in args {
+                    self.expr(arg, env, out);
+                }
+                out.emit_at(Instr::TailCall(args.len() as u16), (self.locate)(span));
+            }
+            Expr::Conditional(cond, e1, e2) => {
+                self.expr(cond, env, out);
+                let on_false = out.label();
+                out.jump(Instr::JumpIfFalse, on_false);
+                self.tail_expr(e1, env, out);
+                out.place(on_false);
+                self.tail_expr(e2, env, out);
+            }
+            _ => {
+                self.expr(e, env, out);
+                out.emit(Instr::Return);
+            }
+        }
+    }
+
+    /// code pushing the value of an expression
+    fn expr(&mut self, e: &Expr, env: &Env, out: &mut Emitter) {
+        match e {
+            Expr::Literal(Literal::Unit) => out.emit(Instr::Unit),
+            &Expr::Literal(Literal::Bool(p)) => out.emit(Instr::Bool(p)),
+            &Expr::Literal(Literal::Int(n)) => {
+                let c = self.constant(Const::Int(n));
+                out.emit(Instr::Const(c));
+            }
+            &Expr::Literal(Literal::String(s)) => {
+                let c = self.constant(Const::Str(s.to_owned()));
+                out.emit(Instr::Const(c));
+            }
+            Expr::Bound(path) => self.load(path, env, out),
+            Expr::Tuple(v) => {
+                for e in v {
+                    self.expr(e, env, out);
+                }
+                out.emit(Instr::Tuple(v.len() as u16));
+            }
+            &Expr::BinOp(ref e1, op, ref e2, span) => {
+                self.expr(e1, env, out);
+                self.expr(e2, env, out);
+                out.emit_at(Instr::BinOp(binop(op)), (self.locate)(span));
+            }
+            &Expr::UnOp(op, ref e) => {
+                self.expr(e, env, out);
+                out.emit(Instr::UnOp(unop(op)));
+            }
+            &Expr::Closure(n) => self.make_closure(n, env, out),
+            &Expr::Application(ref f, ref arg, span) => {
+                self.expr(f, env, out);
+                self.expr(arg, env, out);
+                out.emit_at(Instr::Apply, (self.locate)(span));
+            }
+            &Expr::Call(ref f, ref args, span) => {
+                self.expr(f, env, out);
+                for arg in args {
+                    self.expr(arg, env, out);
+                }
+                out.emit_at(Instr::Call(args.len() as u16), (self.locate)(span));
+            }
+            &Expr::SumVal { target, position, ref value } => {
+                self.expr(value, env, out);
+                out.emit(Instr::Construct(target, position));
+            }
+            Expr::Conditional(cond, e1, e2) => {
+                self.expr(cond, env, out);
+                let (on_false, end) = (out.label(), out.label());
+                out.jump(Instr::JumpIfFalse, on_false);
+                self.expr(e1, env, out);
+                out.jump(Instr::Jump, end);
+                out.place(on_false);
+                self.expr(e2, env, out);
+                out.place(end);
+            }
+            &Expr::Raise(ref e, span) => {
+                self.expr(e, env, out);
+                out.emit_at(Instr::Raise, (self.locate)(span));
+            }
+            &Expr::Try(ref e, handler) => {
+                self.make_closure(handler, env, out);
+                let name = out.code.name.clone();
+                let body = self.block(e, env, name.as_deref());
+                out.emit(Instr::Try(body));
+            }
+            &Expr::Perform(op, ref e) => {
+                self.expr(e, env, out);
+                out.emit(Instr::Perform(op));
+            }
+            Expr::Handle { body, ret, ops } => {
+                if let Some(n) = *ret {
+                    self.make_closure(n, env, out);
+                }
+                for &(_, n) in ops {
+                    self.make_closure(n, env, out);
+                }
+                let name = out.code.name.clone();
+                let body = self.block(body, env, name.as_deref());
+                let table = Table::Handler { body, ret: ret.is_some(), ops: ops.iter().map(|op| op.0).collect() };
+                let n = out.code.tables.len() as u16;
+                out.code.tables.push(table);
+                out.emit(Instr::Handle(n));
+            }
+            Expr::Error => panic!("Error"),
+        }
+    }
+}
+
+/// the decision tree of a closure compiled to jumps and switches in its code
+struct Matching<'c, 'm, 'p> {
+    compiler: &'c mut Compiler<'m, 'p>,
+    env: &'c Env<'c>,
+    out: &'c mut Emitter,
+}
+
+impl<'c, 'm, 'p> Matcher<'m> for Matching<'c, 'm, 'p> {
+    type Target = Label;
+
+    /// the value is pushed
+    fn load(&mut self, value: &ValPath) -> Type {
+        match value {
+            ValPath::Local(v) => {
+                self.out.emit(Instr::Local(v[0]));
+                self.compiler.path(&self.env.locals[v[0] as usize], &v[1..], self.out)
+            }
+            _ => panic!("decision tree testing a value that isn't local"),
+        }
+    }
+
+    fn fail(&mut self) -> Label {
+        self.out.fail_label()
+    }
+
+    fn target(&mut self) -> Label {
+        self.out.label()
+    }
+
+    fn place(&mut self, label: Label) {
+        self.out.place(label);
+    }
+
+    fn jump(&mut self, label: Label) {
+        self.out.jump(Instr::Jump, label);
+    }
+
+    /// the jump to the true branch is dropped when its code follows
+    fn branch(&mut self, on_true: Label, on_false: Label) {
+        self.out.jump(Instr::JumpIfFalse, on_false);
+        self.out.jump(Instr::Jump, on_true);
+    }
+
+    fn switch_tag(&mut self, targets: &[Label], default: Label) {
+        let table = Table::Jump { targets: vec![0; targets.len()], default: 0 };
+        let n = self.out.table(table, targets.to_vec(), default);
+        self.out.emit(Instr::Switch(n));
+    }
+
+    fn switch(&mut self, cases: &[(&ConstraintValue<'m>, Label)], default: Label) {
+        let table = match cases.first() {
+            Some((ConstraintValue::Str(_), _)) => Table::Str {
+                cases: cases
+                    .iter()
+                    .map(|&(c, _)| match *c {
+                        ConstraintValue::Str(s) => (self.compiler.constant(Const::Str(s.to_owned())), 0),
+                        _ => panic!("int and string cases in the same node"),
+                    })
+                    .collect(),
+                default: 0,
+            },
+            _ => Table::Int {
+                cases: cases
+                    .iter()
+                    .map(|&(c, _)| match *c {
+                        ConstraintValue::Int(n) => (n, 0),
+                        _ => panic!("int and string cases in the same node"),
+                    })
+                    .collect(),
+                default: 0,
+            },
+        };
+        let n = self.out.table(table, cases.iter().map(|c| c.1).collect(), default);
+        self.out.emit(Instr::Switch(n));
+    }
+}
+
+fn binop(op: BinOpcode) -> bytecode::BinOp {
+    use bytecode::BinOp;
+    match op {
+        BinOpcode::Add => BinOp::Add,
+        BinOpcode::Sub => BinOp::Sub,
+        BinOpcode::Mul => BinOp::Mul,
+        BinOpcode::Div => BinOp::Div,
+        BinOpcode::Mod => BinOp::Mod,
+        BinOpcode::Concat => BinOp::Concat,
+        BinOpcode::Greater => BinOp::Gt,
+        BinOpcode::Less => BinOp::Lt,
+        BinOpcode::GreaterEq => BinOp::Ge,
+        BinOpcode::LessEq => BinOp::Le,
+        BinOpcode::Equal => BinOp::Eq,
+        BinOpcode::NotEq => BinOp::Ne,
+        BinOpcode::And => BinOp::And,
+        BinOpcode::Or => BinOp::Or,
+        BinOpcode::Assign => BinOp::Assign,
+    }
+}
+
+fn unop(op: UnOpcode) -> bytecode::UnOp {
+    use bytecode::UnOp;
+    match op {
+        UnOpcode::Minus => UnOp::Neg,
+        UnOpcode::Not => UnOp::Not,
+        UnOpcode::Ref => UnOp::Ref,
+        UnOpcode::Deref => UnOp::Deref,
+    }
+}
@@ -54,20 +54,20 @@ mod test {
                 (
                     0,
                     vec![
-                        (ValPath::Local(vec![0]), ConstraintValue::Finite(1, 2)),
-                        (ValPath::Local(vec![1]), ConstraintValue::Finite(2, 2)),
+                        (ValPath::Local(vec![0]), ConstraintValue::Finite(0, 2)),
+                        (ValPath::Local(vec![1]), ConstraintValue::Finite(1, 2)),
                     ],
                 ),
                 (
                     1,
                     vec![
-                        (ValPath::Local(vec![0]), ConstraintValue::Finite(2, 2)),
-                        (ValPath::Local(vec![1]), ConstraintValue::Finite(1, 2)),
+                        (ValPath::Local(vec![0]), ConstraintValue::Finite(1, 2)),
+                        (ValPath::Local(vec![1]), ConstraintValue::Finite(0, 2)),
                     ],
                 ),
                 (
                     2,
-                    vec![(ValPath::Local(vec![0]), ConstraintValue::Finite(1, 2))],
+                    vec![(ValPath::Local(vec![0]), ConstraintValue::Finite(0, 2))],
                 ),
                 (3, vec![]),
             ],
@@ -82,18 +82,18 @@ mod test {
                 (
                     0,
                     vec![
-                        (ValPath::Local(vec![0, 0]), ConstraintValue::Finite(2, 3)),
+                        (ValPath::Local(vec![0, 0]), ConstraintValue::Finite(1, 3)),
                         (ValPath::Local(vec![0, 1]), ConstraintValue::Int(13)),
                     ],
                 ),
                 (1, vec![(ValPath::Local(vec![1]), ConstraintValue::Int(5))]),
                 (
                     2,
-                    vec![(ValPath::Local(vec![0, 0]), ConstraintValue::Finite(1, 3))],
+                    vec![(ValPath::Local(vec![0, 0]), ConstraintValue::Finite(0, 3))],
                 ),
                 (
                     3,
-                    vec![(ValPath::Local(vec![0, 0]), ConstraintValue::Finite(3, 3))],
+                    vec![(ValPath::Local(vec![0, 0]), ConstraintValue::Finite(2, 3))],
                 ),
                 (4, vec![]),
             ],
@@ -2,7 +2,11 @@ use std::{
     io::Error as ioErr,
     convert::From,
 };
-use crate::types::Type;
+use crate::{
+    ast::Span,
+    parse::line_col,
+    types::Type,
+};
 
 
 /// All errors from AST -> imperAST phase, not used yet!
@@ -20,6 +24,29 @@ pub enum Error<'input> {
     VariablePatsNum,
 }
 
+/// Problems that don't stop compilation
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// a top-level declaration whose pattern can fail to match, the span is the declaration's
+    RefutablePattern(Span),
+}
+
+impl Warning {
+    /// describe the warning, src is the parsed source to find line numbers in
+    pub fn report(&self, src: &str) -> String {
+        match *self {
+            Warning::RefutablePattern((start, _)) => {
+                let (line, col) = line_col(src, start);
+                format!(
+                    "warning: refutable pattern in the declaration at line {}, column {}, \
+                     evaluation fails if it doesn't match",
+                    line, col
+                )
+            }
+        }
+    }
+}
+
 impl<'input> From<ioErr> for Error<'input> {
     fn from(e: ioErr) -> Self {
         Error::IOErr(e)
@@ -8,7 +8,9 @@ use std::collections::{
     BTreeMap,
 };
 use crate::{
+    ast::Span,
     dtree::DTree,
+    error::Warning,
     types::{Type, Literal, BinOpcode, UnOpcode, TypeDecl},
 };
 
@@ -21,10 +23,16 @@ pub struct Module<'input> {
     /// e.g. (x, y) = (1, 2) is a single value. The BTreeMap has any
     /// literal constraints on global values e.g.
     /// (1, 2) = f 5;
-    pub globals: Vec<(Expr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type)>,
+    /// The span is that of the whole declaration.
+    pub globals: Vec<(Expr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type, Span)>,
     /// path of exported global name including top-level functions
     pub globals_names: HashMap<&'input str, ValPath>,
     pub type_decls: Vec<TypeDecl<'input>>,
+    /// declared effect operations: name, type performed with and type resumed with
+    pub effects: Vec<(&'input str, Type, Type)>,
+    pub warnings: Vec<Warning>,
+    /// names and types of the functions provided by the runtime
+    pub imports: Vec<(&'static str, Type)>,
 }
 
 /// The path of a value. Together with the type, it can give the actual position
@@ -54,12 +62,25 @@ pub enum ValPath {
     StaticVal(Vec<u16>),
     /// just a marker, constructors are not stored anywhere
     Constructor(u16, u16),
-    Imported(&'static str),
+    /// nth function provided by the runtime, in the order of the module's imports
+    Imported(u16),
+}
+
+impl ValPath {
+    /// the index of a captured value among the captures of its closure
+    pub fn capture_index(&self) -> u16 {
+        match *self {
+            ValPath::CaptureLocal(i, _) | ValPath::CaptureCaptured(i, _) => i,
+            _ => panic!("captured value with path {:?}", self),
+        }
+    }
 }
 
 /// Represents both static (top-level functions) and dynamic closures
 #[derive(Debug)]
 pub struct Closure<'input> {
+    /// name of the top-level binding if the closure is bound directly to one
+    pub name: Option<&'input str>,
     /// values captured from parent
     pub captures: Vec<(ValPath, Type)>,
     pub args: Vec<Type>,
@@ -83,20 +104,24 @@ pub enum ConstraintValue<'input> {
 }
 
 /// Representation of an expression
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Expr<'input> {
     Literal(Literal<'input>),
     /// named value
     Bound(ValPath),
     Tuple(Vec<Expr<'input>>),
 
-    BinOp(Box<Expr<'input>>, BinOpcode, Box<Expr<'input>>),
+    /// the span is used to report runtime errors, e.g. division by zero
+    BinOp(Box<Expr<'input>>, BinOpcode, Box<Expr<'input>>, Span),
     UnOp(UnOpcode, Box<Expr<'input>>),
 
     /// closure which is an index into Module.anon_funcs
     Closure(u16),
-    /// Apply e1 on e2
-    Application(Box<Expr<'input>>, Box<Expr<'input>>),
+    /// Apply e1 on e2, the span is the call site
+    Application(Box<Expr<'input>>, Box<Expr<'input>>, Span),
+    /// Apply e to as many arguments as the closure it's known to be takes, at once
+    /// instead of one at a time. The span is the call site
+    Call(Box<Expr<'input>>, Vec<Expr<'input>>, Span),
     /// Constructor Application
     SumVal {
         target: u16,
@@ -107,5 +132,22 @@ pub enum Expr<'input> {
     /// if e1 then e2 else e3
     Conditional(Box<Expr<'input>>, Box<Expr<'input>>, Box<Expr<'input>>),
 
+    /// raise the exception value of e
+    Raise(Box<Expr<'input>>, Span),
+    /// evaluate e, an exception raised by it is matched against the arms of the
+    /// handler closure (index into Module.closures) and re-raised if none matches
+    Try(Box<Expr<'input>>, u16),
+
+    /// perform the nth effect operation with the value of e
+    Perform(u16, Box<Expr<'input>>),
+    /// evaluate body under an effect handler, all handler arms are closures
+    Handle {
+        body: Box<Expr<'input>>,
+        /// applied to the value of body, identity if missing
+        ret: Option<u16>,
+        /// (effect, closure) pairs, the closure takes the value and the continuation
+        ops: Vec<(u16, u16)>,
+    },
+
     Error,
 }
\ No newline at end of file
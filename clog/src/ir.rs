@@ -2,106 +2,890 @@
 //! represents the program as an array of modules, where each module is a
 //! list of globals (one for each toplevel declaration).
 //!
+//! Each procedure is a control flow graph of blocks. A block is a list of statements
+//! ending in a terminator: a jump or a branch to other blocks, a return, a raise or a
+//! match failure. Values are computed into temporaries, which are numbered per
+//! procedure and can be assigned in more than one block, e.g. the value of a
+//! conditional in both of its branches. Compound values are in memory, read and
+//! written through `MemAddr`s.
+//!
+//! A module is lowered from imper_ast: a procedure for each closure, matching its
+//! arguments with the decision tree and then running the arm of the exit reached,
+//! a procedure for the body of each `try` and `handle`, and the initializer of the
+//! globals. Printing a module gives a textual dump of it.
+//!
+//! Passes over the IR call `check` on the modules they make, which verifies them in
+//! debug builds.
 
-// TODO: currently in the process of migrating imper_ast to this
-// TODO: replace with imper_ast::TypeDecl
-// TODO: make  memaddr's use ValPath instead of tmps
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::parse::parse,
+        crate::type_check::ast2imper_ast,
+    };
 
-use crate::{
-    imper_ast::ValPath,
-    types::{ Type, BinOpcode, UnOpcode, Literal }
-};
-use peterson::graph::Graph;
+    fn imper_module(src: &str) -> imper_ast::Module<'_> {
+        let print = Type::Function(Box::new(Type::String), Box::new(Type::Unit));
+        ast2imper_ast(parse(src).unwrap(), &[("print", print)]).unwrap()
+    }
+
+    /// the last statement of each block is its only terminator
+    fn check_blocks(procedure: &Procedure) {
+        for block in &procedure.control_flow.blocks {
+            let (last, rest) = block.split_last().expect("empty block");
+            assert!(last.is_terminator(), "block ending in {:?}", last);
+            assert!(rest.iter().all(|s| !s.is_terminator()));
+        }
+    }
+
+    #[test]
+    fn lowering() {
+        let module = imper_module(
+            "type Shape = | circle int | rect (int, int)
+            let area = {
+                (circle r) => 3 * r * r,
+                (rect (w, h)) => w * h,
+            }
+            let name = { 0 => \"zero\", n => if n < 0 then \"negative\" else \"many\" end }
+            let safe = { n => try 10 / n with { (DivisionByZero ()) => 0 } }
+            let (1, x) = (1, area (circle 2))",
+        );
+        let ir = lower(&module);
+        assert_eq!(ir.procedures.len(), module.closures.len() + 1);
+        ir.procedures.iter().chain(Some(&ir.init_fn)).for_each(check_blocks);
 
+        let area = &ir.procedures[0];
+        assert_eq!(area.name, Some("area"));
+        assert!(area.control_flow.blocks[0].iter().any(|s| match *s {
+            Statement::Switch { ref cases, .. } => cases.len() == 2,
+            _ => false,
+        }));
+        let body = ir.procedures.last().unwrap();
+        assert!(body.shares_frame);
+        assert_eq!(body.in_ty, [Type::Int]);
 
-struct TypeDecl<'input, 'types> {
-    _empty: ::std::marker::PhantomData<&'input &'types ()>
+        let dump = ir.to_string();
+        assert!(dump.contains("proc 0 area (~4) -> int\n"));
+        assert!(dump.contains(" = _0.tag\n"));
+        assert!(dump.contains(" = _3.value<circle>\n"));
+        assert!(dump.contains("switch _1 [0 => :B1, 1 => :B2] else :B3\n"));
+        assert!(dump.contains("\nproc 1 name (int) -> string\n\
+            :B0\n    _0 = stack[0]\n    switch _0 [0 => :B1] else :B2\n"));
+        assert!(dump.contains(" = try proc 4 with "));
+        assert!(dump.contains("\ninit\n"));
+        assert!(dump.contains("    global[0] = "));
+        assert!(dump.contains("let x = global[3].1\n"));
+    }
+
+    #[test]
+    fn dump() {
+        let module = imper_module("let add = { (x, y) => x + y }\nlet pair = (add (1, 2), \"s\")");
+        assert_eq!(
+            lower(&module).to_string(),
+            "global 0: (int, int) -> int
+global 1: (int, string)
+let add = global[0]
+let pair = global[1]
+
+proc 0 add ((int, int)) -> int
+:B0
+    jump :B1
+:B1
+    _0 = stack[0]
+    _1 = _0.0
+    _2 = stack[0]
+    _3 = _2.1
+    _4 = _1 + _3
+    return _4
+
+init
+:B0
+    _0 = closure 0 []
+    global[0] = _0
+    _1 = global[0]
+    _2 = 1
+    _3 = 2
+    _4 = (_2, _3)
+    _5 = call _1 _4
+    _6 = \"s\"
+    _7 = (_5, _6)
+    global[1] = _7
+    _8 = ()
+    return _8
+"
+        );
+    }
 }
 
+use std::fmt;
 
-struct Module<'input,'types> {
-    /// type definitions in this module
-    ty_def: Vec<TypeDecl<'input,'types>>,
-    /// table of types, the remaining fields' types are references to this vec
-    ty_table: Vec<Type>,
+use crate::{
+    imper_ast::{self, ConstraintValue, Expr, ValPath},
+    lower::{self, Capture, Matcher, Step},
+    types::{BinOpcode, Literal, Type, TypeDecl, UnOpcode},
+    verify::verify,
+};
+
+pub type TmpIdx = u16;
+/// index of a block in the control flow graph of its procedure
+pub type BlockIdx = u32;
+/// index of a type in the type table of a module
+pub type TyIdx = u16;
+
+pub struct Module<'input, 'types> {
+    /// type definitions in this module, the targets of sum types
+    pub ty_def: &'types [TypeDecl<'input>],
+    /// table of types, the types of memory addresses are indices into it
+    pub ty_table: Vec<Type>,
     /// layout of global memory of this module
-    gl_layout: Vec<&'types Type>,
-    /// vec of all functions/closures
-    procedures: Vec<Procedure<'input,'types>>,
+    pub gl_layout: Vec<TyIdx>,
+    /// vec of all functions/closures, by their index in imper_ast, followed by the
+    /// bodies of try and handle
+    pub procedures: Vec<Procedure<'input>>,
     /// name bindings in this module
-    bindings: Vec<(&'input str, ValPath)>,
+    pub bindings: Vec<(&'input str, ValPath)>,
+    /// names of the effect operations and of the functions provided by the runtime
+    pub effects: Vec<&'input str>,
+    pub imports: Vec<&'static str>,
     /// initializes values of bindings
-    init_fn: Procedure<'input,'types>,
+    pub init_fn: Procedure<'input>,
 }
 
-
-struct Procedure<'input,'types> {
-    /// labeled graph where statemnts are edges.
-    control_flow: Graph<u32, Statement<'input, 'types>>,
-    in_ty: Vec<Type>,
-    out_ty: Type,
-    num_generics: u16,
+pub struct Procedure<'input> {
+    pub name: Option<&'input str>,
+    pub control_flow: Cfg<'input>,
+    pub in_ty: Vec<Type>,
+    /// types of the captured values, by their index
+    pub captures: Vec<Type>,
+    /// missing for the bodies of try and handle
+    pub out_ty: Option<Type>,
+    pub num_tmps: u16,
+    /// the procedure is the body of a try or handle, reading the arguments and the
+    /// captured values of the procedure it's in
+    pub shares_frame: bool,
 }
 
-pub type tmpIdx = u16;
+/// the statements of a block, only the last one is a terminator
+pub type Block<'input> = Vec<Statement<'input>>;
+
+/// A control flow graph whose entry is its first block. The edges are the targets of
+/// the terminators.
+pub struct Cfg<'input> {
+    pub blocks: Vec<Block<'input>>,
+}
 
 /// a simple statement.
-enum Statement<'input,'types> {
+#[derive(Debug)]
+pub enum Statement<'input> {
     /// fetch from memaddr to temporary
-    Fetch(tmpIdx, MemAddr<'types>),
+    Fetch(TmpIdx, MemAddr),
     /// assign value to the temporary
-    Assign(tmpIdx, Value<'input>),
+    Assign(TmpIdx, Value<'input>),
     /// store value of temporary to memaddr
-    Store(tmpIdx, MemAddr<'types>),
-    /// 
-    Call(u16, u16),
-    Conditional(u16),
+    Store(TmpIdx, MemAddr),
+    /// apply the function in the second temporary to the value of the third,
+    /// assigning the result to the first
+    Call(TmpIdx, TmpIdx, TmpIdx),
+    /// jump to the block
+    Jump(BlockIdx),
+    /// branch to the first block if the temporary is true, else to the second
+    Conditional(TmpIdx, BlockIdx, BlockIdx),
+    /// branch to the block of the case equal to the temporary, the default if none is
+    Switch {
+        on: TmpIdx,
+        cases: Vec<(Literal<'input>, BlockIdx)>,
+        default: BlockIdx,
+    },
     /// return the value of nth temporary from procedure
-    Return(tmpIdx)
+    Return(TmpIdx),
+    /// raise the exception in the temporary
+    Raise(TmpIdx),
+    /// no pattern matched, raise MatchFailure
+    Fail,
 }
 
-impl<'input, 'types> peterson::graph::Edge for Statement<'input, 'types> {}
+#[derive(Debug)]
+pub enum MemAddr {
+    /// nth argument of the procedure, on the stack
+    Stack(u16),
 
-enum MemAddr<'types> {
-    /// address of the closure struct of the current function if any. 
-    /// Passed as one of the args to function. At runtime, it has
-    /// the representation
+    /// nth value captured by the closure of the current procedure. Passed as
+    /// one of the args to function. At runtime, it has the representation
     /// struct Frame {
     ///     /// A pointer to the entry point of the current procedure
     ///     startPtr: usize,
     ///     args: (arg1_t, arg2_t, ...)
     ///     captures: (cptr1_t, cptr_2, ...)
     /// }
-    SelfFrame,
+    Captured(u16),
 
-    /// nth value on the stack (u16 is not a tmp but the actual position)
-    Stack(u16),
+    /// the value of nth top-level declaration
+    Global(u16),
 
     /// literal address in tmp variable
-    Numeric(tmpIdx),
+    Numeric(TmpIdx),
 
     /// offset from start of an array
     ArrayOffset {
-        elem_type: &'types Type,
+        elem_type: TyIdx,
         /// read array addr from tmp
-        array_addr: tmpIdx,
+        array_addr: TmpIdx,
         /// read offset (in #elems) from tmp
-        offset: tmpIdx,
+        offset: TmpIdx,
     },
-    /// offset in a struct
+    /// offset in a struct, the value at a path in ValPath: nth field of a tuple, or
+    /// the tag of a sum value at 0 and the value of its nth variant at n. The tag of
+    /// the nth variant is n - 1.
     StructOffset {
-        /// types of fields
-        tuple_type: &'types [Type],
+        /// read struct addr from tmp
+        base: TmpIdx,
+        /// type of the struct, a tuple or a sum
+        struct_type: TyIdx,
         /// target is nth field
         target: u16,
     },
 }
 
-
-enum Value<'input> {
+#[derive(Debug)]
+pub enum Value<'input> {
     Literal(Literal<'input>),
-    Variable(tmpIdx),
-    Binop(BinOpcode, tmpIdx, tmpIdx),
-    Unop(UnOpcode, tmpIdx),
-    Construct(u16, u16, u16),
-}
\ No newline at end of file
+    Variable(TmpIdx),
+    Binop(BinOpcode, TmpIdx, TmpIdx),
+    Unop(UnOpcode, TmpIdx),
+    /// nth type's mth constructor applied to the temporary
+    Construct(u16, u16, TmpIdx),
+    /// nth type's mth constructor as a function
+    Constructor(u16, u16),
+    Tuple(Vec<TmpIdx>),
+    /// closure of nth procedure with the captured values
+    Closure(u16, Vec<TmpIdx>),
+    /// nth function provided by the runtime
+    Import(u16),
+    /// perform nth effect operation with the value
+    Perform(u16, TmpIdx),
+    /// run the body in nth procedure, handling its exceptions with the closure
+    Try(u16, TmpIdx),
+    /// run the body in nth procedure under an effect handler of closures
+    Handle {
+        body: u16,
+        ret: Option<TmpIdx>,
+        ops: Vec<(u16, TmpIdx)>,
+    },
+}
+
+impl<'input> Statement<'input> {
+    pub fn is_terminator(&self) -> bool {
+        !matches!(
+            *self,
+            Statement::Fetch(..) | Statement::Assign(..) | Statement::Store(..) | Statement::Call(..)
+        )
+    }
+
+    /// the blocks a terminator goes to
+    pub fn successors(&self) -> Vec<BlockIdx> {
+        match *self {
+            Statement::Jump(b) => vec![b],
+            Statement::Conditional(_, b1, b2) => vec![b1, b2],
+            Statement::Switch { ref cases, default, .. } => {
+                cases.iter().map(|c| c.1).chain(Some(default)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<'input> Cfg<'input> {
+    /// a graph of an empty entry block
+    pub fn new() -> Self {
+        Cfg { blocks: vec![Vec::new()] }
+    }
+
+    pub fn add_block(&mut self) -> BlockIdx {
+        self.blocks.push(Vec::new());
+        (self.blocks.len() - 1) as BlockIdx
+    }
+
+    pub fn successors(&self, block: BlockIdx) -> Vec<BlockIdx> {
+        match self.blocks[block as usize].last() {
+            Some(s) => s.successors(),
+            None => Vec::new(),
+        }
+    }
+
+    /// the blocks reached from the entry
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reached = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(b) = stack.pop() {
+            if !reached[b as usize] {
+                reached[b as usize] = true;
+                stack.extend(self.successors(b));
+            }
+        }
+        reached
+    }
+}
+
+impl<'input> Default for Cfg<'input> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// lower a type-checked module
+pub fn lower<'input, 'types>(module: &'types imper_ast::Module<'input>) -> Module<'input, 'types> {
+    let mut lowering = Lowering {
+        module,
+        ty_table: Vec::new(),
+        procedures: module.closures.iter().map(|_| None).collect(),
+        handlers: lower::handlers(module),
+    };
+    for n in 0..module.closures.len() {
+        let procedure = lowering.closure(n as u16);
+        lowering.procedures[n] = Some(procedure);
+    }
+    let init_fn = lowering.init_fn();
+    let gl_layout = module.globals.iter().map(|g| lowering.ty(&g.2)).collect();
+    let mut bindings: Vec<_> = module.globals_names.iter().map(|(&name, path)| (name, path.clone())).collect();
+    bindings.sort();
+    let lowered = Module {
+        ty_def: &module.type_decls,
+        ty_table: lowering.ty_table,
+        gl_layout,
+        procedures: lowering.procedures.into_iter().map(|p| p.expect("procedure never lowered")).collect(),
+        bindings,
+        effects: module.effects.iter().map(|e| e.0).collect(),
+        imports: module.imports.iter().map(|i| i.0).collect(),
+        init_fn,
+    };
+    check(&lowered, "lowering");
+    lowered
+}
+
+/// panic if a pass made a module breaking the invariants of the IR, in debug builds
+pub fn check(module: &Module, pass: &str) {
+    if cfg!(debug_assertions) {
+        if let Err(e) = verify(module) {
+            panic!("invalid IR after {}: {}", pass, e);
+        }
+    }
+}
+
+struct Lowering<'input, 'types> {
+    module: &'types imper_ast::Module<'input>,
+    ty_table: Vec<Type>,
+    /// the procedures, none until lowered
+    procedures: Vec<Option<Procedure<'input>>>,
+    handlers: Vec<u16>,
+}
+
+/// the procedure being lowered
+struct Builder<'input, 'env> {
+    name: Option<&'input str>,
+    cfg: Cfg<'input>,
+    /// the block statements go to
+    current: BlockIdx,
+    num_tmps: u16,
+    /// types of the arguments and of the captured values
+    locals: &'env [Type],
+    captures: &'env [Type],
+    /// the block failing to match, if it's used
+    fail: Option<BlockIdx>,
+    /// the procedure is an exception handler, failing raises the exception again
+    reraise: bool,
+}
+
+impl<'input, 'env> Builder<'input, 'env> {
+    fn new(name: Option<&'input str>, locals: &'env [Type], captures: &'env [Type]) -> Self {
+        Builder { name, cfg: Cfg::new(), current: 0, num_tmps: 0, locals, captures, fail: None, reraise: false }
+    }
+
+    fn tmp(&mut self) -> TmpIdx {
+        self.num_tmps += 1;
+        self.num_tmps - 1
+    }
+
+    /// add a statement to the current block, the statements after a terminator go to
+    /// a new block that isn't reached unless a later branch goes to it
+    fn emit(&mut self, statement: Statement<'input>) {
+        if matches!(self.cfg.blocks[self.current as usize].last(), Some(s) if s.is_terminator()) {
+            self.current = self.cfg.add_block();
+        }
+        self.cfg.blocks[self.current as usize].push(statement);
+    }
+
+    /// a new temporary assigned the value
+    fn assign(&mut self, value: Value<'input>) -> TmpIdx {
+        let t = self.tmp();
+        self.emit(Statement::Assign(t, value));
+        t
+    }
+
+    fn fetch(&mut self, addr: MemAddr) -> TmpIdx {
+        let t = self.tmp();
+        self.emit(Statement::Fetch(t, addr));
+        t
+    }
+
+    fn fail_block(&mut self) -> BlockIdx {
+        if let Some(b) = self.fail {
+            return b;
+        }
+        let b = self.cfg.add_block();
+        let failure = if self.reraise {
+            let t = self.tmp();
+            vec![Statement::Fetch(t, MemAddr::Stack(0)), Statement::Raise(t)]
+        } else {
+            vec![Statement::Fail]
+        };
+        self.cfg.blocks[b as usize] = failure;
+        self.fail = Some(b);
+        b
+    }
+
+    fn finish(self, in_ty: Vec<Type>, out_ty: Option<Type>, shares_frame: bool) -> Procedure<'input> {
+        Procedure {
+            name: self.name,
+            control_flow: self.cfg,
+            in_ty,
+            captures: self.captures.to_vec(),
+            out_ty,
+            num_tmps: self.num_tmps,
+            shares_frame,
+        }
+    }
+}
+
+impl<'input, 'types> Lowering<'input, 'types> {
+    /// the index of a type in the table
+    fn ty(&mut self, t: &Type) -> TyIdx {
+        match self.ty_table.iter().position(|u| u == t) {
+            Some(i) => i as TyIdx,
+            None => {
+                self.ty_table.push(t.clone());
+                (self.ty_table.len() - 1) as TyIdx
+            }
+        }
+    }
+
+    /// the procedure of nth closure: matching its arguments, then its arms
+    fn closure(&mut self, n: u16) -> Procedure<'input> {
+        let closure = &self.module.closures[n as usize];
+        let captures: Vec<Type> = lower::captures(closure).into_iter().map(|c| c.1.clone()).collect();
+        let mut out = Builder::new(closure.name, &closure.args, &captures);
+        out.reraise = self.handlers.contains(&n);
+        let arms: Vec<BlockIdx> = closure.branches.iter().map(|_| out.cfg.add_block()).collect();
+        lower::match_tree(&mut Matching { lowering: self, out: &mut out, on: 0 }, &closure.dtree, &arms);
+        for (&arm, e) in arms.iter().zip(&closure.branches) {
+            out.current = arm;
+            self.tail_expr(e, &mut out);
+        }
+        out.finish(closure.args.clone(), Some(closure.return_type.clone()), false)
+    }
+
+    /// a procedure running an expression in the frame of the procedure being lowered
+    fn body(&mut self, e: &Expr<'input>, parent: &Builder<'input, '_>) -> u16 {
+        let n = self.procedures.len();
+        self.procedures.push(None);
+        let mut out = Builder::new(parent.name, parent.locals, parent.captures);
+        self.tail_expr(e, &mut out);
+        self.procedures[n] = Some(out.finish(parent.locals.to_vec(), None, true));
+        n as u16
+    }
+
+    /// the procedure setting the globals in order and checking the constraints of
+    /// their patterns
+    fn init_fn(&mut self) -> Procedure<'input> {
+        let mut out = Builder::new(None, &[], &[]);
+        for (i, (e, constraints, t, _)) in self.module.globals.iter().enumerate() {
+            let value = self.expr(e, &mut out);
+            out.emit(Statement::Store(value, MemAddr::Global(i as u16)));
+            // tags come before the paths of variant values in the order of ValPaths,
+            // so a path is only followed if the tags leading to it matched
+            for (path, constraint) in constraints {
+                let path = match path {
+                    ValPath::StaticVal(v) => v,
+                    _ => panic!("constraint on {:?}", path),
+                };
+                let global = out.fetch(MemAddr::Global(path[0]));
+                let (actual, t) = self.path(t, global, &path[1..], &mut out);
+                let expected = out.assign(Value::Literal(lower::expected(constraint, &t)));
+                let matched = out.assign(Value::Binop(BinOpcode::Equal, actual, expected));
+                let (next, fail) = (out.cfg.add_block(), out.fail_block());
+                out.emit(Statement::Conditional(matched, next, fail));
+                out.current = next;
+            }
+        }
+        let unit = out.assign(Value::Literal(Literal::Unit));
+        out.emit(Statement::Return(unit));
+        out.finish(Vec::new(), Some(Type::Unit), false)
+    }
+
+    /// Follow a path in a value of type t, the temporary and the type of the value at
+    /// the end of the path, an int for a tag.
+    fn path(&mut self, t: &Type, mut base: TmpIdx, path: &[u16], out: &mut Builder) -> (TmpIdx, Type) {
+        let (steps, t) = lower::follow(self.module, t, path);
+        for (step, from) in steps {
+            let struct_type = self.ty(&from);
+            let target = match step {
+                Step::Field(n) | Step::Payload(n) => n,
+                Step::Tag => 0,
+            };
+            base = out.fetch(MemAddr::StructOffset { base, struct_type, target });
+        }
+        (base, t)
+    }
+
+    /// a temporary with the value at a path
+    fn load(&mut self, path: &ValPath, out: &mut Builder<'input, '_>) -> (TmpIdx, Type) {
+        match path {
+            ValPath::Local(v) => {
+                let arg = out.fetch(MemAddr::Stack(v[0]));
+                let locals = out.locals;
+                self.path(&locals[v[0] as usize], arg, &v[1..], out)
+            }
+            ValPath::StaticVal(v) => {
+                let global = out.fetch(MemAddr::Global(v[0]));
+                let module = self.module;
+                self.path(&module.globals[v[0] as usize].2, global, &v[1..], out)
+            }
+            &ValPath::CaptureLocal(i, _) | &ValPath::CaptureCaptured(i, _) => {
+                (out.fetch(MemAddr::Captured(i)), out.captures[i as usize].clone())
+            }
+            &ValPath::Constructor(i, j) => {
+                (out.assign(Value::Constructor(i, j)), Type::Constructor { target: i, position: j })
+            }
+            &ValPath::Imported(n) => (out.assign(Value::Import(n)), self.module.imports[n as usize].1.clone()),
+        }
+    }
+
+    /// a closure of nth procedure with its captured values
+    fn make_closure(&mut self, n: u16, out: &mut Builder<'input, '_>) -> TmpIdx {
+        let captures = lower::captures(&self.module.closures[n as usize])
+            .into_iter()
+            .map(|(capture, _)| match capture {
+                Capture::Local(path) => self.load(&path, out).0,
+                Capture::Captured(j) => out.fetch(MemAddr::Captured(j)),
+            })
+            .collect();
+        out.assign(Value::Closure(n, captures))
+    }
+
+    /// return the value of an expression
+    fn tail_expr(&mut self, e: &Expr<'input>, out: &mut Builder<'input, '_>) {
+        if let Expr::Conditional(cond, e1, e2) = e {
+            let cond = self.expr(cond, out);
+            let (on_true, on_false) = (out.cfg.add_block(), out.cfg.add_block());
+            out.emit(Statement::Conditional(cond, on_true, on_false));
+            out.current = on_true;
+            self.tail_expr(e1, out);
+            out.current = on_false;
+            self.tail_expr(e2, out);
+        } else {
+            let value = self.expr(e, out);
+            out.emit(Statement::Return(value));
+        }
+    }
+
+    /// a temporary with the value of an expression
+    fn expr(&mut self, e: &Expr<'input>, out: &mut Builder<'input, '_>) -> TmpIdx {
+        match e {
+            Expr::Literal(l) => out.assign(Value::Literal(l.clone())),
+            Expr::Bound(path) => self.load(path, out).0,
+            Expr::Tuple(v) => {
+                let values = v.iter().map(|e| self.expr(e, out)).collect();
+                out.assign(Value::Tuple(values))
+            }
+            &Expr::BinOp(ref e1, op, ref e2, _) => {
+                let (t1, t2) = (self.expr(e1, out), self.expr(e2, out));
+                out.assign(Value::Binop(op, t1, t2))
+            }
+            &Expr::UnOp(op, ref e) => {
+                let t = self.expr(e, out);
+                out.assign(Value::Unop(op, t))
+            }
+            &Expr::Closure(n) => self.make_closure(n, out),
+            Expr::Application(f, arg, _) => {
+                let (f, arg) = (self.expr(f, out), self.expr(arg, out));
+                let result = out.tmp();
+                out.emit(Statement::Call(result, f, arg));
+                result
+            }
+            // applied one argument at a time, as calls in the IR take one
+            Expr::Call(f, args, _) => {
+                let mut f = self.expr(f, out);
+                let args: Vec<TmpIdx> = args.iter().map(|arg| self.expr(arg, out)).collect();
+                for arg in args {
+                    let result = out.tmp();
+                    out.emit(Statement::Call(result, f, arg));
+                    f = result;
+                }
+                f
+            }
+            &Expr::SumVal { target, position, ref value } => {
+                let value = self.expr(value, out);
+                out.assign(Value::Construct(target, position, value))
+            }
+            Expr::Conditional(cond, e1, e2) => {
+                let cond = self.expr(cond, out);
+                let result = out.tmp();
+                let (on_true, on_false, end) = (out.cfg.add_block(), out.cfg.add_block(), out.cfg.add_block());
+                out.emit(Statement::Conditional(cond, on_true, on_false));
+                for &(block, e) in &[(on_true, e1), (on_false, e2)] {
+                    out.current = block;
+                    let value = self.expr(e, out);
+                    out.emit(Statement::Assign(result, Value::Variable(value)));
+                    out.emit(Statement::Jump(end));
+                }
+                out.current = end;
+                result
+            }
+            Expr::Raise(e, _) => {
+                let exn = self.expr(e, out);
+                out.emit(Statement::Raise(exn));
+                // the value of the expression, which is never assigned
+                out.tmp()
+            }
+            &Expr::Try(ref e, handler) => {
+                let handler = self.make_closure(handler, out);
+                let body = self.body(e, out);
+                out.assign(Value::Try(body, handler))
+            }
+            &Expr::Perform(op, ref e) => {
+                let value = self.expr(e, out);
+                out.assign(Value::Perform(op, value))
+            }
+            Expr::Handle { body, ret, ops } => {
+                let ret = ret.map(|n| self.make_closure(n, out));
+                let ops = ops.iter().map(|&(op, n)| (op, self.make_closure(n, out))).collect();
+                let body = self.body(body, out);
+                out.assign(Value::Handle { body, ret, ops })
+            }
+            Expr::Error => panic!("Error"),
+        }
+    }
+}
+
+/// the decision tree of a closure lowered to conditionals and switches between blocks
+struct Matching<'l, 'input, 'types, 'env> {
+    lowering: &'l mut Lowering<'input, 'types>,
+    out: &'l mut Builder<'input, 'env>,
+    /// the value tested by the node being lowered
+    on: TmpIdx,
+}
+
+impl<'l, 'input, 'types, 'env> Matcher<'input> for Matching<'l, 'input, 'types, 'env> {
+    type Target = BlockIdx;
+
+    fn load(&mut self, value: &ValPath) -> Type {
+        let (on, t) = self.lowering.load(value, self.out);
+        self.on = on;
+        t
+    }
+
+    fn fail(&mut self) -> BlockIdx {
+        self.out.fail_block()
+    }
+
+    fn target(&mut self) -> BlockIdx {
+        self.out.cfg.add_block()
+    }
+
+    fn place(&mut self, block: BlockIdx) {
+        self.out.current = block;
+    }
+
+    fn jump(&mut self, block: BlockIdx) {
+        self.out.emit(Statement::Jump(block));
+    }
+
+    fn branch(&mut self, on_true: BlockIdx, on_false: BlockIdx) {
+        self.out.emit(Statement::Conditional(self.on, on_true, on_false));
+    }
+
+    fn switch_tag(&mut self, targets: &[BlockIdx], default: BlockIdx) {
+        let cases = targets.iter().enumerate().map(|(i, &b)| (Literal::Int(i as isize), b)).collect();
+        self.out.emit(Statement::Switch { on: self.on, cases, default });
+    }
+
+    fn switch(&mut self, cases: &[(&ConstraintValue<'input>, BlockIdx)], default: BlockIdx) {
+        let cases = cases
+            .iter()
+            .map(|&(c, b)| match *c {
+                ConstraintValue::Int(n) => (Literal::Int(n), b),
+                ConstraintValue::Str(s) => (Literal::String(s), b),
+                ConstraintValue::Finite(..) => panic!("finite case of an infinite node"),
+            })
+            .collect();
+        self.out.emit(Statement::Switch { on: self.on, cases, default });
+    }
+}
+
+fn binop(op: BinOpcode) -> &'static str {
+    match op {
+        BinOpcode::Add => "+",
+        BinOpcode::Sub => "-",
+        BinOpcode::Mul => "*",
+        BinOpcode::Div => "/",
+        BinOpcode::Mod => "%",
+        BinOpcode::Concat => "++",
+        BinOpcode::Greater => ">",
+        BinOpcode::Less => "<",
+        BinOpcode::GreaterEq => ">=",
+        BinOpcode::LessEq => "<=",
+        BinOpcode::Equal => "=",
+        BinOpcode::NotEq => "!=",
+        BinOpcode::And => "and",
+        BinOpcode::Or => "or",
+        BinOpcode::Assign => ":=",
+    }
+}
+
+fn unop(op: UnOpcode) -> &'static str {
+    match op {
+        UnOpcode::Minus => "-",
+        UnOpcode::Not => "not ",
+        UnOpcode::Ref => "ref ",
+        UnOpcode::Deref => "!",
+    }
+}
+
+fn write_literal(f: &mut fmt::Formatter, l: &Literal) -> fmt::Result {
+    match *l {
+        Literal::Unit => write!(f, "()"),
+        Literal::Int(n) => write!(f, "{}", n),
+        Literal::Bool(p) => write!(f, "{}", p),
+        Literal::String(s) => write!(f, "{:?}", s),
+    }
+}
+
+impl<'input, 'types> Module<'input, 'types> {
+    fn write_addr(&self, f: &mut fmt::Formatter, addr: &MemAddr) -> fmt::Result {
+        match *addr {
+            MemAddr::Stack(n) => write!(f, "stack[{}]", n),
+            MemAddr::Captured(n) => write!(f, "captured[{}]", n),
+            MemAddr::Global(n) => write!(f, "global[{}]", n),
+            MemAddr::Numeric(t) => write!(f, "*_{}", t),
+            MemAddr::ArrayOffset { elem_type, array_addr, offset } => {
+                write!(f, "_{}[_{}]<{:?}>", array_addr, offset, self.ty_table[elem_type as usize])
+            }
+            MemAddr::StructOffset { base, struct_type, target } => match self.ty_table[struct_type as usize] {
+                Type::Sum(_, _) if target == 0 => write!(f, "_{}.tag", base),
+                Type::Sum(n, _) => {
+                    write!(f, "_{}.value<{}>", base, self.ty_def[n as usize].variants[target as usize - 1].0)
+                }
+                _ => write!(f, "_{}.{}", base, target),
+            },
+        }
+    }
+
+    fn write_value(&self, f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+        let tmps = |v: &[TmpIdx]| v.iter().map(|t| format!("_{}", t)).collect::<Vec<_>>().join(", ");
+        let variant = |n: u16, m: u16| self.ty_def[n as usize].variants[m as usize - 1].0;
+        match *value {
+            Value::Literal(ref l) => write_literal(f, l),
+            Value::Variable(t) => write!(f, "_{}", t),
+            Value::Binop(op, t1, t2) => write!(f, "_{} {} _{}", t1, binop(op), t2),
+            Value::Unop(op, t) => write!(f, "{}_{}", unop(op), t),
+            Value::Construct(n, m, t) => write!(f, "{} _{}", variant(n, m), t),
+            Value::Constructor(n, m) => write!(f, "constructor {}", variant(n, m)),
+            Value::Tuple(ref v) => write!(f, "({})", tmps(v)),
+            Value::Closure(n, ref v) => write!(f, "closure {} [{}]", n, tmps(v)),
+            Value::Import(n) => write!(f, "import {}", self.imports[n as usize]),
+            Value::Perform(op, t) => write!(f, "perform {} _{}", self.effects[op as usize], t),
+            Value::Try(body, handler) => write!(f, "try proc {} with _{}", body, handler),
+            Value::Handle { body, ret, ref ops } => {
+                write!(f, "handle proc {}", body)?;
+                if let Some(t) = ret {
+                    write!(f, " return _{}", t)?;
+                }
+                let ops = ops.iter().map(|&(op, t)| format!("{} => _{}", self.effects[op as usize], t));
+                write!(f, " [{}]", ops.collect::<Vec<_>>().join(", "))
+            }
+        }
+    }
+
+    fn write_statement(&self, f: &mut fmt::Formatter, statement: &Statement) -> fmt::Result {
+        match *statement {
+            Statement::Fetch(t, ref addr) => {
+                write!(f, "_{} = ", t)?;
+                self.write_addr(f, addr)
+            }
+            Statement::Assign(t, ref value) => {
+                write!(f, "_{} = ", t)?;
+                self.write_value(f, value)
+            }
+            Statement::Store(t, ref addr) => {
+                self.write_addr(f, addr)?;
+                write!(f, " = _{}", t)
+            }
+            Statement::Call(t, func, arg) => write!(f, "_{} = call _{} _{}", t, func, arg),
+            Statement::Jump(b) => write!(f, "jump :B{}", b),
+            Statement::Conditional(t, b1, b2) => write!(f, "if _{} then :B{} else :B{}", t, b1, b2),
+            Statement::Switch { on, ref cases, default } => {
+                write!(f, "switch _{} [", on)?;
+                for (i, (l, b)) in cases.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_literal(f, l)?;
+                    write!(f, " => :B{}", b)?;
+                }
+                write!(f, "] else :B{}", default)
+            }
+            Statement::Return(t) => write!(f, "return _{}", t),
+            Statement::Raise(t) => write!(f, "raise _{}", t),
+            Statement::Fail => write!(f, "fail"),
+        }
+    }
+
+    fn write_procedure(&self, f: &mut fmt::Formatter, procedure: &Procedure) -> fmt::Result {
+        for (i, block) in procedure.control_flow.blocks.iter().enumerate() {
+            writeln!(f, ":B{}", i)?;
+            for statement in block {
+                write!(f, "    ")?;
+                self.write_statement(f, statement)?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'input, 'types> fmt::Display for Module<'input, 'types> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &t) in self.gl_layout.iter().enumerate() {
+            writeln!(f, "global {}: {:?}", i, self.ty_table[t as usize])?;
+        }
+        // the names of constructors and imports aren't in memory
+        for (name, path) in &self.bindings {
+            if let ValPath::StaticVal(v) = path {
+                write!(f, "let {} = global[{}]", name, v[0])?;
+                v[1..].iter().try_for_each(|n| write!(f, ".{}", n))?;
+                writeln!(f)?;
+            }
+        }
+        for (i, procedure) in self.procedures.iter().enumerate() {
+            write!(f, "\nproc {}", i)?;
+            if let Some(name) = procedure.name {
+                write!(f, " {}", name)?;
+            }
+            if procedure.shares_frame {
+                write!(f, " body")?;
+            }
+            let types = |v: &[Type]| v.iter().map(|t| format!("{:?}", t)).collect::<Vec<_>>().join(", ");
+            write!(f, " ({})", types(&procedure.in_ty))?;
+            if !procedure.captures.is_empty() {
+                write!(f, " captures ({})", types(&procedure.captures))?;
+            }
+            if let Some(ref t) = procedure.out_ty {
+                write!(f, " -> {:?}", t)?;
+            }
+            writeln!(f)?;
+            self.write_procedure(f, procedure)?;
+        }
+        writeln!(f, "\ninit")?;
+        self.write_procedure(f, &self.init_fn)
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(nll)]
-#![feature(box_syntax)]
 //#![warn(missing_docs)]
 pub mod ast;
 pub mod grammar;
@@ -7,6 +5,11 @@ pub mod error;
 pub mod types;
 pub mod type_check;
 pub mod imper_ast;
+pub mod codegen;
+pub mod optimize;
+pub mod ir;
+mod lower;
+pub mod verify;
 mod unify;
 pub mod dtree;
 mod namescope;
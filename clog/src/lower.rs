@@ -0,0 +1,213 @@
+//! What the backends share when they lower a module, `codegen` to the bytecode of the
+//! VM and `ir` to procedures of blocks: the closures handling exceptions, the steps
+//! following a path in a value, the captures of a closure, the values constraints
+//! expect, and the walk of the decision trees.
+//!
+//! A decision tree is walked the same way by both, each backend emitting the tests,
+//! jumps and switches of its own code through `Matcher`.
+
+use std::mem;
+
+use crate::{
+    dtree::DTree,
+    imper_ast::{Closure, ConstraintValue, Expr, Module, ValPath},
+    types::{Literal, Type},
+};
+
+/// closures handling exceptions, which raise them again when no arm matches
+pub fn handlers(module: &Module) -> Vec<u16> {
+    let mut handlers = Vec::new();
+    for closure in &module.closures {
+        closure.branches.iter().for_each(|e| find_handlers(e, &mut handlers));
+    }
+    module.globals.iter().for_each(|g| find_handlers(&g.0, &mut handlers));
+    handlers
+}
+
+fn find_handlers(e: &Expr, handlers: &mut Vec<u16>) {
+    match e {
+        Expr::Try(e, n) => {
+            handlers.push(*n);
+            find_handlers(e, handlers);
+        }
+        Expr::Tuple(v) => v.iter().for_each(|e| find_handlers(e, handlers)),
+        Expr::BinOp(e1, _, e2, _) | Expr::Application(e1, e2, _) => {
+            find_handlers(e1, handlers);
+            find_handlers(e2, handlers);
+        }
+        Expr::Call(f, args, _) => {
+            find_handlers(f, handlers);
+            args.iter().for_each(|e| find_handlers(e, handlers));
+        }
+        Expr::Conditional(e1, e2, e3) => {
+            find_handlers(e1, handlers);
+            find_handlers(e2, handlers);
+            find_handlers(e3, handlers);
+        }
+        Expr::UnOp(_, e) | Expr::SumVal { value: e, .. } | Expr::Raise(e, _) | Expr::Perform(_, e) => {
+            find_handlers(e, handlers)
+        }
+        Expr::Handle { body, .. } => find_handlers(body, handlers),
+        Expr::Literal(_) | Expr::Bound(_) | Expr::Closure(_) | Expr::Error => (),
+    }
+}
+
+/// a step along a path in a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// nth field of a tuple
+    Field(u16),
+    /// the tag of a sum value, an int
+    Tag,
+    /// the value of the variant at a position of a sum value
+    Payload(u16),
+}
+
+/// The steps following a path in a value of type t, each with the type of the value
+/// it's taken from, and the type of the value at the end of the path.
+pub fn follow(module: &Module, t: &Type, path: &[u16]) -> (Vec<(Step, Type)>, Type) {
+    let mut steps = Vec::with_capacity(path.len());
+    let mut t = t.clone();
+    for (i, &n) in path.iter().enumerate() {
+        let (step, next) = match t {
+            Type::Tuple(ref types) => (Step::Field(n), types[n as usize].clone()),
+            Type::Sum(_, _) if n == 0 && i == path.len() - 1 => (Step::Tag, Type::Int),
+            Type::Sum(target, ref args) => {
+                let variant = &module.type_decls[target as usize].variants[n as usize - 1];
+                (Step::Payload(n), variant.1.substitute_generics(args))
+            }
+            _ => panic!("path {:?} in a value of type {:?}", path, t),
+        };
+        steps.push((step, mem::replace(&mut t, next)));
+    }
+    (steps, t)
+}
+
+/// where a captured value is taken from when its closure is made
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Capture {
+    /// a value of the frame making the closure, at a `ValPath::Local`
+    Local(ValPath),
+    /// nth value captured by the closure making it
+    Captured(u16),
+}
+
+/// the values a closure captures in the order of their indices, with their types
+pub fn captures<'m>(closure: &'m Closure) -> Vec<(Capture, &'m Type)> {
+    let mut captures: Vec<_> = closure.captures.iter().collect();
+    captures.sort_by_key(|c| c.0.capture_index());
+    captures
+        .into_iter()
+        .map(|(path, t)| match *path {
+            ValPath::CaptureLocal(_, ref v) => (Capture::Local(ValPath::Local(v.clone())), t),
+            ValPath::CaptureCaptured(_, j) => (Capture::Captured(j), t),
+            _ => panic!("captured value with path {:?}", path),
+        })
+        .collect()
+}
+
+/// the literal a value of type t satisfying a constraint is equal to
+pub fn expected<'input>(constraint: &ConstraintValue<'input>, t: &Type) -> Literal<'input> {
+    // see Literal::get_constraint
+    match (constraint, t) {
+        (&ConstraintValue::Finite(n, _), Type::Bool) => Literal::Bool(n == 0),
+        (&ConstraintValue::Finite(n, _), _) => Literal::Int(n as isize),
+        (&ConstraintValue::Int(n), _) => Literal::Int(n),
+        (&ConstraintValue::Str(s), _) => Literal::String(s),
+    }
+}
+
+/// The code of a backend matching values with a decision tree. The tests of a node
+/// apply to the value loaded last, and go to targets, labels or blocks, whose code
+/// follows once they're placed.
+pub trait Matcher<'input> {
+    type Target: Copy;
+
+    /// load the value a node tests, its type
+    fn load(&mut self, value: &ValPath) -> Type;
+
+    /// the target failing to match
+    fn fail(&mut self) -> Self::Target;
+
+    /// a new target for the code of a subtree
+    fn target(&mut self) -> Self::Target;
+
+    /// the code that follows goes at a target
+    fn place(&mut self, target: Self::Target);
+
+    fn jump(&mut self, target: Self::Target);
+
+    /// go to one target or the other depending on a boolean
+    fn branch(&mut self, on_true: Self::Target, on_false: Self::Target);
+
+    /// go to the target at the position of the tag of a sum value, to the default
+    /// past the targets
+    fn switch_tag(&mut self, targets: &[Self::Target], default: Self::Target);
+
+    /// go to the target of the case equal to an int or a string value, else to the
+    /// default. The cases are sorted and all ints or all strings.
+    fn switch(&mut self, cases: &[(&ConstraintValue<'input>, Self::Target)], default: Self::Target);
+}
+
+/// match the arguments of a closure, going to the arm of the exit reached
+pub fn match_tree<'input, M: Matcher<'input>>(m: &mut M, tree: &DTree<'input>, arms: &[M::Target]) {
+    match tree {
+        DTree::Empty => {
+            let fail = m.fail();
+            m.jump(fail);
+        }
+        &DTree::Exit(n) => m.jump(arms[n as usize]),
+        DTree::Finite { value, branches } => {
+            let t = m.load(value);
+            let targets: Vec<_> = branches.iter().map(|b| target(m, b, arms)).collect();
+            // see Literal::get_constraint
+            if t == Type::Bool {
+                m.branch(targets[0], targets[1]);
+            } else {
+                // exceptions declared after the tree was built have no branch
+                let fail = m.fail();
+                m.switch_tag(&targets, fail);
+            }
+            match_branches(m, branches.iter().zip(targets), arms);
+        }
+        DTree::Infinite { value, branches, default } => {
+            m.load(value);
+            let mut cases: Vec<_> = branches.iter().collect();
+            cases.sort_by(|(c1, _), (c2, _)| match (c1, c2) {
+                (ConstraintValue::Int(i), ConstraintValue::Int(j)) => i.cmp(j),
+                (ConstraintValue::Str(s1), ConstraintValue::Str(s2)) => s1.cmp(s2),
+                _ => panic!("int and string cases in the same node"),
+            });
+            let targets: Vec<_> = cases.iter().map(|(_, b)| target(m, b, arms)).collect();
+            let default_target = target(m, default, arms);
+            let switch: Vec<_> = cases.iter().map(|(c, _)| *c).zip(targets.iter().copied()).collect();
+            m.switch(&switch, default_target);
+            let subtrees = cases.iter().map(|(_, b)| *b).chain(Some(&**default));
+            match_branches(m, subtrees.zip(targets.into_iter().chain(Some(default_target))), arms);
+        }
+    }
+}
+
+/// the target to go to for a branch of a node: the arm of an exit, the failure, or a
+/// new target for the code of the subtree
+fn target<'input, M: Matcher<'input>>(m: &mut M, tree: &DTree, arms: &[M::Target]) -> M::Target {
+    match *tree {
+        DTree::Exit(n) => arms[n as usize],
+        DTree::Empty => m.fail(),
+        _ => m.target(),
+    }
+}
+
+/// the code of the subtrees of a node that aren't exits or failures
+fn match_branches<'t, 'input: 't, M, I>(m: &mut M, branches: I, arms: &[M::Target])
+where
+    M: Matcher<'input>,
+    I: Iterator<Item = (&'t DTree<'input>, M::Target)>,
+{
+    for (tree, target) in branches {
+        if let DTree::Finite { .. } | DTree::Infinite { .. } = tree {
+            m.place(target);
+            match_tree(m, tree, arms);
+        }
+    }
+}
@@ -0,0 +1,959 @@
+//! Optimizations of a type-checked module, before it's interpreted or compiled:
+//! - operators on literals are evaluated, wrapping around on overflow as at runtime,
+//!   unless they fail on a division by zero, which is left to happen at runtime,
+//! - conditionals on literals are replaced by the branch taken,
+//! - a constructor applied to a value is a `SumVal`,
+//! - a small closure applied to all its arguments is inlined when the arm taken is
+//!   known, and the arguments can be evaluated there instead, e.g. `{ x => x * x } 3`
+//!   is `3 * 3`. Closures reaching themselves aren't,
+//! - the decision tree of a closure applied at its only use is pruned where the
+//!   arguments are known there, and the constraints of a global are removed where
+//!   its value is known to meet them,
+//! - globals that aren't referenced and whose evaluation has no effect are dropped,
+//!   with the closures only they reach. The remaining ones are renumbered,
+//! - a known closure applied to all its arguments is called with them at once, see
+//!   `Expr::Call`.
+//!
+//! A known value is a literal, or a tuple or `SumVal` at the path followed into it.
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::parse::parse,
+        crate::type_check::ast2imper_ast,
+        crate::types::Type,
+    };
+
+    fn module(src: &str) -> Module<'_> {
+        let print = Type::Function(Box::new(Type::String), Box::new(Type::Unit));
+        ast2imper_ast(parse(src).unwrap(), &[("print", print)]).unwrap()
+    }
+
+    /// optimized without inlining, which would leave little to check
+    fn optimized(src: &str) -> Module<'_> {
+        let mut module = module(src);
+        fold_module(&mut module);
+        prune_trees(&mut module);
+        resolve_constraints(&mut module);
+        drop_dead(&mut module);
+        module
+    }
+
+    fn global<'m>(module: &'m Module, name: &str) -> &'m Expr<'m> {
+        match module.globals_names[name] {
+            ValPath::StaticVal(ref v) if v.len() == 1 => &module.globals[v[0] as usize].0,
+            ref path => panic!("{} at {:?}", name, path),
+        }
+    }
+
+    #[test]
+    fn folding() {
+        // the globals folded aren't referenced, which drops them
+        let mut module = module(
+            "let x = 1 + 2 * 2 - 1 + -3
+            let s = if x < 2 then \"small\" else \"big\" end
+            let p = not (s = \"big\") and true
+            let d = 1 / 0
+            let o = some (x % 0)
+            let _ = print s",
+        );
+        fold_module(&mut module);
+        let literal = |name| match *global(&module, name) {
+            Expr::Literal(ref l) => l.clone(),
+            ref e => panic!("{} = {:?}", name, e),
+        };
+        assert_eq!(literal("x"), Literal::Int(1));
+        assert_eq!(literal("s"), Literal::String("small"));
+        assert_eq!(literal("p"), Literal::Bool(true));
+        // failing at runtime
+        assert!(matches!(global(&module, "d"), Expr::BinOp(_, BinOpcode::Div, _, _)));
+        assert!(match global(&module, "o") {
+            Expr::SumVal { value, .. } => matches!(**value, Expr::BinOp(_, BinOpcode::Mod, _, _)),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn inlining() {
+        let mut module = module(
+            "let sq = { x => x * x }
+            let add = { (a, b) => a + b }
+            let twice = { f x => f (f x) }
+            rec fact = { 0 => 1, n => n * fact (n - 1) }
+            let a = sq 3
+            let b = add (a, 1)
+            let t = twice sq 2
+            let c = fact 3
+            let p = sq (fact 2)
+            let g = { x => { y => x + y } 1 }
+            let o = { (some y) => y, (none ()) => 0 } (some 4)",
+        );
+        fold_module(&mut module);
+        inline(&mut module);
+        fold_module(&mut module);
+        let literal = |name| match *global(&module, name) {
+            Expr::Literal(Literal::Int(n)) => n,
+            ref e => panic!("{} = {:?}", name, e),
+        };
+        assert_eq!(literal("a"), 9);
+        assert_eq!(literal("b"), 10);
+        // the calls twice makes are inlined in the next round
+        assert_eq!(literal("t"), 16);
+        assert_eq!(literal("o"), 4);
+        // recursive, and an argument that may not terminate
+        assert!(matches!(global(&module, "c"), Expr::Application(..)));
+        assert!(matches!(global(&module, "p"), Expr::Application(..)));
+        // the captured x is the argument of g
+        let g = match *global(&module, "g") {
+            Expr::Closure(n) => &module.closures[n as usize],
+            ref e => panic!("g = {:?}", e),
+        };
+        assert!(match g.branches[..] {
+            [Expr::BinOp(ref x, BinOpcode::Add, ref y, _)] => matches!(
+                (&**x, &**y),
+                (Expr::Bound(ValPath::Local(v)), Expr::Literal(Literal::Int(1))) if v[..] == [0]
+            ),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn calls() {
+        let mut module = module(
+            "rec sum = { 0 acc => acc, n acc => sum (n - 1) (acc + n) }
+            let (mul, _) = ({ a b => a * b }, 1)
+            let k = { a b => { c => a + b + c } }
+            let neg = { x => -x }
+            let s = sum 10 0
+            let m = mul 3 4
+            let p = k 1
+            let l = k 1 2 3
+            let n = neg 1",
+        );
+        uncurry(&mut module);
+        let call = |e: &Expr| match *e {
+            Expr::Call(ref f, ref args, _) => match **f {
+                Expr::Bound(ValPath::StaticVal(ref v)) => (v.clone(), args.len()),
+                ref f => panic!("calling {:?}", f),
+            },
+            ref e => panic!("{:?} isn't a call", e),
+        };
+        assert_eq!(call(global(&module, "s")), (vec![0], 2));
+        // in the body of sum too
+        let sum = match *global(&module, "sum") {
+            Expr::Closure(n) => &module.closures[n as usize],
+            ref e => panic!("sum = {:?}", e),
+        };
+        assert_eq!(call(&sum.branches[1]), (vec![0], 2));
+        // through the path of a tuple
+        assert_eq!(call(global(&module, "m")), (vec![1, 0], 2));
+        // partially applied, or returning a closure applied to the rest
+        assert!(matches!(global(&module, "p"), Expr::Application(..)));
+        assert!(match *global(&module, "l") {
+            Expr::Application(ref f, _, _) => call(f) == (vec![2], 2),
+            _ => false,
+        });
+        // taking a single argument
+        assert!(matches!(global(&module, "n"), Expr::Application(..)));
+    }
+
+    #[test]
+    fn trees() {
+        let module = optimized(
+            "let n = { (some _) => 1, (none ()) => 0 } (some 4)
+            let r = { (some n) => n, (none ()) => 0 } (some 4)
+            let b = { true => 1, false => 0 } false
+            let m = { (0, \"a\") => 1, (n, _) => n } (0, \"b\")
+            let u = { (0, s) => 1, (n, _) => n } (0, print \"b\")
+            let ((some v), 1) = ((some 4), 1)
+            let ((some _), 2) = ((some 4), 2)
+            let (true, w) = (1 < 1, 2)
+            let _ = print \"\"",
+        );
+        let tree = |name| match *global(&module, name) {
+            Expr::Application(ref f, _, _) => match **f {
+                Expr::Closure(c) => module.closures[c as usize].dtree.clone(),
+                ref e => panic!("{} = {:?}", name, e),
+            },
+            ref e => panic!("{} = {:?}", name, e),
+        };
+        assert!(matches!(tree("n"), DTree::Exit(0)));
+        // the tag is tested before n is read
+        assert!(matches!(tree("r"), DTree::Finite { .. }));
+        assert!(matches!(tree("b"), DTree::Exit(1)));
+        assert!(matches!(tree("m"), DTree::Exit(1)));
+        // the first component is known, the second isn't
+        assert!(matches!(tree("u"), DTree::Exit(0)));
+        // the patterns of constraints always matched are dropped, but the tag of some v
+        // is still checked before v is read, and (true, w) never matches
+        assert_eq!(module.globals.len(), 8);
+        assert!(match module.globals[5].1.iter().collect::<Vec<_>>()[..] {
+            [(ValPath::StaticVal(path), ConstraintValue::Finite(1, 2))] => path[1..] == [0, 0],
+            _ => false,
+        });
+        assert_eq!(module.globals[6].1.len(), 1);
+    }
+
+    #[test]
+    fn dead() {
+        let module = optimized(
+            "let k = 3
+            let unused = { x => x + k }
+            let sq = { x => x * x }
+            let used = { x => if x = 0 then 1 else sq x end }
+            let _ = print (if used 0 = 1 then \"one\" else \"other\" end)
+            let fails = 1 / 0",
+        );
+        assert_eq!(module.globals.len(), 4);
+        assert_eq!(module.closures.len(), 2);
+        assert!(!module.globals_names.contains_key("unused"));
+        assert!(!module.globals_names.contains_key("k"));
+        assert_eq!(module.globals_names["sq"], ValPath::StaticVal(vec![0]));
+        assert_eq!(module.globals_names["fails"], ValPath::StaticVal(vec![3]));
+        assert!(matches!(module.globals[1].0, Expr::Closure(1)));
+    }
+}
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+};
+
+use crate::{
+    dtree::DTree,
+    imper_ast::{ConstraintValue, Expr, Module, ValPath},
+    types::{BinOpcode, Literal, Type, UnOpcode},
+};
+
+/// optimize a type-checked module in place
+pub fn optimize(module: &mut Module) {
+    fold_module(module);
+    inline(module);
+    fold_module(module);
+    prune_trees(module);
+    resolve_constraints(module);
+    drop_dead(module);
+    uncurry(module);
+}
+
+/// Fold the globals in order, then the closures. A reference to a literal in the
+/// value of a global is replaced by the literal.
+fn fold_module(module: &mut Module) {
+    let mut consts = HashMap::new();
+    for (i, g) in module.globals.iter_mut().enumerate() {
+        fold(&mut g.0, &consts);
+        literals(&g.0, &mut vec![i as u16], &mut consts);
+    }
+    for closure in &mut module.closures {
+        for e in &mut closure.branches {
+            fold(e, &consts);
+        }
+    }
+}
+
+/// add the literals in a value by their paths from the path of the value
+fn literals<'input>(e: &Expr<'input>, path: &mut Vec<u16>, consts: &mut HashMap<Vec<u16>, Literal<'input>>) {
+    match *e {
+        Expr::Literal(ref l) => {
+            consts.insert(path.clone(), l.clone());
+        }
+        Expr::Tuple(ref v) => {
+            for (i, e) in v.iter().enumerate() {
+                path.push(i as u16);
+                literals(e, path, consts);
+                path.pop();
+            }
+        }
+        Expr::SumVal { position, ref value, .. } => {
+            path.push(position);
+            literals(value, path, consts);
+            path.pop();
+        }
+        _ => (),
+    }
+}
+
+/// the expressions of the arms of all closures and of the globals
+fn exprs<'m, 'input>(module: &'m Module<'input>) -> impl Iterator<Item = &'m Expr<'input>> {
+    let arms = module.closures.iter().flat_map(|c| &c.branches);
+    arms.chain(module.globals.iter().map(|g| &g.0))
+}
+
+fn exprs_mut<'m, 'input>(module: &'m mut Module<'input>) -> impl Iterator<Item = &'m mut Expr<'input>> {
+    let arms = module.closures.iter_mut().flat_map(|c| &mut c.branches);
+    arms.chain(module.globals.iter_mut().map(|g| &mut g.0))
+}
+
+/// the expressions directly in an expression
+fn children<'e, 'input>(e: &'e Expr<'input>) -> Vec<&'e Expr<'input>> {
+    match e {
+        Expr::Tuple(v) => v.iter().collect(),
+        Expr::BinOp(e1, _, e2, _) | Expr::Application(e1, e2, _) => vec![e1, e2],
+        Expr::Call(f, args, _) => Some(&**f).into_iter().chain(args).collect(),
+        Expr::Conditional(e1, e2, e3) => vec![e1, e2, e3],
+        Expr::UnOp(_, e)
+        | Expr::SumVal { value: e, .. }
+        | Expr::Raise(e, _)
+        | Expr::Try(e, _)
+        | Expr::Perform(_, e)
+        | Expr::Handle { body: e, .. } => vec![e],
+        Expr::Literal(_) | Expr::Bound(_) | Expr::Closure(_) | Expr::Error => Vec::new(),
+    }
+}
+
+fn children_mut<'e, 'input>(e: &'e mut Expr<'input>) -> Vec<&'e mut Expr<'input>> {
+    match e {
+        Expr::Tuple(v) => v.iter_mut().collect(),
+        Expr::BinOp(e1, _, e2, _) | Expr::Application(e1, e2, _) => vec![e1, e2],
+        Expr::Call(f, args, _) => Some(&mut **f).into_iter().chain(args).collect(),
+        Expr::Conditional(e1, e2, e3) => vec![e1, e2, e3],
+        Expr::UnOp(_, e)
+        | Expr::SumVal { value: e, .. }
+        | Expr::Raise(e, _)
+        | Expr::Try(e, _)
+        | Expr::Perform(_, e)
+        | Expr::Handle { body: e, .. } => vec![e],
+        Expr::Literal(_) | Expr::Bound(_) | Expr::Closure(_) | Expr::Error => Vec::new(),
+    }
+}
+
+/// call f on an expression and the expressions in it, outer ones first
+fn visit<'e, 'input>(e: &'e Expr<'input>, f: &mut dyn FnMut(&'e Expr<'input>)) {
+    f(e);
+    for e in children(e) {
+        visit(e, f);
+    }
+}
+
+fn visit_mut(e: &mut Expr, f: &mut dyn FnMut(&mut Expr)) {
+    f(e);
+    for e in children_mut(e) {
+        visit_mut(e, f);
+    }
+}
+
+/// the closures an expression makes, the handlers of try and handle included
+fn closures(e: &Expr) -> Vec<u16> {
+    match *e {
+        Expr::Closure(n) | Expr::Try(_, n) => vec![n],
+        Expr::Handle { ret, ref ops, .. } => ret.into_iter().chain(ops.iter().map(|op| op.1)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// evaluate the operators and conditionals on literals, inner ones first
+fn fold<'input>(e: &mut Expr<'input>, consts: &HashMap<Vec<u16>, Literal<'input>>) {
+    for e in children_mut(e) {
+        fold(e, consts);
+    }
+    let folded = match e {
+        Expr::Bound(ValPath::StaticVal(v)) => consts.get(v).cloned().map(Expr::Literal),
+        Expr::BinOp(e1, op, e2, _) => match (&**e1, &**e2) {
+            (Expr::Literal(l1), Expr::Literal(l2)) => binop(*op, l1, l2).map(Expr::Literal),
+            _ => None,
+        },
+        Expr::UnOp(op, e) => match **e {
+            Expr::Literal(ref l) => unop(*op, l).map(Expr::Literal),
+            _ => None,
+        },
+        Expr::Conditional(cond, e1, e2) => match **cond {
+            Expr::Literal(Literal::Bool(true)) => Some(mem::replace(&mut **e1, Expr::Error)),
+            Expr::Literal(Literal::Bool(false)) => Some(mem::replace(&mut **e2, Expr::Error)),
+            _ => None,
+        },
+        Expr::Application(f, value, _) => match **f {
+            Expr::Bound(ValPath::Constructor(target, position)) => {
+                Some(Expr::SumVal { target, position, value: Box::new(mem::replace(&mut **value, Expr::Error)) })
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(folded) = folded {
+        *e = folded;
+    }
+}
+
+/// the value of an operator on literals, none if it fails
+fn binop<'input>(op: BinOpcode, l1: &Literal<'input>, l2: &Literal<'input>) -> Option<Literal<'input>> {
+    use self::BinOpcode::*;
+    match (l1, l2) {
+        (&Literal::Int(n), &Literal::Int(m)) => match op {
+            Add => Some(Literal::Int(n.wrapping_add(m))),
+            Sub => Some(Literal::Int(n.wrapping_sub(m))),
+            Mul => Some(Literal::Int(n.wrapping_mul(m))),
+            Div | Mod if m == 0 => None,
+            Div => Some(Literal::Int(n.wrapping_div(m))),
+            Mod => Some(Literal::Int(n.wrapping_rem(m))),
+            Greater => Some(Literal::Bool(n > m)),
+            Less => Some(Literal::Bool(n < m)),
+            GreaterEq => Some(Literal::Bool(n >= m)),
+            LessEq => Some(Literal::Bool(n <= m)),
+            Equal => Some(Literal::Bool(n == m)),
+            NotEq => Some(Literal::Bool(n != m)),
+            _ => None,
+        },
+        (&Literal::Bool(p), &Literal::Bool(q)) => match op {
+            And => Some(Literal::Bool(p && q)),
+            Or => Some(Literal::Bool(p || q)),
+            Equal => Some(Literal::Bool(p == q)),
+            NotEq => Some(Literal::Bool(p != q)),
+            _ => None,
+        },
+        (Literal::Unit, Literal::Unit) => match op {
+            Equal => Some(Literal::Bool(true)),
+            NotEq => Some(Literal::Bool(false)),
+            _ => None,
+        },
+        // concatenations make strings that aren't in the source
+        (Literal::String(s1), Literal::String(s2)) => match op {
+            Equal => Some(Literal::Bool(s1 == s2)),
+            NotEq => Some(Literal::Bool(s1 != s2)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn unop<'input>(op: UnOpcode, l: &Literal<'input>) -> Option<Literal<'input>> {
+    match (op, l) {
+        (UnOpcode::Minus, &Literal::Int(n)) => Some(Literal::Int(n.wrapping_neg())),
+        (UnOpcode::Not, &Literal::Bool(p)) => Some(Literal::Bool(!p)),
+        _ => None,
+    }
+}
+
+/// a value known at a path
+enum Known<'e, 'input> {
+    Expr(&'e Expr<'input>),
+    /// the tag of a sum value, the position of its variant from 0
+    Tag(u16),
+}
+
+/// the value at a path into an expression, if it's known
+fn follow<'e, 'input>(mut e: &'e Expr<'input>, path: &[u16]) -> Option<Known<'e, 'input>> {
+    for (i, &n) in path.iter().enumerate() {
+        e = match *e {
+            Expr::Tuple(ref v) => &v[n as usize],
+            Expr::SumVal { position, .. } if n == 0 && i == path.len() - 1 => return Some(Known::Tag(position - 1)),
+            Expr::SumVal { position, ref value, .. } if n == position => value,
+            _ => return None,
+        };
+    }
+    Some(Known::Expr(e))
+}
+
+/// the branch of a finite node or the case of an infinite node taken by a known value
+fn known_branch<'input>(known: Option<Known<'_, 'input>>) -> (Option<usize>, Option<ConstraintValue<'input>>) {
+    match known {
+        // see Literal::get_constraint
+        Some(Known::Tag(m)) => (Some(m as usize), None),
+        Some(Known::Expr(&Expr::Literal(Literal::Bool(p)))) => (Some(if p { 0 } else { 1 }), None),
+        Some(Known::Expr(&Expr::Literal(Literal::Int(n)))) => (None, Some(ConstraintValue::Int(n))),
+        Some(Known::Expr(&Expr::Literal(Literal::String(s)))) => (None, Some(ConstraintValue::Str(s))),
+        _ => (None, None),
+    }
+}
+
+/// the sum whose tag is at a path has a variant value at the other path, which can
+/// only be read after the tag (see ValPath)
+fn under_variant(tag: &[u16], path: &[u16]) -> bool {
+    let sum = &tag[..tag.len() - 1];
+    path.len() > sum.len() && path.starts_with(sum) && path[sum.len()] != 0
+}
+
+/// the paths of the values tested by the nodes of a tree
+fn tested(tree: &DTree, paths: &mut Vec<Vec<u16>>) {
+    match tree {
+        DTree::Finite { value: ValPath::Local(v), branches } => {
+            paths.push(v.clone());
+            branches.iter().for_each(|b| tested(b, paths));
+        }
+        DTree::Infinite { value: ValPath::Local(v), branches, default } => {
+            paths.push(v.clone());
+            branches.values().chain(Some(&**default)).for_each(|b| tested(b, paths));
+        }
+        _ => (),
+    }
+}
+
+/// A decision tree with the nodes testing known arguments replaced by the branch
+/// taken. A tag is still tested if the arms, whose reads are given, or the nodes
+/// left read the value of a variant of its sum.
+fn prune<'input>(tree: &DTree<'input>, args: &[&Expr<'input>], reads: &[Vec<u16>]) -> DTree<'input> {
+    let known = |v: &[u16]| follow(args[v[0] as usize], &v[1..]);
+    match tree {
+        DTree::Finite { value: ValPath::Local(v), branches } => {
+            let known = known(v);
+            let is_tag = matches!(known, Some(Known::Tag(_)));
+            if let Some(m) = known_branch(known).0 {
+                // exceptions declared after the tree was built have no branch
+                let taken = prune(branches.get(m).unwrap_or(&DTree::Empty), args, reads);
+                let mut paths = reads.to_vec();
+                tested(&taken, &mut paths);
+                if !is_tag || !paths.iter().any(|path| under_variant(v, path)) {
+                    return taken;
+                }
+            }
+            DTree::Finite {
+                value: ValPath::Local(v.clone()),
+                branches: branches.iter().map(|b| prune(b, args, reads)).collect(),
+            }
+        }
+        DTree::Infinite { value: ValPath::Local(v), branches, default } => match known_branch(known(v)).1 {
+            Some(c) => prune(branches.get(&c).unwrap_or(default), args, reads),
+            None => DTree::Infinite {
+                value: ValPath::Local(v.clone()),
+                branches: branches.iter().map(|(c, b)| (c.clone(), prune(b, args, reads))).collect(),
+                default: Box::new(prune(default, args, reads)),
+            },
+        },
+        _ => tree.clone(),
+    }
+}
+
+/// prune the trees of the closures applied to all their arguments where they're made,
+/// if that's their only use
+fn prune_trees(module: &mut Module) {
+    let mut uses = vec![0; module.closures.len()];
+    for e in exprs(module) {
+        visit(e, &mut |e| closures(e).into_iter().for_each(|n| uses[n as usize] += 1));
+    }
+    let mut pruned = Vec::new();
+    for e in exprs(module) {
+        visit(e, &mut |e| {
+            let mut args = Vec::new();
+            let mut f = e;
+            while let Expr::Application(g, arg, _) = f {
+                args.push(&**arg);
+                f = &**g;
+            }
+            args.reverse();
+            if let Expr::Closure(n) = *f {
+                let closure = &module.closures[n as usize];
+                if uses[n as usize] == 1 && args.len() == closure.args.len() {
+                    let mut reads = Vec::new();
+                    for e in &closure.branches {
+                        visit(e, &mut |e| {
+                            if let Expr::Bound(ValPath::Local(v)) = e {
+                                reads.push(v.clone());
+                            }
+                            for c in closures(e) {
+                                for capture in &module.closures[c as usize].captures {
+                                    if let ValPath::CaptureLocal(_, ref v) = capture.0 {
+                                        reads.push(v.clone());
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    pruned.push((n, prune(&closure.dtree, &args, &reads)));
+                }
+            }
+        });
+    }
+    for (n, tree) in pruned {
+        module.closures[n as usize].dtree = tree;
+    }
+}
+
+/// the most expressions in the arm of a closure inlined at a call
+const INLINE_SIZE: usize = 24;
+/// inlining makes calls of the closures passed as arguments, which are inlined in
+/// the next round
+const INLINE_ROUNDS: usize = 3;
+
+/// Replace the calls of small closures applied to all their arguments by the arm
+/// taken, with the arguments and the captures substituted. The closure is either
+/// made at the call or the value of a global that doesn't reach itself.
+fn inline(module: &mut Module) {
+    for _ in 0..INLINE_ROUNDS {
+        let recursive: Vec<bool> = (0..module.globals.len())
+            .map(|g| reached(module, vec![&module.globals[g].0]).0[g])
+            .collect();
+        let mut inlined = false;
+        for c in 0..module.closures.len() {
+            for b in 0..module.closures[c].branches.len() {
+                let mut e = mem::replace(&mut module.closures[c].branches[b], Expr::Error);
+                inlined |= inline_calls(&mut e, module, &module.closures[c].captures, &recursive);
+                module.closures[c].branches[b] = e;
+            }
+        }
+        for g in 0..module.globals.len() {
+            let mut e = mem::replace(&mut module.globals[g].0, Expr::Error);
+            inlined |= inline_calls(&mut e, module, &[], &recursive);
+            module.globals[g].0 = e;
+        }
+        if !inlined {
+            break;
+        }
+    }
+}
+
+/// inline the calls in an expression made where the values are captured as given,
+/// inner ones first
+fn inline_calls<'input>(
+    e: &mut Expr<'input>,
+    module: &Module<'input>,
+    captures: &[(ValPath, Type)],
+    recursive: &[bool],
+) -> bool {
+    let mut inlined = false;
+    for e in children_mut(e) {
+        inlined |= inline_calls(e, module, captures, recursive);
+    }
+    match inlined_call(e, module, captures, recursive) {
+        Some(body) => {
+            *e = body;
+            true
+        }
+        None => inlined,
+    }
+}
+
+/// the arm of the closure called by an expression with its arguments and captures
+/// substituted, if it can be inlined
+fn inlined_call<'input>(
+    e: &Expr<'input>,
+    module: &Module<'input>,
+    captures: &[(ValPath, Type)],
+    recursive: &[bool],
+) -> Option<Expr<'input>> {
+    let mut args = Vec::new();
+    let mut f = e;
+    while let Expr::Application(g, arg, span) = f {
+        args.push((&**arg, *span));
+        f = &**g;
+    }
+    args.reverse();
+    let n = match *f {
+        Expr::Closure(n) => n,
+        Expr::Bound(ValPath::StaticVal(ref v)) if v.len() == 1 && !recursive[v[0] as usize] => {
+            match module.globals[v[0] as usize].0 {
+                Expr::Closure(n) => n,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let closure = &module.closures[n as usize];
+    if args.len() < closure.args.len() {
+        return None;
+    }
+    let (args, rest) = args.split_at(closure.args.len());
+    let args: Vec<&Expr> = args.iter().map(|arg| arg.0).collect();
+    if !args.iter().all(|arg| is_movable(arg)) {
+        return None;
+    }
+    // the arguments replace the reads of the arm, the tags tested before them aren't read
+    let arm = match prune(&closure.dtree, &args, &[]) {
+        DTree::Exit(k) => &closure.branches[k as usize],
+        _ => return None,
+    };
+    let (mut size, mut makes_closures) = (0, false);
+    let mut reads = vec![0; args.len()];
+    let mut projected = true;
+    let mut duplicated = vec![false; args.len()];
+    visit(arm, &mut |e| {
+        size += 1;
+        makes_closures |= !closures(e).is_empty();
+        if let Expr::Bound(ValPath::Local(v)) = e {
+            reads[v[0] as usize] += 1;
+            match project(args[v[0] as usize], &v[1..]) {
+                Some(Expr::Literal(_)) | Some(Expr::Bound(_)) => (),
+                Some(_) => duplicated[v[0] as usize] = true,
+                None => projected = false,
+            }
+        }
+    });
+    // an argument other than a literal or a name is only evaluated once
+    let evaluated_once = reads.iter().zip(&duplicated).all(|(&reads, &dup)| reads <= 1 || !dup);
+    if size > INLINE_SIZE || makes_closures || !projected || !evaluated_once {
+        return None;
+    }
+
+    let captured = |i: u16| {
+        let (path, _) = closure.captures.iter().find(|c| c.0.capture_index() == i)?;
+        match *path {
+            ValPath::CaptureLocal(_, ref v) => Some(ValPath::Local(v.clone())),
+            ValPath::CaptureCaptured(_, j) => captures.iter().find(|c| c.0.capture_index() == j).map(|c| c.0.clone()),
+            _ => None,
+        }
+    };
+    let mut body = arm.clone();
+    let mut substituted = true;
+    substitute(&mut body, &mut |path| match *path {
+        ValPath::Local(ref v) => project(args[v[0] as usize], &v[1..]),
+        ValPath::CaptureLocal(i, _) | ValPath::CaptureCaptured(i, _) => {
+            let path = captured(i);
+            substituted &= path.is_some();
+            path.map(Expr::Bound)
+        }
+        _ => None,
+    });
+    if !substituted {
+        return None;
+    }
+    fold(&mut body, &HashMap::new());
+    Some(rest.iter().fold(body, |f, &(arg, span)| Expr::Application(Box::new(f), Box::new(arg.clone()), span)))
+}
+
+/// replace the names in an expression for which f gives an expression
+fn substitute<'input>(e: &mut Expr<'input>, f: &mut dyn FnMut(&ValPath) -> Option<Expr<'input>>) {
+    if let Expr::Bound(ref path) = *e {
+        if let Some(replaced) = f(path) {
+            *e = replaced;
+        }
+        return;
+    }
+    for e in children_mut(e) {
+        substitute(e, f);
+    }
+}
+
+/// an expression for the value at a path into an expression, if there is one
+fn project<'input>(e: &Expr<'input>, path: &[u16]) -> Option<Expr<'input>> {
+    let (&n, rest) = match path.split_first() {
+        Some(first) => first,
+        None => return Some(e.clone()),
+    };
+    match *e {
+        Expr::Tuple(ref v) => project(&v[n as usize], rest),
+        Expr::SumVal { position, ref value, .. } if n == position => project(value, rest),
+        Expr::Bound(ValPath::Local(ref v)) => Some(Expr::Bound(ValPath::Local([&v[..], path].concat()))),
+        Expr::Bound(ValPath::StaticVal(ref v)) => Some(Expr::Bound(ValPath::StaticVal([&v[..], path].concat()))),
+        _ => None,
+    }
+}
+
+/// evaluating the expression later gives the same value
+fn is_movable(e: &Expr) -> bool {
+    let mut reads_ref = false;
+    visit(e, &mut |e| reads_ref |= matches!(e, Expr::UnOp(UnOpcode::Deref, _)));
+    is_pure(e) && !reads_ref
+}
+
+/// Rewrite the applications of a known closure to as many arguments as it takes
+/// into a single call, if it takes more than one. A known closure is made where
+/// it's applied or is in the value of a global.
+fn uncurry(module: &mut Module) {
+    let arities: Vec<usize> = module.closures.iter().map(|c| c.args.len()).collect();
+    let mut known = HashMap::new();
+    for (i, g) in module.globals.iter().enumerate() {
+        closure_paths(&g.0, &mut vec![i as u16], &mut known);
+    }
+    for e in exprs_mut(module) {
+        uncurry_calls(e, &known, &arities);
+    }
+}
+
+/// add the closures in a value by their paths from the path of the value
+fn closure_paths(e: &Expr, path: &mut Vec<u16>, known: &mut HashMap<Vec<u16>, u16>) {
+    match *e {
+        Expr::Closure(n) => {
+            known.insert(path.clone(), n);
+        }
+        Expr::Tuple(ref v) => {
+            for (i, e) in v.iter().enumerate() {
+                path.push(i as u16);
+                closure_paths(e, path, known);
+                path.pop();
+            }
+        }
+        Expr::SumVal { position, ref value, .. } => {
+            path.push(position);
+            closure_paths(value, path, known);
+            path.pop();
+        }
+        _ => (),
+    }
+}
+
+/// rewrite the saturated applications in an expression, inner ones first so an
+/// application to more arguments applies the call
+fn uncurry_calls(e: &mut Expr, known: &HashMap<Vec<u16>, u16>, arities: &[usize]) {
+    for e in children_mut(e) {
+        uncurry_calls(e, known, arities);
+    }
+    let mut applied = 0;
+    let mut f = &*e;
+    while let Expr::Application(g, _, _) = f {
+        applied += 1;
+        f = &**g;
+    }
+    let closure = match *f {
+        Expr::Closure(n) => Some(n),
+        Expr::Bound(ValPath::StaticVal(ref v)) => known.get(v).copied(),
+        _ => None,
+    };
+    match closure {
+        Some(n) if arities[n as usize] > 1 && applied == arities[n as usize] => (),
+        _ => return,
+    }
+    let (mut f, mut args, mut site) = (mem::replace(e, Expr::Error), Vec::new(), None);
+    while let Expr::Application(g, arg, span) = f {
+        // the last argument makes the call
+        site.get_or_insert(span);
+        args.push(*arg);
+        f = *g;
+    }
+    args.reverse();
+    *e = Expr::Call(Box::new(f), args, site.unwrap());
+}
+
+/// remove the constraints a global is known to meet
+fn resolve_constraints(module: &mut Module) {
+    let bound: Vec<&Vec<u16>> = module
+        .globals_names
+        .values()
+        .filter_map(|path| match path {
+            ValPath::StaticVal(v) => Some(v),
+            _ => None,
+        })
+        .collect();
+    for (e, constraints, _, _) in &mut module.globals {
+        let mut met: Vec<Vec<u16>> = constraints
+            .iter()
+            .filter_map(|(path, constraint)| {
+                let path = match path {
+                    ValPath::StaticVal(v) => v,
+                    _ => return None,
+                };
+                let (branch, case) = known_branch(follow(e, &path[1..]));
+                let met = match *constraint {
+                    ConstraintValue::Finite(m, _) => branch == Some(m as usize),
+                    _ => case.as_ref() == Some(constraint),
+                };
+                if met { Some(path.clone()) } else { None }
+            })
+            .collect();
+        // The tag of a sum value is still read before the values of its variants are,
+        // as ValPath requires. A tag is only removed when no binding or remaining
+        // constraint is under a variant of its sum, deeper ones first.
+        met.sort_by_key(|path| std::cmp::Reverse(path.len()));
+        for path in met {
+            let guards = path.last() == Some(&0)
+                && (bound.iter().any(|q| under_variant(&path, q))
+                    || constraints.keys().any(|q| match q {
+                        ValPath::StaticVal(q) => under_variant(&path, q),
+                        _ => false,
+                    }));
+            if !guards {
+                constraints.remove(&ValPath::StaticVal(path));
+            }
+        }
+    }
+}
+
+/// evaluating the expression can't fail, loop or have an effect
+fn is_pure(e: &Expr) -> bool {
+    let pure_here = match *e {
+        Expr::BinOp(_, op, _, _) => !matches!(op, BinOpcode::Div | BinOpcode::Mod | BinOpcode::Assign),
+        Expr::Application(..)
+        | Expr::Call(..)
+        | Expr::Raise(..)
+        | Expr::Try(..)
+        | Expr::Perform(..)
+        | Expr::Handle { .. }
+        | Expr::Error => false,
+        _ => true,
+    };
+    pure_here && children(e).into_iter().all(is_pure)
+}
+
+/// the globals referenced from expressions and the closures they make, and from
+/// the values and arms of those in turn
+fn reached<'m, 'input>(module: &'m Module<'input>, mut work: Vec<&'m Expr<'input>>) -> (Vec<bool>, Vec<bool>) {
+    let mut live_globals = vec![false; module.globals.len()];
+    let mut live_closures = vec![false; module.closures.len()];
+    while let Some(e) = work.pop() {
+        let (mut globals, mut made) = (Vec::new(), Vec::new());
+        visit(e, &mut |e| {
+            if let Expr::Bound(ValPath::StaticVal(v)) = e {
+                globals.push(v[0]);
+            }
+            made.extend(closures(e));
+        });
+        for n in globals {
+            if !live_globals[n as usize] {
+                live_globals[n as usize] = true;
+                work.push(&module.globals[n as usize].0);
+            }
+        }
+        for n in made {
+            if !live_closures[n as usize] {
+                live_closures[n as usize] = true;
+                work.extend(&module.closures[n as usize].branches);
+            }
+        }
+    }
+    (live_globals, live_closures)
+}
+
+/// drop the globals only reached from the pure unconstrained globals, and the
+/// closures only they make
+fn drop_dead(module: &mut Module) {
+    let roots: Vec<bool> = module
+        .globals
+        .iter()
+        .map(|(e, constraints, _, _)| !constraints.is_empty() || !is_pure(e))
+        .collect();
+    let work = module.globals.iter().zip(&roots).filter(|&(_, &root)| root).map(|(g, _)| &g.0).collect();
+    let (mut live_globals, live_closures) = reached(module, work);
+    for (live, root) in live_globals.iter_mut().zip(roots) {
+        *live |= root;
+    }
+
+    let renumbering = |live: &[bool]| {
+        let mut next = 0;
+        live.iter()
+            .map(|&p| {
+                next += p as u16;
+                if p { Some(next - 1) } else { None }
+            })
+            .collect::<Vec<_>>()
+    };
+    let (globals, closures_map) = (renumbering(&live_globals), renumbering(&live_closures));
+    let global = |v: &mut Vec<u16>| v[0] = globals[v[0] as usize].expect("reference to a dropped global");
+    let closure = |n: &mut u16| *n = closures_map[*n as usize].expect("reference to a dropped closure");
+
+    let mut live = live_globals.iter();
+    module.globals.retain(|_| *live.next().unwrap());
+    let mut live = live_closures.iter();
+    module.closures.retain(|_| *live.next().unwrap());
+    module.globals_names.retain(|_, path| match path {
+        ValPath::StaticVal(v) => globals[v[0] as usize].is_some(),
+        _ => true,
+    });
+    for path in module.globals_names.values_mut() {
+        if let ValPath::StaticVal(v) = path {
+            global(v);
+        }
+    }
+    for g in &mut module.globals {
+        let constraints = mem::take(&mut g.1);
+        g.1 = constraints
+            .into_iter()
+            .map(|(mut path, c)| {
+                if let ValPath::StaticVal(ref mut v) = path {
+                    global(v);
+                }
+                (path, c)
+            })
+            .collect::<BTreeMap<_, _>>();
+    }
+    for e in exprs_mut(module) {
+        visit_mut(e, &mut |e| match e {
+            Expr::Bound(ValPath::StaticVal(v)) => global(v),
+            Expr::Closure(n) | Expr::Try(_, n) => closure(n),
+            Expr::Handle { ret, ops, .. } => {
+                if let Some(n) = ret {
+                    closure(n);
+                }
+                ops.iter_mut().for_each(|op| closure(&mut op.1));
+            }
+            _ => (),
+        });
+    }
+}
@@ -7,17 +7,29 @@ use crate::{
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
     #[test]
     fn test() {
         let s = "";
     }
+
+    #[test]
+    fn test_positions() {
+        let src = "let x = 1 /* a\nb */ let y = 2 // c\nlet z = 3";
+        let uncommented = uncomment(src);
+        assert_eq!(uncommented, "let x = 1 \n let y = 2 \nlet z = 3");
+        assert_eq!(line_col(&uncommented, uncommented.find('z').unwrap()), (3, 5));
+        assert_eq!(line_col(&uncommented, 0), (1, 1));
+    }
 }
 
 pub fn parse<'input>(input: &'input str) -> Result<Vec<Binding<'input>>, Vec<usize>> {
     let parser = ProgramParser::new();
     let mut errors = Vec::new();
     if let Ok(ast) = parser.parse(&mut errors, input) {
-        return Ok(ast)
+        // errors recovered from are in place of expressions in the ast
+        return if errors.is_empty() { Ok(ast) } else { Err(errors) }
     }
     let newstr = find_replace(input, r#""(\\.|[^"\\])*""#, "\"\"");
     let re = Regex::new(r"type |fn |rec fn").unwrap();
@@ -25,7 +37,9 @@ pub fn parse<'input>(input: &'input str) -> Result<Vec<Binding<'input>>, Vec<usi
     while index < newstr.len() {
         match parser.parse(&mut errors, &newstr[index..]) {
             Ok(_) => return Err(errors),
-            Err(ParseError::InvalidToken { location }) => {
+            // tokens like ":" only valid in some places are not caught by error recovery
+            Err(ParseError::InvalidToken { location })
+            | Err(ParseError::UnrecognizedToken { token: (location, _, _), .. }) => {
                 errors.push(index + location);
                 if let Some(m) = re.find_at(&newstr, location) {
                     index = index + m.start();
@@ -33,14 +47,31 @@ pub fn parse<'input>(input: &'input str) -> Result<Vec<Binding<'input>>, Vec<usi
                     break;
                 }
             }
+            Err(ParseError::UnrecognizedEOF { location, .. }) => {
+                errors.push(index + location);
+                break;
+            }
             Err(_) => panic!("Parser should have catched this error"),
         };
     }
     Err(errors)
 }
 
+/// remove comments, keeping the line breaks of block comments so that
+/// positions in the result have the same line numbers as in src
 pub fn uncomment(src: &str) -> String {
-    find_replace(src, r"(//.*)|(/\*(.|\n)*?\*/)", "")
+    let re = Regex::new(r"(//.*)|(/\*(.|\n)*?\*/)").unwrap();
+    re.replace_all(src, |caps: &regex::Captures| {
+        caps[0].chars().filter(|&c| c == '\n').collect::<String>()
+    })
+    .into_owned()
+}
+
+/// 1-based line and column of a byte offset in src
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset.min(src.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
 }
 
 fn find_replace(src: &str, re: &str, replace: &str) -> String {
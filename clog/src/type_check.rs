@@ -1,683 +1,985 @@
-//! Author: Mohammed Nurul Hoque (2018)
-//!
-//! This module contains the logic for transforming a compilation unit from AST
-//! to imperAST.
-
-use std::collections::{BTreeMap, HashMap};
-use std::iter::FromIterator;
-
-use crate::{
-    ast::{Binding, Expr, Pattern},
-    dtree::DTree,
-    error::Error,
-    imper_ast::{Closure, ConstraintValue, Expr as iExpr, Module, ValPath},
-    namescope::NameScope,
-    types::{BinOpcode, Literal, ProtoType, Type, UnOpcode,  TypeDecl},
-    unify,
-};
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    #[test]
-    fn test_mk_curried() {
-        use self::Type::{Function, Variable};
-        let t = mk_curried_type(5, 5);
-        assert_eq!(
-            t,
-            Function(
-                Box::new(Variable(5)),
-                Box::new(Function(
-                    Box::new(Variable(6)),
-                    Box::new(Function(
-                        Box::new(Variable(7)),
-                        Box::new(Function(Box::new(Variable(8)), Box::new(Variable(9)),))
-                    ))
-                ))
-            )
-        );
-    }
-
-    #[test]
-    fn test_get_type_decl() {
-        let vars = vec!["T"];
-        let variants = vec![
-            ("Nil", ProtoType::Unit),
-            (
-                "Node",
-                ProtoType::Tuple(vec![
-                    ProtoType::Sum(
-                        "List",
-                        Box::new(ProtoType::Generic("T")),
-                    ),
-                    ProtoType::Sum(
-                        "List",
-                        Box::new(ProtoType::Sum(
-                            "BTree",
-                            Box::new(ProtoType::Generic("T")),
-                        )),
-                    ),
-                ]),
-            ),
-        ];
-        let mut type_map = vec![("List", 0)].into_iter().collect();
-        let mut ns = NameScope::new();
-        let mut errors = Vec::new();
-        let dec = get_type_decl("BTree", vars, variants, &mut type_map, &mut ns, &mut errors);
-        assert_eq!(dec.name, "BTree");
-        assert_eq!(dec.num_generics, 1);
-        assert_eq!(
-            dec.variants,
-            vec![
-                ("Nil", Type::Unit),
-                (
-                    "Node",
-                    Type::Tuple(vec![
-                        Type::Sum(0, vec!(Type::Generic(0))),
-                        Type::Sum(0, vec!(Type::Sum(1, vec!(Type::Generic(0)))))
-                    ])
-                )
-            ]
-        );
-        assert_eq!(type_map["BTree"], 1);
-        assert_eq!(
-            ns.get("Nil").unwrap(),
-            &(
-                ValPath::Constructor(1, 1),
-                Type::Constructor {
-                    target: 1,
-                    position: 1,
-                }
-            )
-        );
-        assert_eq!(
-            ns.get("Node").unwrap(),
-            &(
-                ValPath::Constructor(1, 2),
-                Type::Constructor {
-                    target: 1,
-                    position: 2,
-                }
-            )
-        );
-    }
-
-    #[test]
-    fn test_pattern() {
-        use self::Pattern::*;
-        let pat = Tuple(vec![
-            SumVar(
-                "cons",
-                Box::new(Tuple(vec![Bind("x"), Bind("L1")])),
-            ),
-            SumVar(
-                "cons",
-                Box::new(Tuple(vec![Bind("y"), Bind("L2")])),
-            ),
-        ]);
-        let mut ns = NameScope::new();
-        ns.local().insert(
-            "cons",
-            (
-                ValPath::Constructor(0, 2),
-                Type::Constructor {
-                    position: 2 as u16,
-                    target: 0,
-                },
-            ),
-        );
-        let mut errors = Vec::new();
-        let mut type_consts = Vec::new();
-        let mut val_consts = BTreeMap::new();
-        let mut type_decls = vec![TypeDecl {
-            name: "List",
-            num_generics: 1,
-            variants: vec![
-                ("nil", Type::Unit),
-                (
-                    "cons",
-                    Type::Tuple(vec![Type::Generic(0), Type::Sum(0, vec![Type::Generic(0)])]),
-                ),
-            ],
-        }];
-        let mut closures = Vec::new();
-        let mut path = vec![1];
-        let mut args = Args {
-            namescope: &mut ns,
-            errors: &mut errors,
-            type_consts: &mut type_consts,
-            type_decls: &mut type_decls,
-            closures: &mut closures,
-        };
-        pat.transform(
-            10,
-            20,
-            &mut path,
-            &mut args,
-            ValPath::Local,
-            &mut val_consts,
-        );
-        assert_eq!(
-            args.namescope.get("x").unwrap(),
-            &(ValPath::Local(vec![1, 0, 2, 0]), Type::Variable(24))
-        );
-        assert_eq!(
-            args.namescope.get("L1").unwrap(),
-            &(ValPath::Local(vec![1, 0, 2, 1]), Type::Variable(25))
-        );
-        assert_eq!(
-            args.namescope.get("y").unwrap(),
-            &(ValPath::Local(vec![1, 1, 2, 0]), Type::Variable(28))
-        );
-        assert_eq!(
-            args.namescope.get("L2").unwrap(),
-            &(ValPath::Local(vec![1, 1, 2, 1]), Type::Variable(29))
-        );
-        assert!(args.errors.is_empty());
-        assert_eq!(path, &[1]);
-    }
-}
-
-
-/// A pair of types. Just more expressive
-type TypeConstraint = (Type, Type);
-
-pub struct TypingContext<'input> {
-    type_decls: Vec<TypeDecl<'input>>,
-    closures: Vec<Closure<'input>>,
-    globals: Vec<(iExpr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type)>,
-    namescope: NameScope<'input>,
-    type_consts: Vec<TypeConstraint>,
-    type_map: HashMap<&'input str, u16>,
-    errors: Vec<Error<'input>>,
-}
-
-impl<'input> TypingContext<'input> {
-    pub fn add_binding(&mut self, binding: Binding<'input>) -> Result<(), Error<'input>> {
-        match binding {
-            Binding::Type { name, vars, variants } => 
-                self.type_decls.push(get_type_decl(
-                    name,
-                    vars,
-                    variants,
-                    &mut self.type_map,
-                    &mut self.namescope,
-                    &mut self.errors,
-            )),
-            Binding::Value(pat, expr, is_rec) => {
-                let tuple = self.binding_transform(self.globals.len() as u16, pat, expr, is_rec)?;
-                self.globals.push(tuple)
-            }
-        }
-        Ok(())
-    }
-
-    /// Transform a top-level binding
-    /// # Arguments
-    /// - order in all top-level value bindings (the valpath)
-    /// - pattern
-    /// - expression
-    /// - is_rec: is recursive? if recursive, pattern added to scope before the expression
-    /// 
-    /// # Returns
-    /// Result(tranformed expression, constraints on the expression by the pattern, type of expression)
-    /// 
-    /// # Future
-    /// when non-top-level bindings are allowed, shouldn't generalize types here
-
-    fn binding_transform(
-        &mut self,
-        order: u16,
-        pat: Pattern<'input>,
-        expr: Expr<'input>,
-        is_rec: bool,
-    ) -> Result<(iExpr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type), Error<'input>> {
-        let mut path = vec![order];
-        let mut val_consts = BTreeMap::new();
-        // remember how many closures was already there. Closures are added to global closures vector
-        // as the expression is processed, i.e. before type unification. This means we have to change
-        // their types inside the global vector
-        let closures_num = self.closures.len();
-        // we don't insert directly into the scope because we want to do type unification
-        // before inserting finally
-        let expr = if is_rec {
-            self.namescope.push_layer();
-            let next = pat.transform(0, 1, &mut path, self, ValPath::StaticVal, &mut val_consts);
-            expr.transform(0, next, self).0
-        } else {
-            let (e, next) = expr.transform(0, 1, self);
-            self.namescope.push_layer();
-            pat.transform(0, next, &mut path, self, ValPath::StaticVal, &mut val_consts);
-            e
-        };
-        let mut type_consts = self.type_consts.drain(0..).collect();
-        let mut map = unify::unify(&mut type_consts)?;
-        let mut local = self.namescope.pop_layer();
-        for (_, (_, t)) in local.iter_mut() {
-            t.substitute_vars(&map);
-            t.generalize_type();
-        }
-        self.namescope.extend_local(local);
-
-        // chnage types of closures added for this binding
-        for closure in self.closures.iter_mut().skip(closures_num) {
-            closure.substitute_types(&map);
-        }
-
-        let mut t = Type::Variable(0);
-        t.substitute_vars(&mut map);
-        t.generalize_type();
-        // let mut pretty = String::new();
-        // t.pretty_format(&mut pretty, args.type_decls);
-        // println!("{}",pretty);
-        Ok((expr, val_consts, t))
-    }
-}
-
-/// The transformation function, takes a series of bindings in AST form,
-/// which are either value binding or type declarations. Converts to
-/// a Module struct (see imper_ast.rs) which separated functions and
-/// variables.
-pub fn ast2imper_ast(bindings: Vec<Binding>) -> Result<Module, Error> {
-    let mut closures = Vec::new();
-    let mut global_scope = NameScope::new();
-    let mut globals = Vec::new();
-
-    // XXX hack
-    let map = HashMap::from_iter(vec![
-        ("print", (ValPath::Imported("print"), 
-            Type::Function(Box::new(Type::String), Box::new(Type::Unit)))),
-        ("i2str", (ValPath::Imported("i2str"),
-            Type::Function(Box::new(Type::Int), Box::new(Type::String)))),
-    ]);
-    global_scope.extend_local(map);
-
-    let mut type_decls = Vec::new();
-
-    Ok(Module {
-        closures,
-        globals,
-        type_decls,
-        globals_names: global_scope
-            .pop_layer()
-            .into_iter()
-            .map(|(s, (path, _))| (s, path))
-            .collect(),
-    })
-}
-
-
-fn get_type_decl<'input>(
-    name: &'input str,
-    vars: Vec<&'input str>,
-    variants: Vec<(&'input str, ProtoType<'input>)>,
-    type_map: &mut HashMap<&'input str, u16>,
-    namescope: &mut NameScope<'input>,
-    errors: &mut Vec<Error<'input>>,
-) -> TypeDecl<'input> {
-    let generics_map: HashMap<&'input str, u16> = vars
-        .into_iter()
-        .enumerate()
-        .map(|(i, s)| (s, i as u16))
-        .collect();
-    let len = type_map.len() as u16;
-    type_map.insert(name, len);
-    TypeDecl {
-        name,
-        num_generics: generics_map.len() as u16,
-        variants: variants
-            .into_iter()
-            .enumerate()
-            .map(|(i, (s, t))| {
-                let t = match t.to_type(type_map, &generics_map) {
-                    Ok(t) => t,
-                    Err(e) => { errors.push(e); Type::Unit }
-                };
-                namescope.local().insert(
-                    s,
-                    (
-                        ValPath::Constructor(len, (i+1) as u16),
-                        Type::Constructor {
-                            target: len,
-                            position: (i + 1) as u16,
-                        },
-                    ),
-                );
-                (s, t)
-            })
-            .collect(),
-    }
-}
-
-fn fn_transform<'a, 'b, 'input>(
-    fn_branches: Vec<(Vec<Pattern<'input>>, Expr<'input>)>,
-    var: u16,
-    next: u16,
-    ctx: &mut TypingContext<'input>,
-) -> (u16, u16) {
-    // patterns per branch
-    let len = fn_branches[0].0.len() as u16;
-    debug_assert!(len > 0);
-    ctx.type_consts
-        .push((Type::Variable(var), mk_curried_type(next, len + 1)));
-    let mut nnext = next + len + 1;
-    let mut dtree = DTree::new();
-    let mut branches = Vec::new();
-    ctx.namescope.push_layer();
-    for (i, (pats, e)) in fn_branches.into_iter().enumerate().rev() {
-        if pats.len() as u16 != len {
-            ctx.errors.push(Error::VariablePatsNum);
-        }
-
-        let mut path = vec![];
-        let mut val_consts = BTreeMap::new();
-        for (j, pat) in pats.into_iter().enumerate() {
-            path.push(j as u16);
-            nnext = pat.transform(
-                next + j as u16,
-                nnext,
-                &mut path,
-                ctx,
-                ValPath::Local,
-                &mut val_consts,
-            );
-            path.pop();
-        }
-        dtree.add_pattern(val_consts, i as u16);
-        let (e, tmp) = e.transform(next + len, nnext, ctx);
-        branches.push(e);
-        nnext = tmp;
-        ctx.namescope.drain_local();
-    }
-    let map = ctx.namescope.pop_layer();
-    let mut captures = Vec::new();
-    for (_, (val, t)) in map.into_iter() {
-        match val {
-            ValPath::CaptureCaptured(n, _) | ValPath::CaptureLocal(n, _) => {
-                captures.push((n, (val, t)))
-            }
-            _ => panic!("non capture value path not expected here"),
-        }
-    }
-    captures.sort_unstable_by(|(ord1, _), (ord2, _)| ord1.cmp(ord2));
-    let captures: Vec<(ValPath, Type)> = captures.into_iter().map(|(_, v)| v).collect();
-    let is_static = captures.is_empty();
-    ctx.closures.push(Closure {
-        captures,
-        dtree,
-        branches: branches.into_iter().rev().collect(),
-        args: (next..(next + len)).map(|n| Type::Variable(n)).collect(),
-        return_type: Type::Variable(next + len),
-    });
-    if is_static {}
-
-    ((ctx.closures.len() - 1) as u16, nnext)
-}
-
-impl<'input> Pattern<'input> {
-    /// parse a pattern and fill local with the name bindings, and val_consts with
-    /// value bindings.
-    /// ### RETURNS
-    /// next free variable
-    fn transform<'b, T: Fn(Vec<u16>) -> ValPath + Copy>(
-        self,
-        var: u16,
-        next: u16,
-        path: &mut Vec<u16>,
-        ctx: &mut TypingContext<'input>,
-        valpath_constructor: T,
-        val_consts: &mut BTreeMap<ValPath, ConstraintValue<'input>>,
-    ) -> u16 {
-        match self {
-            Pattern::Error(..) => panic!("Parse Error not supposed to be propagated"),
-            Pattern::Wild => next,
-            Pattern::Literal(l) => {
-                ctx.type_consts.push((Type::Variable(var), l.get_type()));
-                if let Literal::Unit = l {
-                    ()
-                } else {
-                    val_consts.insert(valpath_constructor(path.clone()), l.get_constraint());
-                }
-                next
-            }
-            Pattern::Bind(s) => match ctx.namescope.local().get(&s) {
-                Some(_) => {
-                    ctx.errors.push(Error::MultBindPattern(s));
-                    next
-                }
-                None => {
-                    ctx.namescope
-                        .local()
-                        .insert(s, (valpath_constructor(path.clone()), Type::Variable(var)));
-                    next
-                }
-            },
-            Pattern::Tuple(v) => {
-                let len = v.len() as u16;
-                let mut nnext = next + len;
-                ctx.type_consts.push((
-                    Type::Variable(var),
-                    Type::Tuple((next..nnext).map(|i| Type::Variable(i)).collect()),
-                ));
-                for (i, pat) in v.into_iter().enumerate() {
-                    let i = i as u16;
-                    path.push(i);
-                    nnext =
-                        pat.transform(next + i, nnext, path, ctx, valpath_constructor, val_consts);
-                    path.pop();
-                }
-                nnext
-            }
-            Pattern::SumVar(constructor, pat) => match ctx.namescope.get(&constructor) {
-                None => {
-                    ctx.errors.push(Error::ConstructorNotFound(constructor));
-                    next
-                }
-                Some(ni) => {
-                    if let Type::Constructor { target, position } = ni.1 {
-                        let t = &ctx.type_decls[target as usize];
-                        // The value constraint for the tag
-                        val_consts.insert(
-                            valpath_constructor({
-                                let mut p = path.clone();
-                                p.push(0);
-                                p
-                            }),
-                            // position starts from 1
-                            ConstraintValue::Finite(position - 1, t.variants.len() as u16),
-                        );
-
-                        let (from, n1) = t.variants[position as usize - 1].1.instantiate(next + 1);
-                        let (to, n2) = (
-                            Type::Sum(
-                                target,
-                                (0..t.num_generics)
-                                    .map(|n| Type::Variable(next + 1 + n))
-                                    .collect(),
-                            ),
-                            next + 1 + t.num_generics,
-                        );
-                        ctx.type_consts.push((Type::Variable(var), to));
-                        ctx.type_consts.push((Type::Variable(next), from));
-                        path.push(position);
-                        debug_assert!(n2 >= n1);
-                        let next =
-                            pat.transform(next, n2, path, ctx, valpath_constructor, val_consts);
-                        path.pop();
-                        next
-                    } else {
-                        ctx.errors.push(Error::NonConstAppPattern(constructor));
-                        next
-                    }
-                }
-            },
-        }
-    }
-}
-
-impl<'input> Expr<'input> {
-    fn transform(self, var: u16, next: u16, ctx: &mut TypingContext<'input>) -> (iExpr<'input>, u16) {
-        let sequence = |e1: Expr<'input>, e2: Expr<'input>, var1, var2, next, ctx: &mut TypingContext<'input>| {
-            let (e1, next) = e1.transform(var1, next, ctx);
-            let (e2, next) = e2.transform(var2, next, ctx);
-            (e1, e2, next)
-        };
-        match self {
-            Expr::Error(..) => panic!("Parse Error not supposed to be propagated"),
-            Expr::Literal(l) => {
-                ctx.type_consts.push((Type::Variable(var), l.get_type()));
-                (iExpr::Literal(l), next)
-            }
-            Expr::Bound(s) => match ctx.namescope.get(&s) {
-                Some(ni) => {
-                    let (path, t) = &*ni;
-                    let (t, next) = if let Type::Constructor { target, position } = t {
-                        let ttype = &ctx.type_decls[*target as usize];
-                        let (from, n1) = ttype.variants[*position as usize - 1].1.instantiate(next);
-                        let (to, n2) = (
-                            Type::Sum(
-                                *target,
-                                (0..ttype.num_generics)
-                                    .map(|n| Type::Variable(next + n))
-                                    .collect(),
-                            ),
-                            next + ttype.num_generics,
-                        );
-                        debug_assert!(n2 >= n1);
-                        (Type::Function(Box::new(from), Box::new(to)), n2)
-                    } else {
-                        t.instantiate(next)
-                    };
-                    ctx.type_consts.push((Type::Variable(var), t));
-                    (iExpr::Bound(path.clone()), next)
-                }
-                None => {
-                    ctx.errors.push(Error::NameNotFound(s));
-                    (iExpr::Error, next)
-                }
-            },
-            Expr::BinOp(e1, op, e2) => {
-                use self::BinOpcode::*;
-                let (e1, e2, next) = match op {
-                    Add | Sub | Mul | Div | Mod => {
-                        ctx.type_consts.push((Type::Variable(var), Type::Int));
-                        sequence(*e1, *e2, var, var, next, ctx)
-                    }
-                    Greater | Less | GreaterEq | LessEq => {
-                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
-                        ctx.type_consts.push((Type::Variable(next), Type::Int));
-                        sequence(*e1, *e2, next, next, next + 1, ctx)
-                    }
-                    Concat => {
-                        ctx.type_consts.push((Type::Variable(var), Type::String));
-                        sequence(*e1, *e2, var, var, next, ctx)
-                    }
-                    Equal | NotEq => {
-                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
-                        sequence(*e1, *e2, next, next, next + 1, ctx)
-                    }
-                    And | Or => {
-                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
-                        sequence(*e1, *e2, var, var, next, ctx)
-                    }
-                };
-                (iExpr::BinOp(Box::new(e1), op, Box::new(e2)), next)
-            }
-            Expr::UnOp(UnOpcode::Minus, e) => {
-                ctx.type_consts.push((Type::Variable(var), Type::Int));
-                let (e, next) = e.transform(var, next, ctx);
-                (iExpr::UnOp(UnOpcode::Minus, Box::new(e)), next)
-            }
-            Expr::UnOp(UnOpcode::Not, e) => {
-                ctx.type_consts.push((Type::Variable(var), Type::Bool));
-                let (e, next) = e.transform(var, next, ctx);
-                (iExpr::UnOp(UnOpcode::Not, Box::new(e)), next)
-            }
-            Expr::Tuple(v) => {
-                let mut nnext = next + v.len() as u16;
-                ctx.type_consts.push((
-                    Type::Variable(var),
-                    Type::Tuple(
-                        (0..v.len())
-                            .map(|i| Type::Variable(next + i as u16))
-                            .collect(),
-                    ),
-                ));
-                let mut v2 = Vec::new();
-                for (i, e) in v.into_iter().enumerate() {
-                    // the rhs next is not the outer next, otherwise cannot update mutable nnext
-                    let (e, next) = e.transform(next + i as u16, nnext, ctx);
-                    v2.push(e);
-                    nnext = next;
-                }
-                (iExpr::Tuple(v2), nnext)
-            }
-            Expr::Application(e1, e2) => {
-                // TODO : if e1 is constructor ...
-                if let Expr::Bound(s) = *e1 {
-                    match ctx.namescope.get(s) {
-                        Some((_, Type::Constructor { .. })) => (), // unimplemented!(),
-                        Some(_) => (), // unimplemented!(),
-                        None => (), // unimplemented!(),
-                    }
-                }
-                ctx.type_consts.push((
-                    Type::Variable(next),
-                    Type::Function(
-                        Box::new(Type::Variable(next + 1)),
-                        Box::new(Type::Variable(var)),
-                    ),
-                ));
-                let (e1, e2, next) = sequence(*e1, *e2, next, next + 1, next + 2, ctx);
-                (iExpr::Application(Box::new(e1), Box::new(e2)), next)
-            }
-            Expr::Conditional(cond, e1, e2) => {
-                ctx.type_consts.push((Type::Variable(next), Type::Bool));
-                let (cond, next) = cond.transform(next, next + 1, ctx);
-                let (e1, e2, next) = sequence(*e1, *e2, var, var, next, ctx);
-                (
-                    iExpr::Conditional(Box::new(cond), Box::new(e1), Box::new(e2)),
-                    next,
-                )
-            }
-            Expr::Closure(v) => {
-                let (idx, next) = fn_transform(v, var, next, ctx);
-                (iExpr::Closure(idx), next)
-            }
-        }
-    }
-}
-
-/// ### REQUIRES
-/// count > 0
-fn mk_curried_type(from: u16, count: u16) -> Type {
-    let mut t = Type::Variable(from + count - 1);
-    for i in (from..(from + count - 1)).rev() {
-        t = Type::Function(Box::new(Type::Variable(i)), Box::new(t));
-    }
-    t
-}
-
-impl<'input> Literal<'input> {
-    fn get_constraint(self) -> ConstraintValue<'input> {
-        match self {
-            Literal::Unit => panic!("trying to get constraint from unit"),
-            Literal::Int(n) => ConstraintValue::Int(n),
-            Literal::Bool(true) => ConstraintValue::Finite(0, 2),
-            Literal::Bool(false) => ConstraintValue::Finite(1, 2),
-            Literal::String(s) => ConstraintValue::Str(s),
-        }
-    }
-}
-
-impl<'input> Closure<'input> {
-    fn substitute_types(&mut self, map: &HashMap<u16, Type>) {
-        for (_, t) in &mut self.captures {
-            t.substitute_vars(&map);
-        }
-        for t in &mut self.args {
-            t.substitute_vars(&map);
-        }
-        self.return_type.substitute_vars(&map);
-    }
-}
+//! Author: Mohammed Nurul Hoque (2018)
+//!
+//! This module contains the logic for transforming a compilation unit from AST
+//! to imperAST.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    ast::{Binding, Expr, HandlerArm, Pattern, Span},
+    dtree::DTree,
+    error::{Error, Warning},
+    imper_ast::{Closure, ConstraintValue, Expr as iExpr, Module, ValPath},
+    namescope::NameScope,
+    types::{BinOpcode, Literal, ProtoType, Type, UnOpcode,  TypeDecl, EXN_TYPE},
+    unify,
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_mk_curried() {
+        use self::Type::{Function, Variable};
+        let t = mk_curried_type(5, 5);
+        assert_eq!(
+            t,
+            Function(
+                Box::new(Variable(5)),
+                Box::new(Function(
+                    Box::new(Variable(6)),
+                    Box::new(Function(
+                        Box::new(Variable(7)),
+                        Box::new(Function(Box::new(Variable(8)), Box::new(Variable(9)),))
+                    ))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_type_decl() {
+        let vars = vec!["T"];
+        let variants = vec![
+            ("Nil", ProtoType::Unit),
+            (
+                "Node",
+                ProtoType::Tuple(vec![
+                    ProtoType::Sum(
+                        "List",
+                        Box::new(ProtoType::Generic("T")),
+                    ),
+                    ProtoType::Sum(
+                        "List",
+                        Box::new(ProtoType::Sum(
+                            "BTree",
+                            Box::new(ProtoType::Generic("T")),
+                        )),
+                    ),
+                ]),
+            ),
+        ];
+        let mut type_map = vec![("List", 0)].into_iter().collect();
+        let mut ns = NameScope::new();
+        let mut errors = Vec::new();
+        let dec = get_type_decl(1, "BTree", vars, variants, &mut type_map, &mut ns, &mut errors);
+        assert_eq!(dec.name, "BTree");
+        assert_eq!(dec.num_generics, 1);
+        assert_eq!(
+            dec.variants,
+            vec![
+                ("Nil", Type::Unit),
+                (
+                    "Node",
+                    Type::Tuple(vec![
+                        Type::Sum(0, vec!(Type::Generic(0))),
+                        Type::Sum(0, vec!(Type::Sum(1, vec!(Type::Generic(0)))))
+                    ])
+                )
+            ]
+        );
+        assert_eq!(type_map["BTree"], 1);
+        assert_eq!(
+            ns.get("Nil").unwrap(),
+            &(
+                ValPath::Constructor(1, 1),
+                Type::Constructor {
+                    target: 1,
+                    position: 1,
+                }
+            )
+        );
+        assert_eq!(
+            ns.get("Node").unwrap(),
+            &(
+                ValPath::Constructor(1, 2),
+                Type::Constructor {
+                    target: 1,
+                    position: 2,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_pattern() {
+        use self::Pattern::*;
+        let pat = Tuple(vec![
+            SumVar(
+                "cons",
+                Box::new(Tuple(vec![Bind("x"), Bind("L1")])),
+            ),
+            SumVar(
+                "cons",
+                Box::new(Tuple(vec![Bind("y"), Bind("L2")])),
+            ),
+        ]);
+        // cons is the second variant of the List of every context
+        let mut ctx = TypingContext::new(&[]);
+        ctx.namescope.push_layer();
+        let mut val_consts = BTreeMap::new();
+        let mut path = vec![1];
+        pat.transform(10, 20, &mut path, &mut ctx, ValPath::Local, &mut val_consts);
+        assert_eq!(
+            ctx.namescope.get("x").unwrap(),
+            &(ValPath::Local(vec![1, 0, 2, 0]), Type::Variable(24))
+        );
+        assert_eq!(
+            ctx.namescope.get("L1").unwrap(),
+            &(ValPath::Local(vec![1, 0, 2, 1]), Type::Variable(25))
+        );
+        assert_eq!(
+            ctx.namescope.get("y").unwrap(),
+            &(ValPath::Local(vec![1, 1, 2, 0]), Type::Variable(28))
+        );
+        assert_eq!(
+            ctx.namescope.get("L2").unwrap(),
+            &(ValPath::Local(vec![1, 1, 2, 1]), Type::Variable(29))
+        );
+        assert!(ctx.errors.is_empty());
+        assert_eq!(path, &[1]);
+    }
+
+    #[test]
+    fn test_value_restriction() {
+        use self::Type::*;
+        use crate::parse::parse;
+        let src = "type List t = | nil () | cons (t, List t)
+            let r = ref (nil ())
+            let id = {x => x}
+            let _ = r := cons (id 1, nil ())";
+        let module = ast2imper_ast(parse(src).unwrap(), &[]).unwrap();
+        // the weak variable of r is resolved by the assignment
+        assert_eq!(module.globals[0].2, Ref(Box::new(Sum(4, vec![Int]))));
+        assert_eq!(
+            module.globals[1].2,
+            Function(Box::new(Generic(0)), Box::new(Generic(0)))
+        );
+
+        let src = "type List t = | nil () | cons (t, List t)
+            let r = ref (nil ())
+            let _ = r := cons (1, nil ())
+            let _ = r := cons (\"1\", nil ())";
+        assert!(ast2imper_ast(parse(src).unwrap(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_refutable_warning() {
+        use crate::parse::parse;
+        let src = "type Box t = | box t
+            let (x, box y) = (1, box 2)
+            let (1, z) = (1, 2)
+            let (box true) = box false";
+        let module = ast2imper_ast(parse(src).unwrap(), &[]).unwrap();
+        let declarations: Vec<_> = module.globals.iter().map(|(_, _, _, span)| *span).collect();
+        assert_eq!(
+            module.warnings,
+            vec![
+                Warning::RefutablePattern(declarations[1]),
+                Warning::RefutablePattern(declarations[2]),
+            ]
+        );
+    }
+}
+
+
+/// A pair of types. Just more expressive
+type TypeConstraint = (Type, Type);
+
+pub struct TypingContext<'input> {
+    type_decls: Vec<TypeDecl<'input>>,
+    closures: Vec<Closure<'input>>,
+    globals: Vec<(iExpr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type, Span)>,
+    namescope: NameScope<'input>,
+    type_consts: Vec<TypeConstraint>,
+    type_map: HashMap<&'input str, u16>,
+    errors: Vec<Error<'input>>,
+    warnings: Vec<Warning>,
+    effects: Vec<(&'input str, Type, Type)>,
+    effect_map: HashMap<&'input str, u16>,
+    /// type variables >= weak_floor are weak: they belong to bindings that could
+    /// not be generalized (value restriction) and are resolved by later bindings.
+    /// They are allocated downwards from u16::MAX.
+    weak_floor: u16,
+    imports: Vec<(&'static str, Type)>,
+}
+
+impl<'input> TypingContext<'input> {
+    /// imports are the names and types of functions provided by the runtime
+    pub fn new(imports: &[(&'static str, Type)]) -> Self {
+        let mut namescope = NameScope::new();
+        namescope.extend_local(
+            imports
+                .iter()
+                .enumerate()
+                .map(|(i, &(name, ref t))| (name, (ValPath::Imported(i as u16), t.clone())))
+                .collect()
+        );
+        let mut ctx = TypingContext {
+            type_decls: Vec::new(),
+            closures: Vec::new(),
+            globals: Vec::new(),
+            namescope,
+            type_consts: Vec::new(),
+            type_map: HashMap::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            effects: Vec::new(),
+            effect_map: HashMap::new(),
+            weak_floor: u16::MAX,
+            imports: imports.to_vec(),
+        };
+        // exn, Option, Result and List are always the first types, in the order of their
+        // *_TYPE indices, the order of variants matches their positions e.g. OPTION_*
+        ctx.add_binding(Binding::Type {
+            name: "exn",
+            vars: vec![],
+            variants: vec![
+                ("DivisionByZero", ProtoType::Unit),
+                ("MatchFailure", ProtoType::Unit),
+            ],
+        }).unwrap();
+        ctx.add_binding(Binding::Type {
+            name: "Option",
+            vars: vec!["t"],
+            variants: vec![
+                ("none", ProtoType::Unit),
+                ("some", ProtoType::Generic("t")),
+            ],
+        }).unwrap();
+        ctx.add_binding(Binding::Type {
+            name: "Result",
+            vars: vec!["t", "e"],
+            variants: vec![
+                ("ok", ProtoType::Generic("t")),
+                ("err", ProtoType::Generic("e")),
+            ],
+        }).unwrap();
+        ctx.add_binding(Binding::Type {
+            name: "List",
+            vars: vec!["t"],
+            variants: vec![
+                ("nil", ProtoType::Unit),
+                ("cons", ProtoType::Tuple(vec![
+                    ProtoType::Generic("t"),
+                    ProtoType::Sum("List", Box::new(ProtoType::Generic("t"))),
+                ])),
+            ],
+        }).unwrap();
+        ctx
+    }
+
+    /// finish the compilation unit, fails with the first error encountered if any
+    pub fn into_module(mut self) -> Result<Module<'input>, Error<'input>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
+        }
+        Ok(Module {
+            closures: self.closures,
+            globals: self.globals,
+            type_decls: self.type_decls,
+            effects: self.effects,
+            warnings: self.warnings,
+            imports: self.imports,
+            globals_names: self.namescope
+                .pop_layer()
+                .into_iter()
+                .map(|(s, (path, _))| (s, path))
+                .collect(),
+        })
+    }
+
+    pub fn add_binding(&mut self, binding: Binding<'input>) -> Result<(), Error<'input>> {
+        match binding {
+            Binding::Type { name, vars, variants } => 
+                self.type_decls.push(get_type_decl(
+                    self.type_decls.len() as u16,
+                    name,
+                    vars,
+                    variants,
+                    &mut self.type_map,
+                    &mut self.namescope,
+                    &mut self.errors,
+            )),
+            Binding::Value(pat, expr, is_rec, span) => {
+                let (expr, val_consts, t) = self.binding_transform(self.globals.len() as u16, pat, expr, is_rec)?;
+                // constraints on a tag of a single variant type always hold
+                let refutable = val_consts.values().any(|c| match *c {
+                    ConstraintValue::Finite(_, 1) => false,
+                    _ => true,
+                });
+                if refutable {
+                    self.warnings.push(Warning::RefutablePattern(span));
+                }
+                self.globals.push((expr, val_consts, t, span))
+            }
+            Binding::Exception(name, t) => {
+                let t = match t.to_type(&self.type_map, &HashMap::new()) {
+                    Ok(t) => t,
+                    Err(e) => { self.errors.push(e); Type::Unit }
+                };
+                let variants = &mut self.type_decls[EXN_TYPE as usize].variants;
+                variants.push((name, t));
+                let position = variants.len() as u16;
+                self.namescope.local().insert(
+                    name,
+                    (
+                        ValPath::Constructor(EXN_TYPE, position),
+                        Type::Constructor { target: EXN_TYPE, position },
+                    ),
+                );
+            }
+            Binding::Effect { name, from, to } => {
+                let generics_map = HashMap::new();
+                let mut to_type = |t: ProtoType<'input>| match t.to_type(&self.type_map, &generics_map) {
+                    Ok(t) => t,
+                    Err(e) => { self.errors.push(e); Type::Unit }
+                };
+                let (from, to) = (to_type(from), to_type(to));
+                self.effect_map.insert(name, self.effects.len() as u16);
+                self.effects.push((name, from, to));
+            }
+        }
+        Ok(())
+    }
+
+    /// Transform a top-level binding
+    /// # Arguments
+    /// - order in all top-level value bindings (the valpath)
+    /// - pattern
+    /// - expression
+    /// - is_rec: is recursive? if recursive, pattern added to scope before the expression
+    /// 
+    /// # Returns
+    /// Result(tranformed expression, constraints on the expression by the pattern, type of expression)
+    /// 
+    /// Types are generalized only if the expression is non-expansive (value restriction),
+    /// otherwise its type variables become weak, e.g. in `let r = ref (nil ())`.
+    /// Type variables reachable from weak variables of earlier bindings are never generalized.
+    /// 
+    /// # Future
+    /// when non-top-level bindings are allowed, shouldn't generalize types here
+
+    fn binding_transform(
+        &mut self,
+        order: u16,
+        pat: Pattern<'input>,
+        expr: Expr<'input>,
+        is_rec: bool,
+    ) -> Result<(iExpr<'input>, BTreeMap<ValPath, ConstraintValue<'input>>, Type), Error<'input>> {
+        let mut path = vec![order];
+        let mut val_consts = BTreeMap::new();
+        // remember how many closures was already there. Closures are added to global closures vector
+        // as the expression is processed, i.e. before type unification. This means we have to change
+        // their types inside the global vector
+        let closures_num = self.closures.len();
+        let name = match pat {
+            Pattern::Bind(name) => Some(name),
+            _ => None,
+        };
+        // we don't insert directly into the scope because we want to do type unification
+        // before inserting finally
+        let (expr, next) = if is_rec {
+            self.namescope.push_layer();
+            let next = pat.transform(0, 1, &mut path, self, ValPath::StaticVal, &mut val_consts);
+            expr.transform(0, next, self)
+        } else {
+            let (e, next) = expr.transform(0, 1, self);
+            self.namescope.push_layer();
+            (e, pat.transform(0, next, &mut path, self, ValPath::StaticVal, &mut val_consts))
+        };
+        debug_assert!(next <= self.weak_floor);
+        if let iExpr::Closure(n) = expr {
+            self.closures[n as usize].name = name;
+        }
+        let mut type_consts = self.type_consts.drain(0..).collect();
+        let mut map = unify::unify(&mut type_consts)?;
+        let mut local = self.namescope.pop_layer();
+
+        // variables that must not be generalized get fresh weak variables
+        let mut pinned = Vec::new();
+        for w in self.weak_floor..u16::MAX {
+            let mut t = Type::Variable(w);
+            t.substitute_vars(&map);
+            t.variables(&mut pinned);
+        }
+        let is_value = expr.is_nonexpansive();
+        if !is_value {
+            for (_, (_, t)) in local.iter_mut() {
+                t.substitute_vars(&map);
+                t.variables(&mut pinned);
+            }
+        }
+        let weak_floor = self.weak_floor;
+        for n in pinned.into_iter().filter(|&n| n < weak_floor) {
+            self.weak_floor -= 1;
+            map.insert(n, Type::Variable(self.weak_floor));
+        }
+
+        for (_, (_, t)) in local.iter_mut() {
+            t.substitute_vars(&map);
+            t.generalize_below(self.weak_floor);
+        }
+
+        // resolve weak variables of earlier bindings
+        let weak_map: HashMap<u16, Type> = map
+            .iter()
+            .filter(|(&n, _)| n >= weak_floor)
+            .map(|(&n, t)| {
+                let mut t = t.clone();
+                t.substitute_vars(&map);
+                (n, t)
+            })
+            .collect();
+        if !weak_map.is_empty() {
+            for (_, (_, t)) in self.namescope.local().iter_mut() {
+                t.substitute_vars(&weak_map);
+            }
+            for closure in self.closures.iter_mut().take(closures_num) {
+                closure.substitute_types(&weak_map);
+            }
+            for (_, _, t, _) in self.globals.iter_mut() {
+                t.substitute_vars(&weak_map);
+            }
+        }
+        self.namescope.extend_local(local);
+
+        // chnage types of closures added for this binding
+        for closure in self.closures.iter_mut().skip(closures_num) {
+            closure.substitute_types(&map);
+        }
+
+        let mut t = Type::Variable(0);
+        t.substitute_vars(&mut map);
+        t.generalize_below(self.weak_floor);
+        // let mut pretty = String::new();
+        // t.pretty_format(&mut pretty, args.type_decls);
+        // println!("{}",pretty);
+        Ok((expr, val_consts, t))
+    }
+}
+
+/// The transformation function, takes a series of bindings in AST form,
+/// which are either value binding or type declarations. Converts to
+/// a Module struct (see imper_ast.rs) which separated functions and
+/// variables.
+pub fn ast2imper_ast<'input>(
+    bindings: Vec<Binding<'input>>,
+    imports: &[(&'static str, Type)],
+) -> Result<Module<'input>, Error<'input>> {
+    let mut ctx = TypingContext::new(imports);
+    for binding in bindings {
+        ctx.add_binding(binding)?;
+    }
+    ctx.into_module()
+}
+
+
+/// declaration of the type at an index among the declarations, a redeclared name
+/// refers to the new type from now on
+fn get_type_decl<'input>(
+    index: u16,
+    name: &'input str,
+    vars: Vec<&'input str>,
+    variants: Vec<(&'input str, ProtoType<'input>)>,
+    type_map: &mut HashMap<&'input str, u16>,
+    namescope: &mut NameScope<'input>,
+    errors: &mut Vec<Error<'input>>,
+) -> TypeDecl<'input> {
+    let generics_map: HashMap<&'input str, u16> = vars
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| (s, i as u16))
+        .collect();
+    type_map.insert(name, index);
+    TypeDecl {
+        name,
+        num_generics: generics_map.len() as u16,
+        variants: variants
+            .into_iter()
+            .enumerate()
+            .map(|(i, (s, t))| {
+                let t = match t.to_type(type_map, &generics_map) {
+                    Ok(t) => t,
+                    Err(e) => { errors.push(e); Type::Unit }
+                };
+                namescope.local().insert(
+                    s,
+                    (
+                        ValPath::Constructor(index, (i+1) as u16),
+                        Type::Constructor {
+                            target: index,
+                            position: (i + 1) as u16,
+                        },
+                    ),
+                );
+                (s, t)
+            })
+            .collect(),
+    }
+}
+
+fn fn_transform<'a, 'b, 'input>(
+    fn_branches: Vec<(Vec<Pattern<'input>>, Expr<'input>)>,
+    var: u16,
+    next: u16,
+    ctx: &mut TypingContext<'input>,
+) -> (u16, u16) {
+    // patterns per branch
+    let len = fn_branches[0].0.len() as u16;
+    debug_assert!(len > 0);
+    ctx.type_consts
+        .push((Type::Variable(var), mk_curried_type(next, len + 1)));
+    let mut nnext = next + len + 1;
+    let mut dtree = DTree::new();
+    let mut branches = Vec::new();
+    ctx.namescope.push_layer();
+    for (i, (pats, e)) in fn_branches.into_iter().enumerate().rev() {
+        if pats.len() as u16 != len {
+            ctx.errors.push(Error::VariablePatsNum);
+        }
+
+        let mut path = vec![];
+        let mut val_consts = BTreeMap::new();
+        for (j, pat) in pats.into_iter().enumerate() {
+            path.push(j as u16);
+            nnext = pat.transform(
+                next + j as u16,
+                nnext,
+                &mut path,
+                ctx,
+                ValPath::Local,
+                &mut val_consts,
+            );
+            path.pop();
+        }
+        dtree.add_pattern(val_consts, i as u16);
+        let (e, tmp) = e.transform(next + len, nnext, ctx);
+        branches.push(e);
+        nnext = tmp;
+        ctx.namescope.drain_local();
+    }
+    let map = ctx.namescope.pop_layer();
+    let mut captures = Vec::new();
+    for (_, (val, t)) in map.into_iter() {
+        match val {
+            ValPath::CaptureCaptured(n, _) | ValPath::CaptureLocal(n, _) => {
+                captures.push((n, (val, t)))
+            }
+            _ => panic!("non capture value path not expected here"),
+        }
+    }
+    captures.sort_unstable_by(|(ord1, _), (ord2, _)| ord1.cmp(ord2));
+    let captures: Vec<(ValPath, Type)> = captures.into_iter().map(|(_, v)| v).collect();
+    let is_static = captures.is_empty();
+    ctx.closures.push(Closure {
+        name: None,
+        captures,
+        dtree,
+        branches: branches.into_iter().rev().collect(),
+        args: (next..(next + len)).map(|n| Type::Variable(n)).collect(),
+        return_type: Type::Variable(next + len),
+    });
+    if is_static {}
+
+    ((ctx.closures.len() - 1) as u16, nnext)
+}
+
+impl<'input> Pattern<'input> {
+    /// parse a pattern and fill local with the name bindings, and val_consts with
+    /// value bindings.
+    /// ### RETURNS
+    /// next free variable
+    fn transform<'b, T: Fn(Vec<u16>) -> ValPath + Copy>(
+        self,
+        var: u16,
+        next: u16,
+        path: &mut Vec<u16>,
+        ctx: &mut TypingContext<'input>,
+        valpath_constructor: T,
+        val_consts: &mut BTreeMap<ValPath, ConstraintValue<'input>>,
+    ) -> u16 {
+        match self {
+            Pattern::Error(..) => panic!("Parse Error not supposed to be propagated"),
+            Pattern::Wild => next,
+            Pattern::Literal(l) => {
+                ctx.type_consts.push((Type::Variable(var), l.get_type()));
+                if let Literal::Unit = l {
+                    ()
+                } else {
+                    val_consts.insert(valpath_constructor(path.clone()), l.get_constraint());
+                }
+                next
+            }
+            Pattern::Bind(s) => match ctx.namescope.local().get(&s) {
+                Some(_) => {
+                    ctx.errors.push(Error::MultBindPattern(s));
+                    next
+                }
+                None => {
+                    ctx.namescope
+                        .local()
+                        .insert(s, (valpath_constructor(path.clone()), Type::Variable(var)));
+                    next
+                }
+            },
+            Pattern::Tuple(v) => {
+                let len = v.len() as u16;
+                let mut nnext = next + len;
+                ctx.type_consts.push((
+                    Type::Variable(var),
+                    Type::Tuple((next..nnext).map(|i| Type::Variable(i)).collect()),
+                ));
+                for (i, pat) in v.into_iter().enumerate() {
+                    let i = i as u16;
+                    path.push(i);
+                    nnext =
+                        pat.transform(next + i, nnext, path, ctx, valpath_constructor, val_consts);
+                    path.pop();
+                }
+                nnext
+            }
+            Pattern::SumVar(constructor, pat) => match ctx.namescope.get(&constructor) {
+                None => {
+                    ctx.errors.push(Error::ConstructorNotFound(constructor));
+                    next
+                }
+                Some(ni) => {
+                    if let Type::Constructor { target, position } = ni.1 {
+                        let t = &ctx.type_decls[target as usize];
+                        // The value constraint for the tag
+                        val_consts.insert(
+                            valpath_constructor({
+                                let mut p = path.clone();
+                                p.push(0);
+                                p
+                            }),
+                            // position starts from 1
+                            ConstraintValue::Finite(position - 1, t.variants.len() as u16),
+                        );
+
+                        let (from, n1) = t.variants[position as usize - 1].1.instantiate(next + 1);
+                        let (to, n2) = (
+                            Type::Sum(
+                                target,
+                                (0..t.num_generics)
+                                    .map(|n| Type::Variable(next + 1 + n))
+                                    .collect(),
+                            ),
+                            next + 1 + t.num_generics,
+                        );
+                        ctx.type_consts.push((Type::Variable(var), to));
+                        ctx.type_consts.push((Type::Variable(next), from));
+                        path.push(position);
+                        debug_assert!(n2 >= n1);
+                        let next =
+                            pat.transform(next, n2, path, ctx, valpath_constructor, val_consts);
+                        path.pop();
+                        next
+                    } else {
+                        ctx.errors.push(Error::NonConstAppPattern(constructor));
+                        next
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl<'input> Expr<'input> {
+    fn transform(self, var: u16, next: u16, ctx: &mut TypingContext<'input>) -> (iExpr<'input>, u16) {
+        let sequence = |e1: Expr<'input>, e2: Expr<'input>, var1, var2, next, ctx: &mut TypingContext<'input>| {
+            let (e1, next) = e1.transform(var1, next, ctx);
+            let (e2, next) = e2.transform(var2, next, ctx);
+            (e1, e2, next)
+        };
+        match self {
+            Expr::Error(..) => panic!("Parse Error not supposed to be propagated"),
+            Expr::Literal(l) => {
+                ctx.type_consts.push((Type::Variable(var), l.get_type()));
+                (iExpr::Literal(l), next)
+            }
+            Expr::Bound(s) => match ctx.namescope.get(&s) {
+                Some(ni) => {
+                    let (path, t) = &*ni;
+                    let (t, next) = if let Type::Constructor { target, position } = t {
+                        let ttype = &ctx.type_decls[*target as usize];
+                        let (from, n1) = ttype.variants[*position as usize - 1].1.instantiate(next);
+                        let (to, n2) = (
+                            Type::Sum(
+                                *target,
+                                (0..ttype.num_generics)
+                                    .map(|n| Type::Variable(next + n))
+                                    .collect(),
+                            ),
+                            next + ttype.num_generics,
+                        );
+                        debug_assert!(n2 >= n1);
+                        (Type::Function(Box::new(from), Box::new(to)), n2)
+                    } else {
+                        t.instantiate(next)
+                    };
+                    ctx.type_consts.push((Type::Variable(var), t));
+                    (iExpr::Bound(path.clone()), next)
+                }
+                None => {
+                    ctx.errors.push(Error::NameNotFound(s));
+                    (iExpr::Error, next)
+                }
+            },
+            Expr::BinOp(e1, op, e2, span) => {
+                use self::BinOpcode::*;
+                let (e1, e2, next) = match op {
+                    Add | Sub | Mul | Div | Mod => {
+                        ctx.type_consts.push((Type::Variable(var), Type::Int));
+                        sequence(*e1, *e2, var, var, next, ctx)
+                    }
+                    Greater | Less | GreaterEq | LessEq => {
+                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
+                        ctx.type_consts.push((Type::Variable(next), Type::Int));
+                        sequence(*e1, *e2, next, next, next + 1, ctx)
+                    }
+                    Concat => {
+                        ctx.type_consts.push((Type::Variable(var), Type::String));
+                        sequence(*e1, *e2, var, var, next, ctx)
+                    }
+                    Equal | NotEq => {
+                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
+                        sequence(*e1, *e2, next, next, next + 1, ctx)
+                    }
+                    And | Or => {
+                        ctx.type_consts.push((Type::Variable(var), Type::Bool));
+                        sequence(*e1, *e2, var, var, next, ctx)
+                    }
+                    Assign => {
+                        ctx.type_consts.push((Type::Variable(var), Type::Unit));
+                        ctx.type_consts.push((
+                            Type::Variable(next),
+                            Type::Ref(Box::new(Type::Variable(next + 1))),
+                        ));
+                        sequence(*e1, *e2, next, next + 1, next + 2, ctx)
+                    }
+                };
+                (iExpr::BinOp(Box::new(e1), op, Box::new(e2), span), next)
+            }
+            Expr::UnOp(UnOpcode::Minus, e) => {
+                ctx.type_consts.push((Type::Variable(var), Type::Int));
+                let (e, next) = e.transform(var, next, ctx);
+                (iExpr::UnOp(UnOpcode::Minus, Box::new(e)), next)
+            }
+            Expr::UnOp(UnOpcode::Not, e) => {
+                ctx.type_consts.push((Type::Variable(var), Type::Bool));
+                let (e, next) = e.transform(var, next, ctx);
+                (iExpr::UnOp(UnOpcode::Not, Box::new(e)), next)
+            }
+            Expr::UnOp(UnOpcode::Ref, e) => {
+                ctx.type_consts.push((
+                    Type::Variable(var),
+                    Type::Ref(Box::new(Type::Variable(next))),
+                ));
+                let (e, next) = e.transform(next, next + 1, ctx);
+                (iExpr::UnOp(UnOpcode::Ref, Box::new(e)), next)
+            }
+            Expr::UnOp(UnOpcode::Deref, e) => {
+                ctx.type_consts.push((
+                    Type::Variable(next),
+                    Type::Ref(Box::new(Type::Variable(var))),
+                ));
+                let (e, next) = e.transform(next, next + 1, ctx);
+                (iExpr::UnOp(UnOpcode::Deref, Box::new(e)), next)
+            }
+            Expr::Tuple(v) => {
+                let mut nnext = next + v.len() as u16;
+                ctx.type_consts.push((
+                    Type::Variable(var),
+                    Type::Tuple(
+                        (0..v.len())
+                            .map(|i| Type::Variable(next + i as u16))
+                            .collect(),
+                    ),
+                ));
+                let mut v2 = Vec::new();
+                for (i, e) in v.into_iter().enumerate() {
+                    // the rhs next is not the outer next, otherwise cannot update mutable nnext
+                    let (e, next) = e.transform(next + i as u16, nnext, ctx);
+                    v2.push(e);
+                    nnext = next;
+                }
+                (iExpr::Tuple(v2), nnext)
+            }
+            Expr::Application(e1, e2, span) => {
+                // TODO : if e1 is constructor ...
+                if let Expr::Bound(s) = *e1 {
+                    match ctx.namescope.get(s) {
+                        Some((_, Type::Constructor { .. })) => (), // unimplemented!(),
+                        Some(_) => (), // unimplemented!(),
+                        None => (), // unimplemented!(),
+                    }
+                }
+                ctx.type_consts.push((
+                    Type::Variable(next),
+                    Type::Function(
+                        Box::new(Type::Variable(next + 1)),
+                        Box::new(Type::Variable(var)),
+                    ),
+                ));
+                let (e1, e2, next) = sequence(*e1, *e2, next, next + 1, next + 2, ctx);
+                (iExpr::Application(Box::new(e1), Box::new(e2), span), next)
+            }
+            Expr::Conditional(cond, e1, e2) => {
+                ctx.type_consts.push((Type::Variable(next), Type::Bool));
+                let (cond, next) = cond.transform(next, next + 1, ctx);
+                let (e1, e2, next) = sequence(*e1, *e2, var, var, next, ctx);
+                (
+                    iExpr::Conditional(Box::new(cond), Box::new(e1), Box::new(e2)),
+                    next,
+                )
+            }
+            Expr::Closure(v) => {
+                let (idx, next) = fn_transform(v, var, next, ctx);
+                (iExpr::Closure(idx), next)
+            }
+            Expr::Raise(e, span) => {
+                ctx.type_consts.push((Type::Variable(next), Type::Sum(EXN_TYPE, vec![])));
+                let (e, next) = e.transform(next, next + 1, ctx);
+                (iExpr::Raise(Box::new(e), span), next)
+            }
+            Expr::Try(e, handler) => {
+                // the handler is a closure exn -> type of e
+                if handler.iter().any(|(pats, _)| pats.len() != 1) {
+                    ctx.errors.push(Error::VariablePatsNum);
+                }
+                let (e, next) = e.transform(var, next, ctx);
+                ctx.type_consts.push((
+                    Type::Variable(next),
+                    Type::Function(
+                        Box::new(Type::Sum(EXN_TYPE, vec![])),
+                        Box::new(Type::Variable(var)),
+                    ),
+                ));
+                let (idx, next) = fn_transform(handler, next, next + 1, ctx);
+                (iExpr::Try(Box::new(e), idx), next)
+            }
+            Expr::Perform(name, e) => match ctx.effect_map.get(name) {
+                Some(&effect) => {
+                    let (_, from, to) = &ctx.effects[effect as usize];
+                    let (from, to) = (from.clone(), to.clone());
+                    ctx.type_consts.push((Type::Variable(var), to));
+                    ctx.type_consts.push((Type::Variable(next), from));
+                    let (e, next) = e.transform(next, next + 1, ctx);
+                    (iExpr::Perform(effect, Box::new(e)), next)
+                }
+                None => {
+                    ctx.errors.push(Error::NameNotFound(name));
+                    (iExpr::Error, next)
+                }
+            },
+            Expr::Handle(e, arms) => {
+                let body = next;
+                let (e, mut next) = e.transform(body, next + 1, ctx);
+                // group the arms into closures, one for return and one per operation
+                let mut returns = Vec::new();
+                let mut ops: Vec<(&'input str, Vec<_>)> = Vec::new();
+                for arm in arms {
+                    match arm {
+                        HandlerArm::Return(pat, e) => returns.push((vec![pat], e)),
+                        HandlerArm::Op(name, v, k, e) => {
+                            match ops.iter_mut().find(|(op, _)| *op == name) {
+                                Some((_, op_arms)) => op_arms.push((vec![v, k], e)),
+                                None => ops.push((name, vec![(vec![v, k], e)])),
+                            }
+                        }
+                    }
+                }
+                let ret = if returns.is_empty() {
+                    ctx.type_consts.push((Type::Variable(body), Type::Variable(var)));
+                    None
+                } else {
+                    // return: body -> result
+                    ctx.type_consts.push((
+                        Type::Variable(next),
+                        Type::Function(Box::new(Type::Variable(body)), Box::new(Type::Variable(var))),
+                    ));
+                    let (idx, nnext) = fn_transform(returns, next, next + 1, ctx);
+                    next = nnext;
+                    Some(idx)
+                };
+                let mut op_closures = Vec::new();
+                for (name, op_arms) in ops {
+                    let effect = match ctx.effect_map.get(name) {
+                        Some(&effect) => effect,
+                        None => {
+                            ctx.errors.push(Error::NameNotFound(name));
+                            continue;
+                        }
+                    };
+                    // Op: from -> (to -> result) -> result
+                    let (_, from, to) = &ctx.effects[effect as usize];
+                    let k = Type::Function(Box::new(to.clone()), Box::new(Type::Variable(var)));
+                    ctx.type_consts.push((
+                        Type::Variable(next),
+                        Type::Function(
+                            Box::new(from.clone()),
+                            Box::new(Type::Function(Box::new(k), Box::new(Type::Variable(var)))),
+                        ),
+                    ));
+                    let (idx, nnext) = fn_transform(op_arms, next, next + 1, ctx);
+                    next = nnext;
+                    op_closures.push((effect, idx));
+                }
+                (iExpr::Handle { body: Box::new(e), ret, ops: op_closures }, next)
+            }
+        }
+    }
+}
+
+/// ### REQUIRES
+/// count > 0
+fn mk_curried_type(from: u16, count: u16) -> Type {
+    let mut t = Type::Variable(from + count - 1);
+    for i in (from..(from + count - 1)).rev() {
+        t = Type::Function(Box::new(Type::Variable(i)), Box::new(t));
+    }
+    t
+}
+
+impl<'input> Literal<'input> {
+    fn get_constraint(self) -> ConstraintValue<'input> {
+        match self {
+            Literal::Unit => panic!("trying to get constraint from unit"),
+            Literal::Int(n) => ConstraintValue::Int(n),
+            Literal::Bool(true) => ConstraintValue::Finite(0, 2),
+            Literal::Bool(false) => ConstraintValue::Finite(1, 2),
+            Literal::String(s) => ConstraintValue::Str(s),
+        }
+    }
+}
+
+impl<'input> iExpr<'input> {
+    /// non-expansive expressions cannot allocate reference cells when evaluated,
+    /// so their types can be generalized
+    fn is_nonexpansive(&self) -> bool {
+        match self {
+            iExpr::Literal(_) | iExpr::Bound(_) | iExpr::Closure(_) | iExpr::Error => true,
+            iExpr::Tuple(v) => v.iter().all(|e| e.is_nonexpansive()),
+            iExpr::SumVal { value, .. } => value.is_nonexpansive(),
+            iExpr::Application(f, e, _) => match **f {
+                iExpr::Bound(ValPath::Constructor(..)) => e.is_nonexpansive(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl<'input> Closure<'input> {
+    fn substitute_types(&mut self, map: &HashMap<u16, Type>) {
+        for (_, t) in &mut self.captures {
+            t.substitute_vars(&map);
+        }
+        for t in &mut self.args {
+            t.substitute_vars(&map);
+        }
+        self.return_type.substitute_vars(&map);
+    }
+}
@@ -7,6 +7,11 @@ use crate::{
     error::Error,
 };
 
+/// the built-in types and their variants are where the VM expects them
+pub use lugha_vm::bytecode::{
+    EXN_DIV_BY_ZERO, EXN_MATCH_FAILURE, EXN_TYPE, LIST_CONS, LIST_NIL, LIST_TYPE, OPTION_NONE, OPTION_SOME,
+    OPTION_TYPE, RESULT_ERR, RESULT_OK, RESULT_TYPE,
+};
 
 /// Representation of a sum type
 #[derive(Debug)]
@@ -30,6 +35,7 @@ pub enum ProtoType<'input> {
     Function(Box<ProtoType<'input>>, Box<ProtoType<'input>>),
     Tuple(Vec<ProtoType<'input>>),
     Sum(&'input str, Box<ProtoType<'input>>),
+    Ref(Box<ProtoType<'input>>),
     Generic(&'input str),
     /// Parse error
     Error(usize, usize),
@@ -52,6 +58,8 @@ pub enum Type {
     // a vector is used instead of a box type, because sum is frequently on a tuple type,
     // this optimizes the common case to 1 level of indirection instead of 2.
     Sum(u16, Vec<Type>),
+    /// mutable reference cell
+    Ref(Box<Type>),
     Generic(u16),
     Variable(u16),    // type variable only used for type-checking
 }
@@ -69,6 +77,9 @@ impl Type {
             },
             Type::Sum(n, ref v) => {
                 *result += types[n as usize].name;
+                if v.is_empty() {
+                    return;
+                }
                 result.push_str("(");
                 v[0].to_string_base(result, call_self);
                 for t in v.iter().skip(1) {
@@ -108,6 +119,7 @@ impl Type {
                 }
                 dst.push_str(")")
             }
+            Type::Sum(n, ref v) if v.is_empty() => *dst += &format!("~{}", n),
             Type::Sum(n, ref v) => {
                 *dst += &format!("~{}(", n);
                 f(&v[0], dst);
@@ -117,6 +129,16 @@ impl Type {
                 }
                 dst.push_str(")")
             },
+            Type::Ref(ref t) => {
+                if let Type::Function (..) = **t {
+                    dst.push_str("ref (");
+                    f(t.as_ref(), dst);
+                    dst.push_str(")");
+                } else {
+                    dst.push_str("ref ");
+                    f(t.as_ref(), dst);
+                }
+            }
             Type::Generic(n) => *dst += &format!("{}", ('a' as u16 + n) as u8 as char),
             Type::Variable(n) => *dst += &format!("{}", n),
         }
@@ -171,12 +193,20 @@ pub enum BinOpcode {
 
     And,
     Or,
+
+    /// write to a reference cell
+    Assign,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum UnOpcode {
     Minus,
     Not,
+
+    /// allocate a reference cell
+    Ref,
+    /// read a reference cell
+    Deref,
 }
 
 impl<'input> ProtoType<'input> {
@@ -202,6 +232,7 @@ impl<'input> ProtoType<'input> {
                 Box::new(from.to_type(type_map, generics_map)?),
                 Box::new(to.to_type(type_map, generics_map)?),
             )),
+            P::Ref(t) => Ok(T::Ref(Box::new(t.to_type(type_map, generics_map)?))),
             P::Generic(name) => match generics_map.get(&name) {
                 Some(&n) => Ok(T::Generic(n)),
                 None => Err(Error::NameNotFound(name)),
@@ -253,13 +284,34 @@ impl Type {
                 let next = next.into_iter().fold(var, |acc, elem| max(acc, elem));
                 (Type::Tuple(v), next)
             }
+            Type::Ref(ref t) => {
+                let (t, next) = t.instantiate(var);
+                (Type::Ref(Box::new(t)), next)
+            }
+        }
+    }
+
+    /// the type with Generic(n) replaced by args[n], e.g. the type of a variant's value
+    /// in a sum type applied to args
+    pub fn substitute_generics(&self, args: &[Type]) -> Type {
+        match *self {
+            Type::Generic(n) => args.get(n as usize).cloned().unwrap_or(Type::Generic(n)),
+            Type::Function(ref from, ref to) => Type::Function(
+                Box::new(from.substitute_generics(args)),
+                Box::new(to.substitute_generics(args)),
+            ),
+            Type::Sum(n, ref v) => Type::Sum(n, v.iter().map(|t| t.substitute_generics(args)).collect()),
+            Type::Tuple(ref v) => Type::Tuple(v.iter().map(|t| t.substitute_generics(args)).collect()),
+            Type::Ref(ref t) => Type::Ref(Box::new(t.substitute_generics(args))),
+            _ => self.clone(),
         }
     }
 
-    // convert variables to generics
-    fn generalize(&mut self, map: &mut HashMap<u16, u16>) {
+    // convert variables below bound to generics
+    fn generalize(&mut self, map: &mut HashMap<u16, u16>, bound: u16) {
         match *self {
             Type::Int | Type::Bool | Type::String | Type::Unit | Type::Constructor {..} => (),
+            Type::Variable(n) if n >= bound => (),
             Type::Variable(n) => {
                 match map.get(&n) {
                     Some(&m) => *self = Type::Generic(m),
@@ -271,19 +323,43 @@ impl Type {
                 }
             }
             Type::Function(ref mut from, ref mut to) => {
-                from.generalize(map);
-                to.generalize(map);
+                from.generalize(map, bound);
+                to.generalize(map, bound);
             }
             Type::Tuple(ref mut v) |  Type::Sum(_, ref mut v) => {
                 for t in v {
-                    t.generalize(map);
+                    t.generalize(map, bound);
                 }
             }
+            Type::Ref(ref mut t) => t.generalize(map, bound),
             Type::Generic(_) => panic!("Generic not expected in generalize"), // maybe remove
         }
     }
 
     pub fn generalize_type(&mut self) {
-        self.generalize(&mut HashMap::new())
+        self.generalize(&mut HashMap::new(), u16::MAX)
+    }
+
+    /// like generalize_type, but variables >= bound are weak and stay variables
+    pub fn generalize_below(&mut self, bound: u16) {
+        self.generalize(&mut HashMap::new(), bound)
+    }
+
+    /// collect the type variables in self without repetition
+    pub fn variables(&self, vars: &mut Vec<u16>) {
+        match *self {
+            Type::Int | Type::Bool | Type::String | Type::Unit | Type::Constructor {..} | Type::Generic(_) => (),
+            Type::Variable(n) => if !vars.contains(&n) { vars.push(n) },
+            Type::Function(ref from, ref to) => {
+                from.variables(vars);
+                to.variables(vars);
+            }
+            Type::Tuple(ref v) | Type::Sum(_, ref v) => {
+                for t in v {
+                    t.variables(vars);
+                }
+            }
+            Type::Ref(ref t) => t.variables(vars),
+        }
     }
 }
\ No newline at end of file
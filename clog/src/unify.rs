@@ -112,6 +112,7 @@ impl Type {
                     t.substitute_vars(map);
                 }
             }
+            Type::Ref(ref mut t) => t.substitute_vars(map),
         }
     }
 }
@@ -138,6 +139,7 @@ pub fn unify(consts: &mut Vec<(Type, Type)>) -> Result<HashMap<u16, Type>, Error
                 consts.push((*from1, *from2));
                 consts.push((*to1, *to2));
             }
+            (Type::Ref(t1), Type::Ref(t2)) => consts.push((*t1, *t2)),
             (Type::Tuple(v), Type::Tuple(u)) => {
                 for (x, y) in v.into_iter().zip(u.into_iter()) {
                     consts.push((x, y));
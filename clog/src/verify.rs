@@ -0,0 +1,405 @@
+//! Checks the invariants of the IR that its passes rely on:
+//! - every block ends in its only terminator, whose targets are blocks of the
+//!   procedure,
+//! - every temporary is assigned before it's used on all paths to the use,
+//! - struct and array offsets are in bounds of the types they refer to,
+//! - the tag of a sum value is read before the value of a variant of it, as
+//!   documented on ValPath.
+//!
+//! Tags are tracked by the paths of the values in memory, from an argument, a
+//! captured value or a global, so a tag read through a temporary counts for the
+//! other temporaries fetched from the same path. A tag of a global read by the
+//! initializer counts in every procedure, since the initializer fails before the
+//! globals are used if it doesn't match. The body of a try or handle starts with the
+//! tags read before it, sharing the frame of the procedure it's in.
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::ir::{lower, Statement},
+        crate::parse::parse,
+        crate::type_check::ast2imper_ast,
+    };
+
+    const SRC: &str = "type Shape = | circle int | rect (int, int)
+        let area = {
+            (circle r) => 3 * r * r,
+            (rect (w, h)) => w * h,
+        }
+        let safe = {
+            (some n) => try 10 / n with { (DivisionByZero ()) => 0 },
+            (none ()) => 0,
+        }
+        let (some y) = some 3
+        let z = { n => y + n }";
+
+    /// the error found in the lowered source after changing it
+    fn broken(change: fn(&mut Module)) -> VerifyError {
+        let module = ast2imper_ast(parse(SRC).unwrap(), &[]).unwrap();
+        let mut ir = lower(&module);
+        assert_eq!(verify(&ir), Ok(()));
+        change(&mut ir);
+        verify(&ir).unwrap_err()
+    }
+
+    #[test]
+    fn lowered() {
+        let error = |procedure, block, problem| VerifyError { procedure: Some(procedure), block, problem };
+        // _2 = 3 in the arm of circle
+        assert_eq!(
+            broken(|ir| {
+                ir.procedures[0].control_flow.blocks[1].remove(0);
+            }),
+            error(0, 1, Problem::Unassigned(2))
+        );
+        // _10.0 in the arm of rect
+        assert_eq!(
+            broken(|ir| match ir.procedures[0].control_flow.blocks[2][2] {
+                Statement::Fetch(_, MemAddr::StructOffset { ref mut target, .. }) => *target = 2,
+                ref s => panic!("{:?}", s),
+            }),
+            error(0, 2, Problem::OutOfBounds(2))
+        );
+        // _1 = _0.tag before the switch
+        assert_eq!(
+            broken(|ir| ir.procedures[0].control_flow.blocks[0][1] = Statement::Fetch(1, MemAddr::Stack(0))),
+            error(0, 1, Problem::PayloadBeforeTag(3))
+        );
+        assert_eq!(
+            broken(|ir| {
+                ir.procedures[0].control_flow.blocks[1].pop();
+            }),
+            error(0, 1, Problem::NoTerminator)
+        );
+        assert_eq!(
+            broken(|ir| ir.procedures[0].control_flow.blocks[1].push(Statement::Jump(100))),
+            error(0, 1, Problem::TerminatorInside)
+        );
+        let init = broken(|ir| {
+            ir.init_fn.control_flow.blocks[0].insert(0, Statement::Return(0));
+        });
+        assert_eq!(init.procedure, None);
+        assert_eq!(init.to_string(), "init :B0: a terminator before the end of the block");
+    }
+}
+
+use std::{collections::BTreeSet, error::Error, fmt};
+
+use crate::{
+    ir::{BlockIdx, MemAddr, Module, Procedure, Statement, TmpIdx, TyIdx, Value},
+    types::Type,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    /// the procedure, none for the initializer
+    pub procedure: Option<u16>,
+    pub block: BlockIdx,
+    pub problem: Problem,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    NoTerminator,
+    TerminatorInside,
+    /// a branch to a block that isn't in the procedure
+    NoBlock(BlockIdx),
+    /// a temporary not below the number of temporaries of the procedure
+    NoTmp(TmpIdx),
+    /// a temporary used where it can be unassigned
+    Unassigned(TmpIdx),
+    /// an argument, captured value, global or procedure that doesn't exist
+    NoAddress(u16),
+    NoType(TyIdx),
+    /// an offset in a type that isn't a tuple or a sum
+    NotStruct(TyIdx),
+    /// an offset past the fields of a tuple or the variants of a sum
+    OutOfBounds(u16),
+    /// reading a variant value of the sum value in the temporary before its tag
+    PayloadBeforeTag(TmpIdx),
+    /// a try or handle of a procedure that isn't a body
+    NotBody(u16),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.procedure {
+            Some(n) => write!(f, "proc {} :B{}: ", n, self.block)?,
+            None => write!(f, "init :B{}: ", self.block)?,
+        }
+        match self.problem {
+            Problem::NoTerminator => write!(f, "the block doesn't end in a terminator"),
+            Problem::TerminatorInside => write!(f, "a terminator before the end of the block"),
+            Problem::NoBlock(b) => write!(f, "branch to :B{}, which isn't a block", b),
+            Problem::NoTmp(t) => write!(f, "_{} is past the temporaries of the procedure", t),
+            Problem::Unassigned(t) => write!(f, "_{} can be used before it's assigned", t),
+            Problem::NoAddress(n) => write!(f, "{} isn't an address or a procedure", n),
+            Problem::NoType(n) => write!(f, "type {} isn't in the type table", n),
+            Problem::NotStruct(n) => write!(f, "offset in type {}, which isn't a tuple or a sum", n),
+            Problem::OutOfBounds(n) => write!(f, "offset {} is out of the bounds of its type", n),
+            Problem::PayloadBeforeTag(t) => write!(f, "value of a variant of _{} read before its tag", t),
+            Problem::NotBody(n) => write!(f, "proc {} isn't the body of a try or handle", n),
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// where a value in memory starts from
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Root {
+    Stack(u16),
+    Captured(u16),
+    Global(u16),
+}
+
+/// the position of a value in memory, as in ValPath
+type Path = (Root, Vec<u16>);
+
+/// what holds on every path to a statement
+#[derive(Clone, PartialEq)]
+struct State {
+    assigned: Vec<bool>,
+    /// the paths the temporaries were fetched from, if they were
+    paths: Vec<Option<Path>>,
+    /// the sum values whose tag was read
+    tags: BTreeSet<Path>,
+}
+
+impl State {
+    /// what holds after both of two states
+    fn meet(&self, other: &State) -> State {
+        State {
+            assigned: self.assigned.iter().zip(&other.assigned).map(|(&a, &b)| a && b).collect(),
+            paths: self.paths.iter().zip(&other.paths).map(|(a, b)| if a == b { a.clone() } else { None }).collect(),
+            tags: self.tags.intersection(&other.tags).cloned().collect(),
+        }
+    }
+}
+
+/// check the invariants of a module
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    let mut verifier = Verifier { module, global_tags: BTreeSet::new(), body_tags: Vec::new() };
+    verifier.body_tags.resize(module.procedures.len(), None);
+    verifier.procedure(None, &module.init_fn)?;
+    // bodies come after the procedures they're in
+    for (n, procedure) in module.procedures.iter().enumerate() {
+        verifier.procedure(Some(n as u16), procedure)?;
+    }
+    Ok(())
+}
+
+struct Verifier<'m, 'input, 'types> {
+    module: &'m Module<'input, 'types>,
+    /// the tags of globals read by the initializer
+    global_tags: BTreeSet<Path>,
+    /// the tags read before each body, if it's reached
+    body_tags: Vec<Option<BTreeSet<Path>>>,
+}
+
+impl<'m, 'input, 'types> Verifier<'m, 'input, 'types> {
+    fn procedure(&mut self, n: Option<u16>, procedure: &Procedure) -> Result<(), VerifyError> {
+        let blocks = &procedure.control_flow.blocks;
+        let error = |block: usize, problem| VerifyError { procedure: n, block: block as BlockIdx, problem };
+        for (i, block) in blocks.iter().enumerate() {
+            match block.split_last() {
+                Some((last, rest)) if last.is_terminator() => {
+                    if rest.iter().any(Statement::is_terminator) {
+                        return Err(error(i, Problem::TerminatorInside));
+                    }
+                    if let Some(&b) = last.successors().iter().find(|&&b| b as usize >= blocks.len()) {
+                        return Err(error(i, Problem::NoBlock(b)));
+                    }
+                }
+                _ => return Err(error(i, Problem::NoTerminator)),
+            }
+        }
+
+        let mut tags = self.global_tags.clone();
+        if let Some(Some(ref body_tags)) = n.map(|n| &self.body_tags[n as usize]) {
+            tags.extend(body_tags.iter().cloned());
+        }
+        let num_tmps = procedure.num_tmps as usize;
+        let entry = State { assigned: vec![false; num_tmps], paths: vec![None; num_tmps], tags };
+        // the states at the start of the blocks, none until a block is reached
+        let mut starts: Vec<Option<State>> = vec![None; blocks.len()];
+        starts[0] = Some(entry);
+        // in the order of the blocks, for the first problem found to be the same
+        let mut work: BTreeSet<usize> = Some(0).into_iter().collect();
+        while let Some(b) = work.iter().next().cloned() {
+            work.remove(&b);
+            let mut state = starts[b].clone().expect("block not reached");
+            for statement in &blocks[b] {
+                self.statement(n.is_none(), procedure, statement, &mut state).map_err(|p| error(b, p))?;
+            }
+            // the states only shrink, so a block is checked again until none does
+            for next in blocks[b].last().unwrap().successors() {
+                let next = next as usize;
+                let met = match starts[next] {
+                    Some(ref start) => start.meet(&state),
+                    None => state.clone(),
+                };
+                if starts[next].as_ref() != Some(&met) {
+                    starts[next] = Some(met);
+                    work.insert(next);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(
+        &mut self,
+        init: bool,
+        procedure: &Procedure,
+        statement: &Statement,
+        state: &mut State,
+    ) -> Result<(), Problem> {
+        let used: Vec<TmpIdx> = match *statement {
+            Statement::Fetch(_, ref addr) | Statement::Store(_, ref addr) => {
+                let mut used = addr_tmps(addr);
+                if let Statement::Store(t, _) = *statement {
+                    used.push(t);
+                }
+                used
+            }
+            Statement::Assign(_, ref value) => value_tmps(value),
+            Statement::Call(_, f, arg) => vec![f, arg],
+            Statement::Conditional(t, _, _)
+            | Statement::Switch { on: t, .. }
+            | Statement::Return(t)
+            | Statement::Raise(t) => vec![t],
+            Statement::Jump(_) | Statement::Fail => Vec::new(),
+        };
+        for t in used {
+            match state.assigned.get(t as usize) {
+                None => return Err(Problem::NoTmp(t)),
+                Some(false) => return Err(Problem::Unassigned(t)),
+                Some(true) => (),
+            }
+        }
+
+        let (assigned, path) = match *statement {
+            Statement::Fetch(t, ref addr) => (t, self.fetch(init, procedure, addr, state)?),
+            Statement::Assign(t, ref value) => {
+                self.value(value, state)?;
+                match *value {
+                    Value::Variable(v) => (t, state.paths[v as usize].clone()),
+                    _ => (t, None),
+                }
+            }
+            Statement::Store(_, ref addr) => {
+                // the tags of the value stored over don't hold
+                if let MemAddr::Global(n) = *addr {
+                    state.tags.retain(|p| p.0 != Root::Global(n));
+                }
+                return self.fetch(init, procedure, addr, state).map(|_| ());
+            }
+            Statement::Call(t, _, _) => (t, None),
+            _ => return Ok(()),
+        };
+        if assigned as usize >= state.assigned.len() {
+            return Err(Problem::NoTmp(assigned));
+        }
+        state.assigned[assigned as usize] = true;
+        state.paths[assigned as usize] = path;
+        Ok(())
+    }
+
+    /// check an address, the path of the value at it if it's known
+    fn fetch(
+        &mut self,
+        init: bool,
+        procedure: &Procedure,
+        addr: &MemAddr,
+        state: &mut State,
+    ) -> Result<Option<Path>, Problem> {
+        let root = |root: Root, n: u16, len: usize| {
+            if (n as usize) < len {
+                Ok(Some((root, Vec::new())))
+            } else {
+                Err(Problem::NoAddress(n))
+            }
+        };
+        match *addr {
+            MemAddr::Stack(n) => root(Root::Stack(n), n, procedure.in_ty.len()),
+            MemAddr::Captured(n) => root(Root::Captured(n), n, procedure.captures.len()),
+            MemAddr::Global(n) => root(Root::Global(n), n, self.module.gl_layout.len()),
+            MemAddr::Numeric(_) => Ok(None),
+            MemAddr::ArrayOffset { elem_type, .. } => self.ty(elem_type).map(|_| None),
+            MemAddr::StructOffset { base, struct_type, target } => {
+                let base_path = state.paths[base as usize].clone();
+                let within = |path: Option<Path>| {
+                    path.map(|(root, mut v)| {
+                        v.push(target);
+                        (root, v)
+                    })
+                };
+                match *self.ty(struct_type)? {
+                    Type::Tuple(ref types) if (target as usize) < types.len() => Ok(within(base_path)),
+                    Type::Sum(n, _) if (target as usize) <= self.module.ty_def[n as usize].variants.len() => {
+                        match base_path {
+                            Some(path) if target == 0 => {
+                                if init && matches!(path.0, Root::Global(_)) {
+                                    self.global_tags.insert(path.clone());
+                                }
+                                state.tags.insert(path);
+                                Ok(None)
+                            }
+                            Some(ref path) if state.tags.contains(path) => Ok(within(base_path)),
+                            _ if target == 0 => Ok(None),
+                            _ => Err(Problem::PayloadBeforeTag(base)),
+                        }
+                    }
+                    Type::Tuple(_) | Type::Sum(_, _) => Err(Problem::OutOfBounds(target)),
+                    _ => Err(Problem::NotStruct(struct_type)),
+                }
+            }
+        }
+    }
+
+    fn ty(&self, n: TyIdx) -> Result<&'m Type, Problem> {
+        self.module.ty_table.get(n as usize).ok_or(Problem::NoType(n))
+    }
+
+    /// check the procedures a value refers to
+    fn value(&mut self, value: &Value, state: &State) -> Result<(), Problem> {
+        let procedure = |n: u16| self.module.procedures.get(n as usize).ok_or(Problem::NoAddress(n));
+        match *value {
+            Value::Closure(n, _) => procedure(n).map(|_| ()),
+            Value::Try(body, _) | Value::Handle { body, .. } => {
+                if !procedure(body)?.shares_frame {
+                    return Err(Problem::NotBody(body));
+                }
+                let tags = &mut self.body_tags[body as usize];
+                *tags = Some(match tags.take() {
+                    Some(tags) => tags.intersection(&state.tags).cloned().collect(),
+                    None => state.tags.clone(),
+                });
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn addr_tmps(addr: &MemAddr) -> Vec<TmpIdx> {
+    match *addr {
+        MemAddr::Numeric(t) => vec![t],
+        MemAddr::ArrayOffset { array_addr, offset, .. } => vec![array_addr, offset],
+        MemAddr::StructOffset { base, .. } => vec![base],
+        MemAddr::Stack(_) | MemAddr::Captured(_) | MemAddr::Global(_) => Vec::new(),
+    }
+}
+
+fn value_tmps(value: &Value) -> Vec<TmpIdx> {
+    match *value {
+        Value::Literal(_) | Value::Constructor(..) | Value::Import(_) => Vec::new(),
+        Value::Variable(t) | Value::Unop(_, t) | Value::Construct(_, _, t) | Value::Perform(_, t) => vec![t],
+        Value::Binop(_, t1, t2) => vec![t1, t2],
+        Value::Tuple(ref v) | Value::Closure(_, ref v) => v.clone(),
+        Value::Try(_, handler) => vec![handler],
+        Value::Handle { ret, ref ops, .. } => ret.into_iter().chain(ops.iter().map(|op| op.1)).collect(),
+    }
+}
@@ -0,0 +1,761 @@
+//! A textual form of programs: `disassemble` lists a program and `assemble` reads such
+//! a listing back, so programs can be written by hand.
+//!
+//! A listing declares the constants, types, effects, imports and globals of the
+//! program, each with its position, then its codes:
+//! ```text
+//! const 0 int 1
+//! const 1 str "done\n"
+//! type 0 exn DivisionByZero MatchFailure
+//! import 0 print
+//! global 0 code 1 at 5:1
+//!
+//! code 0 fact arity 1 captures 0
+//!     local 0
+//!     switch 0
+//! :L2
+//!     const 0  ; 1
+//!     return
+//! :L4
+//!     ...
+//!     apply at 3:22
+//!     ...
+//!     table 0 int 0 :L2 default :L4
+//! ```
+//! A code's instructions follow it until the next code, one per line, with labels like
+//! `:L2` before the instructions jumped to and its tables after them. Globals and
+//! instructions may end with their line and column in the source, `at prelude 3:22`
+//! in the prelude. Names that aren't single words are quoted like strings, and
+//! everything after a `;` is a comment.
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bytecode::{BinOp, TypeInfo},
+    };
+
+    const FACT: &str = r#"
+const 0 int 0
+const 1 int 1
+const 2 str "fact \"5\" = "
+type 0 exn DivisionByZero MatchFailure
+effect 0 "tell me"
+import 0 print
+import 1 int2str
+global 0 code 1 at 7:1
+
+code 0 fact arity 1 captures 0
+    local 0
+    switch 0
+:ZERO
+    const 1
+    return
+:MORE
+    local 0
+    global 0
+    local 0
+    const 1
+    sub
+    apply at 3:22  ; fact (n - 1)
+    mul at prelude 3:14
+    return
+    table 0 int 0 :ZERO default :MORE
+
+code 1 arity 0 captures 0
+    closure 0
+    set_global 0
+    import 0
+    const 2
+    import 1
+    global 0
+    constructor 0 2
+    pop
+    const 1
+    const 0
+    lt
+    jump_if_false :END
+    const 1
+    perform 0
+:END
+    const 1
+    apply
+    apply
+    concat
+    tail_apply
+"#;
+
+    #[test]
+    fn assembled() {
+        let program = assemble(FACT).unwrap();
+        assert_eq!(program.consts, [Const::Int(0), Const::Int(1), Const::Str("fact \"5\" = ".to_owned())]);
+        assert_eq!(program.effects, ["tell me"]);
+        assert_eq!(program.imports, ["print", "int2str"]);
+        assert_eq!(program.globals, [1]);
+        assert_eq!(program.positions, [Pos { line: 7, col: 1, prelude: false }]);
+        let fact = &program.codes[0];
+        assert_eq!(fact.name.as_deref(), Some("fact"));
+        assert_eq!(fact.tables, [Table::Int { cases: vec![(0, 2)], default: 4 }]);
+        assert_eq!(fact.instrs[9], Instr::Apply);
+        assert_eq!(fact.instrs[8], Instr::BinOp(BinOp::Sub));
+        let pos = |line, col, prelude| Pos { line, col, prelude };
+        assert_eq!(fact.positions, [(9, pos(3, 22, false)), (10, pos(3, 14, true))]);
+        assert_eq!(program.codes[1].instrs[11], Instr::JumpIfFalse(14));
+        assert_eq!(program.codes[1].name, None);
+    }
+
+    #[test]
+    fn listing() {
+        let program = assemble(FACT).unwrap();
+        let listing = disassemble(&program);
+        assert!(listing.contains("const 2 str \"fact \\\"5\\\" = \"\n"));
+        assert!(listing.contains("effect 0 \"tell me\"\n"));
+        assert!(listing.contains("\ncode 0 fact arity 1 captures 0\n    local 0\n    switch 0\n:L2\n"));
+        assert!(listing.contains("    closure 0  ; fact\n"));
+        assert!(listing.contains("global 0 code 1 at 7:1\n"));
+        assert!(listing.contains("    apply at 3:22\n    mul at prelude 3:14\n"));
+        assert!(listing.contains("    constructor 0 2  ; exn.MatchFailure\n"));
+        assert!(listing.contains("    perform 0  ; \"tell me\"\n"));
+        assert!(listing.contains("    table 0 int 0 :L2 default :L4\n"));
+        assert_eq!(assemble(&listing).unwrap(), program);
+
+        // every instruction and table is read back
+        let mut all = program.clone();
+        all.types.push(TypeInfo { name: "Option".to_owned(), variants: vec!["none".to_owned(), "some".to_owned()] });
+        all.codes[0].instrs.extend_from_slice(&[
+            Instr::Unit,
+            Instr::Bool(true),
+            Instr::Bool(false),
+            Instr::Capture(1),
+            Instr::Dup,
+            Instr::Field(2),
+            Instr::Tag,
+            Instr::Payload,
+            Instr::Tuple(3),
+            Instr::Construct(1, 2),
+            Instr::Jump(0),
+            Instr::Switch(1),
+            Instr::UnOp(UnOp::Deref),
+            Instr::BinOp(BinOp::Assign),
+            Instr::Raise,
+            Instr::Fail,
+            Instr::Try(1),
+            Instr::Handle(3),
+        ]);
+        all.codes[0].tables.extend(vec![
+            Table::Jump { targets: vec![2, 4], default: 0 },
+            Table::Str { cases: vec![(2, 4)], default: 2 },
+            Table::Handler { body: 1, ret: true, ops: vec![0, 0] },
+            Table::Handler { body: 1, ret: false, ops: vec![] },
+        ]);
+        all.codes.push(Code::default());
+        assert_eq!(assemble(&disassemble(&all)).unwrap(), all);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| assemble(text).unwrap_err().to_string();
+        assert_eq!(error("const 1 int 0"), "line 1: expected const 0");
+        assert_eq!(error("const 0 int x"), "line 1: expected a number, found x");
+        assert_eq!(error("const 0 str \"a"), "line 1: unterminated string");
+        assert_eq!(error("\ncode 0 arity 0 captures 0\n    jump :X"), "line 3: no label X in the code");
+        assert_eq!(error("code 0 arity 0 captures 0\n    jump"), "line 2: expected a label");
+        assert_eq!(error("code 0 arity 0 captures 0\n    push 1"), "line 2: unknown instruction push");
+        assert_eq!(error("code 0 arity 0 captures 0\n    pop 1"), "line 2: unexpected 1");
+        // the declarations come before the codes
+        assert_eq!(error("code 0 arity 0 captures 0\nimport 0 print"), "line 2: unexpected print");
+        assert_eq!(error("code 0 arity 0 captures 0\n:A\n:A"), "line 3: label A is already defined");
+        assert_eq!(error("global 0 code 0 at 3"), "line 1: expected a line and column, found 3");
+        assert_eq!(error("code 0 arity 0 captures 0\n    apply at"), "line 2: expected a line and column");
+    }
+}
+
+use {
+    crate::bytecode::{BinOp, Code, Const, Instr, Pos, Program, Table, UnOp},
+    std::{
+        collections::{BTreeSet, HashMap},
+        fmt,
+        str::FromStr,
+    },
+};
+
+/// the listing of a program, any program can be listed
+pub fn disassemble(program: &Program) -> String {
+    let mut s = String::new();
+    for (i, c) in program.consts.iter().enumerate() {
+        match *c {
+            Const::Int(n) => s += &format!("const {} int {}\n", i, n),
+            Const::Str(ref text) => s += &format!("const {} str {:?}\n", i, text),
+        }
+    }
+    for (i, t) in program.types.iter().enumerate() {
+        s += &format!("type {} {}", i, name(&t.name));
+        for v in &t.variants {
+            s += &format!(" {}", name(v));
+        }
+        s += "\n";
+    }
+    for (i, e) in program.effects.iter().enumerate() {
+        s += &format!("effect {} {}\n", i, name(e));
+    }
+    for (i, import) in program.imports.iter().enumerate() {
+        s += &format!("import {} {}\n", i, name(import));
+    }
+    for (i, g) in program.globals.iter().enumerate() {
+        s += &format!("global {} code {}", i, g);
+        if let Some(&pos) = program.positions.get(i) {
+            s += &at(pos);
+        }
+        s += "\n";
+    }
+    for (n, code) in program.codes.iter().enumerate() {
+        s += &format!("\ncode {} ", n);
+        if let Some(ref code_name) = code.name {
+            s += &format!("{} ", name(code_name));
+        }
+        s += &format!("arity {} captures {}\n", code.arity, code.captures);
+        let targets = targets(code);
+        let mut positions = code.positions.iter().peekable();
+        for (pc, &instr) in code.instrs.iter().enumerate() {
+            if targets.contains(&(pc as u32)) {
+                s += &format!(":L{}\n", pc);
+            }
+            let (mut text, comment) = instr_text(program, instr);
+            if let Some(&(_, pos)) = positions.next_if(|p| p.0 == pc as u32) {
+                text += &at(pos);
+            }
+            match comment {
+                Some(comment) => s += &format!("    {}  ; {}\n", text, comment),
+                None => s += &format!("    {}\n", text),
+            }
+        }
+        for (t, table) in code.tables.iter().enumerate() {
+            s += &format!("    table {} {}\n", t, table_text(program, table));
+        }
+    }
+    s
+}
+
+/// the instructions jumped to in a code
+fn targets(code: &Code) -> BTreeSet<u32> {
+    let mut targets = BTreeSet::new();
+    for &instr in &code.instrs {
+        if let Instr::Jump(t) | Instr::JumpIfFalse(t) = instr {
+            targets.insert(t);
+        }
+    }
+    for table in &code.tables {
+        match *table {
+            Table::Jump { targets: ref ts, default } => targets.extend(ts.iter().copied().chain(Some(default))),
+            Table::Int { ref cases, default } => targets.extend(cases.iter().map(|c| c.1).chain(Some(default))),
+            Table::Str { ref cases, default } => targets.extend(cases.iter().map(|c| c.1).chain(Some(default))),
+            Table::Handler { .. } => {}
+        }
+    }
+    targets
+}
+
+/// the position of a global or an instruction, after it
+fn at(pos: Pos) -> String {
+    let prelude = if pos.prelude { "prelude " } else { "" };
+    format!(" at {}{}:{}", prelude, pos.line, pos.col)
+}
+
+/// a name as a word if it's one, else quoted
+fn name(name: &str) -> String {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ';' || c == '"' || c == ':') {
+        format!("{:?}", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+/// an instruction and a comment describing what it refers to
+fn instr_text(program: &Program, instr: Instr) -> (String, Option<String>) {
+    let code_name = |n: u16| program.codes.get(n as usize).and_then(|c| c.name.as_ref()).map(|n| name(n));
+    let variant = |n: u16, m: u16| {
+        let t = program.types.get(n as usize)?;
+        let v = t.variants.get((m as usize).checked_sub(1)?)?;
+        Some(format!("{}.{}", name(&t.name), name(v)))
+    };
+    let op = |text: &str, n: u16| format!("{} {}", text, n);
+    match instr {
+        Instr::Const(n) => {
+            let value = program.consts.get(n as usize).map(|c| match *c {
+                Const::Int(i) => i.to_string(),
+                Const::Str(ref s) => format!("{:?}", s),
+            });
+            (format!("const {}", n), value)
+        }
+        Instr::Unit => ("unit".to_owned(), None),
+        Instr::Bool(b) => (b.to_string(), None),
+        Instr::Local(n) => (op("local", n), None),
+        Instr::Capture(n) => (op("capture", n), None),
+        Instr::Global(n) => (op("global", n), None),
+        Instr::SetGlobal(n) => (op("set_global", n), None),
+        Instr::Dup => ("dup".to_owned(), None),
+        Instr::Pop => ("pop".to_owned(), None),
+        Instr::Field(n) => (op("field", n), None),
+        Instr::Tag => ("tag".to_owned(), None),
+        Instr::Payload => ("payload".to_owned(), None),
+        Instr::Tuple(n) => (op("tuple", n), None),
+        Instr::Construct(n, m) => (format!("construct {} {}", n, m), variant(n, m)),
+        Instr::Constructor(n, m) => (format!("constructor {} {}", n, m), variant(n, m)),
+        Instr::Import(n) => (op("import", n), program.imports.get(n as usize).map(|i| name(i))),
+        Instr::Closure(n) => (op("closure", n), code_name(n)),
+        Instr::Apply => ("apply".to_owned(), None),
+        Instr::TailApply => ("tail_apply".to_owned(), None),
+        Instr::Call(n) => (op("call", n), None),
+        Instr::TailCall(n) => (op("tail_call", n), None),
+        Instr::Return => ("return".to_owned(), None),
+        Instr::Jump(t) => (format!("jump :L{}", t), None),
+        Instr::JumpIfFalse(t) => (format!("jump_if_false :L{}", t), None),
+        Instr::Switch(n) => (op("switch", n), None),
+        Instr::BinOp(o) => (BINOPS.iter().find(|b| b.1 == o).map_or("?", |b| b.0).to_owned(), None),
+        Instr::UnOp(o) => (UNOPS.iter().find(|u| u.1 == o).map_or("?", |u| u.0).to_owned(), None),
+        Instr::Raise => ("raise".to_owned(), None),
+        Instr::Fail => ("fail".to_owned(), None),
+        Instr::Try(n) => (op("try", n), code_name(n)),
+        Instr::Handle(n) => (op("handle", n), None),
+        Instr::Perform(n) => (op("perform", n), program.effects.get(n as usize).map(|e| name(e))),
+    }
+}
+
+fn table_text(program: &Program, table: &Table) -> String {
+    let label = |t: u32| format!(":L{}", t);
+    match *table {
+        Table::Jump { ref targets, default } => {
+            let mut s = "jump".to_owned();
+            for &t in targets {
+                s += &format!(" {}", label(t));
+            }
+            s + &format!(" default {}", label(default))
+        }
+        Table::Int { ref cases, default } => {
+            let mut s = "int".to_owned();
+            for &(i, t) in cases {
+                s += &format!(" {} {}", i, label(t));
+            }
+            s + &format!(" default {}", label(default))
+        }
+        Table::Str { ref cases, default } => {
+            let mut s = "str".to_owned();
+            let mut strs = Vec::new();
+            for &(c, t) in cases {
+                s += &format!(" {} {}", c, label(t));
+                if let Some(Const::Str(text)) = program.consts.get(c as usize) {
+                    strs.push(format!("{:?}", text));
+                }
+            }
+            s += &format!(" default {}", label(default));
+            if !strs.is_empty() {
+                s += &format!("  ; {}", strs.join(" "));
+            }
+            s
+        }
+        Table::Handler { body, ret, ref ops } => {
+            let mut s = format!("handler code {}", body);
+            if ret {
+                s += " return";
+            }
+            s += " ops";
+            for &op in ops {
+                s += &format!(" {}", op);
+            }
+            let names = ops.iter().filter_map(|&op| program.effects.get(op as usize)).map(|e| name(e));
+            let names: Vec<_> = names.collect();
+            if !names.is_empty() {
+                s += &format!("  ; {}", names.join(" "));
+            }
+            s
+        }
+    }
+}
+
+/// mnemonics of the operators
+const BINOPS: [(&str, BinOp); 15] = [
+    ("add", BinOp::Add),
+    ("sub", BinOp::Sub),
+    ("mul", BinOp::Mul),
+    ("div", BinOp::Div),
+    ("mod", BinOp::Mod),
+    ("eq", BinOp::Eq),
+    ("ne", BinOp::Ne),
+    ("lt", BinOp::Lt),
+    ("le", BinOp::Le),
+    ("gt", BinOp::Gt),
+    ("ge", BinOp::Ge),
+    ("and", BinOp::And),
+    ("or", BinOp::Or),
+    ("concat", BinOp::Concat),
+    ("assign", BinOp::Assign),
+];
+const UNOPS: [(&str, UnOp); 4] = [("not", UnOp::Not), ("neg", UnOp::Neg), ("ref", UnOp::Ref), ("deref", UnOp::Deref)];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// counted from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+type AsmResult<T> = Result<T, AsmError>;
+
+/// the program of a listing. The instructions aren't checked to refer to things that
+/// exist, see `lbc::validate`.
+pub fn assemble(text: &str) -> AsmResult<Program> {
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = Line::new(i + 1, line)?;
+        if !line.tokens.is_empty() {
+            lines.push(line);
+        }
+    }
+    let mut program = Program::default();
+    let mut i = 0;
+    while i < lines.len() {
+        let mut line = lines[i].clone();
+        let keyword = line.word()?;
+        i += 1;
+        match keyword {
+            "const" => {
+                line.position("const", program.consts.len())?;
+                let c = match line.word()? {
+                    "int" => Const::Int(line.num()?),
+                    "str" => Const::Str(line.string()?),
+                    kind => return Err(line.error(format!("unknown kind of constant {}", kind))),
+                };
+                program.consts.push(c);
+            }
+            "type" => {
+                line.position("type", program.types.len())?;
+                let name = line.name()?;
+                let mut variants = Vec::new();
+                while !line.done() {
+                    variants.push(line.name()?);
+                }
+                program.types.push(crate::bytecode::TypeInfo { name, variants });
+            }
+            "effect" => {
+                line.position("effect", program.effects.len())?;
+                program.effects.push(line.name()?);
+            }
+            "import" => {
+                line.position("import", program.imports.len())?;
+                program.imports.push(line.name()?);
+            }
+            "global" => {
+                line.position("global", program.globals.len())?;
+                line.keyword("code")?;
+                program.globals.push(line.num()?);
+                if line.peek() == Some("at") {
+                    program.positions.push(line.pos()?);
+                }
+            }
+            "code" => {
+                // the lines of the code go until the next one
+                let end = lines[i..].iter().position(|l| l.tokens[0] == "code").map_or(lines.len(), |n| i + n);
+                line.position("code", program.codes.len())?;
+                let code = code(&mut line, &lines[i..end])?;
+                program.codes.push(code);
+                i = end;
+            }
+            _ => return Err(line.error(format!("unknown declaration {}", keyword))),
+        }
+        line.end()?;
+    }
+    Ok(program)
+}
+
+/// a code from its header and the lines after it
+fn code(header: &mut Line, lines: &[Line]) -> AsmResult<Code> {
+    // the name is optional, it's there when the header has one more token
+    let name = if header.tokens.len() == 7 { Some(header.name()?) } else { None };
+    header.keyword("arity")?;
+    let arity = header.num()?;
+    header.keyword("captures")?;
+    let captures = header.num()?;
+    let mut code = Code { name, arity, captures, ..Code::default() };
+
+    let mut labels = HashMap::new();
+    let mut pc = 0;
+    for line in lines {
+        let first = line.tokens[0];
+        if let Some(label) = first.strip_prefix(':') {
+            if labels.insert(label, pc).is_some() {
+                return Err(line.error(format!("label {} is already defined", label)));
+            }
+        } else if first != "table" {
+            pc += 1;
+        }
+    }
+    for line in lines {
+        let mut line = line.clone();
+        let first = line.word()?;
+        if first.starts_with(':') {
+            // defined above
+        } else if first == "table" {
+            line.position("table", code.tables.len())?;
+            let table = table(&mut line, &labels)?;
+            code.tables.push(table);
+        } else {
+            let instr = instr(first, &mut line, &labels)?;
+            if line.peek() == Some("at") {
+                code.positions.push((code.instrs.len() as u32, line.pos()?));
+            }
+            code.instrs.push(instr);
+        }
+        line.end()?;
+    }
+    Ok(code)
+}
+
+fn instr(mnemonic: &str, line: &mut Line, labels: &HashMap<&str, u32>) -> AsmResult<Instr> {
+    if let Some(&(_, op)) = BINOPS.iter().find(|b| b.0 == mnemonic) {
+        return Ok(Instr::BinOp(op));
+    }
+    if let Some(&(_, op)) = UNOPS.iter().find(|u| u.0 == mnemonic) {
+        return Ok(Instr::UnOp(op));
+    }
+    Ok(match mnemonic {
+        "const" => Instr::Const(line.num()?),
+        "unit" => Instr::Unit,
+        "true" => Instr::Bool(true),
+        "false" => Instr::Bool(false),
+        "local" => Instr::Local(line.num()?),
+        "capture" => Instr::Capture(line.num()?),
+        "global" => Instr::Global(line.num()?),
+        "set_global" => Instr::SetGlobal(line.num()?),
+        "dup" => Instr::Dup,
+        "pop" => Instr::Pop,
+        "field" => Instr::Field(line.num()?),
+        "tag" => Instr::Tag,
+        "payload" => Instr::Payload,
+        "tuple" => Instr::Tuple(line.num()?),
+        "construct" => Instr::Construct(line.num()?, line.num()?),
+        "constructor" => Instr::Constructor(line.num()?, line.num()?),
+        "import" => Instr::Import(line.num()?),
+        "closure" => Instr::Closure(line.num()?),
+        "apply" => Instr::Apply,
+        "tail_apply" => Instr::TailApply,
+        "call" => Instr::Call(line.num()?),
+        "tail_call" => Instr::TailCall(line.num()?),
+        "return" => Instr::Return,
+        "jump" => Instr::Jump(line.label(labels)?),
+        "jump_if_false" => Instr::JumpIfFalse(line.label(labels)?),
+        "switch" => Instr::Switch(line.num()?),
+        "raise" => Instr::Raise,
+        "fail" => Instr::Fail,
+        "try" => Instr::Try(line.num()?),
+        "handle" => Instr::Handle(line.num()?),
+        "perform" => Instr::Perform(line.num()?),
+        _ => return Err(line.error(format!("unknown instruction {}", mnemonic))),
+    })
+}
+
+fn table(line: &mut Line, labels: &HashMap<&str, u32>) -> AsmResult<Table> {
+    Ok(match line.word()? {
+        "jump" => {
+            let mut targets = Vec::new();
+            while line.peek() != Some("default") {
+                targets.push(line.label(labels)?);
+            }
+            line.keyword("default")?;
+            Table::Jump { targets, default: line.label(labels)? }
+        }
+        "int" => {
+            let mut cases = Vec::new();
+            while line.peek() != Some("default") {
+                cases.push((line.num()?, line.label(labels)?));
+            }
+            line.keyword("default")?;
+            Table::Int { cases, default: line.label(labels)? }
+        }
+        "str" => {
+            let mut cases = Vec::new();
+            while line.peek() != Some("default") {
+                cases.push((line.num()?, line.label(labels)?));
+            }
+            line.keyword("default")?;
+            Table::Str { cases, default: line.label(labels)? }
+        }
+        "handler" => {
+            line.keyword("code")?;
+            let body = line.num()?;
+            let ret = line.peek() == Some("return");
+            if ret {
+                line.word()?;
+            }
+            line.keyword("ops")?;
+            let mut ops = Vec::new();
+            while !line.done() {
+                ops.push(line.num()?);
+            }
+            Table::Handler { body, ret, ops }
+        }
+        kind => return Err(line.error(format!("unknown kind of table {}", kind))),
+    })
+}
+
+/// the words and strings of a line without its comment, and the next one to read
+#[derive(Clone)]
+struct Line<'t> {
+    n: usize,
+    tokens: Vec<&'t str>,
+    next: usize,
+}
+
+impl<'t> Line<'t> {
+    fn new(n: usize, text: &'t str) -> AsmResult<Self> {
+        let mut tokens = Vec::new();
+        let mut rest = text.trim_start();
+        while !rest.is_empty() && !rest.starts_with(';') {
+            let len = if rest.starts_with('"') {
+                // to the closing quote, skipping escaped characters
+                let mut escaped = false;
+                let close = rest.char_indices().skip(1).find(|&(_, c)| {
+                    let closes = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    closes
+                });
+                match close {
+                    Some((i, _)) => i + 1,
+                    None => return Err(AsmError { line: n, message: "unterminated string".to_owned() }),
+                }
+            } else {
+                rest.find(|c: char| c.is_whitespace() || c == ';').unwrap_or(rest.len())
+            };
+            tokens.push(&rest[..len]);
+            rest = rest[len..].trim_start();
+        }
+        Ok(Line { n, tokens, next: 0 })
+    }
+
+    fn error(&self, message: String) -> AsmError {
+        AsmError { line: self.n, message }
+    }
+
+    fn peek(&self) -> Option<&'t str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn done(&self) -> bool {
+        self.next == self.tokens.len()
+    }
+
+    fn end(&self) -> AsmResult<()> {
+        match self.peek() {
+            Some(token) => Err(self.error(format!("unexpected {}", token))),
+            None => Ok(()),
+        }
+    }
+
+    fn word(&mut self) -> AsmResult<&'t str> {
+        let token = self.peek().ok_or_else(|| self.error("unexpected end of line".to_owned()))?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> AsmResult<()> {
+        match self.peek() {
+            Some(token) if token == keyword => {
+                self.next += 1;
+                Ok(())
+            }
+            _ => Err(self.error(format!("expected {}", keyword))),
+        }
+    }
+
+    fn num<T: FromStr>(&mut self) -> AsmResult<T> {
+        let token = self.peek().ok_or_else(|| self.error("expected a number".to_owned()))?;
+        let n = token.parse().map_err(|_| self.error(format!("expected a number, found {}", token)))?;
+        self.next += 1;
+        Ok(n)
+    }
+
+    /// the position of a declaration, which must be the next one
+    fn position(&mut self, what: &str, expected: usize) -> AsmResult<()> {
+        match self.num::<usize>() {
+            Ok(n) if n == expected => Ok(()),
+            _ => Err(self.error(format!("expected {} {}", what, expected))),
+        }
+    }
+
+    /// a position after `at`, e.g. `at 3:22` or `at prelude 3:22`
+    fn pos(&mut self) -> AsmResult<Pos> {
+        self.keyword("at")?;
+        let prelude = self.peek() == Some("prelude");
+        if prelude {
+            self.next += 1;
+        }
+        let token = self.peek().ok_or_else(|| self.error("expected a line and column".to_owned()))?;
+        let parsed = token.split_once(':').and_then(|(line, col)| Some((line.parse().ok()?, col.parse().ok()?)));
+        let (line, col) = parsed.ok_or_else(|| self.error(format!("expected a line and column, found {}", token)))?;
+        self.next += 1;
+        Ok(Pos { line, col, prelude })
+    }
+
+    fn label(&mut self, labels: &HashMap<&str, u32>) -> AsmResult<u32> {
+        match self.peek() {
+            Some(token) if token.starts_with(':') => match labels.get(&token[1..]) {
+                Some(&pc) => {
+                    self.next += 1;
+                    Ok(pc)
+                }
+                None => Err(self.error(format!("no label {} in the code", &token[1..]))),
+            },
+            _ => Err(self.error("expected a label".to_owned())),
+        }
+    }
+
+    fn name(&mut self) -> AsmResult<String> {
+        match self.peek() {
+            Some(token) if token.starts_with('"') => self.string(),
+            _ => Ok(self.word()?.to_owned()),
+        }
+    }
+
+    /// a quoted string with the escapes of Rust
+    fn string(&mut self) -> AsmResult<String> {
+        let token = self.word()?;
+        if !token.starts_with('"') {
+            return Err(self.error(format!("expected a string, found {}", token)));
+        }
+        let invalid = || AsmError { line: self.n, message: format!("invalid string {}", token) };
+        let mut s = String::new();
+        let mut chars = token[1..token.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            s.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let close = rest.find('}').ok_or_else(invalid)?;
+                    if !rest.starts_with('{') {
+                        return Err(invalid());
+                    }
+                    let code = u32::from_str_radix(&rest[1..close], 16).map_err(|_| invalid())?;
+                    chars = rest[close + 1..].chars();
+                    std::char::from_u32(code).ok_or_else(invalid)?
+                }
+                _ => return Err(invalid()),
+            });
+        }
+        Ok(s)
+    }
+}
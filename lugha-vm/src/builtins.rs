@@ -0,0 +1,249 @@
+//! Functions provided by the machine, found by the names of the imports of a program.
+//! They're the builtins of the interpreter with the same names and behavior, except
+//! callcc which the machine applies itself because it captures the stack.
+//!
+//! The I/O builtins fail when called unless I/O is allowed by the `Capabilities`.
+
+use {
+    crate::{
+        bytecode::{
+            LIST_CONS, LIST_NIL, LIST_TYPE, OPTION_NONE, OPTION_SOME, OPTION_TYPE, RESULT_ERR, RESULT_OK, RESULT_TYPE,
+        },
+        heap::Heap,
+        vm::{Error, Value},
+    },
+    std::{
+        env, fs,
+        io::{self, BufRead, Write},
+    },
+};
+
+type BuiltinResult = Result<Value, Error>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    /// number of arguments applied before it's called
+    pub arity: usize,
+    /// called with exactly `arity` arguments, whose objects are in the heap
+    pub call: fn(&mut Io, &mut Heap, &[Value]) -> BuiltinResult,
+}
+
+/// what programs may do besides computing and using the standard input and output
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// the I/O builtins can be called
+    pub io: bool,
+    /// the arguments of the program, returned by args
+    pub args: Vec<String>,
+}
+
+/// the output of print and the capabilities of the program
+pub struct Io {
+    pub out: Box<dyn Write>,
+    pub caps: Capabilities,
+}
+
+pub static BUILTINS: &[Builtin] = &[
+    Builtin { name: "print", arity: 1, call: print },
+    Builtin { name: "i2str", arity: 1, call: int2str },
+    Builtin { name: "int2str", arity: 1, call: int2str },
+    Builtin { name: "str2int", arity: 1, call: str2int },
+    Builtin { name: "strlen", arity: 1, call: strlen },
+    Builtin { name: "substr", arity: 3, call: substr },
+    Builtin { name: "ord", arity: 1, call: ord },
+    Builtin { name: "chr", arity: 1, call: chr },
+    Builtin { name: "min", arity: 2, call: min },
+    Builtin { name: "max", arity: 2, call: max },
+    Builtin { name: "abs", arity: 1, call: abs },
+    Builtin { name: "read_line", arity: 1, call: read_line },
+    Builtin { name: "read_file", arity: 1, call: read_file },
+    Builtin { name: "write_file", arity: 2, call: write_file },
+    Builtin { name: "list_dir", arity: 1, call: list_dir },
+    Builtin { name: "getenv", arity: 1, call: getenv },
+    Builtin { name: "args", arity: 1, call: args },
+];
+
+/// the builtin with a name
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+fn some(heap: &mut Heap, v: Value) -> Value {
+    heap.new_sum(OPTION_TYPE, OPTION_SOME, v)
+}
+
+fn none(heap: &mut Heap) -> Value {
+    heap.new_sum(OPTION_TYPE, OPTION_NONE, Value::Unit)
+}
+
+fn ok(heap: &mut Heap, v: Value) -> Value {
+    heap.new_sum(RESULT_TYPE, RESULT_OK, v)
+}
+
+fn err(heap: &mut Heap, e: io::Error) -> Value {
+    let message = heap.new_str(e.to_string());
+    heap.new_sum(RESULT_TYPE, RESULT_ERR, message)
+}
+
+/// a list of strings in the same order
+fn list_of(heap: &mut Heap, strs: Vec<String>) -> Value {
+    let mut l = heap.new_sum(LIST_TYPE, LIST_NIL, Value::Unit);
+    for s in strs.into_iter().rev() {
+        let s = heap.new_str(s);
+        let cell = heap.new_tuple(vec![s, l]);
+        l = heap.new_sum(LIST_TYPE, LIST_CONS, cell);
+    }
+    l
+}
+
+fn int(v: &Value, name: &'static str) -> Result<isize, Error> {
+    match *v {
+        Value::Int(i) => Ok(i),
+        _ => Err(Error::BadArgument(name)),
+    }
+}
+
+fn string<'h>(heap: &'h Heap, v: &Value, name: &'static str) -> Result<&'h str, Error> {
+    match *v {
+        Value::Str(s) => Ok(heap.str(s)),
+        _ => Err(Error::BadArgument(name)),
+    }
+}
+
+fn allowed(io: &Io, name: &'static str) -> Result<(), Error> {
+    if io.caps.io {
+        Ok(())
+    } else {
+        Err(Error::Host(name, "I/O isn't allowed, run cerebral with --allow-io".to_owned()))
+    }
+}
+
+fn print(io: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    let s = string(heap, &args[0], "print")?.replace("\\n", "\n");
+    io.out.write_all(s.as_bytes()).map_err(|e| Error::Host("print", e.to_string()))?;
+    Ok(Value::Unit)
+}
+
+fn int2str(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(heap.new_str(int(&args[0], "int2str")?.to_string()))
+}
+
+fn str2int(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(match string(heap, &args[0], "str2int")?.parse() {
+        Ok(i) => some(heap, Value::Int(i)),
+        Err(_) => none(heap),
+    })
+}
+
+fn strlen(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(Value::Int(string(heap, &args[0], "strlen")?.chars().count() as isize))
+}
+
+/// the characters of a string from a start index with a length
+fn substr(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    let s = string(heap, &args[0], "substr")?;
+    let (start, len) = (int(&args[1], "substr")?, int(&args[2], "substr")?);
+    if start < 0 || len < 0 || start + len > s.chars().count() as isize {
+        return Err(Error::BadArgument("substr"));
+    }
+    let sub = s.chars().skip(start as usize).take(len as usize).collect();
+    Ok(heap.new_str(sub))
+}
+
+/// code of the first character of a string
+fn ord(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    match string(heap, &args[0], "ord")?.chars().next() {
+        Some(c) => Ok(Value::Int(c as isize)),
+        None => Err(Error::BadArgument("ord")),
+    }
+}
+
+/// string of the character with a code
+fn chr(_: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    let code = int(&args[0], "chr")?;
+    if code < 0 || code > u32::MAX as isize {
+        return Err(Error::BadArgument("chr"));
+    }
+    match std::char::from_u32(code as u32) {
+        Some(c) => Ok(heap.new_str(c.to_string())),
+        None => Err(Error::BadArgument("chr")),
+    }
+}
+
+fn min(_: &mut Io, _: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(Value::Int(int(&args[0], "min")?.min(int(&args[1], "min")?)))
+}
+
+fn max(_: &mut Io, _: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(Value::Int(int(&args[0], "max")?.max(int(&args[1], "max")?)))
+}
+
+fn abs(_: &mut Io, _: &mut Heap, args: &[Value]) -> BuiltinResult {
+    Ok(Value::Int(int(&args[0], "abs")?.wrapping_abs()))
+}
+
+/// next line of the standard input without its line ending, none at the end of input
+fn read_line(io: &mut Io, heap: &mut Heap, _: &[Value]) -> BuiltinResult {
+    // a prompt printed before reading is shown
+    let _ = io.out.flush();
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => Ok(none(heap)),
+        Ok(_) => {
+            let len = line.trim_end_matches(&['\n', '\r'][..]).len();
+            line.truncate(len);
+            let line = heap.new_str(line);
+            Ok(some(heap, line))
+        }
+    }
+}
+
+fn read_file(io: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    allowed(io, "read_file")?;
+    Ok(match fs::read_to_string(string(heap, &args[0], "read_file")?) {
+        Ok(contents) => {
+            let contents = heap.new_str(contents);
+            ok(heap, contents)
+        }
+        Err(e) => err(heap, e),
+    })
+}
+
+fn write_file(io: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    allowed(io, "write_file")?;
+    Ok(match fs::write(string(heap, &args[0], "write_file")?, string(heap, &args[1], "write_file")?) {
+        Ok(()) => ok(heap, Value::Unit),
+        Err(e) => err(heap, e),
+    })
+}
+
+/// names of the entries of a directory, sorted
+fn list_dir(io: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    allowed(io, "list_dir")?;
+    let entries: io::Result<Vec<String>> = fs::read_dir(string(heap, &args[0], "list_dir")?)
+        .and_then(|dir| dir.map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned())).collect());
+    Ok(match entries {
+        Ok(mut names) => {
+            names.sort();
+            let names = list_of(heap, names);
+            ok(heap, names)
+        }
+        Err(e) => err(heap, e),
+    })
+}
+
+fn getenv(io: &mut Io, heap: &mut Heap, args: &[Value]) -> BuiltinResult {
+    allowed(io, "getenv")?;
+    Ok(match env::var(string(heap, &args[0], "getenv")?) {
+        Ok(v) => {
+            let v = heap.new_str(v);
+            some(heap, v)
+        }
+        Err(_) => none(heap),
+    })
+}
+
+fn args(io: &mut Io, heap: &mut Heap, _: &[Value]) -> BuiltinResult {
+    allowed(io, "args")?;
+    Ok(list_of(heap, io.caps.args.clone()))
+}
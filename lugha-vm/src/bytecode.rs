@@ -0,0 +1,211 @@
+//! The instruction set of the VM and the programs made of it.
+//!
+//! A program is a table of codes, one per closure of the source. Instructions work on
+//! the operand stack of the running frame, whose locals are the arguments of its
+//! closure and don't change. Instructions are small and `Copy`; jump tables, switches
+//! and effect handlers are kept in tables of the code that refers to them.
+//!
+//! The values of a program are the same as the interpreter's: sum values hold the
+//! position of their constructor counted from 1, a tag is that position minus 1, and
+//! a boolean is tested like a tag with `true` at 0 and `false` at 1.
+
+use std::fmt;
+
+/// index of the built-in exception type among the types of a program, exception
+/// declarations add variants to it
+pub const EXN_TYPE: u16 = 0;
+/// position of the built-in exceptions among the variants of exn
+pub const EXN_DIV_BY_ZERO: u16 = 1;
+pub const EXN_MATCH_FAILURE: u16 = 2;
+/// index of the built-in option type, `type Option t = | none () | some t`
+pub const OPTION_TYPE: u16 = 1;
+pub const OPTION_NONE: u16 = 1;
+pub const OPTION_SOME: u16 = 2;
+/// index of the built-in result type, `type Result (t, e) = | ok t | err e`
+pub const RESULT_TYPE: u16 = 2;
+pub const RESULT_OK: u16 = 1;
+pub const RESULT_ERR: u16 = 2;
+/// index of the built-in list type, `type List t = | nil () | cons (t, List t)`
+pub const LIST_TYPE: u16 = 3;
+pub const LIST_NIL: u16 = 1;
+pub const LIST_CONS: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instr {
+    /// push a constant of the program
+    Const(u32),
+    Unit,
+    Bool(bool),
+    /// push the nth argument of the running closure
+    Local(u16),
+    /// push the nth captured value of the running closure
+    Capture(u16),
+    /// push the value of the nth top-level declaration
+    Global(u16),
+    /// pop a value into the nth top-level declaration
+    SetGlobal(u16),
+    Dup,
+    Pop,
+    /// replace a tuple by its nth element
+    Field(u16),
+    /// replace a sum value by its tag
+    Tag,
+    /// replace a sum value by the value its constructor was applied to
+    Payload,
+    /// pop n values and push the tuple of them, the first value popped is the last
+    Tuple(u16),
+    /// replace a value by the value of nth type's mth constructor applied to it
+    Construct(u16, u16),
+    /// push nth type's mth constructor as a function
+    Constructor(u16, u16),
+    /// push the nth import of the program as a function
+    Import(u16),
+    /// pop the captured values of nth code and push its closure
+    Closure(u16),
+    /// pop an argument and a function and apply the function to it
+    Apply,
+    /// apply in tail position, the running frame is replaced by the call
+    TailApply,
+    /// pop n arguments and a function, a closure of a code of arity n with none
+    /// applied yet, and call it with all of them
+    Call(u16),
+    /// call in tail position
+    TailCall(u16),
+    /// pop the value of the running frame and return it
+    Return,
+    Jump(u32),
+    /// pop a boolean and jump if it's false
+    JumpIfFalse(u32),
+    /// pop a tag, boolean, int or string and jump through nth table of the code
+    Switch(u16),
+    BinOp(BinOp),
+    UnOp(UnOp),
+    /// pop an exception value and raise it
+    Raise,
+    /// raise MatchFailure, no pattern matched
+    Fail,
+    /// pop an exception handler closure and run nth code in the environment of the
+    /// running frame, exceptions raised by it are applied to the handler
+    Try(u16),
+    /// pop the arms of nth table, a Handler, and run its body under them
+    Handle(u16),
+    /// pop a value and perform the nth effect with it
+    Perform(u16),
+}
+
+/// operations on two values, evaluated after both operands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Concat,
+    /// store the right value in the left ref
+    Assign,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Not,
+    Neg,
+    /// a new ref holding the value
+    Ref,
+    Deref,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Const {
+    Int(isize),
+    Str(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Table {
+    /// targets by tag, or by boolean, and the target of any other tag
+    Jump { targets: Vec<u32>, default: u32 },
+    /// targets by int, sorted by int
+    Int { cases: Vec<(isize, u32)>, default: u32 },
+    /// targets by string constant, sorted by string
+    Str { cases: Vec<(u32, u32)>, default: u32 },
+    /// an effect handler: the code of its body, run in the environment of the frame,
+    /// whether it has a return arm, and the effects of its operation arms. The return
+    /// arm, then the operation arms in order, are popped before running the body.
+    Handler { body: u16, ret: bool, ops: Vec<u16> },
+}
+
+/// the instructions of a closure
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    /// name of the top-level declaration bound to the closure if any, for traces
+    pub name: Option<String>,
+    /// number of arguments, called when they're all applied
+    pub arity: u16,
+    /// number of captured values
+    pub captures: u16,
+    pub instrs: Vec<Instr>,
+    pub tables: Vec<Table>,
+    /// positions in the source of the instructions that can fail, e.g. applications,
+    /// operators and raises, sorted by instruction
+    pub positions: Vec<(u32, Pos)>,
+}
+
+impl Code {
+    /// the position of nth instruction, if it's known
+    pub fn position(&self, pc: u32) -> Option<Pos> {
+        let i = self.positions.binary_search_by_key(&pc, |p| p.0).ok()?;
+        Some(self.positions[i].1)
+    }
+}
+
+/// a line and column in the source of a program, for errors
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pos {
+    /// counted from 1
+    pub line: u32,
+    pub col: u32,
+    /// in the prelude rather than the program itself
+    pub prelude: bool,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)?;
+        if self.prelude {
+            write!(f, " of the prelude")?;
+        }
+        Ok(())
+    }
+}
+
+/// names of a type and its constructors, to print values
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeInfo {
+    pub name: String,
+    pub variants: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub consts: Vec<Const>,
+    pub types: Vec<TypeInfo>,
+    /// names of the effects, for errors
+    pub effects: Vec<String>,
+    /// names of the functions provided by the VM, see `builtins`
+    pub imports: Vec<String>,
+    pub codes: Vec<Code>,
+    /// codes without arguments evaluating the top-level declarations in order, each
+    /// setting its global
+    pub globals: Vec<u16>,
+    /// positions of the top-level declarations, empty if they aren't known
+    pub positions: Vec<Pos>,
+}
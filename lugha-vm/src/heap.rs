@@ -0,0 +1,265 @@
+//! The heap of the machine, holding the strings, tuples, sum values, refs, closures
+//! and continuations of a program. Values refer to its objects by `Gc` handles, so
+//! cyclic closures and refs are fine.
+//!
+//! Objects are freed by a mark-sweep collector. The machine collects between
+//! instructions, when every live value is reachable from its roots: the constants,
+//! the globals, the running frame and the entries waiting for it. A value kept
+//! elsewhere, e.g. the result of `Vm::apply`, is only valid until the machine runs
+//! again.
+
+#[cfg(test)]
+mod test {
+    use {super::*, crate::vm::Closure};
+
+    #[test]
+    fn collect() {
+        let mut heap = Heap::new();
+        let s = heap.new_str("kept".to_owned());
+        let nil = heap.new_sum(0, 1, Value::Unit);
+        let list = heap.new_sum(0, 2, Value::Unit);
+        let pair = heap.new_tuple(vec![s, list]);
+        let _garbage = heap.new_tuple(vec![s, nil]);
+        // the list is its own tail
+        if let Value::Sum(_, _, cell) = list {
+            *heap.get_mut(cell) = Object::Cell(pair);
+        }
+        let closure = heap.alloc(Object::Closure(Closure { code: 0, captures: vec![pair], args: vec![] }));
+        assert_eq!(heap.len(), 6);
+
+        let mut roots = Vec::new();
+        Value::Closure(closure).trace(&mut roots);
+        heap.collect(roots);
+        assert_eq!(heap.len(), 4);
+        assert_eq!(heap.collections(), 1);
+        assert_eq!(heap.str(match pair {
+            Value::Tuple(t) => match heap.values(t)[0] {
+                Value::Str(s) => s,
+                _ => panic!("not a string"),
+            },
+            _ => panic!("not a tuple"),
+        }), "kept");
+
+        // freed slots are reused
+        let slots = heap.objects.len();
+        heap.new_str("new".to_owned());
+        heap.new_str("new".to_owned());
+        assert_eq!(heap.objects.len(), slots);
+        heap.collect(Vec::new());
+        assert_eq!(heap.len(), 0);
+    }
+
+    #[test]
+    fn stress() {
+        let mut heap = Heap::new();
+        assert!(!heap.wants_collection());
+        heap.set_stress(true);
+        assert!(!heap.wants_collection());
+        let s = heap.new_str("s".to_owned());
+        assert!(heap.wants_collection());
+        let mut roots = Vec::new();
+        s.trace(&mut roots);
+        heap.collect(roots);
+        assert!(!heap.wants_collection());
+        assert_eq!(heap.len(), 1);
+    }
+}
+
+use crate::vm::{Closure, Kont, Value};
+
+/// a reference to an object of a heap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gc(u32);
+
+#[derive(Debug)]
+pub enum Object {
+    Str(String),
+    /// the elements of a tuple, or the values an import is partially applied to
+    Values(Vec<Value>),
+    /// the value a constructor is applied to, or the contents of a ref
+    Cell(Value),
+    Closure(Closure),
+    Kont(Kont),
+}
+
+impl Object {
+    /// push the objects this one refers to
+    fn trace(&self, gray: &mut Vec<Gc>) {
+        match *self {
+            Object::Str(_) => (),
+            Object::Values(ref values) => values.iter().for_each(|v| v.trace(gray)),
+            Object::Cell(ref v) => v.trace(gray),
+            Object::Closure(ref c) => c.captures.iter().chain(&c.args).for_each(|v| v.trace(gray)),
+            Object::Kont(ref k) => k.trace(gray),
+        }
+    }
+}
+
+/// live objects at which the first collection happens, and at least between two
+const MIN_THRESHOLD: usize = 1 << 16;
+
+pub struct Heap {
+    /// the objects by handle, none for a free slot
+    objects: Vec<Option<Object>>,
+    marks: Vec<bool>,
+    free: Vec<u32>,
+    live: usize,
+    /// live objects at which the machine collects next
+    threshold: usize,
+    /// collect after every allocation, for testing
+    stress: bool,
+    collections: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            live: 0,
+            threshold: MIN_THRESHOLD,
+            stress: false,
+            collections: 0,
+        }
+    }
+
+    pub fn alloc(&mut self, object: Object) -> Gc {
+        self.live += 1;
+        match self.free.pop() {
+            Some(i) => {
+                self.objects[i as usize] = Some(object);
+                Gc(i)
+            }
+            None => {
+                self.objects.push(Some(object));
+                Gc(self.objects.len() as u32 - 1)
+            }
+        }
+    }
+
+    pub fn new_str(&mut self, s: String) -> Value {
+        Value::Str(self.alloc(Object::Str(s)))
+    }
+
+    pub fn new_tuple(&mut self, values: Vec<Value>) -> Value {
+        Value::Tuple(self.alloc(Object::Values(values)))
+    }
+
+    /// nth type's mth constructor applied to a value
+    pub fn new_sum(&mut self, n: u16, m: u16, v: Value) -> Value {
+        Value::Sum(n, m, self.alloc(Object::Cell(v)))
+    }
+
+    /// the object of a handle, which must not be collected
+    pub fn get(&self, gc: Gc) -> &Object {
+        match self.objects.get(gc.0 as usize) {
+            Some(Some(object)) => object,
+            _ => panic!("use of collected object {}", gc.0),
+        }
+    }
+
+    pub fn get_mut(&mut self, gc: Gc) -> &mut Object {
+        match self.objects.get_mut(gc.0 as usize) {
+            Some(Some(object)) => object,
+            _ => panic!("use of collected object {}", gc.0),
+        }
+    }
+
+    /// The contents of an object of a kind, the object of a value is always of the
+    /// kind of the value. The functions panic on another kind.
+    pub fn str(&self, gc: Gc) -> &str {
+        match *self.get(gc) {
+            Object::Str(ref s) => s,
+            ref o => panic!("{:?} isn't a string", o),
+        }
+    }
+
+    pub fn values(&self, gc: Gc) -> &[Value] {
+        match *self.get(gc) {
+            Object::Values(ref values) => values,
+            ref o => panic!("{:?} isn't a tuple", o),
+        }
+    }
+
+    pub fn cell(&self, gc: Gc) -> Value {
+        match *self.get(gc) {
+            Object::Cell(v) => v,
+            ref o => panic!("{:?} isn't a cell", o),
+        }
+    }
+
+    pub fn closure(&self, gc: Gc) -> &Closure {
+        match *self.get(gc) {
+            Object::Closure(ref c) => c,
+            ref o => panic!("{:?} isn't a closure", o),
+        }
+    }
+
+    pub fn kont(&self, gc: Gc) -> &Kont {
+        match *self.get(gc) {
+            Object::Kont(ref k) => k,
+            ref o => panic!("{:?} isn't a continuation", o),
+        }
+    }
+
+    /// number of live objects, and of objects not collected yet
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    pub fn collections(&self) -> usize {
+        self.collections
+    }
+
+    /// collect whenever an object was allocated since the last collection, which
+    /// frees any object a root is missing for as soon as possible
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+        self.set_threshold();
+    }
+
+    fn set_threshold(&mut self) {
+        self.threshold = if self.stress { self.live + 1 } else { MIN_THRESHOLD.max(self.live * 2) };
+    }
+
+    pub(crate) fn wants_collection(&self) -> bool {
+        self.live >= self.threshold
+    }
+
+    /// free the objects not reachable from the roots
+    pub(crate) fn collect(&mut self, mut gray: Vec<Gc>) {
+        self.marks.clear();
+        self.marks.resize(self.objects.len(), false);
+        while let Some(gc) = gray.pop() {
+            let i = gc.0 as usize;
+            if self.marks[i] {
+                continue;
+            }
+            self.marks[i] = true;
+            match self.objects[i] {
+                Some(ref object) => object.trace(&mut gray),
+                None => panic!("use of collected object {}", i),
+            }
+        }
+        for (i, object) in self.objects.iter_mut().enumerate() {
+            if !self.marks[i] && object.is_some() {
+                *object = None;
+                self.free.push(i as u32);
+                self.live -= 1;
+            }
+        }
+        self.collections += 1;
+        self.set_threshold();
+    }
+}
@@ -0,0 +1,720 @@
+//! The `.lbc` file format, programs compiled once and run many times.
+//!
+//! A file is the magic number and the version of the format, then the parts of the
+//! program in order: the constant pool, the types with the names of their variants,
+//! the names of the effects and of the imports, the table of codes, the codes
+//! evaluating the top-level declarations, the entry points, and their positions in
+//! the source. A code ends with the positions of its instructions that can fail, for
+//! errors. Numbers are little-endian,
+//! a sequence is its u32 length followed by its elements and a string is a sequence
+//! of UTF-8 bytes.
+//!
+//! `read` checks the file is well-formed and that everything the instructions refer
+//! to exists, so a machine can run what it returns. It returns an error on any other
+//! input, whatever the bytes.
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bytecode::{BinOp, Code, Table, TypeInfo},
+    };
+
+    fn program() -> Program {
+        let fact = Code {
+            name: Some("fact".to_owned()),
+            arity: 1,
+            captures: 0,
+            instrs: vec![
+                Instr::Local(0),
+                Instr::Switch(0),
+                Instr::Const(0),
+                Instr::Return,
+                Instr::Local(0),
+                Instr::Global(0),
+                Instr::Local(0),
+                Instr::Const(0),
+                Instr::BinOp(BinOp::Sub),
+                Instr::Apply,
+                Instr::BinOp(BinOp::Mul),
+                Instr::Return,
+            ],
+            tables: vec![Table::Int { cases: vec![(0, 2)], default: 4 }],
+            positions: vec![
+                (9, Pos { line: 3, col: 8, prelude: false }),
+                (10, Pos { line: 3, col: 4, prelude: false }),
+            ],
+        };
+        let main = Code {
+            name: None,
+            arity: 0,
+            captures: 0,
+            instrs: vec![
+                Instr::Closure(0),
+                Instr::SetGlobal(0),
+                Instr::Import(0),
+                Instr::Const(1),
+                Instr::Handle(0),
+                Instr::Apply,
+                Instr::Return,
+            ],
+            tables: vec![Table::Handler { body: 2, ret: false, ops: vec![] }],
+            positions: vec![(5, Pos { line: 12, col: 1, prelude: true })],
+        };
+        let body = Code { instrs: vec![Instr::Unit, Instr::Return], ..Code::default() };
+        Program {
+            consts: vec![Const::Int(-1), Const::Str("héllo\n".to_owned())],
+            types: vec![TypeInfo { name: "exn".to_owned(), variants: vec!["MatchFailure".to_owned()] }],
+            effects: vec!["tell".to_owned()],
+            imports: vec!["print".to_owned()],
+            codes: vec![fact, main, body],
+            globals: vec![1],
+            positions: vec![Pos { line: 5, col: 1, prelude: false }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        let bytes = write(&program);
+        assert_eq!(&bytes[..4], &MAGIC);
+        assert_eq!(read(&bytes).unwrap(), program);
+        assert_eq!(read(&write(&Program::default())).unwrap(), Program::default());
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = write(&program());
+        // every prefix is cut short, and nothing may follow the program
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err());
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(read(&longer), Err(FormatError::TrailingBytes));
+
+        let mut other = bytes.clone();
+        other[0] = b'#';
+        assert_eq!(read(&other), Err(FormatError::NotBytecode));
+        let mut newer = bytes.clone();
+        newer[4] = 99;
+        assert_eq!(read(&newer), Err(FormatError::Version(99)));
+
+        // a huge length isn't allocated before the elements are read
+        let mut huge = bytes[..6].to_vec();
+        huge.extend_from_slice(&[0xff; 4]);
+        assert_eq!(read(&huge), Err(FormatError::Truncated));
+
+        // any byte changed is read without panicking
+        for i in 6..bytes.len() {
+            for &b in &[0, 1, 0x7f, 0xff] {
+                let mut changed = bytes.clone();
+                changed[i] = b;
+                let _ = read(&changed);
+            }
+        }
+    }
+
+    #[test]
+    fn dangling() {
+        let invalid = |change: fn(&mut Program)| {
+            let mut program = program();
+            change(&mut program);
+            match read(&write(&program)) {
+                Err(FormatError::Invalid(message)) => message,
+                result => panic!("{:?}", result),
+            }
+        };
+        assert_eq!(invalid(|p| p.codes[0].instrs[2] = Instr::Const(2)), "code 0, instruction 2: no constant 2");
+        assert_eq!(invalid(|p| p.codes[0].instrs[1] = Instr::Switch(1)), "code 0, instruction 1: no table 1");
+        assert_eq!(invalid(|p| p.codes[0].instrs[1] = Instr::Handle(0)), "code 0, instruction 1: not a handler table");
+        assert_eq!(invalid(|p| p.codes[1].instrs[4] = Instr::Switch(0)), "code 1, instruction 4: a handler table");
+        assert_eq!(invalid(|p| p.codes[0].instrs[0] = Instr::Jump(12)), "code 0, instruction 0: jump to 12");
+        assert_eq!(
+            invalid(|p| p.codes[1].instrs[0] = Instr::Construct(0, 0)),
+            "code 1, instruction 0: no variant 0 of type 0"
+        );
+        assert_eq!(invalid(|p| p.codes[1].instrs[1] = Instr::SetGlobal(1)), "code 1, instruction 1: no global 1");
+        assert_eq!(
+            invalid(|p| p.codes[1].tables[0] = Table::Handler { body: 2, ret: false, ops: vec![1] }),
+            "code 1, table 0: no effect 1"
+        );
+        assert_eq!(
+            invalid(|p| p.codes[0].tables[0] = Table::Str { cases: vec![(0, 2)], default: 4 }),
+            "code 0, table 0: constant 0 isn't a string"
+        );
+        assert_eq!(
+            invalid(|p| p.codes[0].tables[0] = Table::Int { cases: vec![(1, 2), (0, 2)], default: 4 }),
+            "code 0, table 0: cases aren't sorted"
+        );
+        assert_eq!(invalid(|p| p.globals[0] = 3), "global 0: no code 3");
+        assert_eq!(invalid(|p| p.positions.push(Pos::default())), "2 positions of 1 globals");
+        assert_eq!(invalid(|p| p.codes[0].positions.reverse()), "code 0: positions aren't sorted");
+        assert_eq!(invalid(|p| p.codes[1].positions[0].0 = 7), "code 1: position of instruction 7");
+    }
+}
+
+use {
+    crate::bytecode::{BinOp, Code, Const, Instr, Pos, Program, Table, TypeInfo, UnOp},
+    std::{convert::TryFrom, fmt},
+};
+
+/// the first bytes of a file
+pub const MAGIC: [u8; 4] = *b"\x7fLBC";
+/// changed whenever the format changes, files of other versions aren't read
+pub const VERSION: u16 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// the file doesn't start with the magic number
+    NotBytecode,
+    /// the file is of another version of the format
+    Version(u16),
+    /// the file ends in the middle of the program
+    Truncated,
+    /// something follows the program
+    TrailingBytes,
+    /// bytes that don't encode anything, at an offset
+    Malformed(usize, &'static str),
+    /// a well-formed program referring to something that doesn't exist
+    Invalid(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::NotBytecode => write!(f, "not a bytecode file"),
+            FormatError::Version(v) => write!(f, "bytecode version {}, expected {}", v, VERSION),
+            FormatError::Truncated => write!(f, "the bytecode file is truncated"),
+            FormatError::TrailingBytes => write!(f, "bytes after the end of the program"),
+            FormatError::Malformed(offset, what) => write!(f, "{} at byte {}", what, offset),
+            FormatError::Invalid(ref message) => write!(f, "invalid program, {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// the bytes of the file of a program
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut w = Writer(MAGIC.to_vec());
+    w.u16(VERSION);
+    w.len(program.consts.len());
+    for c in &program.consts {
+        match *c {
+            Const::Int(i) => {
+                w.u8(0);
+                w.i64(i as i64);
+            }
+            Const::Str(ref s) => {
+                w.u8(1);
+                w.str(s);
+            }
+        }
+    }
+    w.len(program.types.len());
+    for t in &program.types {
+        w.str(&t.name);
+        w.strs(&t.variants);
+    }
+    w.strs(&program.effects);
+    w.strs(&program.imports);
+    w.len(program.codes.len());
+    for code in &program.codes {
+        match code.name {
+            Some(ref name) => {
+                w.u8(1);
+                w.str(name);
+            }
+            None => w.u8(0),
+        }
+        w.u16(code.arity);
+        w.u16(code.captures);
+        w.len(code.instrs.len());
+        for &instr in &code.instrs {
+            w.instr(instr);
+        }
+        w.len(code.tables.len());
+        for table in &code.tables {
+            w.table(table);
+        }
+        w.len(code.positions.len());
+        for &(pc, pos) in &code.positions {
+            w.u32(pc);
+            w.pos(pos);
+        }
+    }
+    w.len(program.globals.len());
+    for &g in &program.globals {
+        w.u16(g);
+    }
+    w.len(program.positions.len());
+    for &pos in &program.positions {
+        w.pos(pos);
+    }
+    w.0
+}
+
+/// the program of a file, checked to be runnable
+pub fn read(bytes: &[u8]) -> Result<Program, FormatError> {
+    let mut r = Reader { bytes, pos: 0 };
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(FormatError::NotBytecode);
+    }
+    r.pos = MAGIC.len();
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(FormatError::Version(version));
+    }
+    let consts = r.seq(|r| match r.u8()? {
+        0 => Ok(Const::Int(r.int()?)),
+        1 => Ok(Const::Str(r.str()?)),
+        _ => Err(r.malformed("unknown kind of constant")),
+    })?;
+    let types = r.seq(|r| Ok(TypeInfo { name: r.str()?, variants: r.seq(Reader::str)? }))?;
+    let effects = r.seq(Reader::str)?;
+    let imports = r.seq(Reader::str)?;
+    let codes = r.seq(|r| {
+        let name = match r.u8()? {
+            0 => None,
+            1 => Some(r.str()?),
+            _ => return Err(r.malformed("unknown kind of name")),
+        };
+        Ok(Code {
+            name,
+            arity: r.u16()?,
+            captures: r.u16()?,
+            instrs: r.seq(Reader::instr)?,
+            tables: r.seq(Reader::table)?,
+            positions: r.seq(|r| Ok((r.u32()?, r.pos()?)))?,
+        })
+    })?;
+    let globals = r.seq(Reader::u16)?;
+    let positions = r.seq(Reader::pos)?;
+    if r.pos != bytes.len() {
+        return Err(FormatError::TrailingBytes);
+    }
+    let program = Program { consts, types, effects, imports, codes, globals, positions };
+    validate(&program)?;
+    Ok(program)
+}
+
+/// check that the constants, types, effects, imports, codes, globals, tables and
+/// instructions referred to by a program exist, and that the positions are those of
+/// its globals and instructions
+pub fn validate(program: &Program) -> Result<(), FormatError> {
+    for (i, &g) in program.globals.iter().enumerate() {
+        if g as usize >= program.codes.len() {
+            return Err(FormatError::Invalid(format!("global {}: no code {}", i, g)));
+        }
+    }
+    if !program.positions.is_empty() && program.positions.len() != program.globals.len() {
+        let (positions, globals) = (program.positions.len(), program.globals.len());
+        return Err(FormatError::Invalid(format!("{} positions of {} globals", positions, globals)));
+    }
+    for (n, code) in program.codes.iter().enumerate() {
+        for (pc, &instr) in code.instrs.iter().enumerate() {
+            check_instr(program, code, instr)
+                .map_err(|e| FormatError::Invalid(format!("code {}, instruction {}: {}", n, pc, e)))?;
+        }
+        for (t, table) in code.tables.iter().enumerate() {
+            check_table(program, code, table)
+                .map_err(|e| FormatError::Invalid(format!("code {}, table {}: {}", n, t, e)))?;
+        }
+        // the machine finds the position of an instruction by binary search
+        if code.positions.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(FormatError::Invalid(format!("code {}: positions aren't sorted", n)));
+        }
+        if let Some(&(pc, _)) = code.positions.last().filter(|p| p.0 as usize >= code.instrs.len()) {
+            return Err(FormatError::Invalid(format!("code {}: position of instruction {}", n, pc)));
+        }
+    }
+    Ok(())
+}
+
+fn check_instr(program: &Program, code: &Code, instr: Instr) -> Result<(), String> {
+    let exists = |n: usize, len: usize, what: &str| {
+        if n < len {
+            Ok(())
+        } else {
+            Err(format!("no {} {}", what, n))
+        }
+    };
+    match instr {
+        Instr::Const(n) => exists(n as usize, program.consts.len(), "constant"),
+        Instr::Global(n) | Instr::SetGlobal(n) => exists(n as usize, program.globals.len(), "global"),
+        Instr::Construct(n, m) | Instr::Constructor(n, m) => {
+            match program.types.get(n as usize) {
+                Some(t) if m >= 1 && m as usize <= t.variants.len() => Ok(()),
+                _ => Err(format!("no variant {} of type {}", m, n)),
+            }
+        }
+        Instr::Import(n) => exists(n as usize, program.imports.len(), "import"),
+        Instr::Closure(n) | Instr::Try(n) => exists(n as usize, program.codes.len(), "code"),
+        Instr::Jump(target) | Instr::JumpIfFalse(target) => {
+            if (target as usize) < code.instrs.len() {
+                Ok(())
+            } else {
+                Err(format!("jump to {}", target))
+            }
+        }
+        Instr::Switch(n) => match code.tables.get(n as usize) {
+            Some(Table::Handler { .. }) => Err("a handler table".to_owned()),
+            Some(_) => Ok(()),
+            None => Err(format!("no table {}", n)),
+        },
+        Instr::Handle(n) => match code.tables.get(n as usize) {
+            Some(Table::Handler { .. }) => Ok(()),
+            Some(_) => Err("not a handler table".to_owned()),
+            None => Err(format!("no table {}", n)),
+        },
+        Instr::Perform(n) => exists(n as usize, program.effects.len(), "effect"),
+        // locals and captures depend on how the code is called, the machine checks them
+        _ => Ok(()),
+    }
+}
+
+fn check_table(program: &Program, code: &Code, table: &Table) -> Result<(), String> {
+    let target = |t: u32| {
+        if (t as usize) < code.instrs.len() {
+            Ok(())
+        } else {
+            Err(format!("jump to {}", t))
+        }
+    };
+    match *table {
+        Table::Jump { ref targets, default } => targets.iter().chain(Some(&default)).try_for_each(|&t| target(t)),
+        Table::Int { ref cases, default } => {
+            // the machine finds a case by binary search
+            if cases.windows(2).any(|w| w[0].0 >= w[1].0) {
+                return Err("cases aren't sorted".to_owned());
+            }
+            cases.iter().map(|c| c.1).chain(Some(default)).try_for_each(target)
+        }
+        Table::Str { ref cases, default } => {
+            let mut strs = Vec::new();
+            for &(c, t) in cases {
+                match program.consts.get(c as usize) {
+                    Some(Const::Str(s)) => strs.push(s),
+                    _ => return Err(format!("constant {} isn't a string", c)),
+                }
+                target(t)?;
+            }
+            if strs.windows(2).any(|w| w[0] >= w[1]) {
+                return Err("cases aren't sorted".to_owned());
+            }
+            target(default)
+        }
+        Table::Handler { body, ref ops, .. } => {
+            if body as usize >= program.codes.len() {
+                return Err(format!("no code {}", body));
+            }
+            match ops.iter().find(|&&op| op as usize >= program.effects.len()) {
+                Some(op) => Err(format!("no effect {}", op)),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, b: u8) {
+        self.0.push(b);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn i64(&mut self, n: i64) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn strs(&mut self, strs: &[String]) {
+        self.len(strs.len());
+        for s in strs {
+            self.str(s);
+        }
+    }
+
+    fn instr(&mut self, instr: Instr) {
+        match instr {
+            Instr::Const(n) => {
+                self.u8(0);
+                self.u32(n);
+            }
+            Instr::Unit => self.u8(1),
+            Instr::Bool(b) => self.u8(if b { 3 } else { 2 }),
+            Instr::Local(n) => self.op16(4, n),
+            Instr::Capture(n) => self.op16(5, n),
+            Instr::Global(n) => self.op16(6, n),
+            Instr::SetGlobal(n) => self.op16(7, n),
+            Instr::Dup => self.u8(8),
+            Instr::Pop => self.u8(9),
+            Instr::Field(n) => self.op16(10, n),
+            Instr::Tag => self.u8(11),
+            Instr::Payload => self.u8(12),
+            Instr::Tuple(n) => self.op16(13, n),
+            Instr::Construct(n, m) => {
+                self.op16(14, n);
+                self.u16(m);
+            }
+            Instr::Constructor(n, m) => {
+                self.op16(15, n);
+                self.u16(m);
+            }
+            Instr::Import(n) => self.op16(16, n),
+            Instr::Closure(n) => self.op16(17, n),
+            Instr::Apply => self.u8(18),
+            Instr::TailApply => self.u8(19),
+            Instr::Return => self.u8(20),
+            Instr::Jump(target) => {
+                self.u8(21);
+                self.u32(target);
+            }
+            Instr::JumpIfFalse(target) => {
+                self.u8(22);
+                self.u32(target);
+            }
+            Instr::Switch(n) => self.op16(23, n),
+            Instr::BinOp(op) => {
+                self.u8(24);
+                self.u8(BINOPS.iter().position(|&o| o == op).unwrap() as u8);
+            }
+            Instr::UnOp(op) => {
+                self.u8(25);
+                self.u8(UNOPS.iter().position(|&o| o == op).unwrap() as u8);
+            }
+            Instr::Raise => self.u8(26),
+            Instr::Fail => self.u8(27),
+            Instr::Try(n) => self.op16(28, n),
+            Instr::Handle(n) => self.op16(29, n),
+            Instr::Perform(n) => self.op16(30, n),
+            Instr::Call(n) => self.op16(31, n),
+            Instr::TailCall(n) => self.op16(32, n),
+        }
+    }
+
+    fn pos(&mut self, pos: Pos) {
+        self.u32(pos.line);
+        self.u32(pos.col);
+        self.u8(pos.prelude as u8);
+    }
+
+    fn op16(&mut self, opcode: u8, n: u16) {
+        self.u8(opcode);
+        self.u16(n);
+    }
+
+    fn table(&mut self, table: &Table) {
+        match *table {
+            Table::Jump { ref targets, default } => {
+                self.u8(0);
+                self.len(targets.len());
+                for &t in targets {
+                    self.u32(t);
+                }
+                self.u32(default);
+            }
+            Table::Int { ref cases, default } => {
+                self.u8(1);
+                self.len(cases.len());
+                for &(i, t) in cases {
+                    self.i64(i as i64);
+                    self.u32(t);
+                }
+                self.u32(default);
+            }
+            Table::Str { ref cases, default } => {
+                self.u8(2);
+                self.len(cases.len());
+                for &(c, t) in cases {
+                    self.u32(c);
+                    self.u32(t);
+                }
+                self.u32(default);
+            }
+            Table::Handler { body, ret, ref ops } => {
+                self.u8(3);
+                self.u16(body);
+                self.u8(ret as u8);
+                self.len(ops.len());
+                for &op in ops {
+                    self.u16(op);
+                }
+            }
+        }
+    }
+}
+
+/// operators by their byte in the file
+const BINOPS: [BinOp; 15] = [
+    BinOp::Add,
+    BinOp::Sub,
+    BinOp::Mul,
+    BinOp::Div,
+    BinOp::Mod,
+    BinOp::Eq,
+    BinOp::Ne,
+    BinOp::Lt,
+    BinOp::Le,
+    BinOp::Gt,
+    BinOp::Ge,
+    BinOp::And,
+    BinOp::Or,
+    BinOp::Concat,
+    BinOp::Assign,
+];
+const UNOPS: [UnOp; 4] = [UnOp::Not, UnOp::Neg, UnOp::Ref, UnOp::Deref];
+
+type ReadResult<T> = Result<T, FormatError>;
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> ReadResult<&'b [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(FormatError::Truncated);
+        }
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn malformed(&self, what: &'static str) -> FormatError {
+        FormatError::Malformed(self.pos - 1, what)
+    }
+
+    fn u8(&mut self) -> ReadResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> ReadResult<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> ReadResult<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn int(&mut self) -> ReadResult<isize> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        isize::try_from(i64::from_le_bytes(b)).map_err(|_| FormatError::Malformed(self.pos - 8, "int too big"))
+    }
+
+    /// the elements of a sequence, its length isn't trusted to allocate
+    fn seq<T, F>(&mut self, mut element: F) -> ReadResult<Vec<T>>
+    where
+        F: FnMut(&mut Self) -> ReadResult<T>,
+    {
+        let len = self.u32()?;
+        let mut elements = Vec::new();
+        for _ in 0..len {
+            elements.push(element(self)?);
+        }
+        Ok(elements)
+    }
+
+    fn str(&mut self) -> ReadResult<String> {
+        let len = self.u32()? as usize;
+        let start = self.pos;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(FormatError::Malformed(start, "invalid UTF-8")),
+        }
+    }
+
+    fn pos(&mut self) -> ReadResult<Pos> {
+        let (line, col) = (self.u32()?, self.u32()?);
+        let prelude = self.bool()?;
+        Ok(Pos { line, col, prelude })
+    }
+
+    fn bool(&mut self) -> ReadResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.malformed("not a boolean")),
+        }
+    }
+
+    fn instr(&mut self) -> ReadResult<Instr> {
+        Ok(match self.u8()? {
+            0 => Instr::Const(self.u32()?),
+            1 => Instr::Unit,
+            2 => Instr::Bool(false),
+            3 => Instr::Bool(true),
+            4 => Instr::Local(self.u16()?),
+            5 => Instr::Capture(self.u16()?),
+            6 => Instr::Global(self.u16()?),
+            7 => Instr::SetGlobal(self.u16()?),
+            8 => Instr::Dup,
+            9 => Instr::Pop,
+            10 => Instr::Field(self.u16()?),
+            11 => Instr::Tag,
+            12 => Instr::Payload,
+            13 => Instr::Tuple(self.u16()?),
+            14 => Instr::Construct(self.u16()?, self.u16()?),
+            15 => Instr::Constructor(self.u16()?, self.u16()?),
+            16 => Instr::Import(self.u16()?),
+            17 => Instr::Closure(self.u16()?),
+            18 => Instr::Apply,
+            19 => Instr::TailApply,
+            20 => Instr::Return,
+            21 => Instr::Jump(self.u32()?),
+            22 => Instr::JumpIfFalse(self.u32()?),
+            23 => Instr::Switch(self.u16()?),
+            24 => match BINOPS.get(self.u8()? as usize) {
+                Some(&op) => Instr::BinOp(op),
+                None => return Err(self.malformed("unknown operator")),
+            },
+            25 => match UNOPS.get(self.u8()? as usize) {
+                Some(&op) => Instr::UnOp(op),
+                None => return Err(self.malformed("unknown operator")),
+            },
+            26 => Instr::Raise,
+            27 => Instr::Fail,
+            28 => Instr::Try(self.u16()?),
+            29 => Instr::Handle(self.u16()?),
+            30 => Instr::Perform(self.u16()?),
+            31 => Instr::Call(self.u16()?),
+            32 => Instr::TailCall(self.u16()?),
+            _ => return Err(self.malformed("unknown instruction")),
+        })
+    }
+
+    fn table(&mut self) -> ReadResult<Table> {
+        Ok(match self.u8()? {
+            0 => Table::Jump { targets: self.seq(Reader::u32)?, default: self.u32()? },
+            1 => Table::Int { cases: self.seq(|r| Ok((r.int()?, r.u32()?)))?, default: self.u32()? },
+            2 => Table::Str { cases: self.seq(|r| Ok((r.u32()?, r.u32()?)))?, default: self.u32()? },
+            3 => {
+                let body = self.u16()?;
+                let ret = self.bool()?;
+                Table::Handler { body, ret, ops: self.seq(Reader::u16)? }
+            }
+            _ => return Err(self.malformed("unknown kind of table")),
+        })
+    }
+}
@@ -1,7 +1,9 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+//! A stack machine running the programs compiled by clog, see `bytecode` for the
+//! instructions and `vm` for the machine.
+
+pub mod asm;
+pub mod builtins;
+pub mod bytecode;
+pub mod heap;
+pub mod lbc;
+pub mod vm;
@@ -0,0 +1,1298 @@
+//! The machine running programs. Each call gets a frame with the arguments of the
+//! closure, its captured values and an operand stack. Frames waiting for a value are
+//! kept on a stack of entries, together with the exception and effect handlers
+//! installed between them, so continuations are copies of a part of that stack.
+//!
+//! The bodies of `try` and `handle` run in a frame of their own sharing the arguments
+//! and captures of the frame that started them, their handler entry sits between the
+//! two frames.
+
+use {
+    crate::{
+        builtins::{self, Builtin, Capabilities, Io},
+        bytecode::{BinOp, Const, Instr, Pos, Program, Table, UnOp, EXN_DIV_BY_ZERO, EXN_MATCH_FAILURE, EXN_TYPE},
+        heap::{Gc, Heap, Object},
+    },
+    std::{
+        cmp::Ordering,
+        fmt,
+        io::{self, Write},
+        mem,
+        rc::Rc,
+    },
+};
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bytecode::{Code, TypeInfo, LIST_CONS, LIST_NIL, LIST_TYPE},
+        std::cell::RefCell,
+    };
+
+    /// output written by a machine, readable after it's moved in
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// a program with the built-in types, the globals being the first codes
+    fn program(consts: Vec<Const>, imports: &[&str], globals: usize, codes: Vec<Code>) -> Program {
+        let names = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect();
+        let types = [
+            ("exn", &["DivisionByZero", "MatchFailure", "Found"][..]),
+            ("Option", &["none", "some"]),
+            ("Result", &["ok", "err"]),
+            ("List", &["nil", "cons"]),
+        ];
+        Program {
+            consts,
+            types: types.iter().map(|&(t, vs)| TypeInfo { name: t.to_owned(), variants: names(vs) }).collect(),
+            effects: names(&["ask"]),
+            imports: names(imports),
+            codes,
+            globals: (0..globals as u16).collect(),
+            positions: vec![],
+        }
+    }
+
+    fn ints(ns: &[isize]) -> Vec<Const> {
+        ns.iter().map(|&n| Const::Int(n)).collect()
+    }
+
+    fn code(arity: u16, captures: u16, instrs: Vec<Instr>) -> Code {
+        Code { name: None, arity, captures, instrs, tables: vec![], positions: vec![] }
+    }
+
+    fn run_with(program: &Program, stress: bool) -> (Result<(), RuntimeError>, String, Vm<'_>) {
+        let out = Output::default();
+        let mut vm = Vm::new(program).unwrap().with_output(Box::new(out.clone()));
+        if stress {
+            vm = vm.with_gc_stress();
+        }
+        let result = vm.run();
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        (result, text, vm)
+    }
+
+    /// run a program, and again collecting the garbage on every allocation which
+    /// mustn't change what it does
+    fn run(program: &Program) -> (Result<(), RuntimeError>, String, Vm<'_>) {
+        let (result, out, vm) = run_with(program, false);
+        let (stressed, stressed_out, stressed_vm) = run_with(program, true);
+        let report = |r: &Result<(), RuntimeError>| r.as_ref().map_err(|e| e.report(program)).err();
+        let globals = |vm: &Vm| {
+            let shown = (0..program.globals.len()).map(|i| vm.global(i).map(|v| vm.show(v)));
+            shown.collect::<Vec<_>>()
+        };
+        assert_eq!(report(&result), report(&stressed));
+        assert_eq!(out, stressed_out);
+        assert_eq!(globals(&vm), globals(&stressed_vm));
+        (result, out, vm)
+    }
+
+    #[test]
+    fn calls() {
+        use Instr::*;
+        // fact n = if n == 0 then 1 else n * fact (n - 1), and add partially applied
+        let mut fact = code(1, 0, vec![
+            Local(0),
+            Switch(0),
+            Const(1),
+            Return,
+            Local(0),
+            Global(0),
+            Local(0),
+            Const(1),
+            BinOp(self::BinOp::Sub),
+            Apply,
+            BinOp(self::BinOp::Mul),
+            Return,
+        ]);
+        fact.tables.push(Table::Int { cases: vec![(0, 2)], default: 4 });
+        let program = program(ints(&[10, 1]), &["print", "int2str"], 3, vec![
+            code(0, 0, vec![Closure(3), SetGlobal(0), Unit, Return]),
+            code(0, 0, vec![Closure(4), Const(0), Apply, SetGlobal(1), Unit, Return]),
+            code(0, 0, vec![
+                Import(0),
+                Import(1),
+                Global(1),
+                Global(0),
+                Const(0),
+                Apply,
+                Apply,
+                Apply,
+                Apply,
+                SetGlobal(2),
+                Unit,
+                Return,
+            ]),
+            fact,
+            code(2, 0, vec![Local(0), Local(1), BinOp(self::BinOp::Add), Return]),
+        ]);
+        let (result, out, vm) = run(&program);
+        assert!(result.is_ok());
+        assert_eq!(out, "3628810");
+        assert_eq!(vm.global(2), Some(Value::Unit));
+        assert!(match vm.global(1) {
+            Some(Value::Closure(c)) => vm.heap().closure(c).code == 4 && vm.heap().closure(c).args == [Value::Int(10)],
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn saturated_calls() {
+        use Instr::*;
+        // sub3 a b c = a - b - c, called with all its arguments at once in and out
+        // of tail position, and on a partial application which can't be
+        let sub3 = code(3, 0, vec![
+            Local(0),
+            Local(1),
+            BinOp(self::BinOp::Sub),
+            Local(2),
+            BinOp(self::BinOp::Sub),
+            Return,
+        ]);
+        let mut program = program(ints(&[10, 3, 2]), &[], 3, vec![
+            code(0, 0, vec![Closure(3), SetGlobal(0), Unit, Return]),
+            code(0, 0, vec![Global(0), Const(0), Const(1), Const(2), Call(3), SetGlobal(1), Unit, Return]),
+            code(0, 0, vec![Closure(4), Unit, Apply, SetGlobal(2), Unit, Return]),
+            sub3,
+            code(1, 0, vec![Global(0), Const(2), Const(1), Const(0), TailCall(3)]),
+        ]);
+        let (result, _, vm) = run(&program);
+        assert!(result.is_ok());
+        assert_eq!((vm.global(1), vm.global(2)), (Some(Value::Int(5)), Some(Value::Int(-11))));
+
+        program.codes[1].instrs[1..5].copy_from_slice(&[Const(0), Apply, Const(1), Call(1)]);
+        let e = run(&program).0.unwrap_err();
+        assert_eq!(e.report(&program), concat!(
+            "error: invalid program, call of a closure of another arity\n",
+            "  in the declaration 1\n",
+        ));
+    }
+
+    #[test]
+    fn tail_calls() {
+        use Instr::*;
+        // count n acc = if n == 0 then acc else count (n - 1) (cons ((), acc))
+        let count = code(2, 0, vec![
+            Local(0),
+            Const(0),
+            BinOp(self::BinOp::Eq),
+            JumpIfFalse(6),
+            Local(1),
+            Return,
+            Global(0),
+            Local(0),
+            Const(1),
+            BinOp(self::BinOp::Sub),
+            Apply,
+            Unit,
+            Local(1),
+            Tuple(2),
+            Construct(LIST_TYPE, LIST_CONS),
+            TailApply,
+        ]);
+        let program = program(ints(&[0, 1, 100_000]), &[], 2, vec![
+            code(0, 0, vec![Closure(2), SetGlobal(0), Unit, Return]),
+            code(0, 0, vec![
+                Global(0),
+                Const(2),
+                Apply,
+                Unit,
+                Construct(LIST_TYPE, LIST_NIL),
+                Apply,
+                SetGlobal(1),
+                Unit,
+                Return,
+            ]),
+            count,
+        ]);
+        // collecting on every allocation takes quadratic time with the list growing
+        let (result, _, vm) = run_with(&program, false);
+        assert!(result.is_ok());
+        let mut len = 0;
+        let mut l = vm.global(1).unwrap();
+        while let Value::Sum(LIST_TYPE, LIST_CONS, cell) = l {
+            len += 1;
+            l = match vm.heap().cell(cell) {
+                Value::Tuple(cell) => vm.heap().values(cell)[1],
+                _ => panic!("cons cell isn't a pair"),
+            };
+        }
+        assert_eq!(len, 100_000);
+    }
+
+    #[test]
+    fn exceptions() {
+        use Instr::*;
+        // handler: DivisionByZero => 0, other exceptions are raised again
+        let mut handler = code(1, 0, vec![Local(0), Tag, Switch(0), Const(0), Return, Local(0), Raise]);
+        handler.tables.push(Table::Jump { targets: vec![3], default: 5 });
+        let program = program(ints(&[0, 7]), &[], 2, vec![
+            code(0, 0, vec![Closure(2), Try(3), SetGlobal(0), Unit, Return]),
+            code(0, 0, vec![Closure(2), Try(4), SetGlobal(1), Unit, Return]),
+            handler,
+            code(0, 0, vec![Const(1), Const(0), BinOp(self::BinOp::Div), Return]),
+            code(0, 0, vec![Fail]),
+        ]);
+        let (result, _, vm) = run(&program);
+        assert_eq!(vm.global(0), Some(Value::Int(0)));
+        let e = result.unwrap_err();
+        assert_eq!(e.global, Some(1));
+        assert_eq!(e.report(&program), concat!(
+            "error: uncaught exception MatchFailure ()\n",
+            "  in <closure 2>\n",
+            "  in the declaration 1\n",
+        ));
+
+        // with the positions of the raise and of the declarations
+        let mut program = program;
+        let pos = |line, col| Pos { line, col, prelude: false };
+        program.codes[2].positions.push((6, pos(4, 5)));
+        program.positions = vec![pos(1, 1), Pos { prelude: true, ..pos(2, 1) }];
+        assert_eq!(run(&program).0.unwrap_err().report(&program), concat!(
+            "error: uncaught exception MatchFailure ()\n",
+            "  at line 4, column 5\n",
+            "  in <closure 2>\n",
+            "  in the declaration at line 2, column 1 of the prelude\n",
+        ));
+    }
+
+    #[test]
+    fn effects() {
+        use Instr::*;
+        // handle (ask () + ask ()) with ask x k -> k 20 + 1, and return v -> v * 2
+        let mut main = code(0, 0, vec![Closure(2), Closure(3), Handle(0), SetGlobal(0), Unit, Return]);
+        main.tables.push(Table::Handler { body: 1, ret: true, ops: vec![0] });
+        let program = program(ints(&[2, 20, 1]), &["callcc"], 2, vec![
+            main,
+            code(0, 0, vec![Unit, Perform(0), Unit, Perform(0), BinOp(self::BinOp::Add), Return]),
+            code(1, 0, vec![Local(0), Const(0), BinOp(self::BinOp::Mul), Return]),
+            code(2, 0, vec![Local(1), Const(1), Apply, Const(2), BinOp(self::BinOp::Add), Return]),
+            // 1 + callcc (k -> 20 + k 2)
+            code(0, 0, vec![Const(2), Import(0), Closure(5), Apply, BinOp(self::BinOp::Add), SetGlobal(1), Unit, Return]),
+            code(1, 0, vec![Const(1), Local(0), Const(0), Apply, BinOp(self::BinOp::Add), Return]),
+        ]);
+        let mut program = program;
+        program.globals = vec![0, 4];
+        let (result, _, vm) = run(&program);
+        assert!(result.is_ok());
+        // ((20 + 20) * 2 + 1) + 1
+        assert_eq!((vm.global(0), vm.global(1)), (Some(Value::Int(82)), Some(Value::Int(3))));
+
+        program.codes[1].instrs[1] = Perform(1);
+        program.effects.push("tell".to_owned());
+        let e = run(&program).0.unwrap_err();
+        assert_eq!(e.report(&program), concat!(
+            "error: unhandled effect tell\n",
+            "  in <closure 1>\n",
+            "  in the declaration 0\n",
+        ));
+    }
+
+    #[test]
+    fn sum_equality() {
+        use Instr::*;
+        // some 1 = some 1, some 1 = some 2 and none () != some 1
+        let some = |n| [Const(n), Construct(1, 2)];
+        let none = [Unit, Construct(1, 1)];
+        let eq = |v1: &[Instr], v2: &[Instr], op, g| {
+            let instrs = [v1, v2, &[BinOp(op), SetGlobal(g), Unit, Return]].concat();
+            code(0, 0, instrs)
+        };
+        let program = program(ints(&[1, 2]), &[], 3, vec![
+            eq(&some(0), &some(0), self::BinOp::Eq, 0),
+            eq(&some(0), &some(1), self::BinOp::Eq, 1),
+            eq(&none, &some(0), self::BinOp::Ne, 2),
+        ]);
+        let (result, _, vm) = run(&program);
+        assert!(result.is_ok());
+        let globals: Vec<_> = (0..3).map(|i| vm.global(i)).collect();
+        assert_eq!(globals, [Some(Value::Bool(true)), Some(Value::Bool(false)), Some(Value::Bool(true))]);
+    }
+
+    #[test]
+    fn strings() {
+        let program = crate::asm::assemble(r#"
+            const 0 str "b"
+            const 1 str "a"
+            const 2 str "c"
+            const 3 str "a or b\n"
+            const 4 str "neither\n"
+            import 0 print
+            global 0 code 0
+
+            code 0 arity 0 captures 0
+                import 0
+                closure 1
+                const 0
+                apply
+                apply
+                pop
+                import 0
+                closure 1
+                const 2
+                apply
+                apply
+                set_global 0
+                unit
+                return
+
+            code 1 classify arity 1 captures 0
+                local 0
+                switch 0
+            :MATCHED
+                const 3
+                return
+            :OTHER
+                const 4
+                return
+                table 0 str 1 :MATCHED 0 :MATCHED default :OTHER
+        "#)
+        .unwrap();
+        crate::lbc::validate(&program).unwrap();
+        let (result, out, vm) = run(&program);
+        assert!(result.is_ok());
+        assert_eq!(out, "a or b\nneither\n");
+        assert_eq!(vm.global(0), Some(Value::Unit));
+    }
+
+    #[test]
+    fn garbage() {
+        // loop n makes a tuple and a ref to a closure capturing the ref, n times
+        let program = crate::asm::assemble(
+            "
+            const 0 int 0
+            const 1 int 1
+            const 2 int 100000
+            global 0 code 0
+
+            code 0 arity 0 captures 0
+                closure 1
+                set_global 0
+                global 0
+                const 2
+                apply
+                return
+
+            code 1 loop arity 1 captures 0
+                local 0
+                const 0
+                eq
+                jump_if_false :MORE
+                unit
+                return
+            :MORE
+                unit
+                ref
+                dup
+                dup
+                closure 2
+                assign
+                pop
+                pop
+                local 0
+                local 0
+                tuple 2
+                pop
+                global 0
+                local 0
+                const 1
+                sub
+                tail_apply
+
+            code 2 arity 1 captures 1
+                capture 0
+                return
+            ",
+        )
+        .unwrap();
+        let (result, _, vm) = run_with(&program, false);
+        assert!(result.is_ok());
+        assert!(vm.heap().collections() > 0);
+        assert!(vm.heap().len() < 100_000);
+        // the loop itself is all that's left after a collection
+        let (result, _, vm) = run_with(&program, true);
+        assert!(result.is_ok());
+        assert!(vm.heap().len() < 10);
+    }
+}
+
+/// Values of a program. Sum values hold the position of their constructor counted
+/// from 1, closures and imports hold the arguments they're partially applied to.
+/// Values other than numbers, booleans and constructors are objects of the heap of
+/// the machine, and are compared by contents (see `equal`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Unit,
+    Int(isize),
+    Bool(bool),
+    Str(Gc),
+    Tuple(Gc),
+    /// nth type's mth constructor applied to the value of a cell
+    Sum(u16, u16, Gc),
+    Closure(Gc),
+    Constructor(u16, u16),
+    /// nth import of the program partially applied to a tuple of values if any
+    Import(u16, Option<Gc>),
+    /// mutable reference cell, shared by all copies of the value
+    Ref(Gc),
+    /// a captured continuation, resumed by applying it to a value
+    Cont(Gc),
+    /// a continuation captured by callcc, applying it abandons the current one
+    Escape(Gc),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub code: u16,
+    pub captures: Vec<Value>,
+    pub args: Vec<Value>,
+}
+
+impl Value {
+    /// push the object of the value if it has one
+    pub(crate) fn trace(&self, gray: &mut Vec<Gc>) {
+        match *self {
+            Value::Str(gc)
+            | Value::Tuple(gc)
+            | Value::Sum(_, _, gc)
+            | Value::Closure(gc)
+            | Value::Import(_, Some(gc))
+            | Value::Ref(gc)
+            | Value::Cont(gc)
+            | Value::Escape(gc) => gray.push(gc),
+            _ => (),
+        }
+    }
+}
+
+/// Values are compared like the interpreter does, by contents. Nested refs are
+/// compared by contents too.
+fn equal(heap: &Heap, v1: Value, v2: Value) -> bool {
+    let all = |vs1: &[Value], vs2: &[Value]| {
+        vs1.len() == vs2.len() && vs1.iter().zip(vs2).all(|(&v1, &v2)| equal(heap, v1, v2))
+    };
+    match (v1, v2) {
+        (Value::Str(s1), Value::Str(s2)) => heap.str(s1) == heap.str(s2),
+        (Value::Tuple(t1), Value::Tuple(t2)) => all(heap.values(t1), heap.values(t2)),
+        (Value::Sum(t1, c1, v1), Value::Sum(t2, c2, v2)) => {
+            (t1, c1) == (t2, c2) && equal(heap, heap.cell(v1), heap.cell(v2))
+        }
+        (Value::Ref(r1), Value::Ref(r2)) => equal(heap, heap.cell(r1), heap.cell(r2)),
+        (Value::Closure(c1), Value::Closure(c2)) => {
+            let (c1, c2) = (heap.closure(c1), heap.closure(c2));
+            c1.code == c2.code && all(&c1.captures, &c2.captures) && all(&c1.args, &c2.args)
+        }
+        (Value::Import(n, a1), Value::Import(m, a2)) => {
+            let args = |a: Option<Gc>| a.map_or(&[][..], |a| heap.values(a));
+            n == m && all(args(a1), args(a2))
+        }
+        _ => v1 == v2,
+    }
+}
+
+fn write_value(program: &Program, heap: &Heap, v: Value, out: &mut String) {
+    match v {
+        Value::Unit => out.push_str("()"),
+        Value::Bool(p) => out.push_str(if p { "true" } else { "false" }),
+        Value::Int(n) => out.push_str(&n.to_string()),
+        Value::Str(s) => out.push_str(&format!("\"{}\"", heap.str(s))),
+        Value::Tuple(t) => write_tuple(program, heap, heap.values(t), out),
+        Value::Sum(n, m, payload) => {
+            out.push_str(&variant_name(program, n, m));
+            match heap.cell(payload) {
+                Value::Tuple(t) => write_tuple(program, heap, heap.values(t), out),
+                payload => {
+                    out.push(' ');
+                    write_arg(program, heap, payload, out);
+                }
+            }
+        }
+        Value::Ref(cell) => {
+            out.push_str("ref ");
+            write_arg(program, heap, heap.cell(cell), out);
+        }
+        Value::Closure(_) | Value::Import(..) => out.push_str("<fn>"),
+        Value::Constructor(n, m) => out.push_str(&variant_name(program, n, m)),
+        Value::Cont(_) | Value::Escape(_) => out.push_str("<continuation>"),
+    }
+}
+
+fn write_tuple(program: &Program, heap: &Heap, values: &[Value], out: &mut String) {
+    out.push('(');
+    for (i, &v) in values.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_value(program, heap, v, out);
+    }
+    out.push(')');
+}
+
+/// the argument of a constructor or ref, in parentheses if it's an application too
+fn write_arg(program: &Program, heap: &Heap, v: Value, out: &mut String) {
+    match v {
+        Value::Sum(..) | Value::Ref(_) => {
+            out.push('(');
+            write_value(program, heap, v, out);
+            out.push(')');
+        }
+        _ => write_value(program, heap, v, out),
+    }
+}
+
+fn variant_name(program: &Program, n: u16, m: u16) -> String {
+    match program.types.get(n as usize).and_then(|t| t.variants.get((m as usize).checked_sub(1)?)) {
+        Some(name) => name.clone(),
+        None => format!("<variant {} of type {}>", m, n),
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// a raised exception, unwinding to the closest handler
+    Exception(Value),
+    /// an exception not caught by the program, as it's printed
+    Uncaught(String),
+    /// nth effect performed outside any handler of it
+    UnhandledEffect(u16),
+    /// a builtin applied to a value it doesn't accept
+    BadArgument(&'static str),
+    /// a builtin failed with a message
+    Host(&'static str, String),
+    /// an import of the program isn't a builtin
+    UnknownImport(String),
+    /// the program isn't well-formed, e.g. an instruction applied to values of the
+    /// wrong kind or a jump out of its code
+    Invalid(&'static str),
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub error: Error,
+    /// position of the instruction that failed, else of the call or declaration
+    /// evaluating it, if the program has them
+    pub pos: Option<Pos>,
+    /// the active calls, innermost first: the called code and the call site
+    pub trace: Vec<(u16, Option<Pos>)>,
+    /// index of the top-level declaration being evaluated
+    pub global: Option<usize>,
+}
+
+impl RuntimeError {
+    pub fn report(&self, program: &Program) -> String {
+        let mut s = match self.error {
+            Error::Exception(_) => "uncaught exception".to_owned(),
+            Error::Uncaught(ref exn) => format!("uncaught exception {}", exn),
+            Error::UnhandledEffect(op) => match program.effects.get(op as usize) {
+                Some(name) => format!("unhandled effect {}", name),
+                None => format!("unhandled effect {}", op),
+            },
+            Error::BadArgument(name) => format!("invalid argument to {}", name),
+            Error::Host(name, ref message) => format!("{} failed: {}", name, message),
+            Error::UnknownImport(ref name) => format!("unknown import {}", name),
+            Error::Invalid(what) => format!("invalid program, {}", what),
+        };
+        s = format!("error: {}\n", s);
+        if let Some(pos) = self.pos {
+            s += &format!("  at {}\n", pos);
+        }
+        // consecutive calls with the same site are folded, e.g. non-tail recursion
+        let mut i = 0;
+        while i < self.trace.len() {
+            let (n, site) = self.trace[i];
+            let repeated = self.trace[i..].iter().take_while(|&&call| call == (n, site)).count();
+            match program.codes.get(n as usize).and_then(|c| c.name.as_ref()) {
+                Some(name) => s += &format!("  in {}", name),
+                None => s += &format!("  in <closure {}>", n),
+            }
+            if let Some(site) = site {
+                s += &format!(", called at {}", site);
+            }
+            if repeated > 1 {
+                s += &format!(" ({} times)", repeated);
+            }
+            s += "\n";
+            i += repeated;
+        }
+        // without positions the declaration is its index, counting those of the prelude
+        match (self.global, self.global.and_then(|i| program.positions.get(i))) {
+            (_, Some(pos)) => s += &format!("  in the declaration at {}\n", pos),
+            (Some(i), None) => s += &format!("  in the declaration {}\n", i),
+            (None, None) => (),
+        }
+        s
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// the frames of a captured continuation, the first entry is the bottom of the stack
+#[derive(Clone)]
+pub struct Kont(Vec<Entry>);
+
+impl Kont {
+    pub(crate) fn trace(&self, gray: &mut Vec<Gc>) {
+        self.0.iter().for_each(|e| e.trace(gray));
+    }
+}
+
+impl fmt::Debug for Kont {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Kont({} entries)", self.0.len())
+    }
+}
+
+
+/// a running or waiting call
+#[derive(Clone)]
+struct Frame {
+    code: u16,
+    /// next instruction
+    pc: u32,
+    locals: Vec<Value>,
+    /// the closure whose captures the code uses, if any
+    closure: Option<Gc>,
+    stack: Vec<Value>,
+    /// position of the call that made the frame, for traces
+    site: Option<Pos>,
+}
+
+impl Frame {
+    fn new(code: u16, locals: Vec<Value>, closure: Option<Gc>) -> Self {
+        Frame { code, pc: 0, locals, closure, stack: Vec::new(), site: None }
+    }
+
+    /// a frame for a code run in the environment of another frame
+    fn block(&self, code: u16) -> Self {
+        Frame::new(code, self.locals.clone(), self.closure)
+    }
+
+    fn trace(&self, gray: &mut Vec<Gc>) {
+        self.locals.iter().chain(&self.stack).for_each(|v| v.trace(gray));
+        gray.extend(self.closure);
+    }
+}
+
+#[derive(Clone)]
+enum Entry {
+    /// a frame waiting for the value of the entries above it
+    Frame(Frame),
+    /// exceptions raised above are applied to the handler closure
+    Try(Value),
+    /// delimits the computation handled by an effect handler
+    Handle(Rc<Handler>),
+}
+
+impl Entry {
+    fn trace(&self, gray: &mut Vec<Gc>) {
+        match *self {
+            Entry::Frame(ref f) => f.trace(gray),
+            Entry::Try(ref handler) => handler.trace(gray),
+            Entry::Handle(ref handler) => {
+                handler.ret.iter().chain(handler.ops.iter().map(|op| &op.1)).for_each(|v| v.trace(gray))
+            }
+        }
+    }
+}
+
+/// the arms of an effect handler as closures
+struct Handler {
+    ret: Option<Value>,
+    ops: Vec<(u16, Value)>,
+}
+
+/// what's left to do with the entries after an instruction
+enum Next {
+    /// the running frame returned a value
+    Return(Value),
+    /// apply a function to an argument, in tail position if the flag is set
+    Apply(Value, Value, bool),
+    /// call a closure with all its arguments
+    Call(Value, Vec<Value>, bool),
+    /// run a block with an exception handler
+    Try(u16, Value),
+    /// run a block with an effect handler
+    Handle(u16, Handler),
+    Perform(u16, Value),
+}
+
+enum Host {
+    Callcc,
+    Builtin(&'static Builtin),
+}
+
+pub struct Vm<'p> {
+    program: &'p Program,
+    heap: Heap,
+    consts: Vec<Value>,
+    hosts: Vec<Host>,
+    globals: Vec<Value>,
+    /// values held outside the machine while it runs, e.g. the arguments of `apply`
+    /// not applied yet
+    pinned: Vec<Value>,
+    io: Io,
+}
+
+impl<'p> Vm<'p> {
+    /// a machine for a program, writing to the standard output, without I/O allowed
+    pub fn new(program: &'p Program) -> Result<Self, Error> {
+        let mut heap = Heap::new();
+        let consts = program
+            .consts
+            .iter()
+            .map(|c| match *c {
+                Const::Int(n) => Value::Int(n),
+                Const::Str(ref s) => heap.new_str(s.clone()),
+            })
+            .collect();
+        let hosts = program
+            .imports
+            .iter()
+            .map(|name| match builtins::find(name) {
+                _ if name == "callcc" => Ok(Host::Callcc),
+                Some(b) => Ok(Host::Builtin(b)),
+                None => Err(Error::UnknownImport(name.clone())),
+            })
+            .collect::<Result<_, _>>()?;
+        let io = Io { out: Box::new(io::stdout()), caps: Capabilities::default() };
+        Ok(Vm { program, heap, consts, hosts, globals: Vec::new(), pinned: Vec::new(), io })
+    }
+
+    /// write the output of print to out instead
+    pub fn with_output(mut self, out: Box<dyn Write>) -> Self {
+        self.io.out = out;
+        self
+    }
+
+    pub fn with_capabilities(mut self, caps: Capabilities) -> Self {
+        self.io.caps = caps;
+        self
+    }
+
+    /// collect the garbage whenever an object was allocated, see `Heap::set_stress`
+    pub fn with_gc_stress(mut self) -> Self {
+        self.heap.set_stress(true);
+        self
+    }
+
+    /// evaluate the top-level declarations in order
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        let program = self.program;
+        for (i, &code) in program.globals.iter().enumerate() {
+            let result = self.exec(Frame::new(code, Vec::new(), None), None);
+            let _ = self.io.out.flush();
+            if let Err(mut e) = result {
+                // the declaration is reported by its position rather than as a call
+                if e.trace.last().map(|call| call.0) == Some(code) {
+                    e.trace.pop();
+                }
+                e.pos = e.pos.or_else(|| program.positions.get(i).copied());
+                e.global = Some(i);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// value of nth top-level declaration once it's evaluated
+    pub fn global(&self, n: usize) -> Option<Value> {
+        self.globals.get(n).copied()
+    }
+
+    /// apply a function to arguments, one at a time
+    pub fn apply(&mut self, mut f: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let pinned = self.pinned.len();
+        self.pinned.extend(args.into_iter().rev());
+        while self.pinned.len() > pinned {
+            let v = self.pinned.pop().unwrap_or(Value::Unit);
+            // replaced by the call, the application is in tail position
+            let result = self.exec(Frame::new(0, Vec::new(), None), Some(Next::Apply(f, v, true)));
+            match result {
+                Ok(result) => f = result,
+                Err(e) => {
+                    self.pinned.truncate(pinned);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(f)
+    }
+
+    /// the heap of the values of the machine
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// the value written with the names of the constructors of the program
+    pub fn show(&self, v: Value) -> String {
+        let mut s = String::new();
+        write_value(self.program, &self.heap, v, &mut s);
+        s
+    }
+
+    /// run a frame, after what's left of an instruction if any, until it returns
+    fn exec(&mut self, mut frame: Frame, mut next: Option<Next>) -> Result<Value, RuntimeError> {
+        let mut entries = Vec::new();
+        loop {
+            let result = match next.take() {
+                Some(n) => self.resolve(n, &mut frame, &mut entries),
+                None => {
+                    // between instructions every value is in the frame or the entries
+                    if self.heap.wants_collection() {
+                        self.collect(&frame, &entries);
+                    }
+                    match self.step(&mut frame) {
+                        Ok(None) => continue,
+                        Ok(Some(n)) => self.resolve(n, &mut frame, &mut entries),
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            match result {
+                Ok(Some(v)) => return Ok(v),
+                Ok(None) => (),
+                Err(e) => next = Some(self.fail(e, &frame, &mut entries)?),
+            }
+        }
+    }
+
+    fn collect(&mut self, frame: &Frame, entries: &[Entry]) {
+        let mut gray = Vec::new();
+        for v in self.consts.iter().chain(&self.globals).chain(&self.pinned) {
+            v.trace(&mut gray);
+        }
+        frame.trace(&mut gray);
+        entries.iter().for_each(|e| e.trace(&mut gray));
+        self.heap.collect(gray);
+    }
+
+    /// run one instruction of the frame, what's left to do with the entries if any
+    fn step(&mut self, frame: &mut Frame) -> Result<Option<Next>, Error> {
+        let program = self.program;
+        let heap = &mut self.heap;
+        let code = program.codes.get(frame.code as usize).ok_or(Error::Invalid("no such code"))?;
+        let instr = *code.instrs.get(frame.pc as usize).ok_or(Error::Invalid("jump out of the code"))?;
+        frame.pc += 1;
+        let stack = &mut frame.stack;
+        match instr {
+            Instr::Const(n) => stack.push(*self.consts.get(n as usize).ok_or(Error::Invalid("no such constant"))?),
+            Instr::Unit => stack.push(Value::Unit),
+            Instr::Bool(p) => stack.push(Value::Bool(p)),
+            Instr::Local(n) => stack.push(*frame.locals.get(n as usize).ok_or(Error::Invalid("no such local"))?),
+            Instr::Capture(n) => {
+                let v = match frame.closure {
+                    Some(c) => heap.closure(c).captures.get(n as usize).copied(),
+                    None => None,
+                };
+                stack.push(v.ok_or(Error::Invalid("no such capture"))?)
+            }
+            Instr::Global(n) => {
+                stack.push(*self.globals.get(n as usize).ok_or(Error::Invalid("global used before it's set"))?)
+            }
+            Instr::SetGlobal(n) => {
+                let v = pop(stack)?;
+                if self.globals.len() <= n as usize {
+                    self.globals.resize(n as usize + 1, Value::Unit);
+                }
+                self.globals[n as usize] = v;
+            }
+            Instr::Dup => {
+                let v = *stack.last().ok_or(Error::Invalid("empty stack"))?;
+                stack.push(v);
+            }
+            Instr::Pop => {
+                pop(stack)?;
+            }
+            Instr::Field(n) => {
+                let v = match pop(stack)? {
+                    Value::Tuple(t) => heap.values(t).get(n as usize).copied(),
+                    _ => None,
+                };
+                stack.push(v.ok_or(Error::Invalid("field of a value that isn't a tuple"))?);
+            }
+            Instr::Tag => match pop(stack)? {
+                Value::Sum(_, m, _) => stack.push(Value::Int(m as isize - 1)),
+                _ => return Err(Error::Invalid("tag of a value that isn't a sum")),
+            },
+            Instr::Payload => match pop(stack)? {
+                Value::Sum(_, _, v) => stack.push(heap.cell(v)),
+                _ => return Err(Error::Invalid("payload of a value that isn't a sum")),
+            },
+            Instr::Tuple(n) => {
+                let values = split_top(stack, n as usize)?;
+                stack.push(heap.new_tuple(values));
+            }
+            Instr::Construct(n, m) => {
+                let v = pop(stack)?;
+                stack.push(heap.new_sum(n, m, v));
+            }
+            Instr::Constructor(n, m) => stack.push(Value::Constructor(n, m)),
+            Instr::Import(n) => stack.push(Value::Import(n, None)),
+            Instr::Closure(n) => {
+                let captures = program.codes.get(n as usize).ok_or(Error::Invalid("no such code"))?.captures;
+                let captures = split_top(stack, captures as usize)?;
+                let closure = heap.alloc(Object::Closure(Closure { code: n, captures, args: Vec::new() }));
+                stack.push(Value::Closure(closure));
+            }
+            Instr::Apply | Instr::TailApply => {
+                let v = pop(stack)?;
+                let f = pop(stack)?;
+                return Ok(Some(Next::Apply(f, v, instr == Instr::TailApply)));
+            }
+            Instr::Call(n) | Instr::TailCall(n) => {
+                let args = split_top(stack, n as usize)?;
+                let f = pop(stack)?;
+                return Ok(Some(Next::Call(f, args, instr != Instr::Call(n))));
+            }
+            Instr::Return => return Ok(Some(Next::Return(pop(stack)?))),
+            Instr::Jump(target) => frame.pc = target,
+            Instr::JumpIfFalse(target) => match pop(stack)? {
+                Value::Bool(true) => (),
+                Value::Bool(false) => frame.pc = target,
+                _ => return Err(Error::Invalid("condition isn't a boolean")),
+            },
+            Instr::Switch(n) => {
+                let v = pop(stack)?;
+                frame.pc = match (code.tables.get(n as usize), v) {
+                    (Some(&Table::Jump { ref targets, default }), Value::Int(tag)) => {
+                        targets.get(tag as usize).copied().unwrap_or(default)
+                    }
+                    (Some(&Table::Jump { ref targets, default }), Value::Bool(p)) => {
+                        targets.get(if p { 0 } else { 1 }).copied().unwrap_or(default)
+                    }
+                    (Some(&Table::Int { ref cases, default }), Value::Int(n)) => {
+                        match cases.binary_search_by_key(&n, |&(m, _)| m) {
+                            Ok(i) => cases[i].1,
+                            Err(_) => default,
+                        }
+                    }
+                    (Some(&Table::Str { ref cases, default }), Value::Str(s)) => {
+                        let s = heap.str(s);
+                        let found = cases.binary_search_by(|&(c, _)| match program.consts.get(c as usize) {
+                            Some(Const::Str(c)) => c.as_str().cmp(s),
+                            _ => Ordering::Less,
+                        });
+                        match found {
+                            Ok(i) => cases[i].1,
+                            Err(_) => default,
+                        }
+                    }
+                    _ => return Err(Error::Invalid("switch on a value its table doesn't match")),
+                };
+            }
+            Instr::BinOp(op) => {
+                let v2 = pop(stack)?;
+                let v1 = pop(stack)?;
+                stack.push(binop(heap, op, v1, v2)?);
+            }
+            Instr::UnOp(op) => {
+                let v = pop(stack)?;
+                stack.push(unop(heap, op, v)?);
+            }
+            Instr::Raise => return Err(Error::Exception(pop(stack)?)),
+            Instr::Fail => return Err(builtin_exn(heap, EXN_MATCH_FAILURE)),
+            Instr::Try(block) => return Ok(Some(Next::Try(block, pop(stack)?))),
+            Instr::Handle(n) => {
+                let (body, ret, ops) = match code.tables.get(n as usize) {
+                    Some(&Table::Handler { body, ret, ref ops }) => (body, ret, ops),
+                    _ => return Err(Error::Invalid("handle without a handler table")),
+                };
+                let arms = split_top(stack, ops.len())?;
+                let ret = if ret { Some(pop(stack)?) } else { None };
+                let ops = ops.iter().copied().zip(arms).collect();
+                return Ok(Some(Next::Handle(body, Handler { ret, ops })));
+            }
+            Instr::Perform(op) => return Ok(Some(Next::Perform(op, pop(stack)?))),
+        }
+        Ok(None)
+    }
+
+    /// Do what's left of an instruction with the entries until a frame can run again.
+    /// If a value is returned to the bottom of the entries it's the result.
+    fn resolve(&mut self, mut next: Next, frame: &mut Frame, entries: &mut Vec<Entry>) -> Result<Option<Value>, Error> {
+        loop {
+            next = match next {
+                Next::Return(v) => match entries.pop() {
+                    None => return Ok(Some(v)),
+                    Some(Entry::Frame(f)) => {
+                        *frame = f;
+                        frame.stack.push(v);
+                        return Ok(None);
+                    }
+                    Some(Entry::Try(_)) => Next::Return(v),
+                    Some(Entry::Handle(handler)) => match handler.ret {
+                        // the frame of the body returned, the arm replaces it
+                        Some(ret) => Next::Apply(ret, v, true),
+                        None => Next::Return(v),
+                    },
+                },
+                Next::Call(f, args, tail) => match f {
+                    Value::Closure(c) => {
+                        let closure = self.heap.closure(c);
+                        let code = self.program.codes.get(closure.code as usize).ok_or(Error::Invalid("no such code"))?;
+                        if !closure.args.is_empty() || code.arity as usize != args.len() {
+                            return Err(Error::Invalid("call of a closure of another arity"));
+                        }
+                        self.enter(Frame::new(closure.code, args, Some(c)), tail, frame, entries);
+                        return Ok(None);
+                    }
+                    _ => return Err(Error::Invalid("call of a value that isn't a closure")),
+                },
+                Next::Apply(f, v, tail) => match f {
+                    Value::Closure(c) => {
+                        let closure = self.heap.closure(c);
+                        let code = self.program.codes.get(closure.code as usize).ok_or(Error::Invalid("no such code"))?;
+                        if closure.args.len() + 1 < code.arity as usize {
+                            let mut args = closure.args.clone();
+                            args.push(v);
+                            let partial = Closure { code: closure.code, captures: closure.captures.clone(), args };
+                            let partial = Value::Closure(self.heap.alloc(Object::Closure(partial)));
+                            match Self::value(partial, tail, frame) {
+                                Some(next) => next,
+                                None => return Ok(None),
+                            }
+                        } else {
+                            let mut args = closure.args.clone();
+                            args.push(v);
+                            self.enter(Frame::new(closure.code, args, Some(c)), tail, frame, entries);
+                            return Ok(None);
+                        }
+                    }
+                    Value::Constructor(n, m) => match Self::value(self.heap.new_sum(n, m, v), tail, frame) {
+                        Some(next) => next,
+                        None => return Ok(None),
+                    },
+                    Value::Import(n, applied) => match self.hosts.get(n as usize) {
+                        Some(Host::Callcc) => {
+                            let mut k = entries.clone();
+                            if !tail {
+                                k.push(Entry::Frame(frame.clone()));
+                            }
+                            let k = self.heap.alloc(Object::Kont(Kont(k)));
+                            Next::Apply(v, Value::Escape(k), tail)
+                        }
+                        Some(&Host::Builtin(b)) => {
+                            let mut args = applied.map_or_else(Vec::new, |a| self.heap.values(a).to_vec());
+                            args.push(v);
+                            let result = if args.len() < b.arity {
+                                Value::Import(n, Some(self.heap.alloc(Object::Values(args))))
+                            } else {
+                                (b.call)(&mut self.io, &mut self.heap, &args)?
+                            };
+                            match Self::value(result, tail, frame) {
+                                Some(next) => next,
+                                None => return Ok(None),
+                            }
+                        }
+                        None => return Err(Error::Invalid("no such import")),
+                    },
+                    Value::Cont(k) => {
+                        if !tail {
+                            entries.push(Entry::Frame(frame.clone()));
+                        }
+                        entries.extend(self.heap.kont(k).0.iter().cloned());
+                        Next::Return(v)
+                    }
+                    Value::Escape(k) => {
+                        entries.clone_from(&self.heap.kont(k).0);
+                        Next::Return(v)
+                    }
+                    _ => return Err(Error::Invalid("application of a value that isn't a function")),
+                },
+                Next::Try(block, handler) => {
+                    let body = frame.block(block);
+                    entries.push(Entry::Frame(mem::replace(frame, body)));
+                    entries.push(Entry::Try(handler));
+                    return Ok(None);
+                }
+                Next::Handle(block, handler) => {
+                    let body = frame.block(block);
+                    entries.push(Entry::Frame(mem::replace(frame, body)));
+                    entries.push(Entry::Handle(Rc::new(handler)));
+                    return Ok(None);
+                }
+                Next::Perform(op, v) => {
+                    let found = entries.iter().enumerate().rev().find_map(|(i, entry)| match entry {
+                        Entry::Handle(handler) => {
+                            handler.ops.iter().find(|&&(effect, _)| effect == op).map(|&(_, arm)| (i, arm))
+                        }
+                        _ => None,
+                    });
+                    let (i, arm) = found.ok_or(Error::UnhandledEffect(op))?;
+                    let mut k = entries.split_off(i);
+                    k.push(Entry::Frame(frame.clone()));
+                    let arm = match arm {
+                        Value::Closure(c) => {
+                            let c = self.heap.closure(c);
+                            let mut args = c.args.clone();
+                            args.push(v);
+                            Closure { code: c.code, captures: c.captures.clone(), args }
+                        }
+                        _ => return Err(Error::Invalid("effect handler arm isn't a closure")),
+                    };
+                    let arm = self.heap.alloc(Object::Closure(arm));
+                    let k = self.heap.alloc(Object::Kont(Kont(k)));
+                    Next::Apply(Value::Closure(arm), Value::Cont(k), true)
+                }
+            }
+        }
+    }
+
+    /// the value of an application evaluated without a call, returned in tail position
+    fn value(v: Value, tail: bool, frame: &mut Frame) -> Option<Next> {
+        if tail {
+            Some(Next::Return(v))
+        } else {
+            frame.stack.push(v);
+            None
+        }
+    }
+
+    fn enter(&self, mut called: Frame, tail: bool, frame: &mut Frame, entries: &mut Vec<Entry>) {
+        called.site = self.position(frame);
+        let caller = mem::replace(frame, called);
+        if !tail {
+            entries.push(Entry::Frame(caller));
+        }
+    }
+
+    /// Unwind the entries to the closest exception handler for an exception, the
+    /// handler is applied to it in place of the frame. Other errors stop the machine.
+    fn fail(&self, e: Error, frame: &Frame, entries: &mut Vec<Entry>) -> Result<Next, RuntimeError> {
+        let exn = match e {
+            Error::Exception(exn) => exn,
+            e => return Err(self.error(e, frame, entries)),
+        };
+        let found = entries.iter().rposition(|entry| matches!(entry, Entry::Try(_)));
+        match found.map(|i| entries.drain(i..).next()) {
+            Some(Some(Entry::Try(handler))) => Ok(Next::Apply(handler, exn, true)),
+            _ => Err(self.error(Error::Exception(exn), frame, entries)),
+        }
+    }
+
+    fn error(&self, error: Error, frame: &Frame, entries: &[Entry]) -> RuntimeError {
+        let waiting = entries.iter().rev().filter_map(|entry| match entry {
+            Entry::Frame(f) => Some((f.code, f.site)),
+            _ => None,
+        });
+        let trace = Some((frame.code, frame.site)).into_iter().chain(waiting).collect();
+        let pos = self.position(frame).or(frame.site);
+        // the exception is printed before its objects can be collected
+        let error = match error {
+            Error::Exception(exn) => Error::Uncaught(self.show(exn)),
+            e => e,
+        };
+        RuntimeError { error, pos, trace, global: None }
+    }
+
+    /// position of the last instruction run in a frame, if the program has it
+    fn position(&self, frame: &Frame) -> Option<Pos> {
+        let code = self.program.codes.get(frame.code as usize)?;
+        code.position(frame.pc.checked_sub(1)?)
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, Error> {
+    stack.pop().ok_or(Error::Invalid("empty stack"))
+}
+
+/// the last n values of the stack, in order
+fn split_top(stack: &mut Vec<Value>, n: usize) -> Result<Vec<Value>, Error> {
+    if stack.len() < n {
+        return Err(Error::Invalid("empty stack"));
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+/// the value of a built-in exception
+fn builtin_exn(heap: &mut Heap, position: u16) -> Error {
+    Error::Exception(heap.new_sum(EXN_TYPE, position, Value::Unit))
+}
+
+fn unop(heap: &mut Heap, op: UnOp, v: Value) -> Result<Value, Error> {
+    match (op, v) {
+        (UnOp::Not, Value::Bool(p)) => Ok(Value::Bool(!p)),
+        (UnOp::Neg, Value::Int(n)) => Ok(Value::Int(n.wrapping_neg())),
+        (UnOp::Ref, _) => Ok(Value::Ref(heap.alloc(Object::Cell(v)))),
+        (UnOp::Deref, Value::Ref(cell)) => Ok(heap.cell(cell)),
+        _ => Err(Error::Invalid("operand of the wrong kind")),
+    }
+}
+
+fn binop(heap: &mut Heap, op: BinOp, v1: Value, v2: Value) -> Result<Value, Error> {
+    let mismatch = Error::Invalid("operands of the wrong kind");
+    if let BinOp::Assign = op {
+        return match v1 {
+            Value::Ref(cell) => {
+                *heap.get_mut(cell) = Object::Cell(v2);
+                Ok(Value::Unit)
+            }
+            _ => Err(mismatch),
+        };
+    }
+    if let BinOp::Eq | BinOp::Ne = op {
+        let equal = match (v1, v2) {
+            // references are equal only if they are the same cell
+            (Value::Ref(r1), Value::Ref(r2)) => r1 == r2,
+            (Value::Int(_), Value::Int(_))
+            | (Value::Bool(_), Value::Bool(_))
+            | (Value::Unit, Value::Unit)
+            | (Value::Str(_), Value::Str(_))
+            | (Value::Tuple(_), Value::Tuple(_))
+            | (Value::Sum(..), Value::Sum(..))
+            | (Value::Closure(_), Value::Closure(_)) => equal(heap, v1, v2),
+            _ => return Err(mismatch),
+        };
+        return Ok(Value::Bool(equal == (op == BinOp::Eq)));
+    }
+    match (v1, v2) {
+        (Value::Int(n), Value::Int(m)) => match op {
+            // integers wrap around on overflow, as in the interpreter
+            BinOp::Add => Ok(Value::Int(n.wrapping_add(m))),
+            BinOp::Sub => Ok(Value::Int(n.wrapping_sub(m))),
+            BinOp::Mul => Ok(Value::Int(n.wrapping_mul(m))),
+            BinOp::Div | BinOp::Mod if m == 0 => Err(builtin_exn(heap, EXN_DIV_BY_ZERO)),
+            BinOp::Div => Ok(Value::Int(n.wrapping_div(m))),
+            BinOp::Mod => Ok(Value::Int(n.wrapping_rem(m))),
+            BinOp::Gt => Ok(Value::Bool(n > m)),
+            BinOp::Ge => Ok(Value::Bool(n >= m)),
+            BinOp::Lt => Ok(Value::Bool(n < m)),
+            BinOp::Le => Ok(Value::Bool(n <= m)),
+            _ => Err(mismatch),
+        },
+        (Value::Bool(p), Value::Bool(q)) => match op {
+            BinOp::And => Ok(Value::Bool(p && q)),
+            BinOp::Or => Ok(Value::Bool(p || q)),
+            _ => Err(mismatch),
+        },
+        (Value::Str(s1), Value::Str(s2)) if op == BinOp::Concat => {
+            let (s1, s2) = (heap.str(s1), heap.str(s2));
+            let mut s = String::with_capacity(s1.len() + s2.len());
+            s.push_str(s1);
+            s.push_str(s2);
+            Ok(heap.new_str(s))
+        }
+        _ => Err(mismatch),
+    }
+}